@@ -0,0 +1,1026 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use super::{
+    BusActivity, BusDevice, EmuResult, ReadResult, audio::AudioOutput, console_event::ConsoleEvent,
+    scheduler::EventQueue, signal::WiredOrSource,
+};
+
+const OAM_DMA_OFFSET: u32 = 0x14;
+const STATUS_OFFSET: u32 = 0x15;
+const FRAME_COUNTER_OFFSET: u32 = 0x17;
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14, 12, 16, 24, 18, 48, 20, 96, 22,
+    192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+const TRIANGLE_TABLE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12,
+    13, 14, 15,
+];
+
+/// The APU's timer periods and frame counter steps, in CPU cycles. PAL
+/// consoles run the APU from a slower CPU clock, and retune the tables to
+/// keep the pitches and frame rate close to NTSC's.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ApuRates {
+    noise_periods: [u16; 16],
+    dmc_periods: [u16; 16],
+    /// Cycles since the sequence started for steps 1 - 3, the 4-step
+    /// sequence's last step, and the 5-step sequence's
+    frame_steps: [u32; 5],
+}
+
+impl ApuRates {
+    pub const NTSC: ApuRates = ApuRates {
+        noise_periods: [
+            4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+        ],
+        dmc_periods: [
+            428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+        ],
+        frame_steps: [7457, 14913, 22371, 29829, 37281],
+    };
+
+    pub const PAL: ApuRates = ApuRates {
+        noise_periods: [
+            4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+        ],
+        dmc_periods: [
+            398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+        ],
+        frame_steps: [8313, 16627, 24939, 33253, 41565],
+    };
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    fn write(&mut self, data: u8) {
+        self.looping = data & 0x20 != 0;
+        self.constant = data & 0x10 != 0;
+        self.volume = data & 0x0F;
+    }
+
+    /// Quarter frame clock
+    pub(crate) fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.constant {
+            self.volume
+        } else {
+            self.decay
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    pub(crate) count: u8,
+}
+
+impl LengthCounter {
+    fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[(index >> 3) as usize];
+        }
+    }
+
+    pub(crate) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    /// Half frame clock
+    pub(crate) fn clock(&mut self) {
+        if !self.halt && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+}
+
+/// A pulse channel. The MMC5 has two more of these, without sweep units.
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Pulse {
+    /// Pulse 1 negates its sweep with ones' complement, pulse 2 with two's
+    ones_complement: bool,
+    /// Without a sweep unit, nothing mutes the channel
+    has_sweep: bool,
+    pub(crate) envelope: Envelope,
+    pub(crate) length: LengthCounter,
+    duty: u8,
+    sequence_pos: u8,
+    timer_period: u16,
+    timer: u16,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_reload: bool,
+    sweep_divider: u8,
+}
+
+impl Pulse {
+    pub(crate) fn new(ones_complement: bool, has_sweep: bool) -> Self {
+        Pulse {
+            ones_complement,
+            has_sweep,
+            ..Default::default()
+        }
+    }
+
+    /// Write one of the channel's four registers
+    pub(crate) fn write(&mut self, reg: u32, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0x80 != 0;
+                self.sweep_period = (data >> 4) & 0x07;
+                self.sweep_negate = data & 0x08 != 0;
+                self.sweep_shift = data & 0x07;
+                self.sweep_reload = true;
+            }
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data);
+                self.sequence_pos = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let extra = if self.ones_complement { 1 } else { 0 };
+            self.timer_period.saturating_sub(change + extra)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    fn muted(&self) -> bool {
+        self.has_sweep && (self.timer_period < 8 || self.sweep_target() > 0x7FF)
+    }
+
+    /// Clocked every other CPU cycle
+    pub(crate) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence_pos = (self.sequence_pos + 1) & 0x07;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    pub(crate) fn output(&self) -> u8 {
+        if self.length.count == 0
+            || self.muted()
+            || DUTY_TABLE[self.duty as usize][self.sequence_pos as usize] == 0
+        {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Triangle {
+    length: LengthCounter,
+    linear_reload_value: u8,
+    linear_reload: bool,
+    linear_counter: u8,
+    timer_period: u16,
+    timer: u16,
+    sequence_pos: u8,
+}
+
+impl Triangle {
+    fn write(&mut self, reg: u32, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x80 != 0;
+                self.linear_reload_value = data & 0x7F;
+            }
+            1 => {}
+            2 => self.timer_period = (self.timer_period & 0x0700) | data as u16,
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((data as u16 & 0x07) << 8);
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.count > 0 && self.linear_counter > 0 {
+                self.sequence_pos = (self.sequence_pos + 1) & 0x1F;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        // The control flag doubles as the length counter halt flag
+        if !self.length.halt {
+            self.linear_reload = false;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        TRIANGLE_TABLE[self.sequence_pos as usize]
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Noise {
+    envelope: Envelope,
+    length: LengthCounter,
+    short_mode: bool,
+    periods: &'static [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    shift: u16,
+}
+
+impl Noise {
+    fn new(periods: &'static [u16; 16]) -> Self {
+        Noise {
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            short_mode: false,
+            periods,
+            timer_period: periods[0],
+            timer: 0,
+            shift: 1,
+        }
+    }
+
+    fn write(&mut self, reg: u32, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0x20 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0x80 != 0;
+                self.timer_period = self.periods[(data & 0x0F) as usize];
+            }
+            _ => {
+                self.length.load(data);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    /// Clocked every CPU cycle
+    fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            let tap = if self.short_mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.length.count == 0 || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+}
+
+/// Way back to the DMC for sample bytes. The DMC schedules a
+/// ConsoleEvent::DmcFetch with the address of the next byte, and whatever
+/// owns the CPU bus reads it and hands the byte back here.
+#[derive(Clone, Debug, Default)]
+pub struct DmcDma {
+    response: Rc<Cell<Option<u8>>>,
+}
+
+impl DmcDma {
+    pub fn complete(&self, data: u8) {
+        self.response.set(Some(data));
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Dmc {
+    irq_enabled: bool,
+    irq: bool,
+    looping: bool,
+    periods: &'static [u16; 16],
+    timer_period: u16,
+    timer: u16,
+    output_level: u8,
+    sample_addr: u16,
+    sample_len: u16,
+    current_addr: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    dma_pending: bool,
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    fn new(periods: &'static [u16; 16]) -> Self {
+        Dmc {
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            periods,
+            timer_period: periods[0],
+            timer: 0,
+            output_level: 0,
+            sample_addr: 0xC000,
+            sample_len: 1,
+            current_addr: 0xC000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            dma_pending: false,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+        }
+    }
+
+    fn write(&mut self, reg: u32, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & 0x80 != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & 0x40 != 0;
+                self.timer_period = self.periods[(data & 0x0F) as usize];
+            }
+            1 => self.output_level = data & 0x7F,
+            2 => self.sample_addr = 0xC000 | ((data as u16) << 6),
+            _ => self.sample_len = ((data as u16) << 4) | 1,
+        }
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_addr = self.sample_addr;
+        self.bytes_remaining = self.sample_len;
+    }
+
+    /// Clocked every CPU cycle. Returns a sample address to fetch, if the
+    /// sample buffer needs refilling.
+    fn clock(&mut self, dma: &DmcDma) -> Option<u16> {
+        if let Some(data) = dma.response.take() {
+            self.dma_pending = false;
+            self.sample_buffer = Some(data);
+            self.current_addr = self.current_addr.checked_add(1).unwrap_or(0x8000);
+            self.bytes_remaining -= 1;
+            if self.bytes_remaining == 0 {
+                if self.looping {
+                    self.restart();
+                } else if self.irq_enabled {
+                    self.irq = true;
+                }
+            }
+        }
+
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+            if !self.silence {
+                if self.shift & 1 != 0 {
+                    if self.output_level <= 125 {
+                        self.output_level += 2;
+                    }
+                } else if self.output_level >= 2 {
+                    self.output_level -= 2;
+                }
+            }
+            self.shift >>= 1;
+            self.bits_remaining -= 1;
+            if self.bits_remaining == 0 {
+                self.bits_remaining = 8;
+                match self.sample_buffer.take() {
+                    Some(data) => {
+                        self.silence = false;
+                        self.shift = data;
+                    }
+                    None => self.silence = true,
+                }
+            }
+        } else {
+            self.timer -= 1;
+        }
+
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 && !self.dma_pending {
+            self.dma_pending = true;
+            return Some(self.current_addr);
+        }
+        None
+    }
+}
+
+/// What one channel is doing, for debuggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChannelState {
+    /// Length counter, or bytes left in the sample for the DMC
+    pub length: u16,
+    /// Timer period in CPU cycles, or APU cycles for the pulse channels
+    pub period: u16,
+    /// Level the channel is sending to the mixer
+    pub output: u8,
+}
+
+/// Snapshot of the APU's channels and frame counter, for debuggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApuState {
+    pub pulse1: ChannelState,
+    pub pulse2: ChannelState,
+    pub triangle: ChannelState,
+    pub noise: ChannelState,
+    pub dmc: ChannelState,
+    pub five_step_mode: bool,
+    pub frame_irq: bool,
+    pub dmc_irq: bool,
+}
+
+/// The 2A03's APU and I/O block at 0x4000 - 0x4017: two pulse channels,
+/// triangle, noise, DMC and the frame counter, mixed through the nonlinear
+/// DAC. Output goes to an AudioOutput if one is attached.
+///
+/// Most of the APU runs off the CPU's clock through tick, but the pulse
+/// timers only count every other cycle, on the half-rate clock that drives
+/// clock_apu_cycle.
+///
+/// DMC sample fetches and writes to $4014 are scheduled as ConsoleEvents
+/// for the bus owner to carry out, once the APU is given an event queue.
+/// DMC fetches don't stall the CPU yet. The frame counter and DMC each drive
+/// their own source on the CPU's IRQ line, once given one.
+#[derive(Debug)]
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    dmc_dma: DmcDma,
+    events: Option<EventQueue<ConsoleEvent>>,
+
+    five_step_mode: bool,
+    frame_irq_inhibit: bool,
+    frame_irq: bool,
+    frame_cycle: u32,
+    rates: &'static ApuRates,
+
+    audio_output: Option<AudioOutput>,
+    frame_irq_line: Option<WiredOrSource>,
+    dmc_irq_line: Option<WiredOrSource>,
+}
+
+impl Apu {
+    pub fn new(rates: &'static ApuRates) -> Self {
+        Apu {
+            pulse1: Pulse::new(true, true),
+            pulse2: Pulse::new(false, true),
+            triangle: Triangle::default(),
+            noise: Noise::new(&rates.noise_periods),
+            dmc: Dmc::new(&rates.dmc_periods),
+            dmc_dma: DmcDma::default(),
+            events: None,
+            five_step_mode: false,
+            frame_irq_inhibit: false,
+            frame_irq: false,
+            frame_cycle: 0,
+            rates,
+            audio_output: None,
+            frame_irq_line: None,
+            dmc_irq_line: None,
+        }
+    }
+
+    pub fn with_audio_output(mut self, audio_output: AudioOutput) -> Self {
+        self.audio_output = Some(audio_output);
+        self
+    }
+
+    /// Let the frame counter and DMC assert the CPU's IRQ line
+    pub fn with_irq_lines(mut self, frame: WiredOrSource, dmc: WiredOrSource) -> Self {
+        self.frame_irq_line = Some(frame);
+        self.dmc_irq_line = Some(dmc);
+        self
+    }
+
+    /// Schedule DMC sample fetches and sprite DMA on the console's events
+    pub fn with_events(mut self, events: EventQueue<ConsoleEvent>) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn dmc_dma(&self) -> DmcDma {
+        self.dmc_dma.clone()
+    }
+
+    /// Clocked every other CPU cycle, by the APU's half-rate clock
+    pub fn clock_apu_cycle(&mut self) {
+        self.pulse1.clock_timer();
+        self.pulse2.clock_timer();
+    }
+
+    fn schedule(&self, event: ConsoleEvent) {
+        if let Some(events) = &self.events {
+            events.schedule_in(0, event);
+        }
+    }
+
+    pub fn state(&self) -> ApuState {
+        let pulse = |pulse: &Pulse| ChannelState {
+            length: pulse.length.count as u16,
+            period: pulse.timer_period,
+            output: pulse.output(),
+        };
+        ApuState {
+            pulse1: pulse(&self.pulse1),
+            pulse2: pulse(&self.pulse2),
+            triangle: ChannelState {
+                length: self.triangle.length.count as u16,
+                period: self.triangle.timer_period,
+                output: self.triangle.output(),
+            },
+            noise: ChannelState {
+                length: self.noise.length.count as u16,
+                period: self.noise.timer_period,
+                output: self.noise.output(),
+            },
+            dmc: ChannelState {
+                length: self.dmc.bytes_remaining,
+                period: self.dmc.timer_period,
+                output: self.dmc.output_level,
+            },
+            five_step_mode: self.five_step_mode,
+            frame_irq: self.frame_irq,
+            dmc_irq: self.dmc.irq,
+        }
+    }
+
+    /// Whether the frame counter or DMC is requesting an interrupt
+    pub fn irq_pending(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    fn update_irq(&mut self) {
+        if let Some(line) = &mut self.frame_irq_line {
+            line.set(self.frame_irq);
+        }
+        if let Some(line) = &mut self.dmc_irq_line {
+            line.set(self.dmc.irq);
+        }
+    }
+
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear();
+    }
+
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    fn clock_frame_counter(&mut self) {
+        self.frame_cycle += 1;
+        let steps = &self.rates.frame_steps;
+        let cycle = self.frame_cycle;
+        if cycle == steps[0] || cycle == steps[2] {
+            self.clock_quarter_frame();
+        } else if cycle == steps[1] {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        } else if cycle == steps[3] && !self.five_step_mode {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            if !self.frame_irq_inhibit {
+                self.frame_irq = true;
+            }
+            self.frame_cycle = 0;
+        } else if cycle == steps[4] && self.five_step_mode {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+            self.frame_cycle = 0;
+        }
+    }
+
+    /// The mixer's output level, from 0.0 to about 1.0
+    fn mix(&self) -> f32 {
+        let pulse = (self.pulse1.output() + self.pulse2.output()) as f32;
+        let pulse_out = if pulse == 0.0 {
+            0.0
+        } else {
+            95.88 / (8128.0 / pulse + 100.0)
+        };
+        let tnd = self.triangle.output() as f32 / 8227.0
+            + self.noise.output() as f32 / 12241.0
+            + self.dmc.output_level as f32 / 22638.0;
+        let tnd_out = if tnd == 0.0 {
+            0.0
+        } else {
+            159.79 / (1.0 / tnd + 100.0)
+        };
+        pulse_out + tnd_out
+    }
+}
+
+impl BusDevice for Apu {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        match addr {
+            STATUS_OFFSET => {
+                let status = (self.pulse1.length.count > 0) as u8
+                    | ((self.pulse2.length.count > 0) as u8) << 1
+                    | ((self.triangle.length.count > 0) as u8) << 2
+                    | ((self.noise.length.count > 0) as u8) << 3
+                    | ((self.dmc.bytes_remaining > 0) as u8) << 4
+                    | (self.frame_irq as u8) << 6
+                    | (self.dmc.irq as u8) << 7;
+                self.frame_irq = false;
+                self.update_irq();
+                Ok(ReadResult::Data(status))
+            }
+            // Every other register in this range is write-only
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        match addr {
+            0x00..=0x03 => self.pulse1.write(addr, data),
+            0x04..=0x07 => self.pulse2.write(addr - 0x04, data),
+            0x08..=0x0B => self.triangle.write(addr - 0x08, data),
+            0x0C..=0x0F => self.noise.write(addr - 0x0C, data),
+            0x10..=0x13 => self.dmc.write(addr - 0x10, data),
+            OAM_DMA_OFFSET => self.schedule(ConsoleEvent::SpriteDma(data)),
+            STATUS_OFFSET => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+                self.triangle.length.set_enabled(data & 0x04 != 0);
+                self.noise.length.set_enabled(data & 0x08 != 0);
+                self.dmc.set_enabled(data & 0x10 != 0);
+            }
+            FRAME_COUNTER_OFFSET => {
+                self.five_step_mode = data & 0x80 != 0;
+                self.frame_irq_inhibit = data & 0x40 != 0;
+                if self.frame_irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                if self.five_step_mode {
+                    self.clock_quarter_frame();
+                    self.clock_half_frame();
+                }
+            }
+            _ => {}
+        }
+        self.update_irq();
+        Ok(())
+    }
+
+    fn power_on(&mut self) {
+        let audio_output = self.audio_output.take();
+        let frame_irq_line = self.frame_irq_line.take();
+        let dmc_irq_line = self.dmc_irq_line.take();
+        let dmc_dma = self.dmc_dma.clone();
+        dmc_dma.response.take();
+        let events = self.events.take();
+        *self = Apu {
+            dmc_dma,
+            events,
+            audio_output,
+            frame_irq_line,
+            dmc_irq_line,
+            ..Apu::new(self.rates)
+        };
+        self.update_irq();
+    }
+
+    fn reset(&mut self) {
+        // Reset acts as a write of 0 to 0x4015, silencing all channels. The
+        // frame counter mode in 0x4017 is left as it was.
+        self.bus_write(STATUS_OFFSET, 0).unwrap();
+        self.frame_irq = false;
+        self.frame_cycle = 0;
+        self.update_irq();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.clock_frame_counter();
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        if let Some(addr) = self.dmc.clock(&self.dmc_dma) {
+            self.schedule(ConsoleEvent::DmcFetch(addr));
+        }
+        self.update_irq();
+
+        if let Some(audio_output) = &self.audio_output {
+            audio_output.mix(self.mix());
+        }
+        Ok(())
+    }
+}
+
+/// An Apu shared between the CPU bus and the console, which clocks it and
+/// inspects it for debuggers. Clones refer to the same APU. The bus's tick
+/// doesn't reach it; its clocks come from the console's scheduler instead.
+#[derive(Clone)]
+pub struct SharedApu {
+    apu: Rc<RefCell<Apu>>,
+}
+
+impl SharedApu {
+    pub fn new(apu: Apu) -> Self {
+        SharedApu {
+            apu: Rc::new(RefCell::new(apu)),
+        }
+    }
+
+    pub fn state(&self) -> ApuState {
+        self.apu.borrow().state()
+    }
+
+    pub fn clock_cpu_cycle(&self) -> EmuResult<()> {
+        self.apu.borrow_mut().tick()
+    }
+
+    pub fn clock_apu_cycle(&self) {
+        self.apu.borrow_mut().clock_apu_cycle();
+    }
+}
+
+impl BusDevice for SharedApu {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.apu.borrow_mut().bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.apu.borrow_mut().bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.apu.borrow_mut().start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.apu.borrow_mut().end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.apu.borrow_mut().power_on();
+    }
+
+    fn reset(&mut self) {
+        self.apu.borrow_mut().reset();
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.apu.borrow_mut().observe_access(addr, activity);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::scheduler::{Scheduler, Tick};
+
+    /// Run CPU cycles, with the half-rate clock on every second one
+    fn run(apu: &mut Apu, cycles: u32) {
+        for cycle in 0..cycles {
+            apu.tick().unwrap();
+            if cycle % 2 == 1 {
+                apu.clock_apu_cycle();
+            }
+        }
+    }
+
+    fn status(apu: &mut Apu) -> u8 {
+        match apu.bus_read(STATUS_OFFSET).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("status read returned open bus"),
+        }
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = Apu::new(&ApuRates::NTSC);
+        // Length counters can't be loaded while their channel is disabled
+        apu.bus_write(0x03, 0x08).unwrap();
+        assert_eq!(status(&mut apu) & 0x01, 0);
+
+        apu.bus_write(STATUS_OFFSET, 0x01).unwrap();
+        // Length index 1 is 254 half frames
+        apu.bus_write(0x03, 0x08).unwrap();
+        assert_eq!(status(&mut apu) & 0x01, 0x01);
+        // Two half frames per 4-step sequence
+        run(&mut apu, ApuRates::NTSC.frame_steps[3] * 126);
+        assert_eq!(apu.pulse1.length.count, 2);
+        run(&mut apu, ApuRates::NTSC.frame_steps[3]);
+        assert_eq!(status(&mut apu) & 0x01, 0);
+
+        // Disabling the channel clears the counter immediately
+        apu.bus_write(0x03, 0x08).unwrap();
+        apu.bus_write(STATUS_OFFSET, 0x00).unwrap();
+        assert_eq!(status(&mut apu) & 0x01, 0);
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        for rates in [&ApuRates::NTSC, &ApuRates::PAL] {
+            let mut apu = Apu::new(rates);
+            run(&mut apu, rates.frame_steps[3] - 1);
+            assert!(!apu.irq_pending());
+            run(&mut apu, 1);
+            assert!(apu.irq_pending());
+            assert_eq!(status(&mut apu) & 0x40, 0x40);
+            // Reading the status acknowledges the IRQ
+            assert!(!apu.irq_pending());
+
+            // Five-step mode never raises the IRQ
+            apu.bus_write(FRAME_COUNTER_OFFSET, 0x80).unwrap();
+            run(&mut apu, rates.frame_steps[4] * 2);
+            assert!(!apu.irq_pending());
+        }
+    }
+
+    #[test]
+    fn test_pulse_output() {
+        let mut apu = Apu::new(&ApuRates::NTSC);
+        apu.bus_write(STATUS_OFFSET, 0x01).unwrap();
+        // 50% duty, constant volume 15, period 0x100
+        apu.bus_write(0x00, 0xBF).unwrap();
+        apu.bus_write(0x02, 0x00).unwrap();
+        apu.bus_write(0x03, 0x09).unwrap();
+
+        let mut high_cycles = 0;
+        let period = (0x100 + 1) * 2 * 8;
+        for cycle in 0..period {
+            apu.tick().unwrap();
+            if cycle % 2 == 1 {
+                apu.clock_apu_cycle();
+            }
+            if apu.pulse1.output() != 0 {
+                assert_eq!(apu.pulse1.output(), 15);
+                high_cycles += 1;
+            }
+        }
+        assert_eq!(high_cycles, period / 2);
+
+        // Periods below 8 are silenced
+        apu.bus_write(0x02, 0x07).unwrap();
+        apu.bus_write(0x03, 0x08).unwrap();
+        assert!(apu.pulse1.muted());
+    }
+
+    #[test]
+    fn test_dmc_fetches() {
+        let mut scheduler = Scheduler::new();
+        scheduler.add_clock(1, 0);
+        let mut apu = Apu::new(&ApuRates::NTSC).with_events(scheduler.event_queue());
+        let dma = apu.dmc_dma();
+        // Fastest rate, sample at 0xC040, 17 bytes, IRQ on completion
+        apu.bus_write(0x10, 0x8F).unwrap();
+        apu.bus_write(0x12, 0x01).unwrap();
+        apu.bus_write(0x13, 0x01).unwrap();
+        apu.bus_write(STATUS_OFFSET, 0x10).unwrap();
+
+        let mut fetched = Vec::new();
+        for _ in 0..20000 {
+            apu.tick().unwrap();
+            // Deliver what this cycle scheduled, up to the next cycle
+            while let Tick::Event(event) = scheduler.advance() {
+                if let ConsoleEvent::DmcFetch(addr) = event {
+                    fetched.push(addr);
+                    dma.complete(0xFF);
+                }
+            }
+        }
+        assert_eq!(fetched, (0xC040..0xC051).collect::<Vec<u16>>());
+        assert!(apu.irq_pending());
+        assert_eq!(status(&mut apu) & 0x90, 0x80);
+        // All ones in the samples ramps the output up in steps of 2 until
+        // it would pass 127
+        assert_eq!(apu.dmc.output_level, 126);
+    }
+
+    #[test]
+    fn test_sprite_dma_write() {
+        let mut scheduler = Scheduler::new();
+        scheduler.add_clock(1, 0);
+        let mut apu = Apu::new(&ApuRates::NTSC).with_events(scheduler.event_queue());
+        apu.bus_write(OAM_DMA_OFFSET, 0x02).unwrap();
+        assert_eq!(
+            scheduler.advance(),
+            Tick::Event(ConsoleEvent::SpriteDma(0x02))
+        );
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut apu = Apu::new(&ApuRates::NTSC);
+        apu.bus_write(FRAME_COUNTER_OFFSET, 0x80).unwrap();
+        apu.bus_write(STATUS_OFFSET, 0x0F).unwrap();
+        for reg in [0x03, 0x07, 0x0B, 0x0F] {
+            apu.bus_write(reg, 0x08).unwrap();
+        }
+        assert_eq!(status(&mut apu) & 0x0F, 0x0F);
+
+        // Reset acts as a write of 0 to 0x4015, but leaves 0x4017 alone
+        apu.reset();
+        assert_eq!(status(&mut apu), 0);
+        assert!(apu.five_step_mode);
+        apu.bus_write(0x03, 0x08).unwrap();
+        assert_eq!(status(&mut apu) & 0x01, 0);
+
+        // Power-on goes back to the 4-step sequence
+        apu.power_on();
+        assert!(!apu.five_step_mode);
+    }
+}
@@ -0,0 +1,110 @@
+use std::{cell::RefCell, rc::Rc};
+
+/// Cutoff of the high-pass filter that removes the mixer's DC offset. The
+/// console's own output stage has a similar filter at about 37Hz.
+const HIGH_PASS_HZ: f64 = 37.0;
+
+#[derive(Debug)]
+struct AudioBuffer {
+    /// CPU cycles per output sample
+    cycles_per_sample: f64,
+    /// Output of every sound source for the current CPU cycle
+    cycle_level: f32,
+    /// Sum of the levels since the last output sample, for averaging
+    level_sum: f64,
+    level_count: u32,
+    /// Position within the current output sample, in CPU cycles
+    phase: f64,
+    high_pass_alpha: f32,
+    /// None until the first sample. The filter starts out settled on that
+    /// one, so the level the APU powers on with doesn't come out as a pop.
+    high_pass_prev_in: Option<f32>,
+    high_pass_prev_out: f32,
+    samples: Vec<f32>,
+}
+
+/// Collects the analog output of the sound sources once per CPU cycle, and
+/// resamples it to an output rate. Sources add their output for the cycle
+/// with mix(), and the system closes off the cycle with end_cycle().
+///
+/// Clones share the same buffer, so each sound source can hold its own
+/// handle.
+#[derive(Clone, Debug)]
+pub struct AudioOutput {
+    inner: Rc<RefCell<AudioBuffer>>,
+}
+
+impl AudioOutput {
+    pub fn new(sample_rate: u32, cpu_clock_hz: f64) -> Self {
+        let rc = 1.0 / (2.0 * std::f64::consts::PI * HIGH_PASS_HZ);
+        let dt = 1.0 / sample_rate as f64;
+        AudioOutput {
+            inner: Rc::new(RefCell::new(AudioBuffer {
+                cycles_per_sample: cpu_clock_hz / sample_rate as f64,
+                cycle_level: 0.0,
+                level_sum: 0.0,
+                level_count: 0,
+                phase: 0.0,
+                high_pass_alpha: (rc / (rc + dt)) as f32,
+                high_pass_prev_in: None,
+                high_pass_prev_out: 0.0,
+                samples: Vec::new(),
+            })),
+        }
+    }
+
+    /// Add a source's output level for the current CPU cycle. The APU's
+    /// mixer produces levels between 0.0 and 1.0.
+    pub fn mix(&self, level: f32) {
+        self.inner.borrow_mut().cycle_level += level;
+    }
+
+    /// Finish the current CPU cycle, producing an output sample whenever a
+    /// sample period has passed
+    pub fn end_cycle(&self) {
+        let mut buffer = self.inner.borrow_mut();
+        let level = std::mem::take(&mut buffer.cycle_level);
+        buffer.level_sum += level as f64;
+        buffer.level_count += 1;
+        buffer.phase += 1.0;
+        if buffer.phase >= buffer.cycles_per_sample {
+            buffer.phase -= buffer.cycles_per_sample;
+            let input = (buffer.level_sum / buffer.level_count as f64) as f32;
+            buffer.level_sum = 0.0;
+            buffer.level_count = 0;
+
+            let prev_in = buffer.high_pass_prev_in.unwrap_or(input);
+            let output = buffer.high_pass_alpha * (buffer.high_pass_prev_out + input - prev_in);
+            buffer.high_pass_prev_in = Some(input);
+            buffer.high_pass_prev_out = output;
+            buffer.samples.push(output);
+        }
+    }
+
+    /// Remove and return the samples produced so far
+    pub fn take_samples(&self) -> Vec<f32> {
+        std::mem::take(&mut self.inner.borrow_mut().samples)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::timing::ConsoleTiming;
+
+    #[test]
+    fn test_resampling_rate() {
+        let cpu_clock_hz = ConsoleTiming::NTSC.cpu_clock_hz();
+        let output = AudioOutput::new(44100, cpu_clock_hz);
+        for _ in 0..cpu_clock_hz as u32 {
+            output.mix(0.5);
+            output.end_cycle();
+        }
+        let samples = output.take_samples();
+        assert!((44099..=44100).contains(&samples.len()));
+        // A constant level is removed by the high-pass filter, without a
+        // pop at the start
+        assert!(samples.iter().all(|sample| sample.abs() < 0.01));
+        assert!(output.take_samples().is_empty());
+    }
+}
@@ -0,0 +1,668 @@
+use std::{
+    cell::RefCell,
+    fmt::{self, Display},
+    ops::RangeInclusive,
+    rc::Rc,
+};
+
+use super::{
+    BusActivity, BusDevice, EmuResult, ReadResult,
+    cpu::{ArchRegs, Interrupt},
+};
+
+/// Size of the PRG-ROM banks that breakpoint bank numbers count. It's the
+/// smallest window any supported board switches.
+pub const PRG_BANK_SIZE: usize = 0x2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct BreakpointId(pub u32);
+
+impl Display for BreakpointId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// The kinds of access a watchpoint stops on
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WatchAccess {
+    pub read: bool,
+    pub write: bool,
+    /// Instructions starting in the range
+    pub exec: bool,
+}
+
+impl Display for WatchAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (set, c) in [(self.read, 'r'), (self.write, 'w'), (self.exec, 'x')] {
+            if set {
+                write!(f, "{}", c)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// What makes a breakpoint fire
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Trigger {
+    /// Reaching the instruction at addr. With a bank, only while that bank
+    /// of PRG-ROM is mapped there.
+    Pc { addr: u16, bank: Option<usize> },
+    /// CPU accesses to an address range. Reads and writes include dummy
+    /// accesses and DMC sample fetches, since they have the same effects.
+    Watch {
+        range: RangeInclusive<u16>,
+        access: WatchAccess,
+    },
+    /// Reaching the first instruction of an interrupt or reset handler
+    Interrupt(Interrupt),
+}
+
+impl Display for Trigger {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Trigger::Pc { addr, bank: None } => write!(f, "pc ${:04X}", addr),
+            Trigger::Pc {
+                addr,
+                bank: Some(bank),
+            } => write!(f, "pc ${:04X} bank {}", addr, bank),
+            Trigger::Watch { range, access } => write!(
+                f,
+                "watch {} ${:04X}-${:04X}",
+                access,
+                range.start(),
+                range.end()
+            ),
+            Trigger::Interrupt(interrupt) => write!(f, "{:?}", interrupt),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub trigger: Trigger,
+    /// Only stop when this holds
+    pub condition: Option<Condition>,
+    pub enabled: bool,
+}
+
+/// The CPU's state at the end of a cycle, which breakpoints are checked
+/// against
+pub struct CpuState<'a, 't> {
+    pub regs: &'a ArchRegs<'t>,
+    /// Whether the CPU is between instructions
+    pub at_boundary: bool,
+    /// The interrupt or reset sequence that just finished, at a boundary
+    pub interrupt: Option<Interrupt>,
+    /// The PRG-ROM bank PC is in, if it's in PRG-ROM
+    pub bank: Option<usize>,
+}
+
+#[derive(Debug, Default)]
+struct BreakpointsInner {
+    next_id: u32,
+    list: Vec<(BreakpointId, Breakpoint)>,
+    /// Watchpoints whose range was accessed since the last check
+    hits: Vec<BreakpointId>,
+}
+
+/// The breakpoints set on a console. Clones share the same set, so the
+/// watchpoint monitor on the bus and the system checking for stops see the
+/// same breakpoints.
+#[derive(Debug, Default, Clone)]
+pub struct Breakpoints {
+    inner: Rc<RefCell<BreakpointsInner>>,
+}
+
+impl Breakpoints {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn add(&self, trigger: Trigger, condition: Option<Condition>) -> BreakpointId {
+        let mut inner = self.inner.borrow_mut();
+        let id = BreakpointId(inner.next_id);
+        inner.next_id += 1;
+        inner.list.push((
+            id,
+            Breakpoint {
+                trigger,
+                condition,
+                enabled: true,
+            },
+        ));
+        id
+    }
+
+    /// Returns false if there's no breakpoint with that id
+    pub fn remove(&self, id: BreakpointId) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        let len = inner.list.len();
+        inner.list.retain(|(bp_id, _)| *bp_id != id);
+        inner.list.len() != len
+    }
+
+    /// Returns false if there's no breakpoint with that id
+    pub fn set_enabled(&self, id: BreakpointId, enabled: bool) -> bool {
+        let mut inner = self.inner.borrow_mut();
+        match inner.list.iter_mut().find(|(bp_id, _)| *bp_id == id) {
+            Some((_, bp)) => {
+                bp.enabled = enabled;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn list(&self) -> Vec<(BreakpointId, Breakpoint)> {
+        self.inner.borrow().list.clone()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.borrow().list.is_empty()
+    }
+
+    fn record_access(&self, addr: u16, activity: BusActivity) {
+        let mut inner = self.inner.borrow_mut();
+        let inner = &mut *inner;
+        for (id, bp) in &inner.list {
+            if let Trigger::Watch { range, access } = &bp.trigger
+                && bp.enabled
+                && range.contains(&addr)
+                && match activity {
+                    BusActivity::Read => access.read,
+                    BusActivity::Write(_) => access.write,
+                    BusActivity::Idle => false,
+                }
+                && !inner.hits.contains(id)
+            {
+                inner.hits.push(*id);
+            }
+        }
+    }
+
+    /// The first breakpoint that fires at the end of a cycle, taking in the
+    /// watched accesses made during it. Memory for conditions is read
+    /// through peek.
+    pub fn check(&self, cpu: &CpuState, peek: &dyn Fn(u16) -> Option<u8>) -> Option<BreakpointId> {
+        let hits = std::mem::take(&mut self.inner.borrow_mut().hits);
+        let inner = self.inner.borrow();
+        let pc = *cpu.regs.pc;
+        inner
+            .list
+            .iter()
+            .find(|(id, bp)| {
+                bp.enabled
+                    && match &bp.trigger {
+                        Trigger::Pc { addr, bank } => {
+                            cpu.at_boundary
+                                && pc == *addr
+                                && bank.is_none_or(|bank| cpu.bank == Some(bank))
+                        }
+                        Trigger::Watch { range, access } => {
+                            hits.contains(id)
+                                || (access.exec && cpu.at_boundary && range.contains(&pc))
+                        }
+                        Trigger::Interrupt(interrupt) => {
+                            cpu.at_boundary && cpu.interrupt == Some(*interrupt)
+                        }
+                    }
+                    && bp
+                        .condition
+                        .as_ref()
+                        .is_none_or(|condition| condition.eval(cpu.regs, peek))
+            })
+            .map(|(id, _)| *id)
+    }
+}
+
+/// Wrapper for the CPU bus which records accesses to watched ranges. It
+/// doesn't stop anything itself, so the access completes along with the
+/// rest of the cycle; the system checks for hits once the cycle is over.
+pub struct WatchpointMonitor<T: BusDevice> {
+    device: T,
+    breakpoints: Breakpoints,
+}
+
+impl<T: BusDevice> WatchpointMonitor<T> {
+    pub fn new(device: T, breakpoints: Breakpoints) -> Self {
+        WatchpointMonitor {
+            device,
+            breakpoints,
+        }
+    }
+
+    pub fn device_mut(&mut self) -> &mut T {
+        &mut self.device
+    }
+}
+
+impl<T: BusDevice> BusDevice for WatchpointMonitor<T> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.breakpoints
+            .record_access(addr as u16, BusActivity::Read);
+        self.device.bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.breakpoints
+            .record_access(addr as u16, BusActivity::Write(data));
+        self.device.bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.device.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.device.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.device.power_on();
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.device.tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.device.observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.device.peek(addr)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Register {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    Pc,
+    N,
+    V,
+    D,
+    I,
+    Z,
+    C,
+}
+
+impl Register {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name.to_ascii_uppercase().as_str() {
+            "A" => Register::A,
+            "X" => Register::X,
+            "Y" => Register::Y,
+            "S" | "SP" => Register::S,
+            "P" => Register::P,
+            "PC" => Register::Pc,
+            "N" => Register::N,
+            "V" => Register::V,
+            "D" => Register::D,
+            "I" => Register::I,
+            "Z" => Register::Z,
+            "C" => Register::C,
+            _ => return None,
+        })
+    }
+
+    fn read(self, regs: &ArchRegs) -> i64 {
+        let p = *regs.p;
+        match self {
+            Register::A => *regs.a as i64,
+            Register::X => *regs.x as i64,
+            Register::Y => *regs.y as i64,
+            Register::S => *regs.s as i64,
+            Register::P => p.value() as i64,
+            Register::Pc => *regs.pc as i64,
+            Register::N => p.n as i64,
+            Register::V => p.v as i64,
+            Register::D => p.d as i64,
+            Register::I => p.i as i64,
+            Register::Z => p.z as i64,
+            Register::C => p.c as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitOr,
+    BitAnd,
+    Add,
+    Sub,
+}
+
+impl BinaryOp {
+    /// The operator and how tightly it binds
+    fn from_token(token: &str) -> Option<(Self, u8)> {
+        Some(match token {
+            "||" => (BinaryOp::Or, 1),
+            "&&" => (BinaryOp::And, 2),
+            "==" => (BinaryOp::Eq, 3),
+            "!=" => (BinaryOp::Ne, 3),
+            "<" => (BinaryOp::Lt, 3),
+            "<=" => (BinaryOp::Le, 3),
+            ">" => (BinaryOp::Gt, 3),
+            ">=" => (BinaryOp::Ge, 3),
+            "|" => (BinaryOp::BitOr, 4),
+            "&" => (BinaryOp::BitAnd, 5),
+            "+" => (BinaryOp::Add, 6),
+            "-" => (BinaryOp::Sub, 6),
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Number(i64),
+    Register(Register),
+    /// The byte at an address
+    Memory(Box<Expr>),
+    Not(Box<Expr>),
+    Negate(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    /// None if the expression reads memory that can't be peeked
+    fn eval(&self, regs: &ArchRegs, peek: &dyn Fn(u16) -> Option<u8>) -> Option<i64> {
+        Some(match self {
+            Expr::Number(value) => *value,
+            Expr::Register(reg) => reg.read(regs),
+            Expr::Memory(addr) => peek(addr.eval(regs, peek)? as u16)? as i64,
+            Expr::Not(expr) => (expr.eval(regs, peek)? == 0) as i64,
+            Expr::Negate(expr) => expr.eval(regs, peek)?.wrapping_neg(),
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(regs, peek)?;
+                // Logical operators skip the right side once the left decides
+                match op {
+                    BinaryOp::Or if lhs != 0 => return Some(1),
+                    BinaryOp::And if lhs == 0 => return Some(0),
+                    _ => {}
+                }
+                let rhs = rhs.eval(regs, peek)?;
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i64,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Number(i64),
+    Name(String),
+    /// Operators and brackets
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 17] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<", ">", "|", "&", "+", "-", "!", "[", "]", "(", ")",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let (token, len) = if c == '$' || rest.starts_with("0x") || rest.starts_with("0X") {
+            let prefix = if c == '$' { 1 } else { 2 };
+            let digits = rest[prefix..]
+                .find(|c: char| !c.is_ascii_hexdigit())
+                .unwrap_or(rest.len() - prefix);
+            let value = i64::from_str_radix(&rest[prefix..prefix + digits], 16)
+                .map_err(|_| format!("bad hex number at `{}`", rest))?;
+            (Token::Number(value), prefix + digits)
+        } else if c.is_ascii_digit() {
+            let digits = rest
+                .find(|c: char| !c.is_ascii_digit())
+                .unwrap_or(rest.len());
+            let value = rest[..digits]
+                .parse()
+                .map_err(|_| format!("bad number at `{}`", rest))?;
+            (Token::Number(value), digits)
+        } else if c.is_ascii_alphabetic() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            (Token::Name(rest[..len].to_string()), len)
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(**symbol))
+                .ok_or_else(|| format!("unexpected `{}`", c))?;
+            (Token::Symbol(symbol), symbol.len())
+        };
+        tokens.push(token);
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek_symbol(&self) -> Option<&'static str> {
+        match self.tokens.get(self.pos) {
+            Some(Token::Symbol(symbol)) => Some(symbol),
+            _ => None,
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.peek_symbol() == Some(symbol) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(format!("expected `{}`", symbol))
+        }
+    }
+
+    /// Parse operators binding at least as tightly as min_level
+    fn parse_binary(&mut self, min_level: u8) -> Result<Expr, String> {
+        let mut lhs = self.parse_unary()?;
+        while let Some((op, level)) = self.peek_symbol().and_then(BinaryOp::from_token)
+            && level >= min_level
+        {
+            self.pos += 1;
+            let rhs = self.parse_binary(level + 1)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, String> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or("unexpected end of condition")?;
+        self.pos += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Number(value)),
+            Token::Name(name) => Register::from_name(&name)
+                .map(Expr::Register)
+                .ok_or_else(|| format!("unknown register `{}`", name)),
+            Token::Symbol("!") => Ok(Expr::Not(Box::new(self.parse_unary()?))),
+            Token::Symbol("-") => Ok(Expr::Negate(Box::new(self.parse_unary()?))),
+            Token::Symbol("[") => {
+                let addr = self.parse_binary(0)?;
+                self.expect("]")?;
+                Ok(Expr::Memory(Box::new(addr)))
+            }
+            Token::Symbol("(") => {
+                let expr = self.parse_binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Token::Symbol(symbol) => Err(format!("unexpected `{}`", symbol)),
+        }
+    }
+}
+
+/// An expression over registers and memory that a breakpoint only stops
+/// when it holds, like `A == $40 && [$0300] > 2`.
+///
+/// Registers are A, X, Y, S, P and PC, and the flags N, V, D, I, Z and C
+/// read as 0 or 1. `[addr]` is the byte at addr. Numbers are decimal, or
+/// hex with a `$` or `0x` prefix. Operators are C's, without
+/// multiplication or shifts.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let expr = parser.parse_binary(0)?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            return Err(format!("unexpected {:?}", token));
+        }
+        Ok(Condition {
+            source: source.trim().to_string(),
+            expr,
+        })
+    }
+
+    /// Whether the condition holds. It doesn't if it reads memory that
+    /// can't be peeked.
+    pub fn eval(&self, regs: &ArchRegs, peek: &dyn Fn(u16) -> Option<u8>) -> bool {
+        self.expr.eval(regs, peek).is_some_and(|value| value != 0)
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mem::RAMDevice;
+
+    fn peek_ram(ram: &[u8]) -> impl Fn(u16) -> Option<u8> + '_ {
+        |addr| ram.get(addr as usize).copied()
+    }
+
+    #[test]
+    fn test_conditions() {
+        let mut regs = ArchRegs::default();
+        regs.a.set(0x40);
+        regs.x.set(3);
+        let mut ram = vec![0; 0x800];
+        ram[0x0300] = 3;
+
+        let eval = |source: &str, regs: &ArchRegs, ram: &[u8]| {
+            Condition::parse(source).unwrap().eval(regs, &peek_ram(ram))
+        };
+        assert!(eval("A == $40 && [$0300] > 2", &regs, &ram));
+        assert!(!eval("A == $40 && [$0300] > 3", &regs, &ram));
+        assert!(eval("[$02FD + x] == 3", &regs, &ram));
+        assert!(eval("a & $C0 == $40 || x == 0", &regs, &ram));
+        assert!(eval("!(z) && pc == 0", &regs, &ram));
+        // Reads past the end of memory can't be peeked
+        assert!(!eval("[$1000] == 0", &regs, &ram));
+        // ...unless they're never evaluated
+        assert!(eval("x == 3 || [$1000] == 0", &regs, &ram));
+
+        for bad in ["A ==", "A == $40)", "Q == 1", "[$10", "A # 2"] {
+            assert!(Condition::parse(bad).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        let breakpoints = Breakpoints::new();
+        let mut bus = WatchpointMonitor::new(RAMDevice::new(0x800), breakpoints.clone());
+        let writes = breakpoints.add(
+            Trigger::Watch {
+                range: 0x0200..=0x02FF,
+                access: WatchAccess {
+                    write: true,
+                    ..Default::default()
+                },
+            },
+            None,
+        );
+        let big_writes = breakpoints.add(
+            Trigger::Watch {
+                range: 0x0200..=0x02FF,
+                access: WatchAccess {
+                    write: true,
+                    ..Default::default()
+                },
+            },
+            Some(Condition::parse("[$0210] >= $80").unwrap()),
+        );
+        let regs = ArchRegs::default();
+        let cpu = CpuState {
+            regs: &regs,
+            at_boundary: false,
+            interrupt: None,
+            bank: None,
+        };
+        let check = |bus: &WatchpointMonitor<RAMDevice>| {
+            breakpoints.check(&cpu, &|addr| bus.peek(addr as u32))
+        };
+
+        bus.bus_read(0x0210).unwrap();
+        bus.bus_write(0x0300, 1).unwrap();
+        assert_eq!(check(&bus), None);
+
+        bus.bus_write(0x0210, 1).unwrap();
+        assert_eq!(check(&bus), Some(writes));
+        // Hits are cleared by the check
+        assert_eq!(check(&bus), None);
+
+        // A disabled breakpoint lets the next one fire
+        breakpoints.set_enabled(writes, false);
+        bus.bus_write(0x0210, 1).unwrap();
+        assert_eq!(check(&bus), None);
+        bus.bus_write(0x0210, 0x80).unwrap();
+        assert_eq!(check(&bus), Some(big_writes));
+
+        assert!(breakpoints.remove(big_writes));
+        assert!(!breakpoints.remove(big_writes));
+        bus.bus_write(0x0210, 0x80).unwrap();
+        assert_eq!(check(&bus), None);
+    }
+}
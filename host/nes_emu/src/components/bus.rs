@@ -6,6 +6,16 @@ pub enum ReadResult {
     OpenBus,
 }
 
+/// What a bus master is doing with the address it has put on the bus
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BusActivity {
+    Read,
+    Write(u8),
+    /// The address is on the bus, but nothing is read or written. The PPU
+    /// does this when PPUADDR changes outside of rendering.
+    Idle,
+}
+
 pub trait BusDevice {
     fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult>;
     fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()>;
@@ -15,6 +25,70 @@ pub trait BusDevice {
     }
 
     fn end_of_simulation(&mut self) {}
+
+    /// Called when the console is powered on. Devices should initialize all
+    /// of their state here.
+    fn power_on(&mut self) {}
+
+    /// Called when the reset button is pressed. Unlike power_on, devices
+    /// only reinitialize the state that the reset line actually affects.
+    fn reset(&mut self) {}
+
+    /// Called once per CPU cycle, before the CPU's bus access for that cycle
+    fn tick(&mut self) -> EmuResult<()> {
+        Ok(())
+    }
+
+    /// Called for every address the bus master drives, before the matching
+    /// bus_read or bus_write. Unlike those, routers pass this to every device
+    /// with the address untranslated, so a device can watch accesses that
+    /// another device answers. Mappers use it to follow the PPU's fetches and
+    /// to snoop writes to the PPU's registers.
+    fn observe_access(&mut self, _addr: u32, _activity: BusActivity) {}
+
+    /// Read addr without side effects, for debuggers. None if there's
+    /// nothing to read there, or reading it would change the device's state.
+    fn peek(&self, _addr: u32) -> Option<u8> {
+        None
+    }
+}
+
+impl<T: BusDevice + ?Sized> BusDevice for Box<T> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        (**self).bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        (**self).bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        (**self).start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        (**self).end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        (**self).power_on();
+    }
+
+    fn reset(&mut self) {
+        (**self).reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        (**self).tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        (**self).observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        (**self).peek(addr)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -35,11 +109,11 @@ impl AddrMapping {
     }
 }
 
-pub struct GenericRouter {
-    devices: Vec<(AddrMapping, Box<dyn BusDevice>)>,
+pub struct GenericRouter<'a> {
+    devices: Vec<(AddrMapping, Box<dyn BusDevice + 'a>)>,
 }
 
-impl GenericRouter {
+impl<'a> GenericRouter<'a> {
     pub fn new() -> Self {
         GenericRouter {
             devices: Vec::new(),
@@ -51,7 +125,7 @@ impl GenericRouter {
         init_start: u32,
         trg_start: u32,
         len: u32,
-        device: Box<dyn BusDevice>,
+        device: Box<dyn BusDevice + 'a>,
     ) {
         self.devices.push((
             AddrMapping {
@@ -63,20 +137,20 @@ impl GenericRouter {
         ));
     }
 
-    fn find_device(&mut self, addr: u32) -> Option<&mut (AddrMapping, Box<dyn BusDevice>)> {
+    fn find_device(&mut self, addr: u32) -> Option<&mut (AddrMapping, Box<dyn BusDevice + 'a>)> {
         self.devices
             .iter_mut()
             .find(|(range, _)| range.matches(addr))
     }
 }
 
-impl Default for GenericRouter {
+impl Default for GenericRouter<'_> {
     fn default() -> Self {
         Self::new()
     }
 }
 
-impl BusDevice for GenericRouter {
+impl BusDevice for GenericRouter<'_> {
     fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
         if let Some((range, device)) = self.find_device(addr) {
             device.bus_read(range.translate(addr))
@@ -105,6 +179,36 @@ impl BusDevice for GenericRouter {
             device.end_of_simulation();
         }
     }
+
+    fn power_on(&mut self) {
+        for (_, device) in self.devices.iter_mut() {
+            device.power_on();
+        }
+    }
+
+    fn reset(&mut self) {
+        for (_, device) in self.devices.iter_mut() {
+            device.reset();
+        }
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        for (_, device) in self.devices.iter_mut() {
+            device.tick()?;
+        }
+        Ok(())
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        for (_, device) in self.devices.iter_mut() {
+            device.observe_access(addr, activity);
+        }
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let (range, device) = self.devices.iter().find(|(range, _)| range.matches(addr))?;
+        device.peek(range.translate(addr))
+    }
 }
 
 pub struct MirroringWrapper<T: BusDevice> {
@@ -139,4 +243,24 @@ impl<T: BusDevice> BusDevice for MirroringWrapper<T> {
     fn end_of_simulation(&mut self) {
         self.device.end_of_simulation();
     }
+
+    fn power_on(&mut self) {
+        self.device.power_on();
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.device.tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.device.observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.device.peek(addr & self.addr_mask)
+    }
 }
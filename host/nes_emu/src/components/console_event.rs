@@ -0,0 +1,14 @@
+use crate::components::reset_controller::ResetKind;
+
+/// One-shot events on the console's scheduler. Components queue them
+/// through an EventQueue, and the console carries them out when they come
+/// due, between clock edges.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConsoleEvent {
+    Restart(ResetKind),
+    /// The DMC wants the sample byte at this address, to be handed back
+    /// through its DmcDma
+    DmcFetch(u16),
+    /// A write to $4014 asked for this page to be copied to OAMDATA
+    SpriteDma(u8),
+}
@@ -6,7 +6,7 @@ use std::fmt::{self, Display};
 
 use sequences::{CpuCycle, MemCycle};
 
-use crate::components::signal::{LevelReceiver, PulseReceiver};
+use crate::components::signal::{PulseReceiver, WiredOrReceiver};
 
 use super::tracer::{TraceElementId, TraceableReg, TraceableValue, Tracer};
 use super::{EmuError, EmuResult};
@@ -77,6 +77,11 @@ impl ArchPSR {
         value
     }
 
+    /// The register as a byte, with the unused bit set and no B flag
+    pub fn value(&self) -> u8 {
+        self.as_stk_u8(false)
+    }
+
     fn with_nz(self, n: bool, z: bool) -> Self {
         Self { n, z, ..self }
     }
@@ -174,19 +179,84 @@ struct InternalRegs {
     rd_val: u8,
 }
 
+/// The 2A03's NMI edge detector. The line is sampled during φ2 of every
+/// cycle, and an edge from released to asserted raises the internal NMI
+/// signal on the following φ1. A line asserted and released between two
+/// samples is never seen, which is how reading PPUSTATUS just as vblank
+/// starts suppresses the NMI.
+#[derive(Debug)]
+struct NmiInput {
+    line: WiredOrReceiver,
+    /// The line's state at the last φ2 sample
+    sampled: bool,
+    edge: bool,
+    pending: bool,
+}
+
+impl NmiInput {
+    fn new(line: WiredOrReceiver) -> Self {
+        NmiInput {
+            line,
+            sampled: false,
+            edge: false,
+            pending: false,
+        }
+    }
+
+    fn phi1(&mut self) {
+        if self.edge {
+            self.edge = false;
+            self.pending = true;
+        }
+    }
+
+    fn phi2(&mut self) {
+        let level = self.line.get();
+        if level && !self.sampled {
+            self.edge = true;
+        }
+        self.sampled = level;
+    }
+
+    /// Consume the internal NMI signal
+    fn take(&mut self) -> bool {
+        std::mem::take(&mut self.pending)
+    }
+
+    /// Forget any edge already detected. The line itself isn't resampled, so
+    /// one held asserted doesn't count as a new edge.
+    fn clear(&mut self) {
+        self.edge = false;
+        self.pending = false;
+    }
+}
+
+/// Events that divert the CPU from the program it's running
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Reset,
+}
+
 pub struct Cpu6502<'a> {
     regs: ArchRegs<'a>,
     internal: InternalRegs,
     sequence: &'static [CpuCycle],
+    /// What the current sequence services, if it isn't an instruction
+    interrupt: Option<Interrupt>,
     op_func: OpFunc,
+    instr_pc: u16,
+    stack_access: bool,
 
     tracer: &'a Tracer,
     mem_trace_element: TraceElementId,
     seq_trace_element: TraceElementId,
     instr_trace_element: TraceElementId,
+    irq_trace_element: TraceElementId,
 
-    nmi_signal: PulseReceiver,
-    irq_signal: LevelReceiver,
+    nmi_input: NmiInput,
+    irq_signal: WiredOrReceiver,
     reset_signal: PulseReceiver,
 }
 
@@ -199,8 +269,8 @@ pub enum BusAccess {
 impl<'a> Cpu6502<'a> {
     pub fn new(
         tracer: &'a Tracer,
-        nmi_signal: PulseReceiver,
-        irq_signal: LevelReceiver,
+        nmi_signal: WiredOrReceiver,
+        irq_signal: WiredOrReceiver,
         reset_signal: PulseReceiver,
     ) -> Self {
         let root_trace_element = tracer.register_element("cpu", None);
@@ -208,23 +278,54 @@ impl<'a> Cpu6502<'a> {
         let regs_trace_element = tracer.register_element("regs", Some(root_trace_element));
         let seq_trace_element = tracer.register_element("seq", Some(root_trace_element));
         let instr_trace_element = tracer.register_element("instr", Some(root_trace_element));
+        let irq_trace_element = tracer.register_element("irq", Some(root_trace_element));
 
         Cpu6502 {
             regs: ArchRegs::new(tracer, Some(regs_trace_element)),
             internal: Default::default(),
             op_func: ops::nop,
             sequence: sequences::RESET_SEQUENCE,
+            interrupt: Some(Interrupt::Reset),
+            instr_pc: 0,
+            stack_access: false,
             tracer,
             mem_trace_element,
             seq_trace_element,
             instr_trace_element,
+            irq_trace_element,
 
-            nmi_signal,
+            nmi_input: NmiInput::new(nmi_signal),
             irq_signal,
             reset_signal,
         }
     }
 
+    /// Return the CPU to its power-on state. Registers are cleared and the
+    /// reset sequence starts on the next cycle.
+    pub fn power_on(&mut self) {
+        self.regs.a.set(0);
+        self.regs.x.set(0);
+        self.regs.y.set(0);
+        self.regs.s.set(0);
+        self.regs.p.set(ArchPSR::default());
+        self.regs.pc.set(0);
+        self.internal = Default::default();
+        self.op_func = ops::nop;
+        self.sequence = sequences::RESET_SEQUENCE;
+        self.interrupt = Some(Interrupt::Reset);
+        self.instr_pc = 0;
+        self.stack_access = false;
+        // Any edge latched before power was removed is lost
+        self.nmi_input.clear();
+        self.reset_signal.check_and_acknowledge();
+    }
+
+    /// Finish the cycle (φ2) once its bus access is done, which is when the
+    /// NMI line is sampled
+    pub fn end_cycle(&mut self) {
+        self.nmi_input.phi2();
+    }
+
     pub fn mem_trace_element(&self) -> TraceElementId {
         self.mem_trace_element
     }
@@ -233,12 +334,40 @@ impl<'a> Cpu6502<'a> {
         &self.regs
     }
 
+    /// Address of the opcode for the instruction currently being executed
+    pub fn instruction_pc(&self) -> u16 {
+        self.instr_pc
+    }
+
+    /// Whether the last instruction, interrupt or reset sequence has
+    /// finished, so the next tick() fetches an opcode. PC points at that
+    /// opcode and the registers hold the results of everything before it.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.sequence.is_empty()
+    }
+
+    /// The interrupt or reset being serviced, or just serviced if the CPU is
+    /// at an instruction boundary. None while running an instruction.
+    pub fn interrupt(&self) -> Option<Interrupt> {
+        self.interrupt
+    }
+
+    /// Whether the bus access returned by the last call to tick() targeted
+    /// the stack, either as a push, a pull, or a dummy stack read
+    pub fn stack_access(&self) -> bool {
+        self.stack_access
+    }
+
+    /// Start the next cycle (φ1), returning the bus access it makes. data_bus
+    /// is the result of the previous cycle's access.
     pub fn tick(&mut self, data_bus: u8) -> EmuResult<BusAccess> {
         self.internal.rd_val = data_bus;
+        self.nmi_input.phi1();
 
         if self.reset_signal.check_and_acknowledge() {
-            self.nmi_signal.check_and_acknowledge();
+            self.nmi_input.clear();
             self.sequence = sequences::RESET_SEQUENCE;
+            self.interrupt = Some(Interrupt::Reset);
         }
 
         if self.sequence.is_empty() {
@@ -254,6 +383,15 @@ impl<'a> Cpu6502<'a> {
         );
         (action.action_func)(self)?;
 
+        self.stack_access = matches!(
+            mem_cycle,
+            MemCycle::IncReadStk
+                | MemCycle::ReadStk
+                | MemCycle::IncPushStk
+                | MemCycle::PushStk
+                | MemCycle::PopStk
+        );
+
         match mem_cycle {
             MemCycle::IncReadPC => {
                 self.regs.pc.update(|pc| pc.wrapping_add(1));
@@ -309,12 +447,30 @@ impl<'a> Cpu6502<'a> {
         // A proper implementation would be to force a BRK opcode, and then the
         // BRK sequence would have the IRQ/NMI checks on the cycle that pushes P
         // to the stack. If IRQ/NMI is detected, the action on that cycle would
-        // swap to the IRQ/NMI sequence
-        if self.nmi_signal.check_and_acknowledge() {
-            self.sequence = sequences::NMI_SEQUENCE;
+        // swap to the IRQ/NMI sequence. The forced BRK also wouldn't advance PC
+        // on its fetches, which stepping back over the discarded opcode below
+        // stands in for.
+        self.interrupt = if self.nmi_input.take() {
+            Some(Interrupt::Nmi)
         } else if self.irq_signal.get() && !self.regs.p.i {
-            self.sequence = sequences::IRQ_SEQUENCE;
+            self.tracer.trace_event(
+                self.irq_trace_element,
+                format_args!("IRQ from {}", self.irq_signal.asserting().join(", ")),
+            );
+            Some(Interrupt::Irq)
+        } else {
+            None
+        };
+        if let Some(interrupt) = self.interrupt {
+            // The fetched opcode is discarded and PC isn't advanced past it,
+            // so the interrupt returns to the instruction it displaced
+            self.regs.pc.update(|pc| pc.wrapping_sub(1));
+            self.sequence = match interrupt {
+                Interrupt::Nmi => sequences::NMI_SEQUENCE,
+                _ => sequences::IRQ_SEQUENCE,
+            };
         } else if let Some(opdesc) = &OPCODE_TABLE[opcode as usize] {
+            self.instr_pc = *self.regs.pc;
             self.tracer.trace_event(
                 self.instr_trace_element,
                 format_args!(
@@ -340,11 +496,73 @@ impl<'a> Cpu6502<'a> {
     }
 }
 
+/// Disassemble the instruction at addr, reading memory through peek.
+/// Returns the instruction's text and its length in bytes. Bytes that
+/// can't be read without side effects show up as "??".
+pub fn disassemble(addr: u16, peek: impl Fn(u16) -> Option<u8>) -> (String, u16) {
+    let Some(opcode) = peek(addr) else {
+        return ("??".to_string(), 1);
+    };
+    let Some(opdesc) = &OPCODE_TABLE[opcode as usize] else {
+        return (format!(".byte ${opcode:02X}"), 1);
+    };
+    let operand = |offset: u16| {
+        peek(addr.wrapping_add(offset)).map_or("??".to_string(), |b| format!("{b:02X}"))
+    };
+    let (text, len) = if opdesc.name.contains("$addr") {
+        let abs = format!("${}{}", operand(2), operand(1));
+        (opdesc.name.replace("$addr", &abs), 3)
+    } else if opdesc.name.contains("$zp") {
+        (opdesc.name.replace("$zp", &format!("${}", operand(1))), 2)
+    } else if opdesc.name.contains("#imm") {
+        (opdesc.name.replace("imm", &format!("${}", operand(1))), 2)
+    } else if opdesc.name.contains("label") {
+        let target = peek(addr.wrapping_add(1)).map_or("$????".to_string(), |offset| {
+            let target = addr.wrapping_add(2).wrapping_add(offset as i8 as u16);
+            format!("${target:04X}")
+        });
+        (opdesc.name.replace("label", &target), 2)
+    } else {
+        (opdesc.name.to_string(), 1)
+    };
+    (text, len)
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::components::signal::WiredOrSignal;
     use proptest::prelude::*;
 
+    #[test]
+    fn test_nmi_edge_detection() {
+        let mut signal = WiredOrSignal::new();
+        let mut ppu = signal.make_source("ppu");
+        let mut nmi = NmiInput::new(signal.make_receiver());
+
+        // An edge sampled on φ2 reaches the CPU on the next φ1
+        ppu.set(true);
+        nmi.phi2();
+        assert!(!nmi.take());
+        nmi.phi1();
+        assert!(nmi.take());
+        assert!(!nmi.take());
+
+        // Holding the line asserted isn't another edge
+        nmi.phi2();
+        nmi.phi1();
+        assert!(!nmi.take());
+
+        // Asserted and released again between samples is never seen
+        ppu.set(false);
+        nmi.phi2();
+        ppu.set(true);
+        ppu.set(false);
+        nmi.phi2();
+        nmi.phi1();
+        assert!(!nmi.take());
+    }
+
     #[test]
     fn test_psr_sanity() {
         let psr = ArchPSR {
@@ -436,4 +654,37 @@ mod test {
             prop_assert_eq!(stk_u8 & 0x01 != 0, c);
         }
     }
+
+    #[test]
+    fn test_disassemble() {
+        let program = [
+            0xA9, 0x12, // LDA #$12
+            0x8D, 0x00, 0x20, // STA $2000
+            0xB1, 0x80, // LDA ($80),Y
+            0xD0, 0xF7, // BNE $C000
+            0x0A, // ASL A
+            0x02, // JAM
+        ];
+        let peek = |addr: u16| program.get(addr.wrapping_sub(0xC000) as usize).copied();
+        let mut addr = 0xC000;
+        let mut lines = Vec::new();
+        while addr < 0xC00C {
+            let (text, len) = disassemble(addr, peek);
+            lines.push(text);
+            addr += len;
+        }
+        assert_eq!(
+            lines,
+            [
+                "LDA #$12",
+                "STA $2000",
+                "LDA ($80),Y",
+                "BNE $C000",
+                "ASL A",
+                ".byte $02",
+                "??"
+            ]
+        );
+        assert_eq!(disassemble(0xC00A, |_| None), ("??".to_string(), 1));
+    }
 }
@@ -9,7 +9,7 @@ seq!(RESET_SEQUENCE => [
     (SET_PC_HI, ReadPC),
 ]);
 seq!(IRQ_SEQUENCE => [
-    (SAVE_PC_HI, IncPushStk),
+    (SAVE_PC_HI, PushStk),
     (SAVE_PC_LO, PushStk),
     (SAVE_P, PushStk),
     (SET_IRQ_VEC, ReadTmp),
@@ -17,7 +17,7 @@ seq!(IRQ_SEQUENCE => [
     (SET_PC_HI, ReadPC),
 ]);
 seq!(NMI_SEQUENCE => [
-    (SAVE_PC_HI, IncPushStk),
+    (SAVE_PC_HI, PushStk),
     (SAVE_PC_LO, PushStk),
     (SAVE_P, PushStk),
     (SET_NMI_VEC, ReadTmp),
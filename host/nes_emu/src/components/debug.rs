@@ -1,4 +1,11 @@
-use super::{BusDevice, EmuError, EmuResult, ReadResult, reset_controller::ResetSource};
+use std::{cell::Cell, rc::Rc};
+
+use super::{
+    BusActivity, BusDevice, EmuError, EmuResult, ReadResult,
+    reset_controller::ResetSource,
+    timing::ConsoleTiming,
+    tracer::{TraceElementId, Tracer},
+};
 
 const STATE_FLAG_OFFSET: u32 = 0x0;
 const TEST_SIG_OFFSET: u32 = 0x1;
@@ -7,25 +14,31 @@ const MSG_OFFSET: u32 = 0x4;
 const SIGNATURE_SIZE: usize = 3;
 const TEST_SIGNATURE: [u8; SIGNATURE_SIZE] = [0xDE, 0xB0, 0x61];
 
-// CPU Clock speed: (236.25 MHz / 11) / 12
-const CPU_TICKS_PER_MS: u64 = (((236_250_000.0 / 11.0) / 12.0) / 1000.0) as u64;
-const RESET_DELAY_TICKS: u64 = 100 * CPU_TICKS_PER_MS;
+/// How long a test ROM's reset request takes to happen
+const RESET_DELAY_MS: u64 = 100;
 
 #[derive(Debug)]
 pub struct TestROMMonitor<T: BusDevice> {
     device: T,
     test_mem_base: u32,
     reset_trigger: ResetSource,
+    reset_delay_ticks: u64,
 
     current_test_signature: [u8; 3],
 }
 
 impl<T: BusDevice> TestROMMonitor<T> {
-    pub fn new(device: T, test_mem_base: u32, reset_trigger: ResetSource) -> Self {
+    pub fn new(
+        device: T,
+        test_mem_base: u32,
+        reset_trigger: ResetSource,
+        timing: &ConsoleTiming,
+    ) -> Self {
         TestROMMonitor {
             device,
             test_mem_base,
             reset_trigger,
+            reset_delay_ticks: timing.ms_to_cpu_cycles(RESET_DELAY_MS),
 
             current_test_signature: [0; SIGNATURE_SIZE],
         }
@@ -63,7 +76,7 @@ impl<T: BusDevice> BusDevice for TestROMMonitor<T> {
                 }
                 0x81 => {
                     // Soft reset requested
-                    self.reset_trigger.schedule_reset(RESET_DELAY_TICKS);
+                    self.reset_trigger.schedule_reset(self.reset_delay_ticks);
                 }
                 0x00 => {
                     // Test completed successfully
@@ -79,6 +92,10 @@ impl<T: BusDevice> BusDevice for TestROMMonitor<T> {
         Ok(())
     }
 
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.device.start_of_simulation()
+    }
+
     fn end_of_simulation(&mut self) {
         // If in test mode, print the message buffer from the test ROM
         if self.test_mode_active() {
@@ -103,5 +120,243 @@ impl<T: BusDevice> BusDevice for TestROMMonitor<T> {
             // Trim any trailing whitespace
             println!("Test ROM message:\n{}", msg.trim_end());
         }
+        self.device.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        // The signature lives in memory that doesn't survive a power cycle
+        self.current_test_signature = [0; SIGNATURE_SIZE];
+        self.device.power_on();
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.device.tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.device.observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.device.peek(addr)
+    }
+}
+
+/// Snapshot of the CPU state for the bus access currently in flight. Used by
+/// debug wrappers that need to attribute an access to the code performing it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CpuAccessInfo {
+    /// Address of the opcode of the instruction performing the access
+    pub pc: u16,
+    /// CPU cycle on which the access occurs
+    pub cycle: u64,
+    /// Full CPU address being accessed, before any mirroring
+    pub addr: u16,
+    /// Whether the access is a stack push, pull, or dummy stack read
+    pub stack: bool,
+}
+
+/// Shared handle to the CpuAccessInfo for the current bus access. The system
+/// updates it before every CPU bus access; debug devices observe it.
+#[derive(Debug, Default, Clone)]
+pub struct CpuAccessMonitor {
+    info: Rc<Cell<CpuAccessInfo>>,
+}
+
+impl CpuAccessMonitor {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn set(&self, info: CpuAccessInfo) {
+        self.info.set(info);
+    }
+
+    pub fn get(&self) -> CpuAccessInfo {
+        self.info.get()
+    }
+}
+
+/// How reads of uninitialized memory are reported
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UninitReadMode {
+    /// Emit a trace event on the "debug.uninit" element and keep running
+    Warn,
+    /// Stop emulation with EmuError::UninitializedRead
+    Strict,
+}
+
+/// Wrapper for a RAM device which tracks whether each byte has been written
+/// since power-on, and reports reads of bytes that never were. Real consoles
+/// power up with semi-random RAM contents, so these reads are a common source
+/// of bugs that never show up in an emulator that zero-fills memory.
+///
+/// Stack pushes and pulls count as writes, since the CPU's dummy stack reads
+/// and pulls of never-pushed data are not program bugs.
+pub struct UninitMemoryDetector<'t, T: BusDevice> {
+    device: T,
+    mode: UninitReadMode,
+    access_monitor: CpuAccessMonitor,
+    initialized: Vec<bool>,
+
+    tracer: &'t Tracer,
+    trace_element: TraceElementId,
+}
+
+impl<'t, T: BusDevice> UninitMemoryDetector<'t, T> {
+    pub fn new(
+        device: T,
+        size: usize,
+        mode: UninitReadMode,
+        access_monitor: CpuAccessMonitor,
+        tracer: &'t Tracer,
+    ) -> Self {
+        let debug_trace_element = tracer.register_element("debug", None);
+        let trace_element = tracer.register_element("uninit", Some(debug_trace_element));
+        UninitMemoryDetector {
+            device,
+            mode,
+            access_monitor,
+            initialized: vec![false; size],
+            tracer,
+            trace_element,
+        }
+    }
+
+    fn mark_initialized(&mut self, addr: u32) {
+        if let Some(slot) = self.initialized.get_mut(addr as usize) {
+            *slot = true;
+        }
+    }
+}
+
+impl<T: BusDevice> BusDevice for UninitMemoryDetector<'_, T> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let access = self.access_monitor.get();
+        if access.stack {
+            self.mark_initialized(addr);
+        } else if self.initialized.get(addr as usize) == Some(&false) {
+            match self.mode {
+                UninitReadMode::Warn => self.tracer.trace_event(
+                    self.trace_element,
+                    format_args!(
+                        "Read of uninitialized memory 0x{:04X} by instruction at 0x{:04X}, cycle {}",
+                        access.addr, access.pc, access.cycle
+                    ),
+                ),
+                UninitReadMode::Strict => {
+                    return Err(EmuError::UninitializedRead {
+                        addr: access.addr,
+                        pc: access.pc,
+                    });
+                }
+            }
+        }
+        self.device.bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.mark_initialized(addr);
+        self.device.bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.device.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.device.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.initialized.fill(false);
+        self.device.power_on();
+    }
+
+    fn reset(&mut self) {
+        // Memory contents survive a reset, so initialization state does too
+        self.device.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.device.tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.device.observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.device.peek(addr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mem::RAMDevice;
+
+    fn make_detector<'t>(
+        tracer: &'t Tracer,
+        monitor: &CpuAccessMonitor,
+    ) -> UninitMemoryDetector<'t, RAMDevice> {
+        UninitMemoryDetector::new(
+            RAMDevice::new(0x800),
+            0x800,
+            UninitReadMode::Strict,
+            monitor.clone(),
+            tracer,
+        )
+    }
+
+    #[test]
+    fn test_uninit_read_reported() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let monitor = CpuAccessMonitor::new();
+        let mut ram = make_detector(&tracer, &monitor);
+        ram.power_on();
+
+        monitor.set(CpuAccessInfo {
+            pc: 0xC123,
+            cycle: 10,
+            addr: 0x0842,
+            stack: false,
+        });
+        assert_eq!(
+            ram.bus_read(0x42),
+            Err(EmuError::UninitializedRead {
+                addr: 0x0842,
+                pc: 0xC123
+            })
+        );
+
+        ram.bus_write(0x42, 0x55).unwrap();
+        assert_eq!(ram.bus_read(0x42), Ok(ReadResult::Data(0x55)));
+    }
+
+    #[test]
+    fn test_stack_accesses_count_as_writes() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let monitor = CpuAccessMonitor::new();
+        let mut ram = make_detector(&tracer, &monitor);
+        ram.power_on();
+
+        monitor.set(CpuAccessInfo {
+            addr: 0x01FD,
+            stack: true,
+            ..Default::default()
+        });
+        assert_eq!(ram.bus_read(0x1FD), Ok(ReadResult::Data(0)));
+
+        monitor.set(CpuAccessInfo {
+            addr: 0x01FD,
+            stack: false,
+            ..Default::default()
+        });
+        assert_eq!(ram.bus_read(0x1FD), Ok(ReadResult::Data(0)));
     }
 }
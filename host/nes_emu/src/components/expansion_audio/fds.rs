@@ -0,0 +1,343 @@
+use super::{APU_PULSE_LEVEL, ExpansionAudio};
+
+/// At full volume and gain, the largest wave sample is about 2.4 times as
+/// loud as an APU pulse at full volume
+const LEVEL_PER_STEP: f32 = APU_PULSE_LEVEL * 2.4 / (63.0 * 32.0);
+
+/// Master volume settings from 0x4089, as fractions of full scale
+const MASTER_VOLUMES: [f32; 4] = [1.0, 2.0 / 3.0, 2.0 / 4.0, 2.0 / 5.0];
+
+/// How each modulation table entry changes the modulation counter. None
+/// resets it to 0.
+const MOD_ADJUSTMENTS: [Option<i8>; 8] = [
+    Some(0),
+    Some(1),
+    Some(2),
+    Some(4),
+    None,
+    Some(-4),
+    Some(-2),
+    Some(-1),
+];
+
+#[derive(Debug, Clone, Default)]
+struct FdsEnvelope {
+    /// Fixed gain rather than an envelope
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    counter: u32,
+}
+
+impl FdsEnvelope {
+    fn write(&mut self, data: u8) {
+        self.disabled = data & 0x80 != 0;
+        self.increase = data & 0x40 != 0;
+        self.speed = data & 0x3F;
+        self.counter = 0;
+        if self.disabled {
+            self.gain = data & 0x3F;
+        }
+    }
+
+    /// Clocked every CPU cycle while envelopes run
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled {
+            return;
+        }
+        self.counter += 1;
+        if self.counter < 8 * (self.speed as u32 + 1) * master_speed as u32 {
+            return;
+        }
+        self.counter = 0;
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+/// Famicom Disk System audio: one 64-step wavetable channel with a volume
+/// envelope, and a frequency modulator driven by a second 64-step table.
+/// Registers are at 0x4040 - 0x4092 in the RAM adapter.
+#[derive(Debug, Clone)]
+pub struct FdsAudio {
+    wave: [u8; 64],
+    wave_write_enabled: bool,
+    master_volume: u8,
+
+    frequency: u16,
+    wave_halted: bool,
+    envelopes_halted: bool,
+    wave_accumulator: u32,
+    /// Held while the wavetable is writable
+    output_sample: u8,
+
+    volume: FdsEnvelope,
+    mod_envelope: FdsEnvelope,
+    envelope_speed: u8,
+
+    mod_table: [u8; 64],
+    mod_table_pos: u8,
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_accumulator: u32,
+    /// 7-bit signed
+    mod_counter: i8,
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave: [0; 64],
+            wave_write_enabled: false,
+            master_volume: 0,
+            frequency: 0,
+            wave_halted: true,
+            envelopes_halted: true,
+            wave_accumulator: 0,
+            output_sample: 0,
+            volume: FdsEnvelope::default(),
+            mod_envelope: FdsEnvelope::default(),
+            envelope_speed: 0xE8,
+            mod_table: [0; 64],
+            mod_table_pos: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_accumulator: 0,
+            mod_counter: 0,
+        }
+    }
+
+    fn set_mod_counter(&mut self, value: i32) {
+        // Wrap to 7 bits, sign extended
+        self.mod_counter = (((value & 0x7F) << 1) as u8 as i8) >> 1;
+    }
+
+    /// The wave frequency after modulation
+    fn modulated_frequency(&self) -> u32 {
+        let gain = self.mod_envelope.gain as i32;
+        let counter = self.mod_counter as i32;
+        let mut temp = counter * gain;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            temp += if counter < 0 { -1 } else { 2 };
+        }
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+        let mut temp = self.frequency as i32 * temp;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+        (self.frequency as i32 + temp).max(0) as u32
+    }
+
+    fn clock_modulator(&mut self) {
+        if self.mod_halted || self.mod_frequency == 0 {
+            return;
+        }
+        self.mod_accumulator += self.mod_frequency as u32;
+        if self.mod_accumulator < 0x10000 {
+            return;
+        }
+        self.mod_accumulator &= 0xFFFF;
+        let entry = self.mod_table[self.mod_table_pos as usize];
+        self.mod_table_pos = (self.mod_table_pos + 1) & 0x3F;
+        match MOD_ADJUSTMENTS[entry as usize] {
+            Some(adjustment) => self.set_mod_counter(self.mod_counter as i32 + adjustment as i32),
+            None => self.mod_counter = 0,
+        }
+    }
+}
+
+impl Default for FdsAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionAudio for FdsAudio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4040..=0x407F if self.wave_write_enabled => {
+                self.wave[addr as usize - 0x4040] = data & 0x3F;
+            }
+            0x4080 => self.volume.write(data),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | data as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.wave_halted = data & 0x80 != 0;
+                self.envelopes_halted = data & 0x40 != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(data),
+            0x4085 => self.set_mod_counter(data as i32),
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | data as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.mod_halted = data & 0x80 != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // The table can only be written while the modulator is halted,
+            // two entries at a time
+            0x4088 if self.mod_halted => {
+                let pos = self.mod_table_pos as usize;
+                self.mod_table[pos] = data & 0x07;
+                self.mod_table[pos + 1] = data & 0x07;
+                self.mod_table_pos = (self.mod_table_pos + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.wave_write_enabled = data & 0x80 != 0;
+                self.master_volume = data & 0x03;
+            }
+            0x408A => self.envelope_speed = data,
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4040..=0x407F => Some(self.wave[addr as usize - 0x4040]),
+            0x4090 => Some(self.volume.gain),
+            0x4092 => Some(self.mod_envelope.gain),
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self) {
+        if !self.envelopes_halted && !self.wave_halted && self.envelope_speed != 0 {
+            self.volume.clock(self.envelope_speed);
+            self.mod_envelope.clock(self.envelope_speed);
+        }
+        self.clock_modulator();
+        if !self.wave_halted && !self.wave_write_enabled {
+            self.wave_accumulator =
+                (self.wave_accumulator + self.modulated_frequency()) & 0x3F_FFFF;
+            self.output_sample = self.wave[(self.wave_accumulator >> 16) as usize];
+        }
+    }
+
+    fn output(&self) -> f32 {
+        let gain = self.volume.gain.min(32);
+        (self.output_sample as u32 * gain as u32) as f32
+            * MASTER_VOLUMES[self.master_volume as usize]
+            * LEVEL_PER_STEP
+    }
+
+    fn power_on(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A square wave: 32 samples at 63, then 32 at 0
+    fn load_square(fds: &mut FdsAudio) {
+        fds.write(0x4089, 0x80);
+        for i in 0..64 {
+            fds.write(0x4040 + i, if i < 32 { 63 } else { 0 });
+        }
+        fds.write(0x4089, 0x00);
+    }
+
+    #[test]
+    fn test_wavetable() {
+        let mut fds = FdsAudio::new();
+        // Writes are ignored unless enabled through 0x4089
+        fds.write(0x4040, 0x12);
+        assert_eq!(fds.read(0x4040), Some(0));
+        load_square(&mut fds);
+        assert_eq!(fds.read(0x4040), Some(63));
+
+        // Fixed gain 32, frequency 0x400 steps the wave every 64 cycles
+        fds.write(0x4080, 0x80 | 32);
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x04);
+        let mut high_cycles = 0;
+        for _ in 0..64 * 64 {
+            fds.tick();
+            if fds.output() > 0.0 {
+                high_cycles += 1;
+            }
+        }
+        assert_eq!(high_cycles, 32 * 64);
+        let peak = 63.0 * 32.0 * LEVEL_PER_STEP;
+        assert!((peak - APU_PULSE_LEVEL * 2.4).abs() < 1e-6);
+
+        // Master volume scales the output
+        fds.write(0x4089, 0x03);
+        fds.output_sample = 63;
+        assert!((fds.output() - peak * 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_volume_envelope() {
+        let mut fds = FdsAudio::new();
+        load_square(&mut fds);
+        fds.write(0x4083, 0x04);
+        // Increasing at speed 0, with the envelope speed at 1: one step
+        // every 8 cycles
+        fds.write(0x408A, 1);
+        fds.write(0x4080, 0x40);
+        for _ in 0..8 * 10 {
+            fds.tick();
+        }
+        assert_eq!(fds.read(0x4090), Some(10));
+        // The envelope stops at 32
+        for _ in 0..8 * 40 {
+            fds.tick();
+        }
+        assert_eq!(fds.read(0x4090), Some(32));
+    }
+
+    #[test]
+    fn test_modulation() {
+        let mut fds = FdsAudio::new();
+        fds.write(0x4082, 0x00);
+        fds.write(0x4083, 0x01);
+        fds.write(0x4084, 0x80 | 32);
+        // Table of all +1 steps, then a reset in the last pair
+        fds.write(0x4087, 0x80);
+        for _ in 0..31 {
+            fds.write(0x4088, 1);
+        }
+        fds.write(0x4088, 4);
+        assert_eq!(fds.modulated_frequency(), 0x100);
+
+        // Modulator frequency 0x800 steps the table every 32 cycles
+        fds.write(0x4086, 0x00);
+        fds.write(0x4087, 0x08);
+        for _ in 0..32 * 10 {
+            fds.tick();
+        }
+        assert_eq!(fds.mod_counter, 10);
+        // counter 10 * gain 32 / 16 = 20, so the pitch rises by 20/64 of
+        // itself
+        assert_eq!(fds.modulated_frequency(), 0x100 + 0x100 * 20 / 64);
+
+        // The reset entry returns the counter to 0
+        for _ in 0..32 * 54 {
+            fds.tick();
+        }
+        assert_eq!(fds.mod_counter, 0);
+
+        // The counter wraps at 7 bits
+        fds.write(0x4085, 0x3F);
+        fds.set_mod_counter(fds.mod_counter as i32 + 1);
+        assert_eq!(fds.mod_counter, -64);
+    }
+}
@@ -0,0 +1,185 @@
+use super::{APU_PULSE_LEVEL, ExpansionAudio};
+use crate::components::apu::Pulse;
+
+/// The MMC5 clocks its envelopes and length counters at a fixed 240Hz, rather
+/// than from a frame counter
+const FRAME_PERIOD: u16 = 7457;
+
+/// The pulses are as loud as the APU's, but mixed linearly
+const PULSE_LEVEL_PER_STEP: f32 = APU_PULSE_LEVEL / 15.0;
+/// The PCM channel's full range is about as loud as the DMC's
+const PCM_LEVEL_PER_STEP: f32 = 0.44 / 255.0;
+
+/// MMC5 audio: two pulse channels like the APU's but without sweep units, and
+/// an 8-bit PCM channel that is either written directly or captures CPU reads
+/// from 0x8000 - 0xBFFF.
+#[derive(Debug, Clone)]
+pub struct Mmc5Audio {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    frame_counter: u16,
+    odd_cycle: bool,
+    pcm_read_mode: bool,
+    pcm_irq_enabled: bool,
+    pcm_irq: bool,
+    pcm: u8,
+}
+
+impl Mmc5Audio {
+    pub fn new() -> Self {
+        Mmc5Audio {
+            pulse1: Pulse::new(false, false),
+            pulse2: Pulse::new(false, false),
+            frame_counter: 0,
+            odd_cycle: false,
+            pcm_read_mode: false,
+            pcm_irq_enabled: false,
+            pcm_irq: false,
+            pcm: 0,
+        }
+    }
+
+    /// Whether the PCM channel is requesting an interrupt
+    pub fn irq_pending(&self) -> bool {
+        self.pcm_irq && self.pcm_irq_enabled
+    }
+
+    fn set_pcm(&mut self, data: u8) {
+        // A zero byte doesn't change the output; it raises the IRQ instead
+        if data == 0 {
+            self.pcm_irq = true;
+        } else {
+            self.pcm = data;
+        }
+    }
+}
+
+impl Default for Mmc5Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionAudio for Mmc5Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5003 => self.pulse1.write((addr - 0x5000) as u32, data),
+            0x5004..=0x5007 => self.pulse2.write((addr - 0x5004) as u32, data),
+            0x5010 => {
+                self.pcm_read_mode = data & 0x01 != 0;
+                self.pcm_irq_enabled = data & 0x80 != 0;
+            }
+            0x5011 if !self.pcm_read_mode => self.set_pcm(data),
+            0x5015 => {
+                self.pulse1.length.set_enabled(data & 0x01 != 0);
+                self.pulse2.length.set_enabled(data & 0x02 != 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 => {
+                let status = (self.irq_pending() as u8) << 7 | self.pcm_read_mode as u8;
+                self.pcm_irq = false;
+                Some(status)
+            }
+            0x5015 => Some(
+                (self.pulse1.length.count > 0) as u8 | ((self.pulse2.length.count > 0) as u8) << 1,
+            ),
+            _ => None,
+        }
+    }
+
+    /// In read mode, the PCM channel captures reads from 0x8000 - 0xBFFF
+    fn observe_read(&mut self, addr: u16, data: u8) {
+        if self.pcm_read_mode && (0x8000..=0xBFFF).contains(&addr) {
+            self.set_pcm(data);
+        }
+    }
+
+    fn tick(&mut self) {
+        self.frame_counter += 1;
+        if self.frame_counter == FRAME_PERIOD {
+            self.frame_counter = 0;
+            for pulse in [&mut self.pulse1, &mut self.pulse2] {
+                pulse.envelope.clock();
+                pulse.length.clock();
+            }
+        }
+        if self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+    }
+
+    fn output(&self) -> f32 {
+        (self.pulse1.output() + self.pulse2.output()) as f32 * PULSE_LEVEL_PER_STEP
+            + self.pcm as f32 * PCM_LEVEL_PER_STEP
+    }
+
+    fn power_on(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5015, 0x01);
+        // 25% duty, constant volume 12, period 4. The APU would mute a
+        // period this short, but the MMC5 has no sweep unit to do it.
+        mmc5.write(0x5000, 0x5C);
+        mmc5.write(0x5002, 0x04);
+        mmc5.write(0x5003, 0x08);
+        assert_eq!(mmc5.read(0x5015), Some(0x01));
+
+        let period = (4 + 1) * 2 * 8;
+        let mut high_cycles = 0;
+        for _ in 0..period {
+            mmc5.tick();
+            if mmc5.pulse1.output() == 12 {
+                high_cycles += 1;
+            }
+        }
+        assert_eq!(high_cycles, period / 4);
+
+        // Length index 1 is 254 frames at 240Hz
+        for _ in 0..FRAME_PERIOD as u32 * 253 {
+            mmc5.tick();
+        }
+        assert_eq!(mmc5.read(0x5015), Some(0x01));
+        for _ in 0..FRAME_PERIOD {
+            mmc5.tick();
+        }
+        assert_eq!(mmc5.read(0x5015), Some(0x00));
+    }
+
+    #[test]
+    fn test_pcm() {
+        let mut mmc5 = Mmc5Audio::new();
+        mmc5.write(0x5011, 0x80);
+        assert!((mmc5.output() - 0x80 as f32 * PCM_LEVEL_PER_STEP).abs() < 1e-6);
+
+        // Read mode captures reads from 0x8000 - 0xBFFF, and ignores writes
+        mmc5.write(0x5010, 0x81);
+        mmc5.write(0x5011, 0x20);
+        mmc5.observe_read(0xC000, 0x30);
+        assert_eq!(mmc5.pcm, 0x80);
+        mmc5.observe_read(0x8000, 0x40);
+        assert_eq!(mmc5.pcm, 0x40);
+
+        // Reading a zero raises the IRQ, which reading 0x5010 acknowledges
+        mmc5.observe_read(0x8001, 0x00);
+        assert_eq!(mmc5.pcm, 0x40);
+        assert!(mmc5.irq_pending());
+        assert_eq!(mmc5.read(0x5010), Some(0x81));
+        assert!(!mmc5.irq_pending());
+    }
+}
@@ -0,0 +1,43 @@
+//! Sound chips on the cartridge. The cartridge connector carries an audio
+//! input that the console mixes with the APU's output, which several mappers
+//! and the Disk System's RAM adapter use for extra channels.
+
+pub mod fds;
+pub mod mmc5;
+pub mod n163;
+pub mod sunsoft5b;
+pub mod vrc6;
+pub mod vrc7;
+
+/// Output of one APU pulse channel at full volume, through the APU's mixer.
+/// Expansion chips scale their output against this, using the relative
+/// loudness measured on hardware.
+pub const APU_PULSE_LEVEL: f32 = 95.88 / (8128.0 / 15.0 + 100.0);
+
+/// A sound generator on the cartridge. The device that owns the chip passes
+/// it the CPU writes it decodes, clocks it once per CPU cycle, and mixes its
+/// output into the console's AudioOutput.
+pub trait ExpansionAudio {
+    /// Handle a CPU write. Chips decode the full CPU address, and ignore
+    /// writes that aren't to one of their registers.
+    fn write(&mut self, addr: u16, data: u8);
+
+    /// Handle a CPU read, for chips with readable registers. None if the
+    /// address isn't one of the chip's registers.
+    fn read(&mut self, _addr: u16) -> Option<u8> {
+        None
+    }
+
+    /// See a CPU read that another device on the cartridge answered. Only the
+    /// MMC5, which can capture PCM samples from PRG reads, needs this.
+    fn observe_read(&mut self, _addr: u16, _data: u8) {}
+
+    /// Advance by one CPU cycle
+    fn tick(&mut self);
+
+    /// The chip's current output, on the same scale as the APU mixer's
+    fn output(&self) -> f32;
+
+    /// Return to the power-on state
+    fn power_on(&mut self);
+}
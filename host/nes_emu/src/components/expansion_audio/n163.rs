@@ -0,0 +1,230 @@
+use super::{APU_PULSE_LEVEL, ExpansionAudio};
+
+/// CPU cycles the chip spends updating each channel
+const CYCLES_PER_CHANNEL: u8 = 15;
+
+/// A lone channel at full volume swings about 1.5 times as far as an APU
+/// pulse at full volume
+const LEVEL_PER_STEP: f32 = APU_PULSE_LEVEL * 1.5 / (8.0 * 15.0);
+
+/// Namco 163 audio: up to eight wavetable channels, with their waveforms and
+/// registers sharing 128 bytes of internal RAM. The RAM is addressed through
+/// 0xF800 and accessed through 0x4800.
+///
+/// The chip has a single DAC, and updates one channel every 15 CPU cycles,
+/// outputting that channel until the next update. Adding channels lowers the
+/// rate each is updated at, and the volume of each.
+#[derive(Debug, Clone)]
+pub struct N163Audio {
+    ram: [u8; 0x80],
+    addr: u8,
+    auto_increment: bool,
+    /// Set by 0xE000 bit 6 on mapper 19 boards
+    disabled: bool,
+    cycle: u8,
+    /// Channel being updated. Channels are updated from 7 down to the lowest
+    /// in use, then back to 7.
+    current_channel: u8,
+    /// Signed sample times volume of the current channel
+    current_output: i16,
+}
+
+impl N163Audio {
+    pub fn new() -> Self {
+        N163Audio {
+            ram: [0; 0x80],
+            addr: 0,
+            auto_increment: false,
+            disabled: false,
+            cycle: 0,
+            current_channel: 0,
+            current_output: 0,
+        }
+    }
+
+    /// Number of channels in use, from the top nibble of the last register
+    fn channel_count(&self) -> u8 {
+        ((self.ram[0x7F] >> 4) & 0x07) + 1
+    }
+
+    /// Advance one channel's phase and work out its output
+    fn update_channel(&mut self, channel: u8) -> i16 {
+        let base = 0x40 + channel as usize * 8;
+        let regs = &self.ram[base..base + 8];
+        let frequency = regs[0] as u32 | (regs[2] as u32) << 8 | ((regs[4] & 0x03) as u32) << 16;
+        let length = (256 - (regs[4] & 0xFC) as u32) << 16;
+        let wave_addr = regs[6];
+        let volume = (regs[7] & 0x0F) as i16;
+        let mut phase = regs[1] as u32 | (regs[3] as u32) << 8 | (regs[5] as u32) << 16;
+
+        phase = (phase + frequency) % length;
+        self.ram[base + 1] = phase as u8;
+        self.ram[base + 3] = (phase >> 8) as u8;
+        self.ram[base + 5] = (phase >> 16) as u8;
+
+        let sample_addr = wave_addr.wrapping_add((phase >> 16) as u8);
+        let byte = self.ram[(sample_addr >> 1) as usize & 0x7F];
+        let sample = if sample_addr & 1 == 0 {
+            byte & 0x0F
+        } else {
+            byte >> 4
+        };
+        (sample as i16 - 8) * volume
+    }
+
+    fn write_data(&mut self, data: u8) {
+        self.ram[self.addr as usize] = data;
+        self.step_addr();
+    }
+
+    fn step_addr(&mut self) {
+        if self.auto_increment {
+            self.addr = (self.addr + 1) & 0x7F;
+        }
+    }
+}
+
+impl Default for N163Audio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionAudio for N163Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4800..=0x4FFF => self.write_data(data),
+            0xE000..=0xE7FF => self.disabled = data & 0x40 != 0,
+            0xF800..=0xFFFF => {
+                self.addr = data & 0x7F;
+                self.auto_increment = data & 0x80 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    fn read(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x4800..=0x4FFF => {
+                let data = self.ram[self.addr as usize];
+                self.step_addr();
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.disabled {
+            self.current_output = 0;
+            return;
+        }
+        self.cycle += 1;
+        if self.cycle < CYCLES_PER_CHANNEL {
+            return;
+        }
+        self.cycle = 0;
+        let lowest_channel = 8 - self.channel_count();
+        self.current_channel = if self.current_channel <= lowest_channel {
+            7
+        } else {
+            self.current_channel - 1
+        };
+        self.current_output = self.update_channel(self.current_channel);
+    }
+
+    fn output(&self) -> f32 {
+        self.current_output as f32 * LEVEL_PER_STEP
+    }
+
+    fn power_on(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_ram(n163: &mut N163Audio, addr: u8, data: &[u8]) {
+        n163.write(0xF800, 0x80 | addr);
+        for byte in data {
+            n163.write(0x4800, *byte);
+        }
+    }
+
+    /// Set up channel 7 (the last) with a 4-sample wave at address 0:
+    /// samples 0, 15, 8, 8
+    fn setup_channel(n163: &mut N163Audio, channel_count: u8, frequency: u32) {
+        write_ram(n163, 0x00, &[0xF0, 0x88]);
+        let length_reg = 256 - 4;
+        write_ram(
+            n163,
+            0x78,
+            &[
+                frequency as u8,
+                0,
+                (frequency >> 8) as u8,
+                0,
+                length_reg as u8 | (frequency >> 16) as u8,
+                0,
+                0,
+                ((channel_count - 1) << 4) | 0x0F,
+            ],
+        );
+    }
+
+    #[test]
+    fn test_ram_access() {
+        let mut n163 = N163Audio::new();
+        write_ram(&mut n163, 0x10, &[1, 2, 3]);
+        n163.write(0xF800, 0x90);
+        assert_eq!(n163.read(0x4800), Some(1));
+        assert_eq!(n163.read(0x4800), Some(2));
+        // Without auto-increment the address stays put
+        n163.write(0xF800, 0x10);
+        assert_eq!(n163.read(0x4800), Some(1));
+        assert_eq!(n163.read(0x4800), Some(1));
+        assert_eq!(n163.read(0x5000), None);
+    }
+
+    #[test]
+    fn test_wavetable_playback() {
+        let mut n163 = N163Audio::new();
+        // One sample step per update
+        setup_channel(&mut n163, 1, 0x10000);
+
+        let mut outputs = Vec::new();
+        for _ in 0..4 * CYCLES_PER_CHANNEL as u32 {
+            n163.tick();
+            if n163.cycle == 0 {
+                outputs.push(n163.current_output);
+            }
+        }
+        // (sample - 8) * volume 15, starting from the second sample
+        assert_eq!(outputs, vec![7 * 15, 0, 0, -8 * 15]);
+    }
+
+    #[test]
+    fn test_channel_multiplexing() {
+        let mut n163 = N163Audio::new();
+        setup_channel(&mut n163, 2, 0x10000);
+        // Channel 6 is silent, at volume 0
+        let mut channels = Vec::new();
+        for _ in 0..4 * CYCLES_PER_CHANNEL as u32 {
+            n163.tick();
+            if n163.cycle == 0 {
+                channels.push(n163.current_channel);
+            }
+        }
+        // The chip alternates between the two channels in use, so channel 7
+        // only advances every other update
+        assert_eq!(channels, vec![7, 6, 7, 6]);
+        assert_eq!(n163.ram[0x7D], 2);
+
+        // Disabling sound silences the output
+        n163.write(0xE000, 0x40);
+        n163.tick();
+        assert_eq!(n163.output(), 0.0);
+    }
+}
@@ -0,0 +1,319 @@
+use super::{APU_PULSE_LEVEL, ExpansionAudio};
+
+/// The 5B divides the CPU clock by 2 internally, and its tone and noise
+/// counters divide that by another 8
+const TONE_CLOCK_DIVIDER: u8 = 16;
+
+/// One channel at full volume is about 1.5 times as loud as an APU pulse at
+/// full volume
+const CHANNEL_LEVEL: f32 = APU_PULSE_LEVEL * 1.5;
+
+// Envelope shape bits
+const SHAPE_HOLD: u8 = 0x01;
+const SHAPE_ALTERNATE: u8 = 0x02;
+const SHAPE_ATTACK: u8 = 0x04;
+const SHAPE_CONTINUE: u8 = 0x08;
+
+/// Output for each of the 32 levels, on a logarithmic scale of 1.5dB steps
+fn level_to_amplitude(level: u8) -> f32 {
+    if level == 0 {
+        0.0
+    } else {
+        10f32.powf(-1.5 * (31 - level) as f32 / 20.0)
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Tone {
+    period: u16,
+    counter: u16,
+    output: bool,
+}
+
+impl Tone {
+    fn clock(&mut self) {
+        self.counter += 1;
+        if self.counter >= self.period.max(1) {
+            self.counter = 0;
+            self.output = !self.output;
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Envelope {
+    period: u16,
+    counter: u16,
+    shape: u8,
+    /// Position within the 32-step ramp
+    step: u8,
+    /// Ramping up rather than down
+    attack: bool,
+    holding: bool,
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Envelope {
+            period: 0,
+            counter: 0,
+            shape: 0,
+            step: 0,
+            attack: false,
+            holding: true,
+        }
+    }
+}
+
+impl Envelope {
+    fn restart(&mut self, shape: u8) {
+        self.shape = shape;
+        self.counter = 0;
+        self.step = 0;
+        self.attack = shape & SHAPE_ATTACK != 0;
+        self.holding = false;
+    }
+
+    /// Clocked at the same rate as the tone counters
+    fn clock(&mut self) {
+        if self.holding {
+            return;
+        }
+        self.counter += 1;
+        // The envelope steps twice as often as a tone of the same period
+        // toggles, so a full 32-step ramp lasts 16 tone periods
+        if self.counter < self.period.max(1).div_ceil(2) {
+            return;
+        }
+        self.counter = 0;
+        if self.step < 31 {
+            self.step += 1;
+            return;
+        }
+        // End of a ramp
+        if self.shape & SHAPE_CONTINUE == 0 {
+            // Shapes 0-7 fall to silence and stay there
+            self.attack = false;
+            self.holding = true;
+            return;
+        }
+        if self.shape & SHAPE_ALTERNATE != 0 {
+            self.attack = !self.attack;
+        }
+        if self.shape & SHAPE_HOLD != 0 {
+            self.holding = true;
+        } else {
+            self.step = 0;
+        }
+    }
+
+    fn level(&self) -> u8 {
+        if self.attack {
+            self.step
+        } else {
+            31 - self.step
+        }
+    }
+}
+
+/// Sunsoft 5B audio, a licensed AY-3-8910: three square wave channels with a
+/// shared noise generator and envelope. Registers are selected by writing
+/// 0xC000 and written through 0xE000.
+#[derive(Debug, Clone)]
+pub struct Sunsoft5BAudio {
+    reg_select: u8,
+    tones: [Tone; 3],
+    noise_period: u8,
+    noise_counter: u8,
+    noise_lfsr: u32,
+    /// Register 7: tone disable in bits 0-2, noise disable in bits 3-5
+    mixer: u8,
+    /// Volume registers: level in bits 0-3, envelope mode in bit 4
+    volumes: [u8; 3],
+    envelope: Envelope,
+    divider: u8,
+}
+
+impl Sunsoft5BAudio {
+    pub fn new() -> Self {
+        Sunsoft5BAudio {
+            reg_select: 0,
+            tones: Default::default(),
+            noise_period: 0,
+            noise_counter: 0,
+            noise_lfsr: 1,
+            mixer: 0,
+            volumes: [0; 3],
+            envelope: Envelope::default(),
+            divider: 0,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, data: u8) {
+        match reg {
+            0x00..=0x05 => {
+                let tone = &mut self.tones[reg as usize / 2];
+                tone.period = if reg & 1 == 0 {
+                    (tone.period & 0x0F00) | data as u16
+                } else {
+                    (tone.period & 0x00FF) | ((data as u16 & 0x0F) << 8)
+                };
+            }
+            0x06 => self.noise_period = data & 0x1F,
+            0x07 => self.mixer = data,
+            0x08..=0x0A => self.volumes[reg as usize - 0x08] = data & 0x1F,
+            0x0B => self.envelope.period = (self.envelope.period & 0xFF00) | data as u16,
+            0x0C => self.envelope.period = (self.envelope.period & 0x00FF) | (data as u16) << 8,
+            0x0D => self.envelope.restart(data & 0x0F),
+            _ => {}
+        }
+    }
+
+    fn clock_noise(&mut self) {
+        // The noise counter runs at half the tone counters' rate
+        self.noise_counter += 1;
+        if self.noise_counter >= self.noise_period.max(1) * 2 {
+            self.noise_counter = 0;
+            let feedback = (self.noise_lfsr ^ (self.noise_lfsr >> 3)) & 1;
+            self.noise_lfsr = (self.noise_lfsr >> 1) | (feedback << 16);
+        }
+    }
+
+    fn channel_level(&self, channel: usize) -> u8 {
+        let tone_on = self.mixer & (1 << channel) == 0;
+        let noise_on = self.mixer & (8 << channel) == 0;
+        let tone_high = !tone_on || self.tones[channel].output;
+        let noise_high = !noise_on || self.noise_lfsr & 1 != 0;
+        if !(tone_high && noise_high) {
+            return 0;
+        }
+        let volume = self.volumes[channel];
+        if volume & 0x10 != 0 {
+            self.envelope.level()
+        } else if volume == 0 {
+            0
+        } else {
+            // Fixed volumes take every other envelope level
+            volume * 2 + 1
+        }
+    }
+}
+
+impl Default for Sunsoft5BAudio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ExpansionAudio for Sunsoft5BAudio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0xC000..=0xDFFF => self.reg_select = data & 0x0F,
+            0xE000..=0xFFFF => self.write_register(self.reg_select, data),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.divider += 1;
+        if self.divider < TONE_CLOCK_DIVIDER {
+            return;
+        }
+        self.divider = 0;
+        for tone in self.tones.iter_mut() {
+            tone.clock();
+        }
+        self.clock_noise();
+        self.envelope.clock();
+    }
+
+    fn output(&self) -> f32 {
+        (0..3)
+            .map(|channel| level_to_amplitude(self.channel_level(channel)))
+            .sum::<f32>()
+            * CHANNEL_LEVEL
+    }
+
+    fn power_on(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn write_reg(chip: &mut Sunsoft5BAudio, reg: u8, data: u8) {
+        chip.write(0xC000, reg);
+        chip.write(0xE000, data);
+    }
+
+    #[test]
+    fn test_tone() {
+        let mut chip = Sunsoft5BAudio::new();
+        // Channel A only, tone period 100, volume 15
+        write_reg(&mut chip, 0x07, 0x3E);
+        write_reg(&mut chip, 0x00, 100);
+        write_reg(&mut chip, 0x08, 0x0F);
+
+        let mut high_cycles = 0;
+        let mut toggles = 0;
+        let mut last = chip.output();
+        for _ in 0..TONE_CLOCK_DIVIDER as u32 * 100 * 20 {
+            chip.tick();
+            let output = chip.output();
+            if output > 0.0 {
+                high_cycles += 1;
+                assert!((output - CHANNEL_LEVEL).abs() < 1e-6);
+            }
+            if output != last {
+                toggles += 1;
+            }
+            last = output;
+        }
+        // A tone period is 32 CPU cycles per unit of its period register
+        assert_eq!(toggles, 20);
+        assert_eq!(high_cycles, TONE_CLOCK_DIVIDER as u32 * 100 * 10);
+
+        // Each volume step is 3dB
+        let ratio = level_to_amplitude(27) / level_to_amplitude(31);
+        assert!((ratio - 10f32.powf(-6.0 / 20.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_envelope_shapes() {
+        let mut chip = Sunsoft5BAudio::new();
+        write_reg(&mut chip, 0x0B, 2);
+        let step_cycles = TONE_CLOCK_DIVIDER as u32;
+        let ramp = |chip: &mut Sunsoft5BAudio| {
+            let mut levels = Vec::new();
+            for _ in 0..32 {
+                levels.push(chip.envelope.level());
+                for _ in 0..step_cycles {
+                    chip.tick();
+                }
+            }
+            levels
+        };
+
+        // Shape 0: fall once, then stay silent
+        write_reg(&mut chip, 0x0D, 0x00);
+        assert_eq!(ramp(&mut chip), (0..32).rev().collect::<Vec<u8>>());
+        assert_eq!(ramp(&mut chip), vec![0; 32]);
+
+        // Shape 14: rise and fall repeatedly
+        write_reg(&mut chip, 0x0D, 0x0E);
+        assert_eq!(ramp(&mut chip), (0..32).collect::<Vec<u8>>());
+        assert_eq!(ramp(&mut chip), (0..32).rev().collect::<Vec<u8>>());
+
+        // Shape 13: rise once and hold at the top
+        write_reg(&mut chip, 0x0D, 0x0D);
+        ramp(&mut chip);
+        assert_eq!(ramp(&mut chip), vec![31; 32]);
+
+        // A channel in envelope mode follows the envelope
+        write_reg(&mut chip, 0x07, 0x3F);
+        write_reg(&mut chip, 0x09, 0x10);
+        assert_eq!(chip.channel_level(1), 31);
+    }
+}
@@ -0,0 +1,222 @@
+use super::{APU_PULSE_LEVEL, ExpansionAudio};
+
+/// A VRC6 pulse at volume 15 is about as loud as an APU pulse at volume 15
+const LEVEL_PER_STEP: f32 = APU_PULSE_LEVEL / 15.0;
+
+#[derive(Debug, Default, Clone)]
+struct Vrc6Pulse {
+    volume: u8,
+    duty: u8,
+    /// Ignore the duty and output the volume constantly
+    digitized: bool,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+}
+
+impl Vrc6Pulse {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.digitized = data & 0x80 != 0;
+                self.duty = (data >> 4) & 0x07;
+                self.volume = data & 0x0F;
+            }
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    // Disabling resets the duty cycle to its start
+                    self.step = 15;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            self.step = self.step.wrapping_sub(1) & 0x0F;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (self.digitized || self.step <= self.duty) {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct Vrc6Saw {
+    rate: u8,
+    enabled: bool,
+    period: u16,
+    timer: u16,
+    /// Counts the timer clocks within the 14 that make up one saw period
+    step: u8,
+    accumulator: u8,
+}
+
+impl Vrc6Saw {
+    fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => self.rate = data & 0x3F,
+            1 => self.period = (self.period & 0x0F00) | data as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((data as u16 & 0x0F) << 8);
+                self.enabled = data & 0x80 != 0;
+                if !self.enabled {
+                    self.step = 0;
+                    self.accumulator = 0;
+                }
+            }
+        }
+    }
+
+    fn clock(&mut self, shift: u8) {
+        if !self.enabled {
+            return;
+        }
+        if self.timer == 0 {
+            self.timer = self.period >> shift;
+            // The accumulator takes the rate on every other clock, and
+            // resets after the seventh addition
+            self.step += 1;
+            if self.step == 14 {
+                self.step = 0;
+                self.accumulator = 0;
+            } else if self.step & 1 == 0 {
+                self.accumulator = self.accumulator.wrapping_add(self.rate);
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        self.accumulator >> 3
+    }
+}
+
+/// Konami VRC6 audio: two pulse channels with 8 duty settings and a sawtooth,
+/// at 0x9000 - 0x9003, 0xA000 - 0xA002 and 0xB000 - 0xB002. Boards that swap
+/// A0 and A1 (mapper 26) must unswap addresses before passing them on.
+#[derive(Debug, Default, Clone)]
+pub struct Vrc6Audio {
+    pulse1: Vrc6Pulse,
+    pulse2: Vrc6Pulse,
+    saw: Vrc6Saw,
+    halt: bool,
+    /// Divides every channel's period by 16 or 256
+    period_shift: u8,
+}
+
+impl Vrc6Audio {
+    pub fn new() -> Self {
+        Default::default()
+    }
+}
+
+impl ExpansionAudio for Vrc6Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9000..=0x9002 => self.pulse1.write(addr - 0x9000, data),
+            0x9003 => {
+                self.halt = data & 0x01 != 0;
+                self.period_shift = if data & 0x04 != 0 {
+                    8
+                } else if data & 0x02 != 0 {
+                    4
+                } else {
+                    0
+                };
+            }
+            0xA000..=0xA002 => self.pulse2.write(addr - 0xA000, data),
+            0xB000..=0xB002 => self.saw.write(addr - 0xB000, data),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        if self.halt {
+            return;
+        }
+        self.pulse1.clock(self.period_shift);
+        self.pulse2.clock(self.period_shift);
+        self.saw.clock(self.period_shift);
+    }
+
+    fn output(&self) -> f32 {
+        let level = self.pulse1.output() + self.pulse2.output() + self.saw.output();
+        level as f32 * LEVEL_PER_STEP
+    }
+
+    fn power_on(&mut self) {
+        *self = Self::new();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pulse_duty() {
+        let mut vrc6 = Vrc6Audio::new();
+        // Duty 3 (4/16), volume 10, period 9 (10 cycles per step)
+        vrc6.write(0x9000, 0x3A);
+        vrc6.write(0x9001, 0x09);
+        vrc6.write(0x9002, 0x80);
+
+        let mut levels = Vec::new();
+        for _ in 0..160 {
+            vrc6.tick();
+            levels.push(vrc6.pulse1.output());
+        }
+        assert_eq!(levels.iter().filter(|level| **level == 10).count(), 40);
+        assert!(levels.iter().all(|level| *level == 0 || *level == 10));
+
+        // Digitized mode outputs the volume constantly
+        vrc6.write(0x9000, 0x8A);
+        assert_eq!(vrc6.pulse1.output(), 10);
+        // Halting freezes the channels
+        vrc6.write(0x9003, 0x01);
+        let step = vrc6.pulse1.step;
+        for _ in 0..100 {
+            vrc6.tick();
+        }
+        assert_eq!(vrc6.pulse1.step, step);
+    }
+
+    #[test]
+    fn test_saw_ramp() {
+        let mut vrc6 = Vrc6Audio::new();
+        // Rate 42, period 0 so the accumulator steps every cycle
+        vrc6.write(0xB000, 42);
+        vrc6.write(0xB001, 0);
+        vrc6.write(0xB002, 0x80);
+
+        let mut levels = Vec::new();
+        for _ in 0..28 {
+            vrc6.tick();
+            levels.push(vrc6.saw.output());
+        }
+        // Six additions of 42 make 252, which outputs 31, then it resets
+        assert_eq!(
+            &levels[..14],
+            &[0, 5, 5, 10, 10, 15, 15, 21, 21, 26, 26, 31, 31, 0]
+        );
+        assert_eq!(&levels[..14], &levels[14..]);
+        assert!((vrc6.output() - 0.0).abs() < f32::EPSILON);
+    }
+}
@@ -0,0 +1,464 @@
+use std::f32::consts::PI;
+
+use super::{APU_PULSE_LEVEL, ExpansionAudio};
+
+/// The OPLL core is clocked at 3.58MHz and produces a sample every 72 of its
+/// clocks, which is every 36 CPU cycles
+const CPU_CYCLES_PER_SAMPLE: u8 = 36;
+
+/// One channel at full scale is a little louder than an APU pulse
+const CHANNEL_LEVEL: f32 = APU_PULSE_LEVEL * 1.25;
+
+/// Operators are silent once attenuated this far, in dB
+const MAX_ATTENUATION: f32 = 48.0;
+
+/// Built-in instruments 1 - 15, in the custom instrument's register format
+const PATCHES: [[u8; 8]; 15] = [
+    [0x03, 0x21, 0x05, 0x06, 0xE8, 0x81, 0x42, 0x27],
+    [0x13, 0x41, 0x14, 0x0D, 0xD8, 0xF6, 0x23, 0x12],
+    [0x11, 0x11, 0x08, 0x08, 0xFA, 0xB2, 0x20, 0x12],
+    [0x31, 0x61, 0x0C, 0x07, 0xA8, 0x64, 0x61, 0x27],
+    [0x32, 0x21, 0x1E, 0x06, 0xE1, 0x76, 0x01, 0x28],
+    [0x02, 0x01, 0x06, 0x00, 0xA3, 0xE2, 0xF4, 0xF4],
+    [0x21, 0x61, 0x1D, 0x07, 0x82, 0x81, 0x11, 0x07],
+    [0x23, 0x21, 0x22, 0x17, 0xA2, 0x72, 0x01, 0x17],
+    [0x35, 0x11, 0x25, 0x00, 0x40, 0x73, 0x72, 0x01],
+    [0xB5, 0x01, 0x0F, 0x0F, 0xA8, 0xA5, 0x51, 0x02],
+    [0x17, 0xC1, 0x24, 0x07, 0xF8, 0xF8, 0x22, 0x12],
+    [0x71, 0x23, 0x11, 0x06, 0x65, 0x74, 0x18, 0x16],
+    [0x01, 0x02, 0xD3, 0x05, 0xC9, 0x95, 0x03, 0x02],
+    [0x61, 0x63, 0x0C, 0x00, 0x94, 0xC0, 0x33, 0xF6],
+    [0x21, 0x72, 0x0D, 0x00, 0xC1, 0xD5, 0x56, 0x06],
+];
+
+const MULTIPLIERS: [f32; 16] = [
+    0.5, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 10.0, 12.0, 12.0, 15.0, 15.0,
+];
+
+/// Key scale attenuation in dB by the top 4 bits of the frequency number,
+/// for block 7. Each lower block is 6dB less.
+const KSL_TABLE: [f32; 16] = [
+    0.0, 18.0, 24.0, 27.75, 30.0, 32.25, 33.75, 35.25, 36.0, 37.5, 38.25, 39.0, 39.75, 40.5, 41.25,
+    42.0,
+];
+/// How much of the key scale attenuation each KSL setting applies
+const KSL_SCALE: [f32; 4] = [0.0, 0.25, 0.5, 1.0];
+
+/// Tremolo and vibrato LFOs
+const AM_HZ: f32 = 3.7;
+const AM_DEPTH_DB: f32 = 4.8;
+const VIBRATO_HZ: f32 = 6.4;
+/// About 7 cents either side
+const VIBRATO_DEPTH: f32 = 0.004;
+
+/// Operator settings from one half of an instrument
+#[derive(Debug, Clone, Copy)]
+struct OperatorPatch {
+    am: bool,
+    vibrato: bool,
+    /// Hold at the sustain level until key off, rather than decaying on
+    sustained: bool,
+    key_scale_rate: bool,
+    multiplier: u8,
+    key_scale_level: u8,
+    /// Discard the negative half of the sine wave
+    rectify: bool,
+    attack: u8,
+    decay: u8,
+    sustain_level: u8,
+    release: u8,
+}
+
+impl OperatorPatch {
+    /// op is 0 for the modulator, 1 for the carrier
+    fn from_patch(patch: &[u8; 8], op: usize) -> Self {
+        let flags = patch[op];
+        OperatorPatch {
+            am: flags & 0x80 != 0,
+            vibrato: flags & 0x40 != 0,
+            sustained: flags & 0x20 != 0,
+            key_scale_rate: flags & 0x10 != 0,
+            multiplier: flags & 0x0F,
+            key_scale_level: patch[2 + op] >> 6,
+            rectify: patch[3] & (0x08 << op) != 0,
+            attack: patch[4 + op] >> 4,
+            decay: patch[4 + op] & 0x0F,
+            sustain_level: patch[6 + op] >> 4,
+            release: patch[6 + op] & 0x0F,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeState {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+    Off,
+}
+
+#[derive(Debug, Clone)]
+struct Operator {
+    /// Position in the waveform, in cycles
+    phase: f32,
+    state: EnvelopeState,
+    /// Envelope attenuation in dB
+    attenuation: f32,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Operator {
+            phase: 0.0,
+            state: EnvelopeState::Off,
+            attenuation: MAX_ATTENUATION,
+        }
+    }
+}
+
+/// Attenuation change per sample when decaying at an effective rate
+fn decay_db_per_sample(rate: u8) -> f32 {
+    (rate as f32 / 4.0).exp2() * 1.23e-5
+}
+
+impl Operator {
+    fn key_on(&mut self) {
+        self.phase = 0.0;
+        self.state = EnvelopeState::Attack;
+    }
+
+    fn key_off(&mut self) {
+        if self.state != EnvelopeState::Off {
+            self.state = EnvelopeState::Release;
+        }
+    }
+
+    /// Step the envelope by one sample. rks is the key scale rate offset,
+    /// and release the rate to use once the key is released.
+    fn clock_envelope(&mut self, patch: &OperatorPatch, rks: u8, release: u8) {
+        let effective = |rate: u8| {
+            if rate == 0 {
+                0
+            } else {
+                (rate * 4 + rks).min(63)
+            }
+        };
+        match self.state {
+            EnvelopeState::Attack => {
+                let rate = effective(patch.attack);
+                if rate >= 60 {
+                    self.attenuation = 0.0;
+                } else if rate > 0 {
+                    self.attenuation *= 1.0 - (rate as f32 / 4.0).exp2() / 131072.0;
+                }
+                if self.attenuation < 0.05 {
+                    self.attenuation = 0.0;
+                    self.state = EnvelopeState::Decay;
+                }
+            }
+            EnvelopeState::Decay => {
+                self.attenuation += decay_db_per_sample(effective(patch.decay));
+                let sustain_level = patch.sustain_level as f32 * 3.0;
+                if self.attenuation >= sustain_level {
+                    self.attenuation = sustain_level;
+                    self.state = EnvelopeState::Sustain;
+                }
+            }
+            EnvelopeState::Sustain => {
+                if !patch.sustained {
+                    self.attenuation += decay_db_per_sample(effective(patch.release));
+                }
+            }
+            EnvelopeState::Release => {
+                self.attenuation += decay_db_per_sample(effective(release));
+            }
+            EnvelopeState::Off => {}
+        }
+        if self.attenuation >= MAX_ATTENUATION {
+            self.attenuation = MAX_ATTENUATION;
+            self.state = EnvelopeState::Off;
+        }
+    }
+
+    /// Output for a phase offset in radians, scaled by an attenuation in dB
+    fn output(&self, phase_offset: f32, attenuation: f32, rectify: bool) -> f32 {
+        if self.state == EnvelopeState::Off {
+            return 0.0;
+        }
+        let sine = (2.0 * PI * self.phase + phase_offset).sin();
+        let sine = if rectify { sine.max(0.0) } else { sine };
+        sine * 10f32.powf(-attenuation / 20.0)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct Channel {
+    fnum: u16,
+    block: u8,
+    key: bool,
+    sustain: bool,
+    instrument: u8,
+    volume: u8,
+    modulator: Operator,
+    carrier: Operator,
+    /// Last two modulator outputs, averaged for feedback
+    feedback: [f32; 2],
+    output: f32,
+}
+
+impl Channel {
+    fn set_key(&mut self, key: bool) {
+        if key && !self.key {
+            self.modulator.key_on();
+            self.carrier.key_on();
+        } else if !key && self.key {
+            self.modulator.key_off();
+            self.carrier.key_off();
+        }
+        self.key = key;
+    }
+
+    fn key_scale_attenuation(&self, patch: &OperatorPatch) -> f32 {
+        let base = KSL_TABLE[(self.fnum >> 5) as usize] - 6.0 * (7 - self.block) as f32;
+        base.max(0.0) * KSL_SCALE[patch.key_scale_level as usize]
+    }
+
+    fn clock(&mut self, patch: &[u8; 8], am_db: f32, vibrato: f32) {
+        let ops = [
+            OperatorPatch::from_patch(patch, 0),
+            OperatorPatch::from_patch(patch, 1),
+        ];
+        let key_scale = (self.block << 1) | (self.fnum >> 8) as u8;
+        let base_increment = self.fnum as f32 * (self.block as f32).exp2() / 524288.0;
+
+        for (op, operator) in [&mut self.modulator, &mut self.carrier]
+            .into_iter()
+            .enumerate()
+        {
+            let patch = &ops[op];
+            let rks = if patch.key_scale_rate {
+                key_scale
+            } else {
+                key_scale >> 2
+            };
+            // The channel's sustain flag slows the release
+            let release = if self.sustain { 5 } else { patch.release };
+            operator.clock_envelope(patch, rks, release);
+            let vibrato = if patch.vibrato { vibrato } else { 1.0 };
+            operator.phase = (operator.phase
+                + base_increment * MULTIPLIERS[patch.multiplier as usize] * vibrato)
+                .fract();
+        }
+
+        let attenuation = |operator: &Operator, patch: &OperatorPatch| {
+            operator.attenuation
+                + self.key_scale_attenuation(patch)
+                + if patch.am { am_db } else { 0.0 }
+        };
+
+        let feedback_level = patch[3] & 0x07;
+        let feedback_phase = if feedback_level == 0 {
+            0.0
+        } else {
+            (self.feedback[0] + self.feedback[1]) / 2.0 * PI * (feedback_level as f32 - 5.0).exp2()
+        };
+        let total_level = (patch[2] & 0x3F) as f32 * 0.75;
+        let modulator_attenuation = attenuation(&self.modulator, &ops[0]) + total_level;
+        let carrier_attenuation = attenuation(&self.carrier, &ops[1]) + self.volume as f32 * 3.0;
+
+        let modulator =
+            self.modulator
+                .output(feedback_phase, modulator_attenuation, ops[0].rectify);
+        self.feedback = [self.feedback[1], modulator];
+        self.output =
+            self.carrier
+                .output(modulator * 4.0 * PI, carrier_attenuation, ops[1].rectify);
+    }
+}
+
+/// Konami VRC7 audio: a cut-down Yamaha OPLL with six two-operator FM
+/// channels, 15 built-in instruments and one custom instrument. Registers are
+/// selected by writing 0x9010 and written through 0x9030.
+#[derive(Debug, Clone)]
+pub struct Vrc7Audio {
+    reg_select: u8,
+    custom_patch: [u8; 8],
+    channels: [Channel; 6],
+    cycle: u8,
+    am_phase: f32,
+    vibrato_phase: f32,
+    /// Samples per second of emulated time, which the LFOs step by
+    sample_rate: f32,
+}
+
+impl Vrc7Audio {
+    /// cpu_clock_hz is the rate the chip is ticked at
+    pub fn new(cpu_clock_hz: f64) -> Self {
+        Vrc7Audio {
+            reg_select: 0,
+            custom_patch: [0; 8],
+            channels: Default::default(),
+            cycle: 0,
+            am_phase: 0.0,
+            vibrato_phase: 0.0,
+            sample_rate: (cpu_clock_hz / CPU_CYCLES_PER_SAMPLE as f64) as f32,
+        }
+    }
+
+    fn write_register(&mut self, reg: u8, data: u8) {
+        let channel = (reg & 0x0F) as usize;
+        match reg {
+            0x00..=0x07 => self.custom_patch[reg as usize] = data,
+            0x10..=0x15 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0x100) | data as u16;
+            }
+            0x20..=0x25 => {
+                let channel = &mut self.channels[channel];
+                channel.fnum = (channel.fnum & 0xFF) | ((data as u16 & 0x01) << 8);
+                channel.block = (data >> 1) & 0x07;
+                channel.sustain = data & 0x20 != 0;
+                channel.set_key(data & 0x10 != 0);
+            }
+            0x30..=0x35 => {
+                let channel = &mut self.channels[channel];
+                channel.instrument = data >> 4;
+                channel.volume = data & 0x0F;
+            }
+            _ => {}
+        }
+    }
+
+    fn clock_sample(&mut self) {
+        self.am_phase = (self.am_phase + AM_HZ / self.sample_rate).fract();
+        self.vibrato_phase = (self.vibrato_phase + VIBRATO_HZ / self.sample_rate).fract();
+        let am_db = (1.0 - (2.0 * PI * self.am_phase).cos()) / 2.0 * AM_DEPTH_DB;
+        let vibrato = 1.0 + (2.0 * PI * self.vibrato_phase).sin() * VIBRATO_DEPTH;
+
+        for channel in self.channels.iter_mut() {
+            let patch = match channel.instrument {
+                0 => &self.custom_patch,
+                instrument => &PATCHES[instrument as usize - 1],
+            };
+            channel.clock(patch, am_db, vibrato);
+        }
+    }
+}
+
+impl ExpansionAudio for Vrc7Audio {
+    fn write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x9010 => self.reg_select = data,
+            0x9030 => self.write_register(self.reg_select, data),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self) {
+        self.cycle += 1;
+        if self.cycle == CPU_CYCLES_PER_SAMPLE {
+            self.cycle = 0;
+            self.clock_sample();
+        }
+    }
+
+    fn output(&self) -> f32 {
+        self.channels
+            .iter()
+            .map(|channel| channel.output)
+            .sum::<f32>()
+            * CHANNEL_LEVEL
+    }
+
+    fn power_on(&mut self) {
+        *self = Vrc7Audio {
+            sample_rate: self.sample_rate,
+            ..Self::new(0.0)
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::timing::ConsoleTiming;
+
+    fn write_reg(vrc7: &mut Vrc7Audio, reg: u8, data: u8) {
+        vrc7.write(0x9010, reg);
+        vrc7.write(0x9030, data);
+    }
+
+    /// Run for a number of CPU cycles, returning the output at each sample
+    fn run(vrc7: &mut Vrc7Audio, cycles: u32) -> Vec<f32> {
+        let mut samples = Vec::new();
+        for cycle in 0..cycles {
+            vrc7.tick();
+            if cycle % CPU_CYCLES_PER_SAMPLE as u32 == 0 {
+                samples.push(vrc7.output());
+            }
+        }
+        samples
+    }
+
+    fn zero_crossings(samples: &[f32]) -> usize {
+        samples
+            .windows(2)
+            .filter(|pair| (pair[0] < 0.0) != (pair[1] < 0.0))
+            .count()
+    }
+
+    #[test]
+    fn test_custom_sine() {
+        let mut vrc7 = Vrc7Audio::new(ConsoleTiming::NTSC.cpu_clock_hz());
+        // Custom instrument: silent modulator, sustained carrier with instant
+        // attack, no decay and the fastest release
+        for (reg, data) in [0x00u8, 0x21, 0x3F, 0x00, 0xF0, 0xF0, 0x0F, 0x0F]
+            .into_iter()
+            .enumerate()
+        {
+            write_reg(&mut vrc7, reg as u8, data);
+        }
+        // Channel 0: full volume, fnum 290 in block 4 is about 440Hz
+        let fnum: u16 = 290;
+        write_reg(&mut vrc7, 0x30, 0x00);
+        write_reg(&mut vrc7, 0x10, fnum as u8);
+        write_reg(&mut vrc7, 0x20, 0x10 | (4 << 1) | (fnum >> 8) as u8);
+
+        // Half a second of a 440Hz wave crosses zero 440 times
+        let samples = run(&mut vrc7, 1_789_773 / 2);
+        let crossings = zero_crossings(&samples);
+        assert!((435..=445).contains(&crossings), "{} crossings", crossings);
+        let peak = samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!((peak - CHANNEL_LEVEL).abs() < CHANNEL_LEVEL * 0.01);
+
+        // Key off releases to silence
+        write_reg(&mut vrc7, 0x20, (4 << 1) | (fnum >> 8) as u8);
+        let samples = run(&mut vrc7, 1_789_773 / 10);
+        assert_eq!(*samples.last().unwrap(), 0.0);
+        assert_eq!(vrc7.channels[0].carrier.state, EnvelopeState::Off);
+    }
+
+    #[test]
+    fn test_volume_and_instruments() {
+        let mut vrc7 = Vrc7Audio::new(ConsoleTiming::NTSC.cpu_clock_hz());
+        // Built-in instrument 3 at volume 0 and volume 10 (30dB down)
+        write_reg(&mut vrc7, 0x10, 0xAC);
+        write_reg(&mut vrc7, 0x30, 0x30);
+        write_reg(&mut vrc7, 0x20, 0x10 | (4 << 1));
+        let loud = run(&mut vrc7, 1_789_773 / 20);
+        let peak = |samples: &[f32]| samples.iter().fold(0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak(&loud) > 0.1 * CHANNEL_LEVEL);
+
+        let mut quiet_vrc7 = Vrc7Audio::new(ConsoleTiming::NTSC.cpu_clock_hz());
+        write_reg(&mut quiet_vrc7, 0x10, 0xAC);
+        write_reg(&mut quiet_vrc7, 0x30, 0x3A);
+        write_reg(&mut quiet_vrc7, 0x20, 0x10 | (4 << 1));
+        let quiet = run(&mut quiet_vrc7, 1_789_773 / 20);
+        let ratio = peak(&quiet) / peak(&loud);
+        assert!((0.02..0.05).contains(&ratio), "ratio {}", ratio);
+
+        // Channels without a key never sound
+        assert!(vrc7.channels[1..].iter().all(|c| c.output == 0.0));
+    }
+}
@@ -0,0 +1,735 @@
+use std::{cell::RefCell, fs, io, path::PathBuf, rc::Rc};
+
+use super::{
+    BusDevice, EmuError, EmuResult, ReadResult,
+    audio::AudioOutput,
+    expansion_audio::{ExpansionAudio, fds::FdsAudio},
+    nametables::{Mirroring, NametableDevice},
+    signal::WiredOrSource,
+    timing::ConsoleTiming,
+};
+use crate::{
+    fds::{FdsImage, fds_crc_update},
+    patch::{apply_patch, create_ips},
+};
+
+/// Bits per second passing under the drive head, about one byte every 149
+/// NTSC CPU cycles
+const BIT_RATE_HZ: f64 = 96_400.0;
+/// Time for the drive to return the head to the start of the disk and spin up
+const HEAD_RETURN_US: u64 = 28_000;
+/// How long a disk stays ejected when changing sides, so the BIOS notices
+const DISK_SWAP_MS: u64 = 1000;
+
+/// Register offsets from 0x4020
+const TIMER_RELOAD_LO: u32 = 0x00;
+const TIMER_RELOAD_HI: u32 = 0x01;
+const TIMER_CONTROL: u32 = 0x02;
+const MASTER_IO_ENABLE: u32 = 0x03;
+const WRITE_DATA: u32 = 0x04;
+const FDS_CONTROL: u32 = 0x05;
+const EXT_CONNECTOR_WRITE: u32 = 0x06;
+const DISK_STATUS: u32 = 0x10;
+const READ_DATA: u32 = 0x11;
+const DRIVE_STATUS: u32 = 0x12;
+const EXT_CONNECTOR_READ: u32 = 0x13;
+/// Start of the sound registers, 0x4040 - 0x4092
+const SOUND_REGS: u32 = 0x20;
+
+#[derive(Debug, Default)]
+struct DiskControlInner {
+    /// Side to show as inserted, applied on the adapter's next tick. The
+    /// outer None means no change is pending; the inner None ejects.
+    requested_side: Option<Option<usize>>,
+    current_side: Option<usize>,
+}
+
+/// Handle for swapping disks from outside the emulation, like the user
+/// reaching for the drive's eject button
+#[derive(Clone, Debug)]
+pub struct FdsDiskControl {
+    inner: Rc<RefCell<DiskControlInner>>,
+}
+
+impl FdsDiskControl {
+    /// Eject the current disk, and insert the given side a second later so
+    /// the BIOS sees the disk change
+    pub fn insert_side(&self, side: usize) {
+        self.inner.borrow_mut().requested_side = Some(Some(side));
+    }
+
+    pub fn eject(&self) {
+        self.inner.borrow_mut().requested_side = Some(None);
+    }
+
+    /// The side currently in the drive, if any
+    pub fn current_side(&self) -> Option<usize> {
+        self.inner.borrow().current_side
+    }
+}
+
+/// The Famicom Disk System RAM adapter's disk, timer and sound registers at
+/// 0x4020 - 0x4092, together with the drive attached to it. The drive sees a
+/// side as a stream of bytes passing under the head at a fixed rate, with
+/// gaps between blocks, so transfers take as long as they do on hardware.
+///
+/// Writes to the disk are kept in memory and saved as an IPS patch against
+/// the disk image, rather than by rewriting the image.
+pub struct FdsRamAdapter {
+    image: FdsImage,
+    /// Serialized image before any saved writes were applied, for diffing
+    original: Vec<u8>,
+    save_path: Option<PathBuf>,
+    raw_sides: Vec<Vec<u8>>,
+    dirty: bool,
+
+    control: Rc<RefCell<DiskControlInner>>,
+    inserted_side: Option<usize>,
+    insert_countdown: Option<(u64, usize)>,
+    disk_swap_ticks: u64,
+    /// CPU cycles per byte passing under the drive head
+    byte_cycles: u32,
+    head_return_cycles: u32,
+
+    irq_signal: WiredOrSource,
+    timer_irq: bool,
+    disk_irq: bool,
+
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+    disk_regs_enabled: bool,
+    sound_regs_enabled: bool,
+
+    audio: FdsAudio,
+    audio_output: Option<AudioOutput>,
+    /// The PPU's nametables, whose mirroring 0x4025 controls
+    nametables: Option<NametableDevice>,
+
+    write_data: u8,
+    read_data: u8,
+    ext_connector: u8,
+    // 0x4025 bits
+    motor_on: bool,
+    transfer_reset: bool,
+    read_mode: bool,
+    horizontal_mirroring: bool,
+    crc_control: bool,
+    transfer_start: bool,
+    disk_irq_enabled: bool,
+
+    // Drive state
+    head_position: usize,
+    byte_delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    byte_transferred: bool,
+    crc: u16,
+    crc_error: bool,
+    previous_crc_control: bool,
+}
+
+impl FdsRamAdapter {
+    pub fn new(
+        image: FdsImage,
+        irq_signal: WiredOrSource,
+        save_path: Option<PathBuf>,
+        timing: &ConsoleTiming,
+    ) -> Self {
+        let original = image.to_bytes();
+        FdsRamAdapter {
+            image,
+            original,
+            save_path,
+            raw_sides: Vec::new(),
+            dirty: false,
+            control: Default::default(),
+            inserted_side: None,
+            insert_countdown: None,
+            disk_swap_ticks: timing.ms_to_cpu_cycles(DISK_SWAP_MS),
+            byte_cycles: (timing.cpu_clock_hz() * 8.0 / BIT_RATE_HZ).round() as u32,
+            head_return_cycles: timing.us_to_cpu_cycles(HEAD_RETURN_US) as u32,
+            irq_signal,
+            timer_irq: false,
+            disk_irq: false,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            disk_regs_enabled: false,
+            sound_regs_enabled: false,
+            audio: FdsAudio::new(),
+            audio_output: None,
+            nametables: None,
+            write_data: 0,
+            read_data: 0,
+            ext_connector: 0,
+            motor_on: false,
+            transfer_reset: false,
+            read_mode: true,
+            horizontal_mirroring: false,
+            crc_control: false,
+            transfer_start: false,
+            disk_irq_enabled: false,
+            head_position: 0,
+            byte_delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            byte_transferred: false,
+            crc: 0,
+            crc_error: false,
+            previous_crc_control: false,
+        }
+    }
+
+    /// Mix the adapter's sound channel into the console's audio
+    pub fn with_audio_output(mut self, audio_output: AudioOutput) -> Self {
+        self.audio_output = Some(audio_output);
+        self
+    }
+
+    /// Let bit 3 of 0x4025 switch the PPU's nametable mirroring
+    pub fn with_nametables(mut self, nametables: NametableDevice) -> Self {
+        self.nametables = Some(nametables);
+        self
+    }
+
+    pub fn make_disk_control(&self) -> FdsDiskControl {
+        FdsDiskControl {
+            inner: Rc::clone(&self.control),
+        }
+    }
+
+    /// Nametable arrangement selected by bit 3 of 0x4025
+    pub fn horizontal_mirroring(&self) -> bool {
+        self.horizontal_mirroring
+    }
+
+    fn set_inserted_side(&mut self, side: Option<usize>) {
+        self.inserted_side = side.filter(|side| *side < self.raw_sides.len());
+        self.control.borrow_mut().current_side = self.inserted_side;
+        self.end_of_head = true;
+        self.scanning = false;
+    }
+
+    fn set_horizontal_mirroring(&mut self, horizontal: bool) {
+        self.horizontal_mirroring = horizontal;
+        if let Some(nametables) = &self.nametables {
+            nametables.set_mirroring(if horizontal {
+                Mirroring::Horizontal
+            } else {
+                Mirroring::Vertical
+            });
+        }
+    }
+
+    fn update_irq(&mut self) {
+        self.irq_signal.set(self.timer_irq || self.disk_irq);
+    }
+
+    fn load(&mut self) -> EmuResult<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        let save_error =
+            |e: &dyn std::fmt::Display| EmuError::SaveFile(format!("{}: {}", path.display(), e));
+        match fs::read(path) {
+            Ok(diff) => {
+                let patched = apply_patch(&self.original, &diff).map_err(|e| save_error(&e))?;
+                self.image = FdsImage::parse(&patched).map_err(|e| save_error(&e))?;
+                Ok(())
+            }
+            // Nothing has been written to the disk yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(save_error(&e)),
+        }
+    }
+
+    fn flush(&mut self) -> EmuResult<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        for (side, raw) in self.raw_sides.iter().enumerate() {
+            self.image.sides[side] = FdsImage::blocks_from_raw_side(raw);
+        }
+        if let Some(path) = &self.save_path {
+            let diff = create_ips(&self.original, &self.image.to_bytes());
+            fs::write(path, diff).map_err(|e| {
+                EmuError::SaveFile(format!("failed to write {}: {}", path.display(), e))
+            })?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn tick_timer(&mut self) {
+        if !self.timer_enabled || !self.disk_regs_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.update_irq();
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn tick_disk_change(&mut self) {
+        let request = self.control.borrow_mut().requested_side.take();
+        if let Some(side) = request {
+            self.set_inserted_side(None);
+            self.insert_countdown = side.map(|side| (self.disk_swap_ticks, side));
+        }
+        if let Some((countdown, side)) = self.insert_countdown {
+            if countdown == 0 {
+                self.insert_countdown = None;
+                self.set_inserted_side(Some(side));
+            } else {
+                self.insert_countdown = Some((countdown - 1, side));
+            }
+        }
+    }
+
+    fn tick_drive(&mut self) {
+        let Some(side) = self.inserted_side else {
+            return;
+        };
+        if !self.motor_on {
+            self.end_of_head = true;
+            self.scanning = false;
+            return;
+        }
+        if self.transfer_reset && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            // The head returns to the start of the disk before scanning
+            self.end_of_head = false;
+            self.head_position = 0;
+            self.byte_delay = self.head_return_cycles;
+            return;
+        }
+        if self.byte_delay > 0 {
+            self.byte_delay -= 1;
+            return;
+        }
+        self.scanning = true;
+
+        let mut transferred = false;
+        if self.read_mode {
+            let data = self.raw_sides[side][self.head_position];
+            if !self.transfer_start {
+                // Waiting for the BIOS to start a block
+                self.gap_ended = false;
+                self.crc = 0;
+            } else {
+                self.crc = fds_crc_update(self.crc, data);
+                if self.gap_ended {
+                    self.read_data = data;
+                    transferred = true;
+                } else if data != 0 {
+                    // The gap terminator isn't passed on to the CPU
+                    self.gap_ended = true;
+                }
+            }
+            if self.crc_control {
+                self.crc_error = self.crc != 0;
+            }
+        } else {
+            let data = if !self.transfer_start {
+                0
+            } else if !self.crc_control {
+                transferred = true;
+                self.crc = fds_crc_update(self.crc, self.write_data);
+                self.write_data
+            } else {
+                if !self.previous_crc_control {
+                    // Shift out the remainder, then send it low byte first
+                    self.crc = fds_crc_update(fds_crc_update(self.crc, 0), 0);
+                }
+                let byte = self.crc as u8;
+                self.crc >>= 8;
+                byte
+            };
+            if !self.transfer_start {
+                self.crc = 0;
+            }
+            let byte = &mut self.raw_sides[side][self.head_position];
+            if *byte != data {
+                *byte = data;
+                self.dirty = true;
+            }
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        if transferred {
+            self.byte_transferred = true;
+            if self.disk_irq_enabled {
+                self.disk_irq = true;
+                self.update_irq();
+            }
+        }
+
+        self.head_position += 1;
+        if self.head_position >= self.raw_sides[side].len() {
+            // Reached the end of the disk; the drive stops until restarted
+            self.motor_on = false;
+            self.end_of_head = true;
+            self.scanning = false;
+        } else {
+            self.byte_delay = self.byte_cycles - 1;
+        }
+    }
+}
+
+impl BusDevice for FdsRamAdapter {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let disk_present = self.inserted_side.is_some();
+        let value = match addr {
+            DISK_STATUS => {
+                let value = (self.timer_irq as u8)
+                    | (self.byte_transferred as u8) << 1
+                    | (self.crc_error as u8) << 4
+                    | (self.end_of_head as u8) << 6
+                    | (self.scanning as u8) << 7;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                self.byte_transferred = false;
+                self.update_irq();
+                value
+            }
+            READ_DATA => {
+                self.byte_transferred = false;
+                self.disk_irq = false;
+                self.update_irq();
+                self.read_data
+            }
+            DRIVE_STATUS => {
+                // Bits are active low: disk inserted, drive ready, writable
+                0x40 | (!disk_present as u8)
+                    | ((!disk_present || !self.scanning) as u8) << 1
+                    | (!disk_present as u8) << 2
+            }
+            // Bit 7 is the battery status, always good
+            EXT_CONNECTOR_READ => 0x80 | (self.ext_connector & 0x7F),
+            SOUND_REGS.. if self.sound_regs_enabled => {
+                match self.audio.read((addr + 0x4020) as u16) {
+                    Some(value) => value,
+                    None => return Ok(ReadResult::OpenBus),
+                }
+            }
+            _ => return Ok(ReadResult::OpenBus),
+        };
+        Ok(ReadResult::Data(value))
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        match addr {
+            TIMER_RELOAD_LO => self.timer_reload = (self.timer_reload & 0xFF00) | data as u16,
+            TIMER_RELOAD_HI => {
+                self.timer_reload = (self.timer_reload & 0x00FF) | (data as u16) << 8
+            }
+            TIMER_CONTROL => {
+                self.timer_repeat = data & 0x01 != 0;
+                self.timer_enabled = data & 0x02 != 0 && self.disk_regs_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                    self.update_irq();
+                }
+            }
+            MASTER_IO_ENABLE => {
+                self.disk_regs_enabled = data & 0x01 != 0;
+                self.sound_regs_enabled = data & 0x02 != 0;
+                if !self.disk_regs_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                    self.update_irq();
+                }
+            }
+            WRITE_DATA if self.disk_regs_enabled => {
+                self.write_data = data;
+                self.byte_transferred = false;
+                self.disk_irq = false;
+                self.update_irq();
+            }
+            FDS_CONTROL if self.disk_regs_enabled => {
+                self.motor_on = data & 0x01 != 0;
+                self.transfer_reset = data & 0x02 != 0;
+                self.read_mode = data & 0x04 != 0;
+                self.set_horizontal_mirroring(data & 0x08 != 0);
+                self.crc_control = data & 0x10 != 0;
+                self.transfer_start = data & 0x40 != 0;
+                self.disk_irq_enabled = data & 0x80 != 0;
+                self.disk_irq = false;
+                self.update_irq();
+            }
+            EXT_CONNECTOR_WRITE if self.disk_regs_enabled => self.ext_connector = data,
+            SOUND_REGS.. if self.sound_regs_enabled => {
+                self.audio.write((addr + 0x4020) as u16, data)
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.load()?;
+        self.raw_sides = (0..self.image.sides.len())
+            .map(|side| self.image.raw_side(side))
+            .collect();
+        self.set_inserted_side(Some(0));
+        Ok(())
+    }
+
+    fn end_of_simulation(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("{}", e);
+        }
+    }
+
+    fn power_on(&mut self) {
+        self.timer_irq = false;
+        self.disk_irq = false;
+        self.update_irq();
+        self.timer_reload = 0;
+        self.timer_counter = 0;
+        self.timer_repeat = false;
+        self.timer_enabled = false;
+        self.disk_regs_enabled = false;
+        self.sound_regs_enabled = false;
+        self.audio.power_on();
+        self.write_data = 0;
+        self.read_data = 0;
+        self.ext_connector = 0;
+        self.motor_on = false;
+        self.transfer_reset = false;
+        self.read_mode = true;
+        self.set_horizontal_mirroring(false);
+        self.crc_control = false;
+        self.transfer_start = false;
+        self.disk_irq_enabled = false;
+        self.end_of_head = true;
+        self.scanning = false;
+        self.gap_ended = false;
+        self.byte_transferred = false;
+        self.crc_error = false;
+    }
+
+    // The RAM adapter isn't connected to the reset line; the BIOS
+    // reinitializes it from the reset vector
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.tick_disk_change();
+        self.tick_timer();
+        self.tick_drive();
+        self.audio.tick();
+        if let Some(audio_output) = &self.audio_output {
+            audio_output.mix(self.audio.output());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        components::signal::WiredOrSignal, fds::test::build_side, nes_file::NametableLayout,
+    };
+
+    fn make_adapter(save_path: Option<PathBuf>) -> FdsRamAdapter {
+        let image = FdsImage::parse(&build_side()).unwrap();
+        let mut adapter = FdsRamAdapter::new(
+            image,
+            WiredOrSignal::new().make_source("fds"),
+            save_path,
+            &ConsoleTiming::NTSC,
+        );
+        adapter.start_of_simulation().unwrap();
+        adapter.power_on();
+        adapter
+    }
+
+    fn read(adapter: &mut FdsRamAdapter, addr: u32) -> u8 {
+        match adapter.bus_read(addr).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus at 0x{:04X}", addr + 0x4020),
+        }
+    }
+
+    fn run(adapter: &mut FdsRamAdapter, cycles: u32) {
+        for _ in 0..cycles {
+            adapter.tick().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut adapter = make_adapter(None);
+        let irq = adapter.irq_signal.make_receiver();
+        adapter.bus_write(MASTER_IO_ENABLE, 0x01).unwrap();
+        adapter.bus_write(TIMER_RELOAD_LO, 10).unwrap();
+        adapter.bus_write(TIMER_RELOAD_HI, 0).unwrap();
+        adapter.bus_write(TIMER_CONTROL, 0x03).unwrap();
+
+        run(&mut adapter, 10);
+        assert!(!irq.get());
+        run(&mut adapter, 1);
+        assert!(irq.get());
+        assert_eq!(read(&mut adapter, DISK_STATUS) & 0x01, 0x01);
+        assert!(!irq.get());
+
+        // Repeat mode reloads the counter
+        run(&mut adapter, 11);
+        assert!(irq.get());
+
+        // Disabling the timer acknowledges the IRQ and stops it
+        adapter.bus_write(TIMER_CONTROL, 0x00).unwrap();
+        assert!(!irq.get());
+        run(&mut adapter, 100);
+        assert!(!irq.get());
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let nametables = NametableDevice::new(NametableLayout::Vertical);
+        let mut ppu_view = nametables.clone();
+        let mut adapter = make_adapter(None).with_nametables(nametables);
+        adapter.power_on();
+        adapter.bus_write(MASTER_IO_ENABLE, 0x01).unwrap();
+        ppu_view.bus_write(0x2000, 0x42).unwrap();
+        assert_eq!(ppu_view.bus_read(0x2800).unwrap(), ReadResult::Data(0x42));
+
+        adapter.bus_write(FDS_CONTROL, 0x08).unwrap();
+        assert_eq!(ppu_view.bus_read(0x2400).unwrap(), ReadResult::Data(0x42));
+        assert_eq!(ppu_view.bus_read(0x2800).unwrap(), ReadResult::Data(0x00));
+    }
+
+    #[test]
+    fn test_drive_status() {
+        let mut adapter = make_adapter(None);
+        let control = adapter.make_disk_control();
+        assert_eq!(control.current_side(), Some(0));
+        assert_eq!(read(&mut adapter, DRIVE_STATUS) & 0x07, 0x02);
+
+        control.eject();
+        run(&mut adapter, 1);
+        assert_eq!(control.current_side(), None);
+        assert_eq!(read(&mut adapter, DRIVE_STATUS) & 0x07, 0x07);
+
+        control.insert_side(0);
+        let swap_ticks = adapter.disk_swap_ticks as u32;
+        run(&mut adapter, swap_ticks);
+        assert_eq!(control.current_side(), None);
+        run(&mut adapter, 2);
+        assert_eq!(control.current_side(), Some(0));
+    }
+
+    /// Wait for the next byte transfer, returning how many cycles it took
+    fn wait_for_transfer(adapter: &mut FdsRamAdapter) -> u32 {
+        let irq = adapter.irq_signal.make_receiver();
+        let mut cycles = 0;
+        while !irq.get() {
+            adapter.tick().unwrap();
+            cycles += 1;
+            assert!(cycles < 1_000_000, "no byte transferred");
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_read_block() {
+        let mut adapter = make_adapter(None);
+        adapter.bus_write(MASTER_IO_ENABLE, 0x01).unwrap();
+        // Motor on, read mode, transfer start, byte IRQs
+        adapter.bus_write(FDS_CONTROL, 0xC5).unwrap();
+        wait_for_transfer(&mut adapter);
+        assert_eq!(read(&mut adapter, READ_DATA), 0x01);
+        let mut cycles = 0;
+        let mut magic = Vec::new();
+        for _ in 0..14 {
+            cycles += wait_for_transfer(&mut adapter);
+            magic.push(read(&mut adapter, READ_DATA));
+        }
+        assert_eq!(magic, b"*NINTENDO-HVC*");
+        assert_eq!(adapter.byte_cycles, 149);
+        assert_eq!(cycles, 14 * adapter.byte_cycles);
+
+        // Read the rest of the block, then let the CRC pass and check it
+        for _ in 15..56 {
+            wait_for_transfer(&mut adapter);
+            read(&mut adapter, READ_DATA);
+        }
+        adapter.bus_write(FDS_CONTROL, 0xD5).unwrap();
+        for _ in 0..2 {
+            wait_for_transfer(&mut adapter);
+            read(&mut adapter, READ_DATA);
+        }
+        assert_eq!(read(&mut adapter, DISK_STATUS) & 0x10, 0);
+    }
+
+    #[test]
+    fn test_pal_drive_speed() {
+        // The disk spins at the same speed, but the CPU is slower
+        let adapter = FdsRamAdapter::new(
+            FdsImage::parse(&build_side()).unwrap(),
+            WiredOrSignal::new().make_source("fds"),
+            None,
+            &ConsoleTiming::PAL,
+        );
+        assert_eq!(adapter.byte_cycles, 138);
+        assert_eq!(adapter.head_return_cycles, 46552);
+    }
+
+    #[test]
+    fn test_write_saved_as_patch() {
+        let save_path =
+            std::env::temp_dir().join(format!("fds_write_test_{}.ips", std::process::id()));
+        let _ = fs::remove_file(&save_path);
+        let mut adapter = make_adapter(Some(save_path.clone()));
+        adapter.bus_write(MASTER_IO_ENABLE, 0x01).unwrap();
+
+        // Move the head into the gap after the file header block: past the
+        // leading gap, and the first three blocks with their terminators and
+        // CRCs, and the gaps between them
+        let gap_start = 3537 + (1 + 56 + 2 + 122) + (1 + 2 + 2 + 122) + (1 + 16 + 2);
+        adapter.bus_write(FDS_CONTROL, 0x05).unwrap();
+        let byte_cycles = adapter.byte_cycles;
+        let head_return_cycles = adapter.head_return_cycles;
+        run(&mut adapter, head_return_cycles + 2);
+        run(&mut adapter, gap_start * byte_cycles);
+
+        // Write a gap, the terminator, and a new file data block
+        adapter.bus_write(FDS_CONTROL, 0x41).unwrap();
+        let block = [0x04, 0x12, 0x34, 0x56, 0x78];
+        for byte in [0x00, 0x80].iter().chain(&block) {
+            adapter.bus_write(WRITE_DATA, *byte).unwrap();
+            run(&mut adapter, byte_cycles);
+        }
+        adapter.bus_write(FDS_CONTROL, 0x51).unwrap();
+        run(&mut adapter, 3 * byte_cycles);
+        adapter.bus_write(FDS_CONTROL, 0x00).unwrap();
+        adapter.end_of_simulation();
+
+        let diff = fs::read(&save_path).unwrap();
+        let patched = apply_patch(&build_side(), &diff).unwrap();
+        let image = FdsImage::parse(&patched).unwrap();
+        assert_eq!(image.sides[0][3], block);
+
+        // A new adapter picks up the saved writes
+        let adapter = make_adapter(Some(save_path.clone()));
+        assert_eq!(adapter.image.sides[0][3], block);
+        fs::remove_file(&save_path).unwrap();
+    }
+}
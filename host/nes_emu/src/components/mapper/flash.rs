@@ -0,0 +1,391 @@
+use std::{fs, io, path::PathBuf};
+
+use crate::{
+    components::{
+        BusDevice, EmuError, EmuResult, ReadResult, mem::NVRAM_FLUSH_INTERVAL_MS,
+        timing::ConsoleTiming,
+    },
+    patch::{apply_patch, create_ips},
+};
+
+const SECTOR_SIZE: usize = 0x1000;
+
+/// Command addresses. The chip only decodes A0 - A14 for commands.
+const COMMAND_ADDR_MASK: u32 = 0x7FFF;
+const UNLOCK_ADDR_1: u32 = 0x5555;
+const UNLOCK_ADDR_2: u32 = 0x2AAA;
+
+const MANUFACTURER_ID: u8 = 0xBF;
+
+// Worst case operation times from the datasheet, in microseconds
+const BYTE_PROGRAM_US: u64 = 20;
+const SECTOR_ERASE_US: u64 = 25_000;
+const CHIP_ERASE_US: u64 = 100_000;
+
+/// Where the chip is in a command sequence. Every command starts with 0xAA
+/// to 0x5555 and 0x55 to 0x2AAA; erases repeat the unlock after 0x80.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CommandState {
+    Idle,
+    Unlocked1,
+    Unlocked2,
+    Program,
+    EraseSetup,
+    EraseUnlocked1,
+    EraseUnlocked2,
+}
+
+/// A program or erase in progress
+#[derive(Debug, Clone, Copy)]
+struct Operation {
+    ticks_left: u32,
+    /// Bit 7 of reads while busy: the complement of the byte being
+    /// programmed, or 0 for an erase
+    data_polling: u8,
+}
+
+/// An SST39SF010/020/040 parallel flash, used as PRG-ROM by boards that
+/// save by reprogramming it. The chip size, from the ROM, picks the device
+/// ID. Programs and erases take as long as the datasheet's worst case, and
+/// until they finish reads return the status bits games poll: DQ7 for data
+/// polling and DQ6 toggling on every read.
+///
+/// Changes are saved as an IPS patch against the original ROM, so only the
+/// sectors a game has written end up in the save file.
+pub struct FlashRom {
+    memory: Vec<u8>,
+    /// The ROM as loaded from the file, for diffing
+    original: Vec<u8>,
+    save_path: Option<PathBuf>,
+    dirty: bool,
+    ticks_since_flush: u64,
+    flush_interval_ticks: u64,
+    /// Operation times in CPU cycles
+    byte_program_ticks: u32,
+    sector_erase_ticks: u32,
+    chip_erase_ticks: u32,
+
+    state: CommandState,
+    software_id: bool,
+    operation: Option<Operation>,
+    toggle: u8,
+}
+
+impl FlashRom {
+    pub fn new(rom: Vec<u8>, save_path: Option<PathBuf>, timing: &ConsoleTiming) -> Self {
+        let ticks = |us| timing.us_to_cpu_cycles(us) as u32;
+        FlashRom {
+            original: rom.clone(),
+            memory: rom,
+            save_path,
+            dirty: false,
+            ticks_since_flush: 0,
+            flush_interval_ticks: timing.ms_to_cpu_cycles(NVRAM_FLUSH_INTERVAL_MS),
+            byte_program_ticks: ticks(BYTE_PROGRAM_US),
+            sector_erase_ticks: ticks(SECTOR_ERASE_US),
+            chip_erase_ticks: ticks(CHIP_ERASE_US),
+            state: CommandState::Idle,
+            software_id: false,
+            operation: None,
+            toggle: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.memory.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.memory.is_empty()
+    }
+
+    /// Whether a program or erase is in progress
+    pub fn busy(&self) -> bool {
+        self.operation.is_some()
+    }
+
+    fn device_id(&self) -> u8 {
+        match self.memory.len() {
+            0..=0x20000 => 0xB5,
+            0x20001..=0x40000 => 0xB6,
+            _ => 0xB7,
+        }
+    }
+
+    fn load(&mut self) -> EmuResult<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        let save_error =
+            |e: &dyn std::fmt::Display| EmuError::SaveFile(format!("{}: {}", path.display(), e));
+        match fs::read(path) {
+            Ok(diff) => {
+                let mut patched = apply_patch(&self.original, &diff).map_err(|e| save_error(&e))?;
+                // The chip can't change size, whatever the patch says
+                patched.resize(self.original.len(), 0xFF);
+                self.memory = patched;
+                Ok(())
+            }
+            // The game hasn't saved yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(save_error(&e)),
+        }
+    }
+
+    fn flush(&mut self) -> EmuResult<()> {
+        self.ticks_since_flush = 0;
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(path) = &self.save_path {
+            let diff = create_ips(&self.original, &self.memory);
+            fs::write(path, diff).map_err(|e| {
+                EmuError::SaveFile(format!("failed to write {}: {}", path.display(), e))
+            })?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    fn start_operation(&mut self, ticks: u32, data_polling: u8) {
+        self.operation = Some(Operation {
+            ticks_left: ticks,
+            data_polling,
+        });
+        self.dirty = true;
+    }
+
+    fn program(&mut self, offset: usize, data: u8) {
+        // Programming can only clear bits
+        self.memory[offset] &= data;
+        self.start_operation(self.byte_program_ticks, !data & 0x80);
+    }
+
+    fn erase_sector(&mut self, offset: usize) {
+        let start = offset & !(SECTOR_SIZE - 1);
+        self.memory[start..start + SECTOR_SIZE].fill(0xFF);
+        self.start_operation(self.sector_erase_ticks, 0);
+    }
+
+    fn erase_chip(&mut self) {
+        self.memory.fill(0xFF);
+        self.start_operation(self.chip_erase_ticks, 0);
+    }
+
+    /// Advance the command sequence with a write of data to addr
+    fn next_state(&mut self, addr: u32, data: u8) -> CommandState {
+        let command_addr = addr & COMMAND_ADDR_MASK;
+        match (self.state, command_addr, data) {
+            (CommandState::Idle, UNLOCK_ADDR_1, 0xAA) => CommandState::Unlocked1,
+            (CommandState::Unlocked1, UNLOCK_ADDR_2, 0x55) => CommandState::Unlocked2,
+            (CommandState::Unlocked2, UNLOCK_ADDR_1, 0xA0) => CommandState::Program,
+            (CommandState::Unlocked2, UNLOCK_ADDR_1, 0x80) => CommandState::EraseSetup,
+            (CommandState::Unlocked2, UNLOCK_ADDR_1, 0x90) => {
+                self.software_id = true;
+                CommandState::Idle
+            }
+            (CommandState::Program, _, _) => {
+                self.program(addr as usize, data);
+                CommandState::Idle
+            }
+            (CommandState::EraseSetup, UNLOCK_ADDR_1, 0xAA) => CommandState::EraseUnlocked1,
+            (CommandState::EraseUnlocked1, UNLOCK_ADDR_2, 0x55) => CommandState::EraseUnlocked2,
+            (CommandState::EraseUnlocked2, _, 0x30) => {
+                self.erase_sector(addr as usize);
+                CommandState::Idle
+            }
+            (CommandState::EraseUnlocked2, UNLOCK_ADDR_1, 0x10) => {
+                self.erase_chip();
+                CommandState::Idle
+            }
+            // 0xF0 leaves software ID mode, with or without the unlock
+            (_, _, 0xF0) => {
+                self.software_id = false;
+                CommandState::Idle
+            }
+            // Anything out of sequence aborts the command
+            _ => CommandState::Idle,
+        }
+    }
+}
+
+impl BusDevice for FlashRom {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let offset = addr as usize;
+        if offset >= self.memory.len() {
+            return Ok(ReadResult::OpenBus);
+        }
+        if let Some(operation) = &self.operation {
+            self.toggle ^= 0x40;
+            return Ok(ReadResult::Data(operation.data_polling | self.toggle));
+        }
+        if self.software_id {
+            return Ok(ReadResult::Data(if offset & 1 == 0 {
+                MANUFACTURER_ID
+            } else {
+                self.device_id()
+            }));
+        }
+        Ok(ReadResult::Data(self.memory[offset]))
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        if addr as usize >= self.memory.len() || self.operation.is_some() {
+            return Ok(());
+        }
+        self.state = self.next_state(addr, data);
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.load()
+    }
+
+    fn end_of_simulation(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("{}", e);
+        }
+    }
+
+    fn power_on(&mut self) {
+        // An interrupted operation has already changed the memory, which is
+        // as good a guess as any for what a real chip is left with
+        self.state = CommandState::Idle;
+        self.software_id = false;
+        self.operation = None;
+        self.toggle = 0;
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        if let Some(operation) = &mut self.operation {
+            operation.ticks_left -= 1;
+            if operation.ticks_left == 0 {
+                self.operation = None;
+            }
+        }
+        // Only flush between operations, so the save never has a half
+        // erased sector
+        self.ticks_since_flush += 1;
+        if self.ticks_since_flush >= self.flush_interval_ticks && self.operation.is_none() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// The stored contents, rather than the status a read returns while an
+    /// operation is in progress
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.memory.get(addr as usize).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(flash: &mut FlashRom, addr: u32) -> u8 {
+        match flash.bus_read(addr).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus at 0x{:05X}", addr),
+        }
+    }
+
+    fn command(flash: &mut FlashRom, command: u8) {
+        flash.bus_write(0x5555, 0xAA).unwrap();
+        flash.bus_write(0x2AAA, 0x55).unwrap();
+        flash.bus_write(0x5555, command).unwrap();
+    }
+
+    /// Ticks until the operation in progress finishes
+    fn wait(flash: &mut FlashRom) -> u32 {
+        let mut ticks = 0;
+        while flash.busy() {
+            flash.tick().unwrap();
+            ticks += 1;
+        }
+        ticks
+    }
+
+    #[test]
+    fn test_program() {
+        let mut flash = FlashRom::new(vec![0xFF; 0x80000], None, &ConsoleTiming::NTSC);
+        command(&mut flash, 0xA0);
+        flash.bus_write(0x12345, 0x5A).unwrap();
+        // DQ7 reads inverted and DQ6 toggles until the program finishes
+        let status = [read(&mut flash, 0x12345), read(&mut flash, 0x12345)];
+        assert_eq!(status[0] & 0x80, 0x80);
+        assert_ne!(status[0] & 0x40, status[1] & 0x40);
+        assert_eq!(wait(&mut flash), 35);
+        assert_eq!(read(&mut flash, 0x12345), 0x5A);
+
+        // Programming can't set bits, and unlocked writes do nothing
+        command(&mut flash, 0xA0);
+        flash.bus_write(0x12345, 0xA5).unwrap();
+        wait(&mut flash);
+        assert_eq!(read(&mut flash, 0x12345), 0x00);
+        flash.bus_write(0x12346, 0x00).unwrap();
+        assert_eq!(read(&mut flash, 0x12346), 0xFF);
+    }
+
+    #[test]
+    fn test_erase() {
+        let mut flash = FlashRom::new(vec![0x00; 0x80000], None, &ConsoleTiming::NTSC);
+        command(&mut flash, 0x80);
+        flash.bus_write(0x5555, 0xAA).unwrap();
+        flash.bus_write(0x2AAA, 0x55).unwrap();
+        flash.bus_write(0x23456, 0x30).unwrap();
+        assert_eq!(read(&mut flash, 0x23000) & 0x80, 0x00);
+        assert_eq!(wait(&mut flash), 44_744);
+        assert_eq!(read(&mut flash, 0x22FFF), 0x00);
+        assert_eq!(read(&mut flash, 0x23000), 0xFF);
+        assert_eq!(read(&mut flash, 0x23FFF), 0xFF);
+        assert_eq!(read(&mut flash, 0x24000), 0x00);
+
+        command(&mut flash, 0x80);
+        command(&mut flash, 0x10);
+        assert_eq!(wait(&mut flash), 178_977);
+        assert_eq!(read(&mut flash, 0x00000), 0xFF);
+    }
+
+    #[test]
+    fn test_software_id() {
+        let mut flash = FlashRom::new(vec![0x00; 0x40000], None, &ConsoleTiming::NTSC);
+        command(&mut flash, 0x90);
+        assert_eq!(read(&mut flash, 0x0000), MANUFACTURER_ID);
+        assert_eq!(read(&mut flash, 0x0001), 0xB6);
+        flash.bus_write(0x0000, 0xF0).unwrap();
+        assert_eq!(read(&mut flash, 0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_save() {
+        let save_path =
+            std::env::temp_dir().join(format!("flash_save_test_{}.ips", std::process::id()));
+        let _ = fs::remove_file(&save_path);
+        let rom: Vec<u8> = (0..0x40000).map(|i| i as u8).collect();
+
+        let mut flash = FlashRom::new(rom.clone(), Some(save_path.clone()), &ConsoleTiming::NTSC);
+        flash.start_of_simulation().unwrap();
+        command(&mut flash, 0x80);
+        flash.bus_write(0x5555, 0xAA).unwrap();
+        flash.bus_write(0x2AAA, 0x55).unwrap();
+        flash.bus_write(0x3F000, 0x30).unwrap();
+        wait(&mut flash);
+        command(&mut flash, 0xA0);
+        flash.bus_write(0x3F010, 0x42).unwrap();
+        wait(&mut flash);
+        flash.end_of_simulation();
+
+        // The save only covers the erased sector
+        let diff = fs::read(&save_path).unwrap();
+        assert!(diff.len() < 2 * SECTOR_SIZE);
+
+        let mut flash = FlashRom::new(rom, Some(save_path.clone()), &ConsoleTiming::NTSC);
+        flash.start_of_simulation().unwrap();
+        assert_eq!(read(&mut flash, 0x3F010), 0x42);
+        assert_eq!(read(&mut flash, 0x3F011), 0xFF);
+        assert_eq!(read(&mut flash, 0x3EFFF), 0xFF);
+        assert_eq!(read(&mut flash, 0x3EFFE), 0xFE);
+        fs::remove_file(&save_path).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use super::{CartridgeParts, Mapper};
+use crate::components::{
+    BusDevice, EmuResult, ReadResult,
+    expansion_audio::{ExpansionAudio, sunsoft5b::Sunsoft5BAudio},
+    nametables::Mirroring,
+};
+
+/// Sunsoft FME-7 and its 5B variant, mapper 69. Registers are written
+/// through a command port at 0x8000 and a parameter port at 0xA000: eight
+/// 1KB CHR banks, three switchable 8KB PRG banks, and a fourth bank at
+/// 0x6000 that can hold PRG-ROM or PRG-RAM. The IRQ is a 16-bit counter
+/// that counts down every CPU cycle. The 5B's sound chip sits at 0xC000 and
+/// 0xE000; on the plain FME-7 the writes just go nowhere.
+pub struct Fme7<'t> {
+    parts: CartridgeParts<'t>,
+    audio: Sunsoft5BAudio,
+    command: u8,
+    chr_banks: [u8; 8],
+    /// Command 8: RAM enable, RAM select and the bank at 0x6000
+    low_bank: u8,
+    prg_banks: [u8; 3],
+    irq_enabled: bool,
+    counter_enabled: bool,
+    counter: u16,
+    irq_pending: bool,
+}
+
+impl<'t> Fme7<'t> {
+    pub fn new(parts: CartridgeParts<'t>) -> Self {
+        let mut fme7 = Fme7 {
+            parts,
+            audio: Sunsoft5BAudio::new(),
+            command: 0,
+            chr_banks: [0; 8],
+            low_bank: 0,
+            prg_banks: [0; 3],
+            irq_enabled: false,
+            counter_enabled: false,
+            counter: 0,
+            irq_pending: false,
+        };
+        fme7.set_mirroring(0);
+        fme7
+    }
+
+    fn set_mirroring(&mut self, data: u8) {
+        self.parts.nametables.set_mirroring(match data & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        });
+    }
+
+    fn ram_selected(&self) -> bool {
+        self.low_bank & 0x40 != 0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.low_bank & 0x80 != 0
+    }
+
+    fn write_parameter(&mut self, data: u8) {
+        match self.command {
+            0x0..=0x7 => self.chr_banks[self.command as usize] = data,
+            0x8 => self.low_bank = data,
+            0x9..=0xB => self.prg_banks[self.command as usize - 0x9] = data & 0x3F,
+            0xC => self.set_mirroring(data),
+            0xD => {
+                self.irq_enabled = data & 0x01 != 0;
+                self.counter_enabled = data & 0x80 != 0;
+                self.irq_pending = false;
+            }
+            0xE => self.counter = (self.counter & 0xFF00) | data as u16,
+            _ => self.counter = (self.counter & 0x00FF) | ((data as u16) << 8),
+        }
+    }
+}
+
+impl BusDevice for Fme7<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() => {
+                if self.ram_enabled() {
+                    let bank = (self.low_bank & 0x3F) as usize;
+                    self.parts.read_prg_ram(bank, 0x2000, addr)
+                } else {
+                    Ok(ReadResult::OpenBus)
+                }
+            }
+            _ => Ok(self
+                .prg_rom_offset(addr)
+                .map_or(ReadResult::OpenBus, |offset| {
+                    ReadResult::Data(self.parts.prg_rom[offset])
+                })),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() && self.ram_enabled() => {
+                let bank = (self.low_bank & 0x3F) as usize;
+                self.parts.write_prg_ram(bank, 0x2000, addr, data)?;
+            }
+            0x8000..=0x9FFF => self.command = data & 0x0F,
+            0xA000..=0xBFFF => {
+                self.write_parameter(data);
+                self.parts.irq.set(self.irq_pending);
+            }
+            0xC000..=0xFFFF => self.audio.write(addr, data),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.audio.power_on();
+        self.command = 0;
+        self.chr_banks = [0; 8];
+        self.low_bank = 0;
+        self.prg_banks = [0; 3];
+        self.set_mirroring(0);
+        self.irq_enabled = false;
+        self.counter_enabled = false;
+        self.counter = 0;
+        self.irq_pending = false;
+        self.parts.irq.set(false);
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()?;
+        if self.counter_enabled {
+            self.counter = self.counter.wrapping_sub(1);
+            if self.counter == 0xFFFF && self.irq_enabled {
+                self.irq_pending = true;
+            }
+        }
+        self.parts.irq.set(self.irq_pending);
+        self.audio.tick();
+        if let Some(audio_output) = &self.parts.audio {
+            audio_output.mix(self.audio.output());
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.ram_selected() => {
+                let bank = (self.low_bank & 0x3F) as usize;
+                self.ram_enabled()
+                    .then(|| self.parts.peek_prg_ram(bank, 0x2000, addr))
+                    .flatten()
+            }
+            _ => self
+                .prg_rom_offset(addr)
+                .map(|offset| self.parts.prg_rom[offset]),
+        }
+    }
+}
+
+impl Mapper for Fme7<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] as usize;
+                self.parts.chr.read(bank, 0x400, addr)
+            }
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] as usize;
+                self.parts.chr.write(bank, 0x400, addr, data);
+            }
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        let bank = match addr {
+            0x6000..=0x7FFF if !self.ram_selected() => (self.low_bank & 0x3F) as usize,
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            0xE000..=0xFFFF => self.parts.prg_rom.len() / 0x2000 - 1,
+            _ => return None,
+        };
+        Some(self.parts.prg_rom_offset(bank, 0x2000, addr))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::test::{cpu_read, make_parts, ppu_fetch};
+
+    fn make_fme7() -> Fme7<'static> {
+        let mut fme7 = Fme7::new(make_parts(0x40000, 0x40000));
+        fme7.power_on();
+        fme7
+    }
+
+    fn write_command(fme7: &mut Fme7, command: u8, data: u8) {
+        fme7.bus_write(0x8000, command).unwrap();
+        fme7.bus_write(0xA000, data).unwrap();
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut fme7 = make_fme7();
+        write_command(&mut fme7, 0x9, 3);
+        write_command(&mut fme7, 0xA, 4);
+        write_command(&mut fme7, 0xB, 5);
+        assert_eq!(cpu_read(&mut fme7, 0x8000), 3);
+        assert_eq!(cpu_read(&mut fme7, 0xA000), 4);
+        assert_eq!(cpu_read(&mut fme7, 0xC000), 5);
+        assert_eq!(cpu_read(&mut fme7, 0xE000), 31);
+
+        write_command(&mut fme7, 0x1, 0x21);
+        write_command(&mut fme7, 0x7, 0x27);
+        assert_eq!(ppu_fetch(&mut fme7, 0x0400), 0x21);
+        assert_eq!(ppu_fetch(&mut fme7, 0x1C00), 0x27);
+
+        // One-screen, upper page
+        write_command(&mut fme7, 0xC, 3);
+        fme7.ppu_write(0x2000, 0x24).unwrap();
+        assert_eq!(ppu_fetch(&mut fme7, 0x2C00), 0x24);
+    }
+
+    #[test]
+    fn test_low_bank() {
+        let mut fme7 = make_fme7();
+        // PRG-ROM at 0x6000, and writes don't reach the RAM
+        write_command(&mut fme7, 0x8, 6);
+        fme7.bus_write(0x6000, 0x42).unwrap();
+        assert_eq!(cpu_read(&mut fme7, 0x6000), 6);
+
+        // RAM selected but disabled
+        write_command(&mut fme7, 0x8, 0x40);
+        assert_eq!(fme7.bus_read(0x6000).unwrap(), ReadResult::OpenBus);
+
+        write_command(&mut fme7, 0x8, 0xC1);
+        fme7.bus_write(0x6000, 0x42).unwrap();
+        assert_eq!(cpu_read(&mut fme7, 0x6000), 0x42);
+        write_command(&mut fme7, 0x8, 0xC0);
+        assert_ne!(cpu_read(&mut fme7, 0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut fme7 = make_fme7();
+        let irq = fme7.parts.irq.make_receiver();
+        write_command(&mut fme7, 0xE, 0x02);
+        write_command(&mut fme7, 0xF, 0x00);
+        write_command(&mut fme7, 0xD, 0x81);
+        // Counts 2, 1, 0, and raises the IRQ when it wraps to 0xFFFF
+        for _ in 0..2 {
+            fme7.tick().unwrap();
+        }
+        assert!(!irq.get());
+        fme7.tick().unwrap();
+        assert!(irq.get());
+        // Writing the control register acknowledges
+        write_command(&mut fme7, 0xD, 0x00);
+        assert!(!irq.get());
+
+        // The counter runs with the IRQ disabled, without raising it
+        fme7.counter = 0;
+        write_command(&mut fme7, 0xD, 0x80);
+        fme7.tick().unwrap();
+        assert_eq!(fme7.counter, 0xFFFF);
+        assert!(!irq.get());
+    }
+}
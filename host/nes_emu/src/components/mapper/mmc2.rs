@@ -0,0 +1,273 @@
+use super::{CartridgeParts, Mapper};
+use crate::components::{BusDevice, EmuResult, ReadResult, nametables::Mirroring};
+
+/// Tiles whose pattern fetches flip a CHR latch
+const LATCH_FD: u8 = 0xFD;
+const LATCH_FE: u8 = 0xFE;
+
+/// Nintendo MMC2 (mapper 9) and MMC4 (mapper 10). Each 4KB pattern table
+/// has two CHR banks and a latch choosing between them, which flips when
+/// the PPU fetches tile 0xFD or 0xFE from that table. Games put the trigger
+/// tiles in the picture, so the bank switches partway through a scanline.
+///
+/// The MMC2 has one switchable 8KB PRG bank; the MMC4 has a 16KB bank and
+/// PRG-RAM. The MMC2 only watches the first table for one exact address per
+/// tile, where the MMC4 watches the tile's whole second half in both.
+pub struct Mmc2<'t> {
+    parts: CartridgeParts<'t>,
+    mmc4: bool,
+    prg_bank: u8,
+    /// Banks for each table, selected by latch 0xFD and 0xFE
+    chr_banks: [[u8; 2]; 2],
+    latches: [u8; 2],
+}
+
+impl<'t> Mmc2<'t> {
+    pub fn new(parts: CartridgeParts<'t>, mapper: u16) -> Self {
+        let mut mmc2 = Mmc2 {
+            parts,
+            mmc4: mapper == 10,
+            prg_bank: 0,
+            chr_banks: [[0; 2]; 2],
+            latches: [LATCH_FE; 2],
+        };
+        mmc2.set_mirroring(0);
+        mmc2
+    }
+
+    fn set_mirroring(&mut self, data: u8) {
+        self.parts.nametables.set_mirroring(if data & 0x01 != 0 {
+            Mirroring::Horizontal
+        } else {
+            Mirroring::Vertical
+        });
+    }
+
+    fn prg_bank(&self, addr: u16) -> (usize, usize) {
+        if self.mmc4 {
+            match addr {
+                0x8000..=0xBFFF => (self.prg_bank as usize, 0x4000),
+                _ => (
+                    (self.parts.prg_rom.len() / 0x4000).saturating_sub(1),
+                    0x4000,
+                ),
+            }
+        } else {
+            let banks = (self.parts.prg_rom.len() / 0x2000).max(1);
+            match addr {
+                0x8000..=0x9FFF => (self.prg_bank as usize, 0x2000),
+                // The last three banks are fixed. Counting back from a whole
+                // number of ROMs past the end wraps around one smaller than
+                // 32KB, the way its missing address lines would.
+                _ => (banks * 4 - 4 + (addr as usize - 0x8000) / 0x2000, 0x2000),
+            }
+        }
+    }
+
+    fn chr_bank(&self, addr: u16) -> usize {
+        let table = addr as usize / 0x1000;
+        let latch = (self.latches[table] == LATCH_FE) as usize;
+        self.chr_banks[table][latch] as usize
+    }
+
+    /// Flip a latch if addr is a fetch of one of the trigger tiles. Called
+    /// after the fetch, which still sees the old bank.
+    fn update_latch(&mut self, addr: u16) {
+        let table = addr as usize / 0x1000;
+        let tile = ((addr >> 4) & 0xFF) as u8;
+        let row = addr & 0x0F;
+        let triggers = if self.mmc4 || table == 1 {
+            row >= 8
+        } else {
+            row == 8
+        };
+        if triggers && (tile == LATCH_FD || tile == LATCH_FE) {
+            self.latches[table] = tile;
+        }
+    }
+}
+
+impl BusDevice for Mmc2<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.mmc4 => self.parts.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => {
+                let (bank, size) = self.prg_bank(addr);
+                Ok(ReadResult::Data(self.parts.read_prg_rom(bank, size, addr)))
+            }
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.mmc4 => self.parts.write_prg_ram(0, 0x2000, addr, data)?,
+            0xA000..=0xAFFF => self.prg_bank = data & 0x0F,
+            0xB000..=0xEFFF => {
+                let reg = (addr as usize - 0xB000) / 0x1000;
+                self.chr_banks[reg / 2][reg % 2] = data & 0x1F;
+            }
+            0xF000..=0xFFFF => self.set_mirroring(data),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.prg_bank = 0;
+        self.chr_banks = [[0; 2]; 2];
+        self.latches = [LATCH_FE; 2];
+        self.set_mirroring(0);
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.mmc4 => self.parts.peek_prg_ram(0, 0x2000, addr),
+            _ => self
+                .prg_rom_offset(addr)
+                .map(|offset| self.parts.prg_rom[offset]),
+        }
+    }
+}
+
+impl Mapper for Mmc2<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => {
+                let data = self.parts.chr.read(self.chr_bank(addr), 0x1000, addr);
+                self.update_latch(addr);
+                data
+            }
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_bank(addr);
+                self.parts.chr.write(bank, 0x1000, addr, data);
+            }
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => {
+                let (bank, size) = self.prg_bank(addr);
+                Some(self.parts.prg_rom_offset(bank, size, addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::test::{cpu_read, make_parts, ppu_fetch};
+
+    fn make_mmc2(mapper: u16) -> Mmc2<'static> {
+        let mut mmc2 = Mmc2::new(make_parts(0x20000, 0x20000), mapper);
+        mmc2.power_on();
+        mmc2
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc2 = make_mmc2(9);
+        mmc2.bus_write(0xA000, 5).unwrap();
+        assert_eq!(cpu_read(&mut mmc2, 0x8000), 5);
+        assert_eq!(cpu_read(&mut mmc2, 0xA000), 13);
+        assert_eq!(cpu_read(&mut mmc2, 0xE000), 15);
+
+        let mut mmc4 = make_mmc2(10);
+        mmc4.bus_write(0xA000, 5).unwrap();
+        assert_eq!(cpu_read(&mut mmc4, 0x8000), 10);
+        assert_eq!(cpu_read(&mut mmc4, 0xA000), 11);
+        assert_eq!(cpu_read(&mut mmc4, 0xC000), 14);
+        mmc4.bus_write(0x6000, 0x42).unwrap();
+        assert_eq!(cpu_read(&mut mmc4, 0x6000), 0x42);
+    }
+
+    #[test]
+    fn test_small_prg_rom() {
+        // 16KB holds two 8KB banks, which the fixed windows mirror
+        let mut mmc2 = Mmc2::new(make_parts(0x4000, 0x20000), 9);
+        mmc2.power_on();
+        assert_eq!(cpu_read(&mut mmc2, 0xA000), 1);
+        assert_eq!(cpu_read(&mut mmc2, 0xC000), 0);
+        assert_eq!(cpu_read(&mut mmc2, 0xE000), 1);
+        mmc2.bus_write(0xA000, 1).unwrap();
+        assert_eq!(cpu_read(&mut mmc2, 0x8000), 1);
+
+        let mut mmc4 = Mmc2::new(make_parts(0x2000, 0x20000), 10);
+        mmc4.power_on();
+        assert_eq!(cpu_read(&mut mmc4, 0xC000), 0);
+    }
+
+    #[test]
+    fn test_chr_latches() {
+        let mut mmc2 = make_mmc2(9);
+        // 4KB banks 1 and 2 for the first table, 3 and 4 for the second
+        for (reg, bank) in [(0xB000, 1), (0xC000, 2), (0xD000, 3), (0xE000, 4)] {
+            mmc2.bus_write(reg, bank).unwrap();
+        }
+        // Latches start at 0xFE
+        assert_eq!(ppu_fetch(&mut mmc2, 0x0000), 8);
+        assert_eq!(ppu_fetch(&mut mmc2, 0x1000), 16);
+
+        // The fetch that flips the latch still sees the old bank
+        assert_eq!(ppu_fetch(&mut mmc2, 0x0FD8), 0);
+        assert_eq!(ppu_fetch(&mut mmc2, 0x0000), 4);
+        // On the MMC2, only the exact address flips the first latch
+        ppu_fetch(&mut mmc2, 0x0FE9);
+        assert_eq!(ppu_fetch(&mut mmc2, 0x0000), 4);
+        ppu_fetch(&mut mmc2, 0x0FE8);
+        assert_eq!(ppu_fetch(&mut mmc2, 0x0000), 8);
+
+        // The second latch takes any address in the tile's second half
+        ppu_fetch(&mut mmc2, 0x1FDF);
+        assert_eq!(ppu_fetch(&mut mmc2, 0x1000), 12);
+        // But not the first half
+        ppu_fetch(&mut mmc2, 0x1FE0);
+        assert_eq!(ppu_fetch(&mut mmc2, 0x1000), 12);
+
+        // The MMC4 treats the first table like the second
+        let mut mmc4 = make_mmc2(10);
+        mmc4.bus_write(0xB000, 1).unwrap();
+        ppu_fetch(&mut mmc4, 0x0FDA);
+        assert_eq!(ppu_fetch(&mut mmc4, 0x0000), 4);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut mmc2 = make_mmc2(9);
+        mmc2.ppu_write(0x2000, 0x11).unwrap();
+        assert_eq!(ppu_fetch(&mut mmc2, 0x2800), 0x11);
+        mmc2.bus_write(0xF000, 1).unwrap();
+        assert_eq!(ppu_fetch(&mut mmc2, 0x2400), 0x11);
+    }
+}
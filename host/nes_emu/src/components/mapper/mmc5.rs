@@ -0,0 +1,856 @@
+use super::{CartridgeParts, Mapper};
+use crate::components::{
+    BusActivity, BusDevice, EmuResult, ReadResult,
+    expansion_audio::{ExpansionAudio, mmc5::Mmc5Audio},
+};
+
+/// Fetches per scanline, counted from the nametable fetch that completes
+/// the scanline detection: 32 tiles of 4 (the first two of which belong to
+/// the previous line's prefetch), 8 sprites of 4, 2 prefetched tiles of 4,
+/// and 2 dummy nametable reads
+const BG_FETCHES: u16 = 128;
+const SPRITE_FETCHES_END: u16 = 160;
+const PREFETCH_END: u16 = 168;
+
+/// CPU cycles without a PPU read after which the MMC5 decides rendering has
+/// stopped
+const IDLE_CYCLES_OUT_OF_FRAME: u8 = 3;
+
+const VISIBLE_SCANLINES: u16 = 240;
+
+/// What the PPU is fetching, as far as the MMC5 can tell from its position
+/// in the scanline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Fetch {
+    /// Outside of rendering, or a garbage fetch
+    Other,
+    BgNametable,
+    BgAttribute,
+    BgPattern,
+    SpritePattern,
+}
+
+/// Nintendo MMC5, mapper 5. Up to 1MB of PRG and CHR in four banking modes
+/// each, banked PRG-RAM, 1KB of internal ExRAM that can act as a nametable
+/// or as extended tile attributes, a vertical split screen, a scanline IRQ,
+/// an 8x8 multiplier and two extra pulse channels.
+///
+/// The scanline IRQ works the way the chip does: the MMC5 sees no PPU
+/// registers, so it counts scanlines by spotting the three identical
+/// nametable reads at the end of each line, and tells sprite and background
+/// fetches apart by counting fetches from there.
+pub struct Mmc5<'t> {
+    parts: CartridgeParts<'t>,
+    audio: Mmc5Audio,
+
+    prg_mode: u8,
+    chr_mode: u8,
+    ram_protect: [u8; 2],
+    exram_mode: u8,
+    /// Source for each nametable, two bits each: CIRAM page 0 or 1, ExRAM,
+    /// or fill mode
+    nametable_mapping: u8,
+    fill_tile: u8,
+    fill_attribute: u8,
+    /// 0x5113 - 0x5117. Bit 7 selects ROM over RAM.
+    prg_banks: [u8; 5],
+    /// 0x5120 - 0x512B, with the upper bits from 0x5130 at the time of the
+    /// write
+    chr_banks: [u16; 12],
+    chr_upper: u8,
+    /// Whether 0x5128 - 0x512B were written more recently than 0x5120 -
+    /// 0x5127
+    last_chr_set_b: bool,
+    exram: [u8; 0x400],
+    multiplier: [u8; 2],
+
+    split_control: u8,
+    split_scroll: u8,
+    split_bank: u8,
+    /// Split coordinates of the tile being fetched, if it is in the split
+    split_tile: Option<(u16, u16)>,
+    /// ExRAM byte for the tile being fetched, in extended attribute mode
+    ext_attribute: u8,
+
+    irq_compare: u8,
+    irq_enabled: bool,
+    irq_pending: bool,
+    in_frame: bool,
+    scanline: u16,
+
+    /// Snooped from PPUCTRL and PPUMASK writes
+    sprites_8x16: bool,
+    rendering_enabled: bool,
+
+    last_fetch_addr: u16,
+    matching_fetches: u8,
+    fetch_index: u16,
+    fetch: Fetch,
+    ppu_read_seen: bool,
+    idle_cycles: u8,
+}
+
+impl<'t> Mmc5<'t> {
+    pub fn new(parts: CartridgeParts<'t>) -> Self {
+        let mut mmc5 = Mmc5 {
+            parts,
+            audio: Mmc5Audio::new(),
+            prg_mode: 0,
+            chr_mode: 0,
+            ram_protect: [0; 2],
+            exram_mode: 0,
+            nametable_mapping: 0,
+            fill_tile: 0,
+            fill_attribute: 0,
+            prg_banks: [0; 5],
+            chr_banks: [0; 12],
+            chr_upper: 0,
+            last_chr_set_b: false,
+            exram: [0; 0x400],
+            multiplier: [0; 2],
+            split_control: 0,
+            split_scroll: 0,
+            split_bank: 0,
+            split_tile: None,
+            ext_attribute: 0,
+            irq_compare: 0,
+            irq_enabled: false,
+            irq_pending: false,
+            in_frame: false,
+            scanline: 0,
+            sprites_8x16: false,
+            rendering_enabled: false,
+            last_fetch_addr: 0,
+            matching_fetches: 0,
+            fetch_index: 0,
+            fetch: Fetch::Other,
+            ppu_read_seen: false,
+            idle_cycles: 0,
+        };
+        mmc5.init_registers();
+        mmc5
+    }
+
+    fn init_registers(&mut self) {
+        // Games expect to start in 8KB mode with the last bank at 0xE000
+        self.prg_mode = 3;
+        self.chr_mode = 0;
+        self.ram_protect = [0; 2];
+        self.exram_mode = 0;
+        self.nametable_mapping = 0;
+        self.fill_tile = 0;
+        self.fill_attribute = 0;
+        self.prg_banks = [0, 0, 0, 0, 0xFF];
+        self.chr_banks = [0; 12];
+        self.chr_upper = 0;
+        self.last_chr_set_b = false;
+        self.multiplier = [0xFF; 2];
+        self.split_control = 0;
+        self.split_scroll = 0;
+        self.split_bank = 0;
+        self.irq_compare = 0;
+        self.irq_enabled = false;
+        self.irq_pending = false;
+        self.sprites_8x16 = false;
+        self.rendering_enabled = false;
+        self.leave_frame();
+    }
+
+    fn leave_frame(&mut self) {
+        self.in_frame = false;
+        self.matching_fetches = 0;
+        self.fetch = Fetch::Other;
+        self.split_tile = None;
+    }
+
+    fn update_irq(&mut self) {
+        let irq = (self.irq_pending && self.irq_enabled) || self.audio.irq_pending();
+        self.parts.irq.set(irq);
+    }
+
+    /// The PRG bank behind a CPU address in 0x8000 - 0xFFFF, in 8KB units,
+    /// and whether it is ROM
+    fn prg_bank(&self, addr: u16) -> (usize, bool) {
+        let (value, size) = match (self.prg_mode, addr) {
+            (0, _) => (self.prg_banks[4] | 0x80, 0x8000),
+            (1, 0x8000..=0xBFFF) | (2, 0x8000..=0xBFFF) => (self.prg_banks[2], 0x4000),
+            (1, _) => (self.prg_banks[4] | 0x80, 0x4000),
+            (2, 0xC000..=0xDFFF) => (self.prg_banks[3], 0x2000),
+            (2, _) => (self.prg_banks[4] | 0x80, 0x2000),
+            // 0xE000 is always ROM
+            (_, 0xE000..=0xFFFF) => (self.prg_banks[4] | 0x80, 0x2000),
+            (_, _) => (
+                self.prg_banks[(addr as usize - 0x8000) / 0x2000 + 1],
+                0x2000,
+            ),
+        };
+        // Larger windows ignore the low bits of the bank number
+        let banks_per_window = size / 0x2000;
+        let bank = (value as usize & 0x7F & !(banks_per_window - 1))
+            | ((addr as usize & (size - 1)) / 0x2000);
+        (bank, value & 0x80 != 0)
+    }
+
+    fn ram_writable(&self) -> bool {
+        self.ram_protect == [0x02, 0x01]
+    }
+
+    fn read_prg(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        let (bank, rom) = self.prg_bank(addr);
+        if rom {
+            Ok(ReadResult::Data(
+                self.parts.read_prg_rom(bank, 0x2000, addr),
+            ))
+        } else {
+            self.parts.read_prg_ram(bank & 0x07, 0x2000, addr)
+        }
+    }
+
+    fn write_prg(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        let (bank, rom) = self.prg_bank(addr);
+        if !rom && self.ram_writable() {
+            self.parts.write_prg_ram(bank & 0x07, 0x2000, addr, data)?;
+        }
+        Ok(())
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x5000..=0x5015 => {
+                self.audio.write(addr, data);
+                self.update_irq();
+            }
+            0x5100 => self.prg_mode = data & 0x03,
+            0x5101 => self.chr_mode = data & 0x03,
+            0x5102 => self.ram_protect[0] = data & 0x03,
+            0x5103 => self.ram_protect[1] = data & 0x03,
+            0x5104 => self.exram_mode = data & 0x03,
+            0x5105 => self.nametable_mapping = data,
+            0x5106 => self.fill_tile = data,
+            0x5107 => self.fill_attribute = data & 0x03,
+            0x5113..=0x5117 => self.prg_banks[addr as usize - 0x5113] = data,
+            0x5120..=0x512B => {
+                self.chr_banks[addr as usize - 0x5120] = data as u16 | (self.chr_upper as u16) << 8;
+                self.last_chr_set_b = addr >= 0x5128;
+            }
+            0x5130 => self.chr_upper = data & 0x03,
+            0x5200 => self.split_control = data,
+            0x5201 => self.split_scroll = data,
+            0x5202 => self.split_bank = data,
+            0x5203 => self.irq_compare = data,
+            0x5204 => {
+                self.irq_enabled = data & 0x80 != 0;
+                self.update_irq();
+            }
+            0x5205 => self.multiplier[0] = data,
+            0x5206 => self.multiplier[1] = data,
+            0x5C00..=0x5FFF => {
+                let offset = addr as usize - 0x5C00;
+                match self.exram_mode {
+                    // While the PPU uses ExRAM, the CPU can only write it
+                    // during rendering; other writes store 0
+                    0 | 1 => self.exram[offset] = if self.in_frame { data } else { 0 },
+                    2 => self.exram[offset] = data,
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_register(&mut self, addr: u16) -> Option<u8> {
+        match addr {
+            0x5010 | 0x5015 => {
+                let value = self.audio.read(addr);
+                self.update_irq();
+                value
+            }
+            0x5204 => {
+                let status = (self.irq_pending as u8) << 7 | (self.in_frame as u8) << 6;
+                self.irq_pending = false;
+                self.update_irq();
+                Some(status)
+            }
+            0x5205 => Some((self.multiplier[0] as u16 * self.multiplier[1] as u16) as u8),
+            0x5206 => Some(((self.multiplier[0] as u16 * self.multiplier[1] as u16) >> 8) as u8),
+            0x5C00..=0x5FFF if self.exram_mode >= 2 => Some(self.exram[addr as usize - 0x5C00]),
+            _ => None,
+        }
+    }
+
+    /// Called for the third identical nametable read in a row, which is the
+    /// first fetch of a scanline
+    fn detect_scanline(&mut self) {
+        if self.in_frame {
+            self.scanline += 1;
+            if self.scanline == self.irq_compare as u16 {
+                self.irq_pending = true;
+                self.update_irq();
+            }
+        } else {
+            self.in_frame = true;
+            self.scanline = 0;
+            self.irq_pending = false;
+            self.update_irq();
+        }
+        self.fetch_index = 0;
+    }
+
+    fn classify_fetch(&self) -> Fetch {
+        if !self.in_frame {
+            return Fetch::Other;
+        }
+        match self.fetch_index {
+            0..BG_FETCHES | SPRITE_FETCHES_END..PREFETCH_END => match self.fetch_index % 4 {
+                0 => Fetch::BgNametable,
+                1 => Fetch::BgAttribute,
+                _ => Fetch::BgPattern,
+            },
+            BG_FETCHES..SPRITE_FETCHES_END if self.fetch_index % 4 >= 2 => Fetch::SpritePattern,
+            _ => Fetch::Other,
+        }
+    }
+
+    /// Work out where the tile whose nametable entry is being fetched will
+    /// be drawn, and whether the split covers it
+    fn start_tile(&mut self, addr: u16) {
+        let (column, line) = if self.fetch_index < BG_FETCHES {
+            (self.fetch_index / 4 + 2, self.scanline)
+        } else {
+            (
+                (self.fetch_index - SPRITE_FETCHES_END) / 4,
+                self.scanline + 1,
+            )
+        };
+        let column = column & 0x1F;
+
+        self.ext_attribute = self.exram[addr as usize & 0x3FF];
+
+        let threshold = (self.split_control & 0x1F) as u16;
+        let in_split = if self.split_control & 0x40 != 0 {
+            column >= threshold
+        } else {
+            column < threshold
+        };
+        self.split_tile = if self.split_control & 0x80 != 0 && self.exram_mode <= 1 && in_split {
+            let y = (self.split_scroll as u16 + line) % VISIBLE_SCANLINES;
+            Some((column, y))
+        } else {
+            None
+        };
+    }
+
+    fn read_nametable(&self, addr: u16) -> u8 {
+        let attribute = addr & 0x3FF >= 0x3C0;
+        if let Some((column, y)) = self.split_tile {
+            return match self.fetch {
+                Fetch::BgNametable => self.exram[(y as usize / 8) * 32 + column as usize],
+                Fetch::BgAttribute => {
+                    let byte = self.exram[0x3C0 + (y as usize / 32) * 8 + column as usize / 4];
+                    let shift = ((y / 16) & 1) * 4 + ((column / 2) & 1) * 2;
+                    ((byte >> shift) & 0x03) * 0x55
+                }
+                _ => 0,
+            };
+        }
+        if self.fetch == Fetch::BgAttribute && self.exram_mode == 1 {
+            return (self.ext_attribute >> 6) * 0x55;
+        }
+        let table = (addr >> 10) & 0x03;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            page @ (0 | 1) => self.parts.nametables.read_page(page, addr),
+            2 if self.exram_mode <= 1 => self.exram[addr as usize & 0x3FF],
+            2 => 0,
+            _ if attribute => self.fill_attribute * 0x55,
+            _ => self.fill_tile,
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, data: u8) {
+        let table = (addr >> 10) & 0x03;
+        match (self.nametable_mapping >> (table * 2)) & 0x03 {
+            page @ (0 | 1) => self.parts.nametables.write_page(page, addr, data),
+            2 if self.exram_mode <= 1 => self.exram[addr as usize & 0x3FF] = data,
+            _ => {}
+        }
+    }
+
+    /// The CHR bank register and bank size for a pattern table address. In
+    /// 8x16 sprite mode, sprites use set A (0x5120 - 0x5127) and the
+    /// background set B (0x5128 - 0x512B); otherwise whichever set was
+    /// written last applies to both.
+    fn chr_bank(&self, addr: u16) -> (usize, usize) {
+        let use_set_b = if self.sprites_8x16 && self.in_frame {
+            self.fetch == Fetch::BgPattern
+        } else {
+            self.last_chr_set_b
+        };
+        let size = 0x2000 >> self.chr_mode;
+        let slot = addr as usize / size;
+        let reg = if use_set_b {
+            // Set B only covers 4KB, repeated in both pattern tables
+            let slots = ((1 << self.chr_mode) / 2).max(1);
+            8 + (slot % slots + 1) * (4 / slots) - 1
+        } else {
+            (slot + 1) * (8 >> self.chr_mode) - 1
+        };
+        (self.chr_banks[reg] as usize, size)
+    }
+
+    fn read_chr(&self, addr: u16) -> u8 {
+        if self.fetch == Fetch::BgPattern {
+            if let Some((_, y)) = self.split_tile {
+                let addr = (addr & 0x0FF8) | (y & 0x07);
+                return self.parts.chr.read(self.split_bank as usize, 0x1000, addr);
+            }
+            if self.exram_mode == 1 {
+                let bank = (self.chr_upper as usize) << 6 | (self.ext_attribute as usize & 0x3F);
+                return self.parts.chr.read(bank, 0x1000, addr);
+            }
+        }
+        let (bank, size) = self.chr_bank(addr);
+        self.parts.chr.read(bank, size, addr)
+    }
+}
+
+impl BusDevice for Mmc5<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x5000..=0x5FFF => Ok(match self.read_register(addr) {
+                Some(value) => ReadResult::Data(value),
+                None => ReadResult::OpenBus,
+            }),
+            0x6000..=0x7FFF => {
+                self.parts
+                    .read_prg_ram(self.prg_banks[0] as usize & 0x07, 0x2000, addr)
+            }
+            0x8000..=0xFFFF => {
+                let result = self.read_prg(addr)?;
+                if let ReadResult::Data(value) = result {
+                    self.audio.observe_read(addr, value);
+                    self.update_irq();
+                }
+                Ok(result)
+            }
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x5000..=0x5FFF => {
+                self.write_register(addr, data);
+                Ok(())
+            }
+            0x6000..=0x7FFF if self.ram_writable() => {
+                let bank = self.prg_banks[0] as usize & 0x07;
+                self.parts.write_prg_ram(bank, 0x2000, addr, data)
+            }
+            0x8000..=0xFFFF => self.write_prg(addr, data),
+            _ => Ok(()),
+        }
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.audio.power_on();
+        self.exram = [0; 0x400];
+        self.init_registers();
+        self.update_irq();
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+        self.init_registers();
+        self.update_irq();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()?;
+        self.audio.tick();
+        if let Some(audio_output) = &self.parts.audio {
+            audio_output.mix(self.audio.output());
+        }
+
+        if self.ppu_read_seen {
+            self.idle_cycles = 0;
+        } else {
+            self.idle_cycles = self.idle_cycles.saturating_add(1);
+            if self.idle_cycles >= IDLE_CYCLES_OUT_OF_FRAME && self.in_frame {
+                self.leave_frame();
+            }
+        }
+        self.ppu_read_seen = false;
+        self.update_irq();
+        Ok(())
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        match (addr, activity) {
+            // The CPU fetching the NMI vector marks the end of the frame
+            (0xFFFA | 0xFFFB, BusActivity::Read) => self.leave_frame(),
+            (0x2000..=0x3FFF, BusActivity::Write(data)) => match addr & 0x07 {
+                0 => self.sprites_8x16 = data & 0x20 != 0,
+                1 => {
+                    self.rendering_enabled = data & 0x18 != 0;
+                    if !self.rendering_enabled {
+                        self.leave_frame();
+                    }
+                }
+                _ => {}
+            },
+            _ => {}
+        }
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => {
+                self.parts
+                    .peek_prg_ram(self.prg_banks[0] as usize & 0x07, 0x2000, addr)
+            }
+            0x8000..=0xFFFF => match self.prg_bank(addr) {
+                (bank, false) => self.parts.peek_prg_ram(bank & 0x07, 0x2000, addr),
+                _ => self
+                    .prg_rom_offset(addr)
+                    .map(|offset| self.parts.prg_rom[offset]),
+            },
+            _ => None,
+        }
+    }
+}
+
+impl Mapper for Mmc5<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => self.read_chr(addr),
+            _ => self.read_nametable(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let (bank, size) = self.chr_bank(addr);
+                self.parts.chr.write(bank, size, addr, data);
+            }
+            _ => self.write_nametable(addr, data),
+        }
+        Ok(())
+    }
+
+    fn ppu_observe(&mut self, addr: u16, activity: BusActivity) {
+        if activity != BusActivity::Read {
+            self.fetch = Fetch::Other;
+            return;
+        }
+        self.ppu_read_seen = true;
+        if (0x2000..=0x2FFF).contains(&addr) && addr == self.last_fetch_addr {
+            self.matching_fetches += 1;
+            if self.matching_fetches == 2 {
+                self.detect_scanline();
+            }
+        } else {
+            self.matching_fetches = 0;
+        }
+        self.last_fetch_addr = addr;
+
+        self.fetch = self.classify_fetch();
+        if self.fetch == Fetch::BgNametable {
+            self.start_tile(addr);
+        }
+        self.fetch_index = self.fetch_index.saturating_add(1);
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => match self.prg_bank(addr) {
+                (bank, true) => Some(self.parts.prg_rom_offset(bank, 0x2000, addr)),
+                (_, false) => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::test::{cpu_read, make_parts, ppu_fetch};
+
+    fn make_mmc5() -> Mmc5<'static> {
+        let mut mmc5 = Mmc5::new(make_parts(0x20000, 0x40000));
+        mmc5.power_on();
+        mmc5
+    }
+
+    fn write(mmc5: &mut Mmc5, addr: u16, data: u8) {
+        mmc5.observe_access(addr as u32, BusActivity::Write(data));
+        mmc5.bus_write(addr as u32, data).unwrap();
+    }
+
+    /// The fetches the PPU makes for one scanline, starting with the dot 1
+    /// nametable read. Every tile uses nametable entry 0x2000 + column and
+    /// pattern address 0x0000 or 0x1000.
+    fn fetch_line(mmc5: &mut Mmc5, bg_table: u16, sprite_table: u16) -> Vec<u8> {
+        let mut data = Vec::new();
+        let mut fetch_tile = |mmc5: &mut Mmc5, column: u16| {
+            data.push(ppu_fetch(mmc5, 0x2000 + column));
+            data.push(ppu_fetch(mmc5, 0x23C0));
+            data.push(ppu_fetch(mmc5, bg_table));
+            data.push(ppu_fetch(mmc5, bg_table + 8));
+        };
+        for column in 2..34 {
+            fetch_tile(mmc5, column);
+        }
+        for _ in 0..8 {
+            ppu_fetch(mmc5, 0x2000);
+            ppu_fetch(mmc5, 0x2000);
+            ppu_fetch(mmc5, sprite_table);
+            ppu_fetch(mmc5, sprite_table + 8);
+        }
+        fetch_tile(mmc5, 0);
+        fetch_tile(mmc5, 1);
+        ppu_fetch(mmc5, 0x2002);
+        ppu_fetch(mmc5, 0x2002);
+        data
+    }
+
+    /// Everything up to the first scanline's dot 1 fetch: the end of the
+    /// pre-render line
+    fn start_frame(mmc5: &mut Mmc5) {
+        for column in 0..2 {
+            ppu_fetch(mmc5, 0x2000 + column);
+            ppu_fetch(mmc5, 0x23C0);
+            ppu_fetch(mmc5, 0x0000);
+            ppu_fetch(mmc5, 0x0008);
+        }
+        ppu_fetch(mmc5, 0x2002);
+        ppu_fetch(mmc5, 0x2002);
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut mmc5 = make_mmc5();
+        // Power-on: mode 3, last bank at 0xE000
+        assert_eq!(cpu_read(&mut mmc5, 0xE000), 15);
+
+        write(&mut mmc5, 0x5114, 0x83);
+        write(&mut mmc5, 0x5115, 0x85);
+        write(&mut mmc5, 0x5116, 0x87);
+        assert_eq!(cpu_read(&mut mmc5, 0x8000), 3);
+        assert_eq!(cpu_read(&mut mmc5, 0xA000), 5);
+        assert_eq!(cpu_read(&mut mmc5, 0xC000), 7);
+
+        // Mode 2: 16KB at 0x8000 ignores the low bit
+        write(&mut mmc5, 0x5100, 2);
+        assert_eq!(cpu_read(&mut mmc5, 0x8000), 4);
+        assert_eq!(cpu_read(&mut mmc5, 0xA000), 5);
+        assert_eq!(cpu_read(&mut mmc5, 0xC000), 7);
+
+        // Mode 1: 16KB at 0xC000 from 0x5117
+        write(&mut mmc5, 0x5100, 1);
+        assert_eq!(cpu_read(&mut mmc5, 0xC000), 14);
+        assert_eq!(cpu_read(&mut mmc5, 0xE000), 15);
+
+        // Mode 0: 32KB from 0x5117
+        write(&mut mmc5, 0x5100, 0);
+        assert_eq!(cpu_read(&mut mmc5, 0x8000), 12);
+        assert_eq!(cpu_read(&mut mmc5, 0xE000), 15);
+
+        // Debuggers see the same mapping, including RAM banked into the window
+        assert_eq!(mmc5.prg_rom_offset(0x8001), Some(12 * 0x2000 + 1));
+        assert_eq!(mmc5.peek(0xE000), Some(15));
+        write(&mut mmc5, 0x5100, 3);
+        write(&mut mmc5, 0x5114, 0x03);
+        assert_eq!(mmc5.prg_rom_offset(0x8000), None);
+        assert_eq!(mmc5.peek(0x8000), Some(cpu_read(&mut mmc5, 0x8000)));
+    }
+
+    #[test]
+    fn test_prg_ram() {
+        let mut mmc5 = make_mmc5();
+        write(&mut mmc5, 0x5113, 2);
+        // Writes are ignored until both protect registers are set
+        write(&mut mmc5, 0x6000, 0x42);
+        assert_eq!(cpu_read(&mut mmc5, 0x6000), 0);
+        write(&mut mmc5, 0x5102, 2);
+        write(&mut mmc5, 0x5103, 1);
+        write(&mut mmc5, 0x6000, 0x42);
+        assert_eq!(cpu_read(&mut mmc5, 0x6000), 0x42);
+
+        // The same RAM bank in the 0x8000 window
+        write(&mut mmc5, 0x5114, 0x02);
+        assert_eq!(cpu_read(&mut mmc5, 0x8000), 0x42);
+        write(&mut mmc5, 0x8001, 0x43);
+        assert_eq!(cpu_read(&mut mmc5, 0x6001), 0x43);
+    }
+
+    #[test]
+    fn test_chr_banking() {
+        let mut mmc5 = make_mmc5();
+        // 1KB mode
+        write(&mut mmc5, 0x5101, 3);
+        for reg in 0..8 {
+            write(&mut mmc5, 0x5120 + reg, 0x10 + reg as u8);
+        }
+        for slot in 0..8 {
+            assert_eq!(ppu_fetch(&mut mmc5, slot * 0x400), 0x10 + slot as u8);
+        }
+
+        // Set B is last written, and repeats every 4KB
+        for reg in 0..4 {
+            write(&mut mmc5, 0x5128 + reg, 0x20 + reg as u8);
+        }
+        assert_eq!(ppu_fetch(&mut mmc5, 0x0400), 0x21);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x1400), 0x21);
+
+        // 2KB mode uses the odd registers
+        write(&mut mmc5, 0x5101, 2);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x1000), 0x21 * 2);
+        write(&mut mmc5, 0x5123, 0x05);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x0800), 10);
+
+        // The upper bits apply to later bank writes
+        write(&mut mmc5, 0x5101, 3);
+        write(&mut mmc5, 0x5130, 1);
+        write(&mut mmc5, 0x5120, 0x00);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x0000), 0x00);
+        assert_eq!(mmc5.chr_banks[0], 0x100);
+    }
+
+    #[test]
+    fn test_scanline_irq() {
+        let mut mmc5 = make_mmc5();
+        write(&mut mmc5, 0x2001, 0x18);
+        write(&mut mmc5, 0x5203, 3);
+        write(&mut mmc5, 0x5204, 0x80);
+
+        let irq = mmc5.parts.irq.make_receiver();
+
+        start_frame(&mut mmc5);
+        for line in 0..3 {
+            assert!(!irq.get(), "IRQ before line {}", line);
+            fetch_line(&mut mmc5, 0x0000, 0x1000);
+        }
+        assert_eq!(mmc5.bus_read(0x5204).unwrap(), ReadResult::Data(0x40));
+        // The next line's first fetch completes the detection
+        ppu_fetch(&mut mmc5, 0x2002);
+        assert_eq!(mmc5.scanline, 3);
+        assert!(irq.get());
+        // Reading the status acknowledges it
+        assert_eq!(mmc5.bus_read(0x5204).unwrap(), ReadResult::Data(0xC0));
+        assert!(!irq.get());
+
+        // Three CPU cycles without fetches, after the one with them, end the
+        // frame
+        for _ in 0..4 {
+            mmc5.tick().unwrap();
+        }
+        assert_eq!(mmc5.bus_read(0x5204).unwrap(), ReadResult::Data(0x00));
+    }
+
+    #[test]
+    fn test_8x16_chr_sets() {
+        let mut mmc5 = make_mmc5();
+        write(&mut mmc5, 0x5101, 1);
+        write(&mut mmc5, 0x5123, 0x01);
+        write(&mut mmc5, 0x5127, 0x02);
+        write(&mut mmc5, 0x512B, 0x03);
+        write(&mut mmc5, 0x2000, 0x20);
+        write(&mut mmc5, 0x2001, 0x18);
+
+        start_frame(&mut mmc5);
+        let data = fetch_line(&mut mmc5, 0x0000, 0x1000);
+        // Background pattern fetches use set B; 4KB bank 3 starts with 1KB
+        // bank 12
+        assert_eq!(data[2], 12);
+        assert_eq!(data[6], 12);
+        // Sprites use set A. fetch_line doesn't return their data, so start
+        // another line and stop at the first sprite pattern fetch.
+        ppu_fetch(&mut mmc5, 0x2002);
+        for _ in 1..BG_FETCHES + 2 {
+            ppu_fetch(&mut mmc5, 0x3000);
+        }
+        assert_eq!(mmc5.fetch_index, BG_FETCHES + 2);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x1000), 8);
+    }
+
+    #[test]
+    fn test_exram_modes() {
+        let mut mmc5 = make_mmc5();
+        // ExRAM as a nametable, fill mode for the rest
+        write(&mut mmc5, 0x5105, 0b11_11_11_10);
+        write(&mut mmc5, 0x5106, 0x77);
+        write(&mut mmc5, 0x5107, 0x02);
+        mmc5.ppu_write(0x2005, 0x55).unwrap();
+        assert_eq!(ppu_fetch(&mut mmc5, 0x2005), 0x55);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x2405), 0x77);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x27C0), 0xAA);
+        // Out of rendering, CPU writes store 0
+        write(&mut mmc5, 0x5C05, 0x12);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x2005), 0x00);
+
+        // Mode 2 is plain RAM for the CPU, and hidden from the PPU
+        write(&mut mmc5, 0x5104, 2);
+        write(&mut mmc5, 0x5C05, 0x12);
+        assert_eq!(cpu_read(&mut mmc5, 0x5C05), 0x12);
+        assert_eq!(ppu_fetch(&mut mmc5, 0x2005), 0x00);
+
+        // Mode 1: each tile's ExRAM byte picks its 4KB CHR bank and palette
+        mmc5.exram[2] = 0xC5;
+        mmc5.exram[3] = 0x43;
+        write(&mut mmc5, 0x5104, 1);
+        write(&mut mmc5, 0x2001, 0x18);
+        start_frame(&mut mmc5);
+        let data = fetch_line(&mut mmc5, 0x0000, 0x1000);
+        assert_eq!(data[1], 0xFF);
+        assert_eq!(data[2], 5 * 4);
+        assert_eq!(data[5], 0x55);
+        assert_eq!(data[6], 3 * 4);
+    }
+
+    #[test]
+    fn test_vertical_split() {
+        let mut mmc5 = make_mmc5();
+        write(&mut mmc5, 0x5104, 1);
+        write(&mut mmc5, 0x2001, 0x18);
+        start_frame(&mut mmc5);
+        // Tile row 1 of the split, with the attribute for its top-right
+        // quadrant
+        mmc5.exram[32 + 2] = 0x42;
+        mmc5.exram[0x3C0] = 0x0C;
+        write(&mut mmc5, 0x5104, 0);
+        // Left split of 4 tiles, scrolled down 8 lines, from CHR bank 2
+        write(&mut mmc5, 0x5200, 0x84);
+        write(&mut mmc5, 0x5201, 8);
+        write(&mut mmc5, 0x5202, 2);
+
+        let data = fetch_line(&mut mmc5, 0x0000, 0x1000);
+        assert_eq!(data[0], 0x42);
+        assert_eq!(data[1], 0xFF);
+        assert_eq!(data[2], 8);
+        // Column 4 is past the split
+        assert_eq!(data[8], 0);
+    }
+
+    #[test]
+    fn test_multiplier() {
+        let mut mmc5 = make_mmc5();
+        write(&mut mmc5, 0x5205, 200);
+        write(&mut mmc5, 0x5206, 100);
+        assert_eq!(cpu_read(&mut mmc5, 0x5205), (20000 & 0xFF) as u8);
+        assert_eq!(cpu_read(&mut mmc5, 0x5206), (20000 >> 8) as u8);
+    }
+}
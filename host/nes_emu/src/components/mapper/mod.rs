@@ -0,0 +1,369 @@
+//! Cartridge boards. A board sits on both of the console's buses: the CPU
+//! side holds PRG-ROM, PRG-RAM and the mapper's registers, and the PPU side
+//! holds CHR memory and decides where the nametables come from. Both sides
+//! share one Mapper, which the console connects through a CartCpuPort and a
+//! CartPpuPort.
+
+pub mod flash;
+pub mod fme7;
+pub mod mmc2;
+pub mod mmc5;
+pub mod nrom;
+pub mod unrom512;
+pub mod vrc4;
+pub mod vrc6;
+pub mod vrc7;
+pub mod vrc_irq;
+
+use std::{cell::RefCell, path::PathBuf, rc::Rc};
+
+use super::{
+    BusActivity, BusDevice, EmuResult, ReadResult, audio::AudioOutput, nametables::Nametables,
+    signal::WiredOrSource, timing::ConsoleTiming,
+};
+use crate::nes_file::{MapperId, NametableLayout};
+
+/// A cartridge board. The BusDevice half is the CPU side, mapped from 0x4020
+/// with addresses passed through unchanged. The rest is the PPU side, which
+/// sees the PPU's whole address space below the palette.
+pub trait Mapper: BusDevice {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult>;
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()>;
+
+    /// Watch the PPU's address bus. Called for every PPU bus access before
+    /// ppu_read or ppu_write.
+    fn ppu_observe(&mut self, _addr: u16, _activity: BusActivity) {}
+
+    /// Offset into PRG-ROM of the byte the CPU sees at addr, or None if
+    /// something else is mapped there. Debuggers use it to tell which bank
+    /// code is running from.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> {
+        None
+    }
+}
+
+/// Everything the console hands a board: the memory chips from the ROM file,
+/// and the resources on the cartridge connector
+pub struct CartridgeParts<'t> {
+    pub prg_rom: Vec<u8>,
+    pub chr: ChrMemory,
+    /// PRG-RAM, addressed from 0. Boards with banked PRG-RAM wrap bank
+    /// numbers at prg_ram_size.
+    pub prg_ram: Box<dyn BusDevice + 't>,
+    pub prg_ram_size: usize,
+    pub nametables: Nametables,
+    /// The header's nametable layout, for boards where it picks between
+    /// wiring options rather than fixing the mirroring
+    pub layout: NametableLayout,
+    /// The header's battery flag
+    pub battery: bool,
+    /// Save file for boards that keep saves somewhere other than PRG-RAM
+    pub save_path: Option<PathBuf>,
+    /// This board's source on the CPU's IRQ line
+    pub irq: WiredOrSource,
+    pub audio: Option<AudioOutput>,
+    /// The console's clock rates, for boards that time things in real time
+    pub timing: &'static ConsoleTiming,
+}
+
+impl CartridgeParts<'_> {
+    /// Read PRG-ROM through a bank of bank_size bytes. Bank numbers past
+    /// the end of the ROM wrap around.
+    pub fn read_prg_rom(&self, bank: usize, bank_size: usize, addr: u16) -> u8 {
+        self.prg_rom[self.prg_rom_offset(bank, bank_size, addr)]
+    }
+
+    /// Offset into PRG-ROM that read_prg_rom reads
+    pub fn prg_rom_offset(&self, bank: usize, bank_size: usize, addr: u16) -> usize {
+        bank_offset(bank, bank_size, addr, self.prg_rom.len())
+    }
+
+    pub fn read_prg_ram(
+        &mut self,
+        bank: usize,
+        bank_size: usize,
+        addr: u16,
+    ) -> EmuResult<ReadResult> {
+        if self.prg_ram_size == 0 {
+            return Ok(ReadResult::OpenBus);
+        }
+        let offset = bank_offset(bank, bank_size, addr, self.prg_ram_size);
+        self.prg_ram.bus_read(offset as u32)
+    }
+
+    pub fn write_prg_ram(
+        &mut self,
+        bank: usize,
+        bank_size: usize,
+        addr: u16,
+        data: u8,
+    ) -> EmuResult<()> {
+        if self.prg_ram_size == 0 {
+            return Ok(());
+        }
+        let offset = bank_offset(bank, bank_size, addr, self.prg_ram_size);
+        self.prg_ram.bus_write(offset as u32, data)
+    }
+
+    pub fn peek_prg_ram(&self, bank: usize, bank_size: usize, addr: u16) -> Option<u8> {
+        if self.prg_ram_size == 0 {
+            return None;
+        }
+        let offset = bank_offset(bank, bank_size, addr, self.prg_ram_size);
+        self.prg_ram.peek(offset as u32)
+    }
+
+    /// Lifecycle calls that the board passes on to the PRG-RAM
+    pub fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.prg_ram.start_of_simulation()
+    }
+
+    pub fn end_of_simulation(&mut self) {
+        self.prg_ram.end_of_simulation();
+    }
+
+    pub fn power_on(&mut self) {
+        self.prg_ram.power_on();
+        self.chr.power_on();
+        self.nametables.power_on();
+    }
+
+    pub fn reset(&mut self) {
+        self.prg_ram.reset();
+    }
+
+    pub fn tick(&mut self) -> EmuResult<()> {
+        self.prg_ram.tick()
+    }
+}
+
+/// Offset into a memory of len bytes, for addr within a bank_size window
+/// showing the given bank
+pub fn bank_offset(bank: usize, bank_size: usize, addr: u16, len: usize) -> usize {
+    (bank * bank_size + (addr as usize & (bank_size - 1))) % len
+}
+
+/// CHR-ROM, or CHR-RAM for boards without it
+#[derive(Debug, Clone)]
+pub struct ChrMemory {
+    data: Vec<u8>,
+    writable: bool,
+}
+
+impl ChrMemory {
+    /// CHR-ROM if the file has any, otherwise ram_size bytes of CHR-RAM
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        if rom.is_empty() {
+            ChrMemory {
+                data: vec![0; ram_size.max(0x2000)],
+                writable: true,
+            }
+        } else {
+            ChrMemory {
+                data: rom,
+                writable: false,
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn read(&self, bank: usize, bank_size: usize, addr: u16) -> u8 {
+        self.data[bank_offset(bank, bank_size, addr, self.data.len())]
+    }
+
+    /// Writes are ignored for CHR-ROM
+    pub fn write(&mut self, bank: usize, bank_size: usize, addr: u16, data: u8) {
+        if self.writable {
+            let offset = bank_offset(bank, bank_size, addr, self.data.len());
+            self.data[offset] = data;
+        }
+    }
+
+    fn power_on(&mut self) {
+        if self.writable {
+            self.data.fill(0);
+        }
+    }
+}
+
+/// Whether a board saves by writing its own PRG-ROM, so the battery flag
+/// doesn't mean battery-backed PRG-RAM
+pub fn saves_to_prg_rom(mapper: MapperId, battery: bool) -> bool {
+    mapper.id == 30 && battery
+}
+
+/// Build the board for a mapper, or None if it isn't supported
+pub fn build_mapper<'t>(
+    mapper: MapperId,
+    parts: CartridgeParts<'t>,
+) -> Option<Box<dyn Mapper + 't>> {
+    Some(match mapper.id {
+        0 => Box::new(nrom::Nrom::new(parts)),
+        5 => Box::new(mmc5::Mmc5::new(parts)),
+        9 | 10 => Box::new(mmc2::Mmc2::new(parts, mapper.id)),
+        21 | 22 | 23 | 25 => Box::new(vrc4::Vrc4::new(parts, vrc4::VrcBoard::from_mapper(mapper)?)),
+        24 | 26 => Box::new(vrc6::Vrc6::new(parts, mapper.id)),
+        30 => Box::new(unrom512::Unrom512::new(parts, mapper)),
+        69 => Box::new(fme7::Fme7::new(parts)),
+        85 => Box::new(vrc7::Vrc7::new(parts, mapper)),
+        _ => return None,
+    })
+}
+
+/// Split a board into the devices the console maps on its two buses. The
+/// CPU port carries the board's lifecycle calls; the PPU port only passes on
+/// PPU bus accesses.
+pub fn connect_cartridge<'t>(mapper: Box<dyn Mapper + 't>) -> (CartCpuPort<'t>, CartPpuPort<'t>) {
+    let mapper = Rc::new(RefCell::new(mapper));
+    (
+        CartCpuPort {
+            mapper: Rc::clone(&mapper),
+        },
+        CartPpuPort { mapper },
+    )
+}
+
+pub struct CartCpuPort<'t> {
+    mapper: Rc<RefCell<Box<dyn Mapper + 't>>>,
+}
+
+impl<'t> CartCpuPort<'t> {
+    /// Handle for looking up where the board maps PRG-ROM, which stays
+    /// valid once the port is on the bus
+    pub fn prg_rom_map(&self) -> PrgRomMap<'t> {
+        PrgRomMap {
+            mapper: Rc::clone(&self.mapper),
+        }
+    }
+}
+
+/// Where a board currently maps PRG-ROM into the CPU's address space
+#[derive(Clone)]
+pub struct PrgRomMap<'t> {
+    mapper: Rc<RefCell<Box<dyn Mapper + 't>>>,
+}
+
+impl PrgRomMap<'_> {
+    pub fn offset(&self, addr: u16) -> Option<usize> {
+        self.mapper.borrow().prg_rom_offset(addr)
+    }
+}
+
+impl BusDevice for CartCpuPort<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.mapper.borrow_mut().bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.mapper.borrow_mut().bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.mapper.borrow_mut().start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.mapper.borrow_mut().end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.mapper.borrow_mut().power_on();
+    }
+
+    fn reset(&mut self) {
+        self.mapper.borrow_mut().reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.mapper.borrow_mut().tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.mapper.borrow_mut().observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.mapper.borrow().peek(addr)
+    }
+}
+
+pub struct CartPpuPort<'t> {
+    mapper: Rc<RefCell<Box<dyn Mapper + 't>>>,
+}
+
+impl BusDevice for CartPpuPort<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.mapper.borrow_mut().ppu_read(addr as u16)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.mapper.borrow_mut().ppu_write(addr as u16, data)
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.mapper.borrow_mut().ppu_observe(addr as u16, activity);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::components::{mem::RAMDevice, signal::WiredOrSignal};
+
+    /// Parts for a test board. PRG-ROM banks start with their 8KB bank
+    /// number, and CHR-ROM 1KB banks with theirs.
+    pub(crate) fn make_parts(prg_size: usize, chr_size: usize) -> CartridgeParts<'static> {
+        let mut prg_rom = vec![0; prg_size];
+        for (bank, chunk) in prg_rom.chunks_mut(0x2000).enumerate() {
+            chunk[0] = bank as u8;
+        }
+        let mut chr_rom = vec![0; chr_size];
+        for (bank, chunk) in chr_rom.chunks_mut(0x400).enumerate() {
+            chunk[0] = bank as u8;
+        }
+        CartridgeParts {
+            prg_rom,
+            chr: ChrMemory::new(chr_rom, 0),
+            prg_ram: Box::new(RAMDevice::new(0x10000)),
+            prg_ram_size: 0x10000,
+            nametables: Nametables::new(NametableLayout::Vertical),
+            layout: NametableLayout::Vertical,
+            battery: false,
+            save_path: None,
+            irq: WiredOrSignal::new().make_source("cart"),
+            audio: None,
+            timing: &ConsoleTiming::NTSC,
+        }
+    }
+
+    pub(crate) fn cpu_read(mapper: &mut dyn Mapper, addr: u16) -> u8 {
+        match mapper.bus_read(addr as u32).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus at 0x{:04X}", addr),
+        }
+    }
+
+    /// A PPU read the way the PPU does it: observed, then read
+    pub(crate) fn ppu_fetch(mapper: &mut dyn Mapper, addr: u16) -> u8 {
+        mapper.ppu_observe(addr, BusActivity::Read);
+        match mapper.ppu_read(addr).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus at PPU 0x{:04X}", addr),
+        }
+    }
+
+    #[test]
+    fn test_bank_offset() {
+        assert_eq!(bank_offset(1, 0x2000, 0x8123, 0x8000), 0x2123);
+        // Banks past the end wrap
+        assert_eq!(bank_offset(5, 0x2000, 0xA000, 0x8000), 0x2000);
+        assert_eq!(bank_offset(3, 0x400, 0x0BFF, 0x2000), 0x0FFF);
+    }
+}
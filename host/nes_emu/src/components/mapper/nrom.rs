@@ -0,0 +1,87 @@
+use super::{CartridgeParts, Mapper};
+use crate::components::{BusDevice, EmuResult, ReadResult};
+
+/// NROM, mapper 0: no bank switching. 16KB of PRG-ROM is mirrored to fill
+/// 0x8000 - 0xFFFF, and the nametable layout is soldered in.
+pub struct Nrom<'t> {
+    parts: CartridgeParts<'t>,
+}
+
+impl<'t> Nrom<'t> {
+    pub fn new(parts: CartridgeParts<'t>) -> Self {
+        Nrom { parts }
+    }
+}
+
+impl BusDevice for Nrom<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => self.parts.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => Ok(ReadResult::Data(self.parts.read_prg_rom(0, 0x8000, addr))),
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => self.parts.write_prg_ram(0, 0x2000, addr, data),
+            _ => Ok(()),
+        }
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => self.parts.peek_prg_ram(0, 0x2000, addr),
+            _ => self
+                .prg_rom_offset(addr)
+                .map(|offset| self.parts.prg_rom[offset]),
+        }
+    }
+}
+
+impl Mapper for Nrom<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => self.parts.chr.read(0, 0x2000, addr),
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => self.parts.chr.write(0, 0x2000, addr, data),
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.parts.prg_rom_offset(0, 0x8000, addr)),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use super::{CartridgeParts, Mapper, bank_offset, flash::FlashRom};
+use crate::{
+    components::{BusDevice, EmuResult, ReadResult, nametables::Mirroring},
+    nes_file::{MapperId, NametableLayout},
+};
+
+/// UNROM 512, mapper 30, a homebrew board. A 16KB PRG bank at 0x8000 with
+/// the last bank fixed at 0xC000, and four 8KB banks of CHR-RAM. The header's
+/// layout bits pick the mirroring: horizontal, vertical, one-screen chosen by
+/// bit 7 of the bank register, or four-screen from the last 8KB of CHR-RAM.
+///
+/// With the battery flag set, PRG-ROM is an SST39SF040 flash that the game
+/// saves to by reprogramming it through 0x8000 - 0xBFFF, and the bank
+/// register moves to 0xC000 - 0xFFFF. Without it, the register covers
+/// 0x8000 - 0xFFFF and has bus conflicts, except on submapper 1.
+pub struct Unrom512<'t> {
+    parts: CartridgeParts<'t>,
+    flash: FlashRom,
+    flashable: bool,
+    bus_conflicts: bool,
+    /// Bit 7: one-screen page, bits 5 - 6: CHR bank, bits 0 - 4: PRG bank
+    bank: u8,
+}
+
+impl<'t> Unrom512<'t> {
+    pub fn new(mut parts: CartridgeParts<'t>, mapper: MapperId) -> Self {
+        let flashable = parts.battery;
+        let save_path = if flashable {
+            parts.save_path.take()
+        } else {
+            None
+        };
+        let flash = FlashRom::new(std::mem::take(&mut parts.prg_rom), save_path, parts.timing);
+        // The layout's alternative bit picks this board's own options, not
+        // cartridge VRAM
+        parts.nametables.set_layout(NametableLayout::Vertical);
+        let mut unrom = Unrom512 {
+            parts,
+            flash,
+            flashable,
+            bus_conflicts: !flashable && mapper.sub_id != 1,
+            bank: 0,
+        };
+        unrom.write_bank(0);
+        unrom
+    }
+
+    fn write_bank(&mut self, data: u8) {
+        self.bank = data;
+        match self.parts.layout {
+            NametableLayout::AlternateVertical => {
+                self.parts.nametables.set_mirroring(if data & 0x80 != 0 {
+                    Mirroring::SingleScreenB
+                } else {
+                    Mirroring::SingleScreenA
+                });
+            }
+            // Four-screen doesn't use CIRAM
+            NametableLayout::AlternateHorizontal => {}
+            layout => self.parts.nametables.set_layout(layout),
+        }
+    }
+
+    fn four_screen(&self) -> bool {
+        self.parts.layout == NametableLayout::AlternateHorizontal
+    }
+
+    /// Offset into the flash for a CPU address
+    fn prg_offset(&self, addr: u16) -> usize {
+        let bank = match addr {
+            0x8000..=0xBFFF => (self.bank & 0x1F) as usize,
+            // The last bank, or the only one for a ROM smaller than a bank
+            _ => (self.flash.len() / 0x4000).saturating_sub(1),
+        };
+        bank_offset(bank, 0x4000, addr, self.flash.len())
+    }
+
+    fn chr_bank(&self) -> usize {
+        ((self.bank >> 5) & 0x03) as usize
+    }
+
+    fn last_chr_bank(&self) -> usize {
+        self.parts.chr.len() / 0x2000 - 1
+    }
+}
+
+impl BusDevice for Unrom512<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x8000..=0xFFFF => self.flash.bus_read(self.prg_offset(addr) as u32),
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x8000..=0xBFFF if self.flashable => {
+                self.flash.bus_write(self.prg_offset(addr) as u32, data)?;
+            }
+            0x8000..=0xFFFF => {
+                let data = if self.bus_conflicts {
+                    // The ROM drives the bus too, and wins on 0 bits
+                    match self.flash.bus_read(self.prg_offset(addr) as u32)? {
+                        ReadResult::Data(rom) => data & rom,
+                        ReadResult::OpenBus => data,
+                    }
+                } else {
+                    data
+                };
+                self.write_bank(data);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.flash.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.flash.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.flash.power_on();
+        self.write_bank(0);
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.flash.tick()
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.flash.peek(self.prg_rom_offset(addr as u16)? as u32)
+    }
+}
+
+impl Mapper for Unrom512<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => self.parts.chr.read(self.chr_bank(), 0x2000, addr),
+            _ if self.four_screen() => self.parts.chr.read(self.last_chr_bank(), 0x2000, addr),
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_bank();
+                self.parts.chr.write(bank, 0x2000, addr, data);
+            }
+            _ if self.four_screen() => {
+                let bank = self.last_chr_bank();
+                self.parts.chr.write(bank, 0x2000, addr, data);
+            }
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.prg_offset(addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::{
+        ChrMemory,
+        test::{cpu_read, make_parts, ppu_fetch},
+    };
+
+    fn make_unrom(battery: bool, sub_id: u8, layout: NametableLayout) -> Unrom512<'static> {
+        let mut parts = make_parts(0x80000, 0);
+        parts.chr = ChrMemory::new(Vec::new(), 0x8000);
+        parts.battery = battery;
+        parts.layout = layout;
+        let mut unrom = Unrom512::new(parts, MapperId { id: 30, sub_id });
+        unrom.start_of_simulation().unwrap();
+        unrom.power_on();
+        unrom
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut unrom = make_unrom(false, 1, NametableLayout::Vertical);
+        unrom.bus_write(0x8000, 0x45).unwrap();
+        assert_eq!(cpu_read(&mut unrom, 0x8000), 10);
+        assert_eq!(cpu_read(&mut unrom, 0xA000), 11);
+        assert_eq!(cpu_read(&mut unrom, 0xC000), 62);
+        assert_eq!(cpu_read(&mut unrom, 0xE000), 63);
+
+        // CHR bank 2
+        unrom.ppu_write(0x0010, 0x77).unwrap();
+        unrom.bus_write(0x8000, 0x00).unwrap();
+        assert_eq!(ppu_fetch(&mut unrom, 0x0010), 0x00);
+        unrom.bus_write(0x8000, 0x40).unwrap();
+        assert_eq!(ppu_fetch(&mut unrom, 0x0010), 0x77);
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        let mut unrom = make_unrom(false, 0, NametableLayout::Vertical);
+        // 0xC000 holds bank number 62, which masks the write
+        unrom.bus_write(0xC000, 0x05).unwrap();
+        assert_eq!(unrom.bank, 0x04);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut unrom = make_unrom(false, 1, NametableLayout::AlternateVertical);
+        unrom.ppu_write(0x2000, 0x11).unwrap();
+        assert_eq!(ppu_fetch(&mut unrom, 0x2C00), 0x11);
+        unrom.bus_write(0x8000, 0x80).unwrap();
+        assert_eq!(ppu_fetch(&mut unrom, 0x2C00), 0x00);
+
+        // Four separate nametables in the last CHR-RAM bank
+        let mut unrom = make_unrom(false, 1, NametableLayout::AlternateHorizontal);
+        for (table, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].into_iter().enumerate() {
+            unrom.ppu_write(addr, table as u8 + 1).unwrap();
+        }
+        for (table, addr) in [0x2000, 0x2400, 0x2800, 0x2C00].into_iter().enumerate() {
+            assert_eq!(ppu_fetch(&mut unrom, addr), table as u8 + 1);
+        }
+        unrom.bus_write(0x8000, 0x60).unwrap();
+        assert_eq!(ppu_fetch(&mut unrom, 0x0400), 2);
+    }
+
+    #[test]
+    fn test_self_flashing() {
+        let mut unrom = make_unrom(true, 0, NametableLayout::Vertical);
+        // The flash sees 0x5555 through bank 1 and 0x2AAA through bank 0
+        let mut flash_write = |bank: u8, addr: u16, data: u8| {
+            unrom.bus_write(0xC000, bank).unwrap();
+            unrom.bus_write(addr as u32, data).unwrap();
+        };
+        flash_write(1, 0x9555, 0xAA);
+        flash_write(0, 0xAAAA, 0x55);
+        flash_write(1, 0x9555, 0xA0);
+        flash_write(4, 0x8000, 0x00);
+        assert!(unrom.flash.busy());
+        while unrom.flash.busy() {
+            unrom.tick().unwrap();
+        }
+        assert_eq!(cpu_read(&mut unrom, 0x8000), 0x00);
+        unrom.bus_write(0xC000, 0).unwrap();
+        assert_eq!(cpu_read(&mut unrom, 0xA000), 1);
+    }
+}
@@ -0,0 +1,379 @@
+use super::{CartridgeParts, Mapper, vrc_irq::VrcIrq};
+use crate::{
+    components::{BusDevice, EmuResult, ReadResult, nametables::Mirroring},
+    nes_file::MapperId,
+};
+
+/// Which CPU address lines a board connects to the chip's two register
+/// select inputs, as masks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VrcWiring {
+    pub a0: u16,
+    pub a1: u16,
+}
+
+impl VrcWiring {
+    pub const fn new(a0: u16, a1: u16) -> Self {
+        VrcWiring { a0, a1 }
+    }
+
+    /// Wiring that answers to both of two boards' address lines. Games only
+    /// write the addresses their own board decodes, so this works for
+    /// either, which is how iNES 1.0 files sharing a mapper number play.
+    pub const fn either(first: VrcWiring, second: VrcWiring) -> Self {
+        VrcWiring::new(first.a0 | second.a0, first.a1 | second.a1)
+    }
+
+    /// The register number, 0 - 3, that a CPU address selects
+    pub fn register(&self, addr: u16) -> u16 {
+        (addr & self.a0 != 0) as u16 | ((addr & self.a1 != 0) as u16) << 1
+    }
+}
+
+const VRC4A: VrcWiring = VrcWiring::new(0x02, 0x04);
+const VRC4B: VrcWiring = VrcWiring::new(0x02, 0x01);
+const VRC4C: VrcWiring = VrcWiring::new(0x40, 0x80);
+const VRC4D: VrcWiring = VrcWiring::new(0x08, 0x04);
+const VRC4E: VrcWiring = VrcWiring::new(0x04, 0x08);
+const VRC4F: VrcWiring = VrcWiring::new(0x01, 0x02);
+const VRC2A: VrcWiring = VrcWiring::new(0x02, 0x01);
+const VRC2B: VrcWiring = VrcWiring::new(0x01, 0x02);
+const VRC2C: VrcWiring = VrcWiring::new(0x02, 0x01);
+
+/// Which chip a board carries, and how it is wired
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct VrcBoard {
+    pub wiring: VrcWiring,
+    pub vrc2: bool,
+    /// VRC2a boards leave out CHR A10, so banks are in 2KB steps
+    pub chr_shift: u8,
+}
+
+impl VrcBoard {
+    /// The board for mappers 21, 22, 23 and 25. NES 2.0 submappers name the
+    /// board; without one, the mapper number narrows it to two VRC4 boards,
+    /// or a VRC4 and a VRC2 that the VRC4 can stand in for.
+    pub fn from_mapper(mapper: MapperId) -> Option<Self> {
+        let vrc4 = |wiring| VrcBoard {
+            wiring,
+            vrc2: false,
+            chr_shift: 0,
+        };
+        let vrc2 = |wiring| VrcBoard {
+            wiring,
+            vrc2: true,
+            chr_shift: 0,
+        };
+        Some(match (mapper.id, mapper.sub_id) {
+            (21, 1) => vrc4(VRC4A),
+            (21, 2) => vrc4(VRC4C),
+            (21, _) => vrc4(VrcWiring::either(VRC4A, VRC4C)),
+            (22, _) => VrcBoard {
+                chr_shift: 1,
+                ..vrc2(VRC2A)
+            },
+            (23, 1) => vrc4(VRC4F),
+            (23, 2) => vrc4(VRC4E),
+            (23, 3) => vrc2(VRC2B),
+            (23, _) => vrc4(VrcWiring::either(VRC4F, VRC4E)),
+            (25, 1) => vrc4(VRC4B),
+            (25, 2) => vrc4(VRC4D),
+            (25, 3) => vrc2(VRC2C),
+            (25, _) => vrc4(VrcWiring::either(VRC4B, VRC4D)),
+            _ => return None,
+        })
+    }
+}
+
+/// Konami VRC2 and VRC4, mappers 21, 22, 23 and 25. Two switchable 8KB PRG
+/// banks, eight 1KB CHR banks and mirroring control; the VRC4 adds a PRG
+/// swap mode, one-screen mirroring and the VRC IRQ counter. Boards connect
+/// different CPU address lines to the register selects, so the same chip
+/// answers at different addresses depending on the board.
+pub struct Vrc4<'t> {
+    parts: CartridgeParts<'t>,
+    board: VrcBoard,
+    prg_banks: [u8; 2],
+    /// VRC4: 0x8000 is fixed to the second-last bank and the first register
+    /// switches 0xC000 instead
+    prg_swapped: bool,
+    chr_banks: [u16; 8],
+    mirroring: u8,
+    irq: VrcIrq,
+}
+
+impl<'t> Vrc4<'t> {
+    pub fn new(parts: CartridgeParts<'t>, board: VrcBoard) -> Self {
+        let mut vrc4 = Vrc4 {
+            parts,
+            board,
+            prg_banks: [0; 2],
+            prg_swapped: false,
+            chr_banks: [0; 8],
+            mirroring: 0,
+            irq: VrcIrq::new(),
+        };
+        vrc4.set_mirroring(0);
+        vrc4
+    }
+
+    fn set_mirroring(&mut self, data: u8) {
+        self.mirroring = if self.board.vrc2 {
+            data & 0x01
+        } else {
+            data & 0x03
+        };
+        self.parts.nametables.set_mirroring(match self.mirroring {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        });
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        let second_last = (self.parts.prg_rom.len() / 0x2000).saturating_sub(2);
+        match (addr, self.prg_swapped) {
+            (0x8000..=0x9FFF, false) | (0xC000..=0xDFFF, true) => self.prg_banks[0] as usize,
+            (0xA000..=0xBFFF, _) => self.prg_banks[1] as usize,
+            (0xE000..=0xFFFF, _) => second_last + 1,
+            _ => second_last,
+        }
+    }
+
+    fn write_chr_bank(&mut self, addr: u16, reg: u16, data: u8) {
+        // 0xB000 - 0xE003 hold two banks each, a nibble per register
+        let bank = ((addr - 0xB000) >> 12) as usize * 2 + (reg >> 1) as usize;
+        let value = &mut self.chr_banks[bank];
+        if reg & 1 == 0 {
+            *value = (*value & 0x1F0) | (data as u16 & 0x0F);
+        } else {
+            *value = (*value & 0x0F) | ((data as u16 & 0x1F) << 4);
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let reg = self.board.wiring.register(addr);
+        match (addr & 0xF000, reg) {
+            (0x8000, _) => self.prg_banks[0] = data & 0x1F,
+            (0x9000, _) if self.board.vrc2 => self.set_mirroring(data),
+            (0x9000, 0) => self.set_mirroring(data),
+            (0x9000, 2 | 3) => self.prg_swapped = data & 0x02 != 0,
+            (0xA000, _) => self.prg_banks[1] = data & 0x1F,
+            (0xB000..=0xE000, _) => self.write_chr_bank(addr & 0xF000, reg, data),
+            (0xF000, _) if self.board.vrc2 => {}
+            (0xF000, 0) => self.irq.write_latch_low(data),
+            (0xF000, 1) => self.irq.write_latch_high(data),
+            (0xF000, 2) => self.irq.write_control(data),
+            (0xF000, _) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Vrc4<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => self.parts.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => {
+                let bank = self.prg_bank(addr);
+                Ok(ReadResult::Data(
+                    self.parts.read_prg_rom(bank, 0x2000, addr),
+                ))
+            }
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => self.parts.write_prg_ram(0, 0x2000, addr, data),
+            0x8000..=0xFFFF => {
+                self.write_register(addr, data);
+                self.parts.irq.set(self.irq.pending());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.prg_banks = [0; 2];
+        self.prg_swapped = false;
+        self.chr_banks = [0; 8];
+        self.set_mirroring(0);
+        self.irq = VrcIrq::new();
+        self.parts.irq.set(false);
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()?;
+        self.irq.tick();
+        self.parts.irq.set(self.irq.pending());
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF => self.parts.peek_prg_ram(0, 0x2000, addr),
+            _ => self
+                .prg_rom_offset(addr)
+                .map(|offset| self.parts.prg_rom[offset]),
+        }
+    }
+}
+
+impl Mapper for Vrc4<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] >> self.board.chr_shift;
+                self.parts.chr.read(bank as usize, 0x400, addr)
+            }
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] >> self.board.chr_shift;
+                self.parts.chr.write(bank as usize, 0x400, addr, data);
+            }
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.parts.prg_rom_offset(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::test::{cpu_read, make_parts, ppu_fetch};
+
+    fn make_vrc4(id: u16, sub_id: u8) -> Vrc4<'static> {
+        let board = VrcBoard::from_mapper(MapperId { id, sub_id }).unwrap();
+        let mut vrc4 = Vrc4::new(make_parts(0x20000, 0x40000), board);
+        vrc4.power_on();
+        vrc4
+    }
+
+    #[test]
+    fn test_prg_banking() {
+        let mut vrc4 = make_vrc4(21, 1);
+        vrc4.bus_write(0x8000, 3).unwrap();
+        vrc4.bus_write(0xA000, 5).unwrap();
+        assert_eq!(cpu_read(&mut vrc4, 0x8000), 3);
+        assert_eq!(cpu_read(&mut vrc4, 0xA000), 5);
+        assert_eq!(cpu_read(&mut vrc4, 0xC000), 14);
+        assert_eq!(cpu_read(&mut vrc4, 0xE000), 15);
+
+        // Swap mode, on VRC4a at 0x9004
+        vrc4.bus_write(0x9004, 0x02).unwrap();
+        assert_eq!(cpu_read(&mut vrc4, 0x8000), 14);
+        assert_eq!(cpu_read(&mut vrc4, 0xC000), 3);
+    }
+
+    #[test]
+    fn test_wiring() {
+        // The second CHR bank's high nibble is register 3 of 0xB000
+        for (id, sub_id, addr) in [
+            (21, 1, 0xB006),
+            (21, 2, 0xB0C0),
+            (23, 1, 0xB003),
+            (23, 2, 0xB00C),
+            (23, 3, 0xB003),
+            (25, 1, 0xB003),
+            (25, 2, 0xB00C),
+            (25, 3, 0xB003),
+        ] {
+            let mut vrc4 = make_vrc4(id, sub_id);
+            vrc4.bus_write(addr, 0x01).unwrap();
+            assert_eq!(
+                vrc4.chr_banks[1], 0x10,
+                "{}.{} at 0x{:04X}",
+                id, sub_id, addr
+            );
+            assert_eq!(ppu_fetch(&mut vrc4, 0x0400), 0x10);
+        }
+
+        // Without a submapper, either board's addresses work
+        for addr in [0xB003, 0xB00C] {
+            let mut vrc4 = make_vrc4(25, 0);
+            vrc4.bus_write(addr, 0x01).unwrap();
+            assert_eq!(vrc4.chr_banks[1], 0x10);
+        }
+    }
+
+    #[test]
+    fn test_vrc2() {
+        // VRC2a drops the low bit of CHR banks
+        let mut vrc2 = make_vrc4(22, 0);
+        vrc2.bus_write(0xC000, 0x06).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc2, 0x0800), 3);
+
+        // Only one mirroring bit, and no IRQ
+        vrc2.bus_write(0x9000, 0x03).unwrap();
+        assert_eq!(vrc2.mirroring, 1);
+        vrc2.bus_write(0xF002, 0x07).unwrap();
+        for _ in 0..1000 {
+            vrc2.tick().unwrap();
+        }
+        assert!(!vrc2.irq.pending());
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut vrc4 = make_vrc4(23, 2);
+        // One-screen, upper page
+        vrc4.bus_write(0x9000, 0x03).unwrap();
+        vrc4.ppu_write(0x2000, 0x42).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc4, 0x2C00), 0x42);
+        // Vertical
+        vrc4.bus_write(0x9000, 0x00).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc4, 0x2400), 0x42);
+        assert_eq!(ppu_fetch(&mut vrc4, 0x2000), 0x00);
+    }
+
+    #[test]
+    fn test_irq_line() {
+        let mut vrc4 = make_vrc4(25, 1);
+        let irq = vrc4.parts.irq.make_receiver();
+        // Latch 0xFE in cycle mode: two cycles to overflow. VRC4b swaps the
+        // register selects, so 0xF002 is the latch's high nibble and 0xF001
+        // the control register.
+        vrc4.bus_write(0xF000, 0x0E).unwrap();
+        vrc4.bus_write(0xF002, 0x0F).unwrap();
+        vrc4.bus_write(0xF001, 0x07).unwrap();
+        vrc4.tick().unwrap();
+        assert!(!irq.get());
+        vrc4.tick().unwrap();
+        assert!(irq.get());
+        // Acknowledge
+        vrc4.bus_write(0xF003, 0).unwrap();
+        assert!(!irq.get());
+    }
+}
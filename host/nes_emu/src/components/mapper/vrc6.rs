@@ -0,0 +1,274 @@
+use super::{CartridgeParts, Mapper, vrc_irq::VrcIrq, vrc4::VrcWiring};
+use crate::components::{
+    BusDevice, EmuResult, ReadResult,
+    expansion_audio::{ExpansionAudio, vrc6::Vrc6Audio},
+    nametables::Mirroring,
+};
+
+/// Konami VRC6, mappers 24 (VRC6a) and 26 (VRC6b, with the register selects
+/// swapped). A 16KB and an 8KB PRG bank, eight CHR registers with several
+/// banking modes, the VRC IRQ counter, and two pulse channels and a sawtooth.
+/// Nametables always come from CIRAM; the modes that take them from CHR-ROM
+/// aren't used by any game.
+pub struct Vrc6<'t> {
+    parts: CartridgeParts<'t>,
+    wiring: VrcWiring,
+    audio: Vrc6Audio,
+    prg_banks: [u8; 2],
+    chr_banks: [u8; 8],
+    /// 0xB003: CHR banking mode, mirroring and PRG-RAM enable
+    control: u8,
+    irq: VrcIrq,
+}
+
+impl<'t> Vrc6<'t> {
+    pub fn new(parts: CartridgeParts<'t>, mapper: u16) -> Self {
+        let wiring = if mapper == 26 {
+            VrcWiring::new(0x02, 0x01)
+        } else {
+            VrcWiring::new(0x01, 0x02)
+        };
+        let mut vrc6 = Vrc6 {
+            parts,
+            wiring,
+            audio: Vrc6Audio::new(),
+            prg_banks: [0; 2],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+        };
+        vrc6.write_control(0);
+        vrc6
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.control = data;
+        self.parts
+            .nametables
+            .set_mirroring(match (data >> 2) & 0x03 {
+                0 => Mirroring::Vertical,
+                1 => Mirroring::Horizontal,
+                2 => Mirroring::SingleScreenA,
+                _ => Mirroring::SingleScreenB,
+            });
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn prg_bank(&self, addr: u16) -> (usize, usize) {
+        match addr {
+            0x8000..=0xBFFF => (self.prg_banks[0] as usize, 0x4000),
+            0xC000..=0xDFFF => (self.prg_banks[1] as usize, 0x2000),
+            _ => (self.parts.prg_rom.len() / 0x2000 - 1, 0x2000),
+        }
+    }
+
+    /// The 1KB CHR bank for a pattern table address. In the 2KB modes, bit
+    /// 5 of the control register picks whether a register selects a 2KB
+    /// pair, or the same 1KB bank for both halves.
+    fn chr_bank(&self, addr: u16) -> usize {
+        let slot = addr as usize / 0x400;
+        let reg = match (self.control & 0x03, slot) {
+            (0, _) => return self.chr_banks[slot] as usize,
+            (1, _) => slot / 2,
+            (_, 0..=3) => return self.chr_banks[slot] as usize,
+            (_, _) => 4 + (slot - 4) / 2,
+        };
+        let bank = self.chr_banks[reg] as usize;
+        if self.control & 0x20 != 0 {
+            (bank & !1) | (slot & 1)
+        } else {
+            bank
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        // The register the chip sees, with the board's wiring undone
+        let addr = (addr & 0xF000) | self.wiring.register(addr);
+        match addr {
+            0x8000..=0x8003 => self.prg_banks[0] = data & 0x0F,
+            0xB003 => self.write_control(data),
+            0x9000..=0xB002 => self.audio.write(addr, data),
+            0xC000..=0xC003 => self.prg_banks[1] = data & 0x1F,
+            0xD000..=0xD003 => self.chr_banks[addr as usize - 0xD000] = data,
+            0xE000..=0xE003 => self.chr_banks[addr as usize - 0xE000 + 4] = data,
+            0xF000 => self.irq.write_latch(data),
+            0xF001 => self.irq.write_control(data),
+            0xF002 => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Vrc6<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.parts.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => {
+                let (bank, size) = self.prg_bank(addr);
+                Ok(ReadResult::Data(self.parts.read_prg_rom(bank, size, addr)))
+            }
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.parts.write_prg_ram(0, 0x2000, addr, data)
+            }
+            0x8000..=0xFFFF => {
+                self.write_register(addr, data);
+                self.parts.irq.set(self.irq.pending());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.audio.power_on();
+        self.prg_banks = [0; 2];
+        self.chr_banks = [0; 8];
+        self.write_control(0);
+        self.irq = VrcIrq::new();
+        self.parts.irq.set(false);
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()?;
+        self.irq.tick();
+        self.parts.irq.set(self.irq.pending());
+        self.audio.tick();
+        if let Some(audio_output) = &self.parts.audio {
+            audio_output.mix(self.audio.output());
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.parts.peek_prg_ram(0, 0x2000, addr),
+            _ => self
+                .prg_rom_offset(addr)
+                .map(|offset| self.parts.prg_rom[offset]),
+        }
+    }
+}
+
+impl Mapper for Vrc6<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => self.parts.chr.read(self.chr_bank(addr), 0x400, addr),
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_bank(addr);
+                self.parts.chr.write(bank, 0x400, addr, data);
+            }
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => {
+                let (bank, size) = self.prg_bank(addr);
+                Some(self.parts.prg_rom_offset(bank, size, addr))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::test::{cpu_read, make_parts, ppu_fetch};
+
+    fn make_vrc6(mapper: u16) -> Vrc6<'static> {
+        let mut vrc6 = Vrc6::new(make_parts(0x40000, 0x40000), mapper);
+        vrc6.power_on();
+        vrc6
+    }
+
+    #[test]
+    fn test_banking() {
+        let mut vrc6 = make_vrc6(24);
+        vrc6.bus_write(0x8000, 3).unwrap();
+        vrc6.bus_write(0xC000, 9).unwrap();
+        assert_eq!(cpu_read(&mut vrc6, 0x8000), 6);
+        assert_eq!(cpu_read(&mut vrc6, 0xA000), 7);
+        assert_eq!(cpu_read(&mut vrc6, 0xC000), 9);
+        assert_eq!(cpu_read(&mut vrc6, 0xE000), 31);
+
+        // Mode 0: 1KB banks
+        vrc6.bus_write(0xB003, 0x20).unwrap();
+        vrc6.bus_write(0xD001, 0x11).unwrap();
+        vrc6.bus_write(0xE003, 0x17).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc6, 0x0400), 0x11);
+        assert_eq!(ppu_fetch(&mut vrc6, 0x1C00), 0x17);
+
+        // Mode 1: 2KB banks from R0 - R3
+        vrc6.bus_write(0xB003, 0x21).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc6, 0x0000), 0x00);
+        assert_eq!(ppu_fetch(&mut vrc6, 0x0800), 0x10);
+        assert_eq!(ppu_fetch(&mut vrc6, 0x0C00), 0x11);
+        // Without bit 5, both halves show the register's bank
+        vrc6.bus_write(0xB003, 0x01).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc6, 0x0800), 0x11);
+        assert_eq!(ppu_fetch(&mut vrc6, 0x0C00), 0x11);
+    }
+
+    #[test]
+    fn test_vrc6b_wiring() {
+        let mut vrc6 = make_vrc6(26);
+        // 0xD002 is R1 on VRC6b
+        vrc6.bus_write(0xD002, 0x15).unwrap();
+        assert_eq!(vrc6.chr_banks[1], 0x15);
+        // And 0xB003 is still the control register
+        vrc6.bus_write(0xB003, 0x8C).unwrap();
+        vrc6.bus_write(0x6000, 0x42).unwrap();
+        assert_eq!(cpu_read(&mut vrc6, 0x6000), 0x42);
+        // One-screen, upper page
+        vrc6.ppu_write(0x2000, 0x24).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc6, 0x2400), 0x24);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut vrc6 = make_vrc6(24);
+        let irq = vrc6.parts.irq.make_receiver();
+        vrc6.bus_write(0xF000, 0xFE).unwrap();
+        vrc6.bus_write(0xF001, 0x06).unwrap();
+        vrc6.tick().unwrap();
+        assert!(!irq.get());
+        vrc6.tick().unwrap();
+        assert!(irq.get());
+        vrc6.bus_write(0xF002, 0).unwrap();
+        assert!(!irq.get());
+    }
+}
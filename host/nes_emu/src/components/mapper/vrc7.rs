@@ -0,0 +1,258 @@
+use super::{CartridgeParts, Mapper, vrc_irq::VrcIrq, vrc4::VrcWiring};
+use crate::{
+    components::{
+        BusDevice, EmuResult, ReadResult,
+        expansion_audio::{ExpansionAudio, vrc7::Vrc7Audio},
+        nametables::Mirroring,
+    },
+    nes_file::MapperId,
+};
+
+/// Konami VRC7, mapper 85. Three switchable 8KB PRG banks, eight 1KB CHR
+/// banks, the VRC IRQ counter, and a six-channel FM synthesizer. VRC7a
+/// (submapper 2) selects the odd registers with A4, VRC7b (submapper 1)
+/// with A3; without a submapper both are decoded.
+pub struct Vrc7<'t> {
+    parts: CartridgeParts<'t>,
+    wiring: VrcWiring,
+    audio: Vrc7Audio,
+    /// Held in reset by bit 6 of 0xE000
+    audio_reset: bool,
+    prg_banks: [u8; 3],
+    chr_banks: [u8; 8],
+    /// 0xE000: mirroring, sound reset and PRG-RAM enable
+    control: u8,
+    irq: VrcIrq,
+}
+
+impl<'t> Vrc7<'t> {
+    pub fn new(parts: CartridgeParts<'t>, mapper: MapperId) -> Self {
+        let select = match mapper.sub_id {
+            1 => 0x08,
+            2 => 0x10,
+            _ => 0x18,
+        };
+        let mut vrc7 = Vrc7 {
+            audio: Vrc7Audio::new(parts.timing.cpu_clock_hz()),
+            parts,
+            wiring: VrcWiring::new(select, 0),
+            audio_reset: false,
+            prg_banks: [0; 3],
+            chr_banks: [0; 8],
+            control: 0,
+            irq: VrcIrq::new(),
+        };
+        vrc7.write_control(0);
+        vrc7
+    }
+
+    fn write_control(&mut self, data: u8) {
+        self.control = data;
+        self.parts.nametables.set_mirroring(match data & 0x03 {
+            0 => Mirroring::Vertical,
+            1 => Mirroring::Horizontal,
+            2 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        });
+        self.audio_reset = data & 0x40 != 0;
+        if self.audio_reset {
+            self.audio.power_on();
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.control & 0x80 != 0
+    }
+
+    fn prg_bank(&self, addr: u16) -> usize {
+        match addr {
+            0x8000..=0xDFFF => self.prg_banks[(addr as usize - 0x8000) / 0x2000] as usize,
+            _ => self.parts.prg_rom.len() / 0x2000 - 1,
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, data: u8) {
+        let odd = self.wiring.register(addr) == 1;
+        match (addr & 0xF000, odd) {
+            (0x8000, false) => self.prg_banks[0] = data & 0x3F,
+            (0x8000, true) => self.prg_banks[1] = data & 0x3F,
+            (0x9000, false) => self.prg_banks[2] = data & 0x3F,
+            // The sound chip's address and data ports, told apart by A5
+            (0x9000, true) if !self.audio_reset => self.audio.write(0x9010 | (addr & 0x20), data),
+            (0xA000..=0xD000, _) => {
+                let bank = ((addr - 0xA000) >> 12) as usize * 2 + odd as usize;
+                self.chr_banks[bank] = data;
+            }
+            (0xE000, false) => self.write_control(data),
+            (0xE000, true) => self.irq.write_latch(data),
+            (0xF000, false) => self.irq.write_control(data),
+            (0xF000, true) => self.irq.acknowledge(),
+            _ => {}
+        }
+    }
+}
+
+impl BusDevice for Vrc7<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.parts.read_prg_ram(0, 0x2000, addr),
+            0x8000..=0xFFFF => {
+                let bank = self.prg_bank(addr);
+                Ok(ReadResult::Data(
+                    self.parts.read_prg_rom(bank, 0x2000, addr),
+                ))
+            }
+            _ => Ok(ReadResult::OpenBus),
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => {
+                self.parts.write_prg_ram(0, 0x2000, addr, data)
+            }
+            0x8000..=0xFFFF => {
+                self.write_register(addr, data);
+                self.parts.irq.set(self.irq.pending());
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.parts.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.parts.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.parts.power_on();
+        self.audio.power_on();
+        self.prg_banks = [0; 3];
+        self.chr_banks = [0; 8];
+        self.write_control(0);
+        self.irq = VrcIrq::new();
+        self.parts.irq.set(false);
+    }
+
+    fn reset(&mut self) {
+        self.parts.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.parts.tick()?;
+        self.irq.tick();
+        self.parts.irq.set(self.irq.pending());
+        if !self.audio_reset {
+            self.audio.tick();
+            if let Some(audio_output) = &self.parts.audio {
+                audio_output.mix(self.audio.output());
+            }
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        let addr = addr as u16;
+        match addr {
+            0x6000..=0x7FFF if self.prg_ram_enabled() => self.parts.peek_prg_ram(0, 0x2000, addr),
+            _ => self
+                .prg_rom_offset(addr)
+                .map(|offset| self.parts.prg_rom[offset]),
+        }
+    }
+}
+
+impl Mapper for Vrc7<'_> {
+    fn ppu_read(&mut self, addr: u16) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] as usize;
+                self.parts.chr.read(bank, 0x400, addr)
+            }
+            _ => self.parts.nametables.read(addr),
+        }))
+    }
+
+    fn ppu_write(&mut self, addr: u16, data: u8) -> EmuResult<()> {
+        match addr {
+            0x0000..=0x1FFF => {
+                let bank = self.chr_banks[addr as usize / 0x400] as usize;
+                self.parts.chr.write(bank, 0x400, addr, data);
+            }
+            _ => self.parts.nametables.write(addr, data),
+        }
+        Ok(())
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        match addr {
+            0x8000..=0xFFFF => Some(self.parts.prg_rom_offset(self.prg_bank(addr), 0x2000, addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::mapper::test::{cpu_read, make_parts, ppu_fetch};
+
+    fn make_vrc7(sub_id: u8) -> Vrc7<'static> {
+        let mapper = MapperId { id: 85, sub_id };
+        let mut vrc7 = Vrc7::new(make_parts(0x40000, 0x40000), mapper);
+        vrc7.power_on();
+        vrc7
+    }
+
+    #[test]
+    fn test_banking() {
+        for (sub_id, odd) in [(1, 0x08), (2, 0x10), (0, 0x08), (0, 0x10)] {
+            let mut vrc7 = make_vrc7(sub_id);
+            vrc7.bus_write(0x8000, 3).unwrap();
+            vrc7.bus_write(0x8000 | odd, 4).unwrap();
+            vrc7.bus_write(0x9000, 5).unwrap();
+            assert_eq!(cpu_read(&mut vrc7, 0x8000), 3);
+            assert_eq!(cpu_read(&mut vrc7, 0xA000), 4);
+            assert_eq!(cpu_read(&mut vrc7, 0xC000), 5);
+            assert_eq!(cpu_read(&mut vrc7, 0xE000), 31);
+
+            vrc7.bus_write(0xA000 | odd, 0x21).unwrap();
+            vrc7.bus_write(0xD000 | odd, 0x27).unwrap();
+            assert_eq!(ppu_fetch(&mut vrc7, 0x0400), 0x21);
+            assert_eq!(ppu_fetch(&mut vrc7, 0x1C00), 0x27);
+        }
+    }
+
+    #[test]
+    fn test_control() {
+        let mut vrc7 = make_vrc7(2);
+        // PRG-RAM is disabled until bit 7 is set
+        vrc7.bus_write(0x6000, 0x42).unwrap();
+        assert_eq!(vrc7.bus_read(0x6000).unwrap(), ReadResult::OpenBus);
+        vrc7.bus_write(0xE000, 0x81).unwrap();
+        vrc7.bus_write(0x6000, 0x42).unwrap();
+        assert_eq!(cpu_read(&mut vrc7, 0x6000), 0x42);
+        // Horizontal mirroring
+        vrc7.ppu_write(0x2000, 0x24).unwrap();
+        assert_eq!(ppu_fetch(&mut vrc7, 0x2400), 0x24);
+        assert_eq!(ppu_fetch(&mut vrc7, 0x2800), 0x00);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut vrc7 = make_vrc7(2);
+        let irq = vrc7.parts.irq.make_receiver();
+        vrc7.bus_write(0xE010, 0xFF).unwrap();
+        vrc7.bus_write(0xF000, 0x06).unwrap();
+        vrc7.tick().unwrap();
+        assert!(irq.get());
+        vrc7.bus_write(0xF010, 0).unwrap();
+        assert!(!irq.get());
+    }
+}
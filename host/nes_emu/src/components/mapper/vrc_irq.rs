@@ -0,0 +1,170 @@
+/// Dots per scanline. In scanline mode the prescaler counts down by 3 per
+/// CPU cycle from this, so the counter steps once per 113 2/3 CPU cycles.
+const PRESCALER_PERIOD: i16 = 341;
+
+/// The IRQ counter shared by Konami's VRC4, VRC6 and VRC7. It has no view of
+/// the PPU, so "scanline" mode is a CPU cycle divider that runs at the
+/// scanline rate; cycle mode steps the counter every CPU cycle. Either way,
+/// the counter counts up from the latch and raises the IRQ when it
+/// overflows.
+#[derive(Debug, Clone)]
+pub struct VrcIrq {
+    latch: u8,
+    counter: u8,
+    prescaler: i16,
+    enabled: bool,
+    enable_after_ack: bool,
+    cycle_mode: bool,
+    pending: bool,
+}
+
+impl VrcIrq {
+    pub fn new() -> Self {
+        VrcIrq {
+            latch: 0,
+            counter: 0,
+            prescaler: PRESCALER_PERIOD,
+            enabled: false,
+            enable_after_ack: false,
+            cycle_mode: false,
+            pending: false,
+        }
+    }
+
+    pub fn write_latch(&mut self, data: u8) {
+        self.latch = data;
+    }
+
+    /// The VRC4 writes the latch a nibble at a time
+    pub fn write_latch_low(&mut self, data: u8) {
+        self.latch = (self.latch & 0xF0) | (data & 0x0F);
+    }
+
+    pub fn write_latch_high(&mut self, data: u8) {
+        self.latch = (self.latch & 0x0F) | (data << 4);
+    }
+
+    /// Set the mode and enables. Acknowledges the IRQ, and reloads the
+    /// counter if it is now enabled.
+    pub fn write_control(&mut self, data: u8) {
+        self.enable_after_ack = data & 0x01 != 0;
+        self.enabled = data & 0x02 != 0;
+        self.cycle_mode = data & 0x04 != 0;
+        self.pending = false;
+        if self.enabled {
+            self.counter = self.latch;
+            self.prescaler = PRESCALER_PERIOD;
+        }
+    }
+
+    pub fn acknowledge(&mut self) {
+        self.pending = false;
+        self.enabled = self.enable_after_ack;
+    }
+
+    pub fn pending(&self) -> bool {
+        self.pending
+    }
+
+    /// Advance by one CPU cycle
+    pub fn tick(&mut self) {
+        if !self.enabled {
+            return;
+        }
+        if self.cycle_mode {
+            self.clock_counter();
+            return;
+        }
+        self.prescaler -= 3;
+        if self.prescaler <= 0 {
+            self.prescaler += PRESCALER_PERIOD;
+            self.clock_counter();
+        }
+    }
+
+    fn clock_counter(&mut self) {
+        if self.counter == 0xFF {
+            self.counter = self.latch;
+            self.pending = true;
+        } else {
+            self.counter += 1;
+        }
+    }
+}
+
+impl Default for VrcIrq {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// CPU cycles until the IRQ is raised
+    fn cycles_to_irq(irq: &mut VrcIrq) -> u32 {
+        let mut cycles = 0;
+        while !irq.pending() {
+            irq.tick();
+            cycles += 1;
+            assert!(cycles < 1_000_000, "no IRQ");
+        }
+        cycles
+    }
+
+    #[test]
+    fn test_cycle_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xF0);
+        irq.write_control(0x07);
+        // 15 steps up to 0xFF, and one more to overflow
+        assert_eq!(cycles_to_irq(&mut irq), 16);
+        // The counter reloads and keeps going
+        irq.acknowledge();
+        assert_eq!(cycles_to_irq(&mut irq), 16);
+
+        // Acknowledging with A clear stops the counter
+        irq.write_control(0x06);
+        irq.acknowledge();
+        for _ in 0..100 {
+            irq.tick();
+        }
+        assert!(!irq.pending());
+    }
+
+    #[test]
+    fn test_scanline_mode() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch_low(0x0D);
+        irq.write_latch_high(0x0F);
+        assert_eq!(irq.latch, 0xFD);
+        irq.write_control(0x02);
+        // Three scanlines of 341 dots, in CPU cycles, rounded up
+        assert_eq!(cycles_to_irq(&mut irq), (3 * 341u32).div_ceil(3));
+
+        // The prescaler carries the remainder, so lines alternate between
+        // 113 and 114 cycles
+        irq.write_latch(0xFF);
+        irq.write_control(0x03);
+        let lines: Vec<u32> = (0..6)
+            .map(|_| {
+                irq.acknowledge();
+                cycles_to_irq(&mut irq)
+            })
+            .collect();
+        assert_eq!(lines.iter().sum::<u32>(), 6 * 341 / 3);
+        assert!(lines.iter().all(|cycles| (113..=114).contains(cycles)));
+    }
+
+    #[test]
+    fn test_control_write_acknowledges() {
+        let mut irq = VrcIrq::new();
+        irq.write_latch(0xFF);
+        irq.write_control(0x04 | 0x02);
+        irq.tick();
+        assert!(irq.pending());
+        irq.write_control(0x00);
+        assert!(!irq.pending());
+    }
+}
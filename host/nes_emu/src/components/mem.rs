@@ -1,4 +1,9 @@
-use super::{BusDevice, EmuResult, ReadResult};
+use std::{fs, io, path::PathBuf};
+
+use super::{BusActivity, BusDevice, EmuError, EmuResult, ReadResult, timing::ConsoleTiming};
+
+// Flush dirty battery RAM roughly once per second of emulated time
+pub(crate) const NVRAM_FLUSH_INTERVAL_MS: u64 = 1000;
 
 pub struct RAMDevice {
     memory: Vec<u8>,
@@ -29,6 +34,16 @@ impl BusDevice for RAMDevice {
         }
         Ok(())
     }
+
+    fn power_on(&mut self) {
+        self.memory.fill(0);
+    }
+
+    // Contents are retained across a reset
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.memory.get(addr as usize).copied()
+    }
 }
 
 pub struct ROMDevice {
@@ -55,4 +70,274 @@ impl BusDevice for ROMDevice {
         // ROM is read-only, so we ignore writes
         Ok(())
     }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.contents.get(addr as usize).copied()
+    }
+}
+
+/// Wrapper which loads a fixed image into a writable device every time the
+/// console is powered on, such as an iNES trainer copied into PRG-RAM.
+/// The image is written like any other data, so battery-backed memory
+/// should start out with it instead.
+pub struct PowerOnImage<T: BusDevice> {
+    device: T,
+    offset: u32,
+    image: Vec<u8>,
+}
+
+impl<T: BusDevice> PowerOnImage<T> {
+    pub fn new(device: T, offset: u32, image: Vec<u8>) -> Self {
+        PowerOnImage {
+            device,
+            offset,
+            image,
+        }
+    }
+}
+
+impl<T: BusDevice> BusDevice for PowerOnImage<T> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.device.bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.device.bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.device.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.device.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.device.power_on();
+        for (addr, byte) in (self.offset..).zip(self.image.iter()) {
+            // Writes to plain memory can't fail
+            let _ = self.device.bus_write(addr, *byte);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.device.reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.device.tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.device.observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.device.peek(addr)
+    }
+}
+
+/// Battery-backed RAM on the cartridge. Contents survive power cycles, and
+/// when a save path is given they're loaded from it at the start of simulation
+/// and written back whenever they change, so progress persists between runs.
+pub struct BatteryRAMDevice {
+    memory: Vec<u8>,
+    save_path: Option<PathBuf>,
+    dirty: bool,
+    ticks_since_flush: u64,
+    flush_interval_ticks: u64,
+}
+
+impl BatteryRAMDevice {
+    pub fn new(size: usize, save_path: Option<PathBuf>, timing: &ConsoleTiming) -> Self {
+        BatteryRAMDevice {
+            memory: vec![0; size],
+            save_path,
+            dirty: false,
+            ticks_since_flush: 0,
+            flush_interval_ticks: timing.ms_to_cpu_cycles(NVRAM_FLUSH_INTERVAL_MS),
+        }
+    }
+
+    /// Start out with image at offset instead of blank memory. A save
+    /// loaded over it replaces it, and it's never saved unless the game
+    /// changes memory.
+    pub fn with_image(mut self, offset: usize, image: &[u8]) -> Self {
+        let end = (offset + image.len()).min(self.memory.len());
+        self.memory[offset..end].copy_from_slice(&image[..end - offset]);
+        self
+    }
+
+    fn load(&mut self) -> EmuResult<()> {
+        let Some(path) = &self.save_path else {
+            return Ok(());
+        };
+        match fs::read(path) {
+            Ok(contents) => {
+                // Tolerate size mismatches, e.g. from a save made with a
+                // different header. Whatever overlaps is kept.
+                let len = contents.len().min(self.memory.len());
+                self.memory[..len].copy_from_slice(&contents[..len]);
+                Ok(())
+            }
+            // No save yet, start out with blank memory
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(EmuError::SaveFile(format!(
+                "failed to read {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    fn flush(&mut self) -> EmuResult<()> {
+        self.ticks_since_flush = 0;
+        if !self.dirty {
+            return Ok(());
+        }
+        if let Some(path) = &self.save_path {
+            fs::write(path, &self.memory).map_err(|e| {
+                EmuError::SaveFile(format!("failed to write {}: {}", path.display(), e))
+            })?;
+        }
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl BusDevice for BatteryRAMDevice {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let addr = addr as usize;
+        if addr < self.memory.len() {
+            Ok(ReadResult::Data(self.memory[addr]))
+        } else {
+            Ok(ReadResult::OpenBus)
+        }
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as usize;
+        if addr < self.memory.len() && self.memory[addr] != data {
+            self.memory[addr] = data;
+            self.dirty = true;
+        }
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.load()
+    }
+
+    fn end_of_simulation(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("{}", e);
+        }
+    }
+
+    // The battery keeps the contents intact across power cycles and resets
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.ticks_since_flush += 1;
+        if self.ticks_since_flush >= self.flush_interval_ticks {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.memory.get(addr as usize).copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn save_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("{}_{}.sav", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn read(device: &mut impl BusDevice, addr: u32) -> u8 {
+        match device.bus_read(addr).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus at 0x{:X}", addr),
+        }
+    }
+
+    #[test]
+    fn test_battery_ram_load() {
+        // A missing save loads as blank memory
+        let path = save_path("battery_ram_load");
+        let mut ram = BatteryRAMDevice::new(0x10, Some(path.clone()), &ConsoleTiming::NTSC);
+        ram.start_of_simulation().unwrap();
+        assert!((0..0x10).all(|addr| read(&mut ram, addr) == 0));
+
+        // Shorter saves fill the start of memory
+        fs::write(&path, [1, 2, 3]).unwrap();
+        let mut ram = BatteryRAMDevice::new(0x10, Some(path.clone()), &ConsoleTiming::NTSC);
+        ram.start_of_simulation().unwrap();
+        assert_eq!(read(&mut ram, 0x0), 1);
+        assert_eq!(read(&mut ram, 0x2), 3);
+        assert_eq!(read(&mut ram, 0x3), 0);
+
+        // Longer ones are cut short
+        fs::write(&path, [0x55; 0x20]).unwrap();
+        let mut ram = BatteryRAMDevice::new(0x10, Some(path.clone()), &ConsoleTiming::NTSC);
+        ram.start_of_simulation().unwrap();
+        assert_eq!(read(&mut ram, 0xF), 0x55);
+        assert_eq!(ram.bus_read(0x10).unwrap(), ReadResult::OpenBus);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_battery_ram_flush() {
+        let path = save_path("battery_ram_flush");
+        let mut ram = BatteryRAMDevice::new(0x10, Some(path.clone()), &ConsoleTiming::NTSC);
+        ram.start_of_simulation().unwrap();
+
+        // Writing what's already there doesn't need saving
+        ram.bus_write(0x4, 0).unwrap();
+        ram.end_of_simulation();
+        assert!(!path.exists());
+
+        // Changes are saved once a flush interval has passed
+        ram.bus_write(0x4, 0x42).unwrap();
+        for _ in 1..ram.flush_interval_ticks {
+            ram.tick().unwrap();
+        }
+        assert!(!path.exists());
+        ram.tick().unwrap();
+        assert_eq!(fs::read(&path).unwrap()[0x4], 0x42);
+
+        // and when the simulation ends
+        ram.bus_write(0x5, 0x43).unwrap();
+        ram.end_of_simulation();
+        assert_eq!(fs::read(&path).unwrap()[0x5], 0x43);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_battery_ram_power() {
+        let mut ram = BatteryRAMDevice::new(0x10, None, &ConsoleTiming::NTSC);
+        ram.start_of_simulation().unwrap();
+        ram.bus_write(0x1, 0x42).unwrap();
+        ram.reset();
+        assert_eq!(read(&mut ram, 0x1), 0x42);
+        ram.power_on();
+        assert_eq!(read(&mut ram, 0x1), 0x42);
+        ram.end_of_simulation();
+    }
+
+    #[test]
+    fn test_ram_power() {
+        let mut ram = RAMDevice::new(0x10);
+        ram.bus_write(0x1, 0x42).unwrap();
+        ram.reset();
+        assert_eq!(read(&mut ram, 0x1), 0x42);
+        ram.power_on();
+        assert_eq!(read(&mut ram, 0x1), 0);
+    }
 }
@@ -1,25 +1,45 @@
+pub mod apu;
+pub mod audio;
+pub mod breakpoint;
 pub mod bus;
+pub mod console_event;
 pub mod cpu;
 pub mod debug;
+pub mod expansion_audio;
+pub mod fds;
+pub mod mapper;
 pub mod mem;
+pub mod nametables;
+pub mod nsf;
+pub mod ppu;
 pub mod reset_controller;
+pub mod scheduler;
 pub mod signal;
+pub mod timing;
 pub mod tracer;
 
 use thiserror::Error;
 
-pub use bus::{BusDevice, ReadResult};
+pub use bus::{BusActivity, BusDevice, ReadResult};
 
 #[derive(Debug, Error, PartialEq, Eq)]
 pub enum EmuError {
     #[error("Normal emulation stop")]
     StopEmulation,
-    #[error("Cycle limit reached")]
-    CycleLimitReached,
     #[error("Illegal CPU opcode: 0x{0:02X}")]
     IllegalCpuOpcode(u8),
     #[error("Test ROM reported failure with code {0}")]
     TestROMFailure(u8),
+    #[error("Read of uninitialized memory 0x{addr:04X} by instruction at 0x{pc:04X}")]
+    UninitializedRead { addr: u16, pc: u16 },
+    #[error("Save file error: {0}")]
+    SaveFile(String),
+    #[error("Mapper {0} is not supported")]
+    UnsupportedMapper(u16),
+    #[error("ROM has no PRG-ROM")]
+    EmptyPrgRom,
+    #[error("FDS BIOS is {0} bytes, expected 8192")]
+    BadFdsBiosSize(usize),
 }
 
 pub type EmuResult<T> = Result<T, EmuError>;
@@ -0,0 +1,279 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{BusDevice, EmuResult, ReadResult};
+use crate::nes_file::NametableLayout;
+
+const PAGE_SIZE: usize = 0x400;
+
+/// How the four nametables map onto 1KB pages of VRAM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    /// 0x2000 and 0x2400 share a page, as do 0x2800 and 0x2C00
+    Horizontal,
+    /// 0x2000 and 0x2800 share a page, as do 0x2400 and 0x2C00
+    Vertical,
+    /// Every nametable shows the first CIRAM page
+    SingleScreenA,
+    /// Every nametable shows the second CIRAM page
+    SingleScreenB,
+    /// Each nametable has its own page, which needs cartridge VRAM
+    FourScreen,
+}
+
+impl Mirroring {
+    /// The page behind each nametable
+    pub fn pages(self) -> [u8; 4] {
+        match self {
+            Mirroring::Horizontal => [0, 0, 1, 1],
+            Mirroring::Vertical => [0, 1, 0, 1],
+            Mirroring::SingleScreenA => [0; 4],
+            Mirroring::SingleScreenB => [1; 4],
+            Mirroring::FourScreen => [0, 1, 2, 3],
+        }
+    }
+
+    /// The mirroring a board with hardwired nametables has. The header's
+    /// arrangement bit names how the nametables are laid out, which is the
+    /// opposite of the mirroring: a vertical arrangement mirrors
+    /// horizontally.
+    pub fn from_layout(layout: NametableLayout) -> Self {
+        match layout {
+            NametableLayout::Vertical => Mirroring::Horizontal,
+            NametableLayout::Horizontal => Mirroring::Vertical,
+            NametableLayout::AlternateVertical | NametableLayout::AlternateHorizontal => {
+                Mirroring::FourScreen
+            }
+        }
+    }
+}
+
+/// The console's 2KB of nametable RAM, CIRAM, plus the 2KB of VRAM that
+/// four-screen cartridges add. The PPU addresses four 1KB nametables at
+/// 0x2000 - 0x2FFF, mirrored up to 0x3EFF, and the cartridge decides which
+/// page backs each of them.
+///
+/// With cartridge VRAM, the board wires up all four nametables itself, so
+/// whatever a mapper asks for is ignored.
+#[derive(Debug, Clone)]
+pub struct Nametables {
+    ram: Vec<u8>,
+    /// Page for each nametable
+    pages: [u8; 4],
+}
+
+impl Nametables {
+    pub fn new(layout: NametableLayout) -> Self {
+        let mut nametables = Nametables {
+            ram: Vec::new(),
+            pages: [0; 4],
+        };
+        nametables.set_layout(layout);
+        nametables
+    }
+
+    /// Wire up the pages the way the header describes. As NES 2.0
+    /// specifies, the Alternate layouts add cartridge VRAM for four-screen
+    /// mirroring.
+    pub fn set_layout(&mut self, layout: NametableLayout) {
+        let mirroring = Mirroring::from_layout(layout);
+        let page_count = if mirroring == Mirroring::FourScreen {
+            4
+        } else {
+            2
+        };
+        self.ram.resize(page_count * PAGE_SIZE, 0);
+        self.pages = mirroring.pages();
+    }
+
+    /// Whether the cartridge adds its own VRAM for four-screen mirroring
+    pub fn has_cartridge_vram(&self) -> bool {
+        self.page_count() == 4
+    }
+
+    fn page_count(&self) -> usize {
+        self.ram.len() / PAGE_SIZE
+    }
+
+    /// Switch mirroring, for mappers that control it
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.set_pages(mirroring.pages());
+    }
+
+    /// Select the page behind each nametable, for mappers that control
+    /// mirroring. Without cartridge VRAM, pages 2 and 3 wrap back to CIRAM.
+    pub fn set_pages(&mut self, pages: [u8; 4]) {
+        if self.has_cartridge_vram() {
+            return;
+        }
+        self.pages = pages.map(|page| page & 1);
+    }
+
+    pub fn pages(&self) -> [u8; 4] {
+        self.pages
+    }
+
+    fn page_offset(&self, page: u8, offset: u16) -> usize {
+        (page as usize % self.page_count()) * PAGE_SIZE + (offset as usize & (PAGE_SIZE - 1))
+    }
+
+    fn offset(&self, addr: u16) -> usize {
+        let table = (addr as usize >> 10) & 0x03;
+        self.page_offset(self.pages[table], addr)
+    }
+
+    /// Read through the page mapping. addr is a PPU address in 0x2000 -
+    /// 0x3EFF.
+    pub fn read(&self, addr: u16) -> u8 {
+        self.ram[self.offset(addr)]
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        let offset = self.offset(addr);
+        self.ram[offset] = data;
+    }
+
+    /// Access one page directly, bypassing the nametable mapping
+    pub fn read_page(&self, page: u8, offset: u16) -> u8 {
+        self.ram[self.page_offset(page, offset)]
+    }
+
+    pub fn write_page(&mut self, page: u8, offset: u16, data: u8) {
+        let offset = self.page_offset(page, offset);
+        self.ram[offset] = data;
+    }
+
+    pub fn power_on(&mut self) {
+        self.ram.fill(0);
+    }
+}
+
+/// Nametables as a device on the PPU bus, for consoles where something
+/// other than a cartridge Mapper controls the mirroring. Clones share the
+/// same nametables, so the controlling device keeps one to retarget them.
+#[derive(Debug, Clone)]
+pub struct NametableDevice {
+    nametables: Rc<RefCell<Nametables>>,
+}
+
+impl NametableDevice {
+    pub fn new(layout: NametableLayout) -> Self {
+        NametableDevice {
+            nametables: Rc::new(RefCell::new(Nametables::new(layout))),
+        }
+    }
+
+    pub fn set_mirroring(&self, mirroring: Mirroring) {
+        self.nametables.borrow_mut().set_mirroring(mirroring);
+    }
+}
+
+impl BusDevice for NametableDevice {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        Ok(ReadResult::Data(self.nametables.borrow().read(addr as u16)))
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.nametables.borrow_mut().write(addr as u16, data);
+        Ok(())
+    }
+
+    fn power_on(&mut self) {
+        self.nametables.borrow_mut().power_on();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TABLES: [u16; 4] = [0x2000, 0x2400, 0x2800, 0x2C00];
+
+    /// Write a distinct byte through each nametable, then report which
+    /// nametable's byte each one reads back
+    fn sharing(nametables: &mut Nametables) -> [u8; 4] {
+        nametables.power_on();
+        for (table, addr) in TABLES.into_iter().enumerate() {
+            nametables.write(addr + 0x11, table as u8 + 1);
+        }
+        TABLES.map(|addr| nametables.read(addr + 0x11) - 1)
+    }
+
+    #[test]
+    fn test_layouts() {
+        let mut nametables = Nametables::new(NametableLayout::Vertical);
+        assert_eq!(sharing(&mut nametables), [1, 1, 3, 3]);
+        nametables.set_layout(NametableLayout::Horizontal);
+        assert_eq!(sharing(&mut nametables), [2, 3, 2, 3]);
+
+        for layout in [
+            NametableLayout::AlternateVertical,
+            NametableLayout::AlternateHorizontal,
+        ] {
+            let mut nametables = Nametables::new(layout);
+            assert!(nametables.has_cartridge_vram());
+            assert_eq!(sharing(&mut nametables), [0, 1, 2, 3]);
+        }
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let mut nametables = Nametables::new(NametableLayout::Vertical);
+        for (mirroring, expected) in [
+            (Mirroring::Horizontal, [1, 1, 3, 3]),
+            (Mirroring::Vertical, [2, 3, 2, 3]),
+            (Mirroring::SingleScreenA, [3; 4]),
+            (Mirroring::SingleScreenB, [3; 4]),
+            // Without cartridge VRAM, four-screen wraps to vertical
+            (Mirroring::FourScreen, [2, 3, 2, 3]),
+        ] {
+            nametables.set_mirroring(mirroring);
+            assert_eq!(sharing(&mut nametables), expected, "{:?}", mirroring);
+        }
+
+        // The two single-screen settings show different pages
+        nametables.set_mirroring(Mirroring::SingleScreenA);
+        nametables.write(0x2000, 0xAA);
+        nametables.set_mirroring(Mirroring::SingleScreenB);
+        nametables.write(0x2000, 0xBB);
+        assert_eq!(nametables.read_page(0, 0), 0xAA);
+        assert_eq!(nametables.read_page(1, 0), 0xBB);
+        nametables.set_mirroring(Mirroring::SingleScreenA);
+        assert_eq!(nametables.read(0x2C00), 0xAA);
+    }
+
+    #[test]
+    fn test_mirrors_above_0x3000() {
+        let mut nametables = Nametables::new(NametableLayout::Horizontal);
+        nametables.write(0x2400, 0x24);
+        nametables.write(0x2BFF, 0x2B);
+        assert_eq!(nametables.read(0x3400), 0x24);
+        assert_eq!(nametables.read(0x3BFF), 0x2B);
+        assert_eq!(nametables.read(0x3C00), 0x24);
+    }
+
+    #[test]
+    fn test_cartridge_vram_ignores_mapper() {
+        let mut nametables = Nametables::new(NametableLayout::AlternateHorizontal);
+        nametables.set_mirroring(Mirroring::SingleScreenA);
+        assert_eq!(nametables.pages(), [0, 1, 2, 3]);
+        nametables.write_page(3, 0x10, 0x33);
+        assert_eq!(nametables.read(0x2C10), 0x33);
+
+        // Switching to a plain layout drops the extra VRAM
+        nametables.set_layout(NametableLayout::Vertical);
+        assert!(!nametables.has_cartridge_vram());
+        nametables.set_mirroring(Mirroring::SingleScreenB);
+        assert_eq!(nametables.pages(), [1; 4]);
+    }
+
+    #[test]
+    fn test_device() {
+        let mut device = NametableDevice::new(NametableLayout::Vertical);
+        let control = device.clone();
+        device.bus_write(0x2000, 0x42).unwrap();
+        assert_eq!(device.bus_read(0x2400).unwrap(), ReadResult::Data(0x42));
+        control.set_mirroring(Mirroring::Vertical);
+        assert_eq!(device.bus_read(0x2400).unwrap(), ReadResult::Data(0x00));
+        assert_eq!(device.bus_read(0x2800).unwrap(), ReadResult::Data(0x42));
+    }
+}
@@ -0,0 +1,491 @@
+use super::{
+    BusDevice, EmuResult, ReadResult,
+    audio::AudioOutput,
+    expansion_audio::{
+        ExpansionAudio, fds::FdsAudio, mmc5::Mmc5Audio, n163::N163Audio, sunsoft5b::Sunsoft5BAudio,
+        vrc6::Vrc6Audio, vrc7::Vrc7Audio,
+    },
+    signal::WiredOrSource,
+};
+use crate::nsf::{ExpansionChip, NsfFile, NsfRegion};
+
+const BANK_SIZE: usize = 0x1000;
+
+const DRIVER_BASE: u32 = 0x4100;
+const DRIVER_RESET: u16 = 0x4100;
+const DRIVER_NMI: u16 = 0x4152;
+const DRIVER_IRQ: u16 = 0x4165;
+
+// Driver registers
+const REG_SONG: u32 = 0x4180;
+const REG_REGION: u32 = 0x4181;
+const REG_STATUS: u32 = 0x4182;
+const REG_INIT_ADDR: u32 = 0x4184;
+const REG_PLAY_ADDR: u32 = 0x4186;
+
+// Values the driver writes to REG_STATUS
+const STATUS_INIT_STARTED: u8 = 1;
+const STATUS_INIT_DONE: u8 = 2;
+const STATUS_PLAY_DONE: u8 = 3;
+
+/// Player program at 0x4100. It clears RAM, initializes the APU the way the
+/// NSF spec requires, calls INIT with the song in A and the region in X, then
+/// idles. The cart triggers NMI at the play rate, and the NMI handler calls
+/// PLAY. Both report back through REG_STATUS so the cart knows when it's safe
+/// to call PLAY.
+#[rustfmt::skip]
+const DRIVER_CODE: [u8; 0x66] = [
+    // 0x4100: reset
+    0x78,                   // SEI
+    0xD8,                   // CLD
+    0xA2, 0xFF,             // LDX #$FF
+    0x9A,                   // TXS
+    0xA9, 0x00,             // LDA #$00
+    0xAA,                   // TAX
+    // 0x4108: clear internal RAM
+    0x95, 0x00,             // STA $00,X
+    0x9D, 0x00, 0x01,       // STA $0100,X
+    0x9D, 0x00, 0x02,       // STA $0200,X
+    0x9D, 0x00, 0x03,       // STA $0300,X
+    0x9D, 0x00, 0x04,       // STA $0400,X
+    0x9D, 0x00, 0x05,       // STA $0500,X
+    0x9D, 0x00, 0x06,       // STA $0600,X
+    0x9D, 0x00, 0x07,       // STA $0700,X
+    0xE8,                   // INX
+    0xD0, 0xE6,             // BNE $4108
+    // 0x4122: clear the channel registers
+    0xA2, 0x13,             // LDX #$13
+    0x9D, 0x00, 0x40,       // STA $4000,X
+    0xCA,                   // DEX
+    0x10, 0xFA,             // BPL $4124
+    0xA9, 0x0F,             // LDA #$0F
+    0x8D, 0x15, 0x40,       // STA $4015
+    0xA9, 0x40,             // LDA #$40
+    0x8D, 0x17, 0x40,       // STA $4017
+    // 0x4134: call INIT
+    0xA9, STATUS_INIT_STARTED, // LDA #STATUS_INIT_STARTED
+    0x8D, 0x82, 0x41,       // STA REG_STATUS
+    0xAD, 0x80, 0x41,       // LDA REG_SONG
+    0xAE, 0x81, 0x41,       // LDX REG_REGION
+    0xA0, 0x00,             // LDY #$00
+    0x20, 0x4C, 0x41,       // JSR $414C
+    0xA9, STATUS_INIT_DONE, // LDA #STATUS_INIT_DONE
+    0x8D, 0x82, 0x41,       // STA REG_STATUS
+    // 0x4149: idle
+    0x4C, 0x49, 0x41,       // JMP $4149
+    // 0x414C: trampolines
+    0x6C, 0x84, 0x41,       // JMP (REG_INIT_ADDR)
+    0x6C, 0x86, 0x41,       // JMP (REG_PLAY_ADDR)
+    // 0x4152: NMI, call PLAY
+    0x48,                   // PHA
+    0x8A,                   // TXA
+    0x48,                   // PHA
+    0x98,                   // TYA
+    0x48,                   // PHA
+    0x20, 0x4F, 0x41,       // JSR $414F
+    0xA9, STATUS_PLAY_DONE, // LDA #STATUS_PLAY_DONE
+    0x8D, 0x82, 0x41,       // STA REG_STATUS
+    0x68,                   // PLA
+    0xA8,                   // TAY
+    0x68,                   // PLA
+    0xAA,                   // TAX
+    0x68,                   // PLA
+    0x40,                   // RTI
+    // 0x4165: IRQ
+    0x40,                   // RTI
+];
+
+/// Everything an NSF tune sees at 0x4040 - 0xFFFF: the player driver, the
+/// bankswitch registers at 0x5FF8 - 0x5FFF, 8KB of RAM at 0x6000, the tune
+/// itself at 0x8000, and any expansion sound chips the tune uses. The
+/// interrupt vectors are replaced with the driver's.
+///
+/// Expects to be mapped with addresses passed through unchanged.
+pub struct NsfCart {
+    prg: Vec<u8>,
+    banked: bool,
+    initial_banks: [u8; 8],
+    banks: [u8; 8],
+    ram: Vec<u8>,
+    /// FDS tunes can write to 0x8000 - 0xDFFF, which is RAM on the Disk System
+    prg_writable: bool,
+    /// The MMC5's 1KB of ExRAM at 0x5C00, usable as plain RAM
+    exram: Option<Vec<u8>>,
+    multiplier: [u8; 2],
+
+    chips: Vec<Box<dyn ExpansionAudio>>,
+    audio_output: Option<AudioOutput>,
+
+    song: u8,
+    region: NsfRegion,
+    init_addr: u16,
+    play_addr: u16,
+    non_returning_init: bool,
+    suppress_play: bool,
+
+    /// Stands in for the PPU's NMI, held for one cycle to start PLAY
+    nmi_line: WiredOrSource,
+    cycles_per_play: f64,
+    play_timer: f64,
+    init_started: bool,
+    init_done: bool,
+    play_running: bool,
+    play_pending: bool,
+}
+
+impl NsfCart {
+    /// song counts from 0. A dual-region tune runs in its NTSC mode unless
+    /// region says otherwise.
+    pub fn new(
+        nsf: &NsfFile,
+        song: u8,
+        region: NsfRegion,
+        cpu_clock_hz: f64,
+        nmi_line: WiredOrSource,
+    ) -> Self {
+        let (prg, initial_banks) = match nsf.initial_banks {
+            Some(banks) => {
+                // Banked data is aligned so the load address falls at its
+                // offset within the first bank
+                let padding = nsf.load_addr as usize & (BANK_SIZE - 1);
+                let mut prg = vec![0; padding];
+                prg.extend_from_slice(&nsf.data);
+                prg.resize(prg.len().next_multiple_of(BANK_SIZE), 0);
+                (prg, banks)
+            }
+            None => {
+                let mut prg = vec![0; 0x8000];
+                let start = nsf.load_addr as usize - 0x8000;
+                let len = nsf.data.len().min(prg.len() - start);
+                prg[start..start + len].copy_from_slice(&nsf.data[..len]);
+                (prg, [0, 1, 2, 3, 4, 5, 6, 7])
+            }
+        };
+        let play_period_us = match region {
+            NsfRegion::Pal => nsf.pal_play_period_us,
+            _ => nsf.ntsc_play_period_us,
+        };
+        let chips = nsf.expansion_chips();
+        NsfCart {
+            prg,
+            banked: nsf.initial_banks.is_some(),
+            initial_banks,
+            banks: initial_banks,
+            ram: vec![0; 0x2000],
+            prg_writable: chips.contains(&ExpansionChip::Fds),
+            exram: chips.contains(&ExpansionChip::Mmc5).then(|| vec![0; 0x400]),
+            multiplier: [0; 2],
+            chips: chips
+                .into_iter()
+                .map(|chip| make_chip(chip, cpu_clock_hz))
+                .collect(),
+            audio_output: None,
+            song,
+            region,
+            init_addr: nsf.init_addr,
+            play_addr: nsf.play_addr,
+            non_returning_init: nsf.non_returning_init,
+            suppress_play: nsf.suppress_play,
+            nmi_line,
+            cycles_per_play: play_period_us as f64 * cpu_clock_hz / 1_000_000.0,
+            play_timer: 0.0,
+            init_started: false,
+            init_done: false,
+            play_running: false,
+            play_pending: false,
+        }
+    }
+
+    /// Mix the expansion chips' output into the console's audio
+    pub fn with_audio_output(mut self, audio_output: AudioOutput) -> Self {
+        self.audio_output = Some(audio_output);
+        self
+    }
+
+    fn prg_offset(&self, addr: u32) -> usize {
+        let slot = (addr as usize - 0x8000) / BANK_SIZE;
+        let bank_count = self.prg.len() / BANK_SIZE;
+        let bank = self.banks[slot] as usize % bank_count;
+        bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1))
+    }
+
+    fn read_prg(&self, addr: u32) -> u8 {
+        self.prg[self.prg_offset(addr)]
+    }
+
+    fn read_driver(&self, addr: u32) -> Option<u8> {
+        let [init_lo, init_hi] = self.init_addr.to_le_bytes();
+        let [play_lo, play_hi] = self.play_addr.to_le_bytes();
+        match addr {
+            REG_SONG => Some(self.song),
+            REG_REGION => Some((self.region == NsfRegion::Pal) as u8),
+            REG_INIT_ADDR => Some(init_lo),
+            0x4185 => Some(init_hi),
+            REG_PLAY_ADDR => Some(play_lo),
+            0x4187 => Some(play_hi),
+            _ => DRIVER_CODE.get((addr - DRIVER_BASE) as usize).copied(),
+        }
+    }
+
+    fn can_play(&self) -> bool {
+        !self.suppress_play
+            && !self.play_running
+            && (self.init_done || (self.non_returning_init && self.init_started))
+    }
+}
+
+fn make_chip(chip: ExpansionChip, cpu_clock_hz: f64) -> Box<dyn ExpansionAudio> {
+    match chip {
+        ExpansionChip::Vrc6 => Box::new(Vrc6Audio::new()),
+        ExpansionChip::Vrc7 => Box::new(Vrc7Audio::new(cpu_clock_hz)),
+        ExpansionChip::Fds => Box::new(FdsAudio::new()),
+        ExpansionChip::Mmc5 => Box::new(Mmc5Audio::new()),
+        ExpansionChip::N163 => Box::new(N163Audio::new()),
+        ExpansionChip::Sunsoft5B => Box::new(Sunsoft5BAudio::new()),
+    }
+}
+
+impl BusDevice for NsfCart {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let value = match addr {
+            0x4100..=0x41FF => self.read_driver(addr),
+            0x5205 | 0x5206 if self.exram.is_some() => {
+                let product = self.multiplier[0] as u16 * self.multiplier[1] as u16;
+                Some(product.to_le_bytes()[addr as usize - 0x5205])
+            }
+            0x5C00..=0x5FF5 => self
+                .exram
+                .as_ref()
+                .map(|exram| exram[addr as usize - 0x5C00]),
+            0x6000..=0x7FFF => Some(self.ram[addr as usize - 0x6000]),
+            0xFFFA..=0xFFFF => {
+                let vector = match addr & !1 {
+                    0xFFFA => DRIVER_NMI,
+                    0xFFFC => DRIVER_RESET,
+                    _ => DRIVER_IRQ,
+                };
+                Some(vector.to_le_bytes()[addr as usize & 1])
+            }
+            0x8000..=0xFFF9 => {
+                let data = self.read_prg(addr);
+                for chip in self.chips.iter_mut() {
+                    chip.observe_read(addr as u16, data);
+                }
+                Some(data)
+            }
+            _ => self
+                .chips
+                .iter_mut()
+                .find_map(|chip| chip.read(addr as u16)),
+        };
+        Ok(value.map_or(ReadResult::OpenBus, ReadResult::Data))
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        match addr {
+            REG_STATUS => match data {
+                STATUS_INIT_STARTED => self.init_started = true,
+                STATUS_INIT_DONE => self.init_done = true,
+                STATUS_PLAY_DONE => self.play_running = false,
+                _ => {}
+            },
+            0x5205 | 0x5206 => self.multiplier[addr as usize - 0x5205] = data,
+            0x5C00..=0x5FF5 => {
+                if let Some(exram) = &mut self.exram {
+                    exram[addr as usize - 0x5C00] = data;
+                }
+            }
+            0x5FF8..=0x5FFF if self.banked => self.banks[addr as usize - 0x5FF8] = data,
+            0x6000..=0x7FFF => self.ram[addr as usize - 0x6000] = data,
+            0x8000..=0xDFFF if self.prg_writable => {
+                let offset = self.prg_offset(addr);
+                self.prg[offset] = data;
+            }
+            _ => {}
+        }
+        for chip in self.chips.iter_mut() {
+            chip.write(addr as u16, data);
+        }
+        Ok(())
+    }
+
+    fn power_on(&mut self) {
+        self.ram.fill(0);
+        if let Some(exram) = &mut self.exram {
+            exram.fill(0);
+        }
+        for chip in self.chips.iter_mut() {
+            chip.power_on();
+        }
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.banks = self.initial_banks;
+        self.play_timer = 0.0;
+        self.init_started = false;
+        self.init_done = false;
+        self.play_running = false;
+        self.play_pending = false;
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        if !self.chips.is_empty() {
+            let mut level = 0.0;
+            for chip in self.chips.iter_mut() {
+                chip.tick();
+                level += chip.output();
+            }
+            if let Some(audio_output) = &self.audio_output {
+                audio_output.mix(level);
+            }
+        }
+
+        self.nmi_line.set(false);
+        self.play_timer += 1.0;
+        if self.play_timer >= self.cycles_per_play {
+            self.play_timer -= self.cycles_per_play;
+            // A PLAY that overruns its period delays the next one, the way a
+            // real player waiting on NMI would
+            self.play_pending = true;
+        }
+        if self.play_pending && self.can_play() {
+            self.play_pending = false;
+            self.play_running = true;
+            self.nmi_line.set(true);
+        }
+        Ok(())
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        match addr {
+            0x4100..=0x41FF => self.read_driver(addr),
+            0x6000..=0x7FFF => Some(self.ram[addr as usize - 0x6000]),
+            0x8000..=0xFFF9 => Some(self.read_prg(addr)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{components::signal::WiredOrSignal, nsf::test::build_nsf};
+
+    fn read(cart: &mut NsfCart, addr: u32) -> u8 {
+        match cart.bus_read(addr).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus at 0x{:04X}", addr),
+        }
+    }
+
+    fn make_cart(banks: [u8; 8]) -> NsfCart {
+        let mut data = build_nsf(banks);
+        // Four banks of data, each starting with its own number
+        data.truncate(0x80);
+        for bank in 0..4 {
+            data.push(bank);
+            data.resize(data.len() + BANK_SIZE - 1, 0xEA);
+        }
+        let nsf = NsfFile::parse(&data).unwrap();
+        NsfCart::new(
+            &nsf,
+            0,
+            NsfRegion::Ntsc,
+            1_789_773.0,
+            WiredOrSignal::new().make_source("nsf"),
+        )
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut cart = make_cart([3, 2, 1, 0, 0, 0, 0, 0]);
+        cart.power_on();
+        assert_eq!(read(&mut cart, 0x8000), 3);
+        assert_eq!(read(&mut cart, 0x9000), 2);
+        assert_eq!(read(&mut cart, 0xA000), 1);
+        assert_eq!(read(&mut cart, 0xB000), 0);
+
+        cart.bus_write(0x5FF8, 2).unwrap();
+        assert_eq!(read(&mut cart, 0x8000), 2);
+        // Banks past the end wrap around
+        cart.bus_write(0x5FFF, 5).unwrap();
+        assert_eq!(read(&mut cart, 0xF000), 1);
+
+        // The vectors always point at the driver
+        assert_eq!(read(&mut cart, 0xFFFC), 0x00);
+        assert_eq!(read(&mut cart, 0xFFFD), 0x41);
+
+        cart.reset();
+        assert_eq!(read(&mut cart, 0x8000), 3);
+    }
+
+    #[test]
+    fn test_unbanked_load() {
+        let mut cart = make_cart([0; 8]);
+        cart.power_on();
+        for bank in 0..4 {
+            assert_eq!(read(&mut cart, 0x8000 + bank * 0x1000), bank as u8);
+        }
+        // Unbanked tunes ignore the bank registers
+        cart.bus_write(0x5FF8, 0).unwrap();
+        cart.bus_write(0x5FF9, 0).unwrap();
+        assert_eq!(read(&mut cart, 0x9000), 1);
+    }
+
+    #[test]
+    fn test_expansion_audio() {
+        let audio = AudioOutput::new(44100, 1_789_773.0);
+        // The test tune uses the VRC6
+        let mut cart = make_cart([0; 8]).with_audio_output(audio.clone());
+        cart.power_on();
+        assert_eq!(cart.chips.len(), 1);
+        let run = |cart: &mut NsfCart, cycles| {
+            for _ in 0..cycles {
+                cart.tick().unwrap();
+                audio.end_cycle();
+            }
+        };
+        run(&mut cart, 100);
+
+        // Pulse 1 held high at full volume
+        cart.bus_write(0x9000, 0x8F).unwrap();
+        cart.bus_write(0x9002, 0x80).unwrap();
+        run(&mut cart, 1000);
+        assert!(audio.take_samples().iter().any(|sample| *sample > 0.0));
+    }
+
+    #[test]
+    fn test_play_timing() {
+        let mut nmi_signal = WiredOrSignal::new();
+        let nmi = nmi_signal.make_receiver();
+        let nsf = NsfFile::parse(&build_nsf([0; 8])).unwrap();
+        let mut cart = NsfCart::new(
+            &nsf,
+            0,
+            NsfRegion::Ntsc,
+            1_000_000.0,
+            nmi_signal.make_source("nsf"),
+        );
+        cart.power_on();
+        // Count the cycles the NMI line is held
+        let run = |cart: &mut NsfCart, cycles| {
+            (0..cycles)
+                .filter(|_| {
+                    cart.tick().unwrap();
+                    nmi.get()
+                })
+                .count()
+        };
+
+        // No PLAY until INIT has returned
+        assert_eq!(run(&mut cart, 20000), 0);
+        cart.bus_write(REG_STATUS, STATUS_INIT_DONE).unwrap();
+        assert_eq!(run(&mut cart, 1), 1);
+
+        // The next PLAY waits for the current one to finish
+        assert_eq!(run(&mut cart, 20000), 0);
+        cart.bus_write(REG_STATUS, STATUS_PLAY_DONE).unwrap();
+        assert_eq!(run(&mut cart, 2), 1);
+    }
+}
@@ -0,0 +1,1092 @@
+use std::{cell::RefCell, rc::Rc};
+
+use super::{
+    BusActivity, BusDevice, EmuResult, ReadResult, signal::WiredOrSource, timing::ConsoleTiming,
+};
+
+const PPUCTRL: usize = 0;
+const PPUMASK: usize = 1;
+const PPUSTATUS: usize = 2;
+const OAMADDR: usize = 3;
+const OAMDATA: usize = 4;
+const PPUSCROLL: usize = 5;
+const PPUADDR: usize = 6;
+const PPUDATA: usize = 7;
+
+// PPUCTRL bits
+const CTRL_INCREMENT_32: u8 = 0x04;
+const CTRL_SPRITE_TABLE: u8 = 0x08;
+const CTRL_BG_TABLE: u8 = 0x10;
+const CTRL_SPRITE_8X16: u8 = 0x20;
+const CTRL_NMI_ENABLE: u8 = 0x80;
+
+// PPUMASK bits
+const MASK_GRAYSCALE: u8 = 0x01;
+const MASK_BG_LEFT: u8 = 0x02;
+const MASK_SPRITES_LEFT: u8 = 0x04;
+const MASK_BG: u8 = 0x08;
+const MASK_SPRITES: u8 = 0x10;
+
+// PPUSTATUS bits
+const STATUS_OVERFLOW: u8 = 0x20;
+const STATUS_SPRITE_ZERO: u8 = 0x40;
+const STATUS_VBLANK: u8 = 0x80;
+
+const DOTS_PER_SCANLINE: u16 = 341;
+const VISIBLE_SCANLINES: u16 = 240;
+
+const PALETTE_BASE: u16 = 0x3F00;
+const MAX_SPRITES_PER_LINE: usize = 8;
+
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+
+/// One sprite's data for the scanline being drawn
+#[derive(Debug, Default, Clone, Copy)]
+struct LineSprite {
+    x: u8,
+    attributes: u8,
+    pattern_lo: u8,
+    pattern_hi: u8,
+}
+
+/// Snapshot of the PPU's registers and where it is in the frame, for
+/// debuggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpuState {
+    pub scanline: u16,
+    pub dot: u16,
+    pub frame: u64,
+    pub ctrl: u8,
+    pub mask: u8,
+    pub status: u8,
+    pub oam_addr: u8,
+    pub v: u16,
+    pub t: u16,
+    pub fine_x: u8,
+    pub write_toggle: bool,
+}
+
+/// The 2C02 PPU. Registers are at 0x2000 - 0x2007, and the PPU's own bus
+/// (pattern tables and nametables, everything below the palette at 0x3F00)
+/// is a separate BusDevice supplied by the console and the cartridge.
+///
+/// Rendering follows the real PPU's fetch schedule dot by dot, so devices on
+/// the PPU bus see the same sequence of addresses a mapper would on
+/// hardware. Each pixel is written to the frame buffer as a palette index.
+///
+/// The PPU runs on its own divider of the master clock, so dots are clocked
+/// by step_dot rather than tick; the console's scheduler interleaves them
+/// with CPU cycles. The frame's length and where vblank falls come from the
+/// region's timing.
+pub struct Ppu<'t> {
+    bus: Box<dyn BusDevice + 't>,
+    nmi_line: WiredOrSource,
+    timing: &'static ConsoleTiming,
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    oam: [u8; 256],
+    palette: [u8; 32],
+    /// Current VRAM address, and the temporary address that scroll and
+    /// address writes build up in
+    v: u16,
+    t: u16,
+    fine_x: u8,
+    write_toggle: bool,
+    read_buffer: u8,
+    /// Last value written to or read from a register. Reads of bits a
+    /// register doesn't drive return this.
+    io_latch: u8,
+
+    scanline: u16,
+    dot: u16,
+    odd_frame: bool,
+    frame_count: u64,
+    /// PPUSTATUS was read just before vblank starts, so this frame's flag
+    /// never gets set
+    suppress_vblank: bool,
+
+    // Background pipeline
+    nametable_latch: u8,
+    attribute_latch: u8,
+    pattern_lo_latch: u8,
+    pattern_hi_latch: u8,
+    bg_pattern_lo: u16,
+    bg_pattern_hi: u16,
+    bg_attribute_lo: u16,
+    bg_attribute_hi: u16,
+
+    // Sprites found for the next scanline, and the ones being drawn
+    next_sprites: [[u8; 4]; MAX_SPRITES_PER_LINE],
+    next_sprite_count: usize,
+    next_sprite_zero: bool,
+    line_sprites: [LineSprite; MAX_SPRITES_PER_LINE],
+    line_sprite_count: usize,
+    line_sprite_zero: bool,
+
+    frame: Vec<u8>,
+    warmup_remaining: u32,
+}
+
+impl<'t> Ppu<'t> {
+    /// bus covers the PPU's address space below the palette. nmi_line is
+    /// asserted while the vblank flag is set and NMIs are enabled.
+    pub fn new(
+        bus: Box<dyn BusDevice + 't>,
+        nmi_line: WiredOrSource,
+        timing: &'static ConsoleTiming,
+    ) -> Self {
+        Ppu {
+            bus,
+            nmi_line,
+            timing,
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            oam: [0; 256],
+            palette: [0; 32],
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            write_toggle: false,
+            read_buffer: 0,
+            io_latch: 0,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            frame_count: 0,
+            suppress_vblank: false,
+            nametable_latch: 0,
+            attribute_latch: 0,
+            pattern_lo_latch: 0,
+            pattern_hi_latch: 0,
+            bg_pattern_lo: 0,
+            bg_pattern_hi: 0,
+            bg_attribute_lo: 0,
+            bg_attribute_hi: 0,
+            next_sprites: [[0; 4]; MAX_SPRITES_PER_LINE],
+            next_sprite_count: 0,
+            next_sprite_zero: false,
+            line_sprites: [LineSprite::default(); MAX_SPRITES_PER_LINE],
+            line_sprite_count: 0,
+            line_sprite_zero: false,
+            frame: vec![0; FRAME_WIDTH * FRAME_HEIGHT],
+            warmup_remaining: timing.ppu_warmup_cpu_cycles,
+        }
+    }
+
+    /// Whether the PPU is still ignoring register writes after power-on or reset
+    pub fn warming_up(&self) -> bool {
+        self.warmup_remaining > 0
+    }
+
+    /// The last frame drawn, as palette indices, FRAME_WIDTH pixels per row
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    /// Number of frames that have reached vertical blank since power-on
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// Current scanline and dot. The last scanline of the frame is the
+    /// pre-render line: 261 on NTSC, 311 on PAL and Dendy.
+    pub fn position(&self) -> (u16, u16) {
+        (self.scanline, self.dot)
+    }
+
+    pub fn state(&self) -> PpuState {
+        PpuState {
+            scanline: self.scanline,
+            dot: self.dot,
+            frame: self.frame_count,
+            ctrl: self.ctrl,
+            mask: self.mask,
+            status: self.status,
+            oam_addr: self.oam_addr,
+            v: self.v,
+            t: self.t,
+            fine_x: self.fine_x,
+            write_toggle: self.write_toggle,
+        }
+    }
+
+    fn update_nmi(&mut self) {
+        self.nmi_line
+            .set(self.status & STATUS_VBLANK != 0 && self.ctrl & CTRL_NMI_ENABLE != 0);
+    }
+
+    fn rendering_enabled(&self) -> bool {
+        self.mask & (MASK_BG | MASK_SPRITES) != 0
+    }
+
+    fn pre_render_line(&self) -> bool {
+        self.scanline == self.timing.pre_render_scanline()
+    }
+
+    fn rendering_active(&self) -> bool {
+        self.rendering_enabled() && (self.scanline < VISIBLE_SCANLINES || self.pre_render_line())
+    }
+
+    fn sprite_height(&self) -> u16 {
+        if self.ctrl & CTRL_SPRITE_8X16 != 0 {
+            16
+        } else {
+            8
+        }
+    }
+
+    fn fetch(&mut self, addr: u16) -> EmuResult<u8> {
+        let addr = addr as u32 & 0x3FFF;
+        self.bus.observe_access(addr, BusActivity::Read);
+        Ok(match self.bus.bus_read(addr)? {
+            ReadResult::Data(value) => value,
+            // With nothing driving the bus, the low byte of the address
+            // lingers on the multiplexed address/data lines
+            ReadResult::OpenBus => addr as u8,
+        })
+    }
+
+    fn palette_index(addr: u16) -> usize {
+        let index = addr as usize & 0x1F;
+        // The sprite palettes' backdrop entries mirror the background's
+        if index & 0x13 == 0x10 {
+            index & 0x0F
+        } else {
+            index
+        }
+    }
+
+    fn read_palette(&self, addr: u16) -> u8 {
+        let value = self.palette[Self::palette_index(addr)];
+        if self.mask & MASK_GRAYSCALE != 0 {
+            value & 0x30
+        } else {
+            value
+        }
+    }
+
+    fn increment_v(&mut self) {
+        if self.rendering_active() {
+            // During rendering the increment logic is shared with the
+            // scrolling counters, which both step
+            self.increment_coarse_x();
+            self.increment_y();
+        } else if self.ctrl & CTRL_INCREMENT_32 != 0 {
+            self.v = self.v.wrapping_add(32) & 0x7FFF;
+        } else {
+            self.v = self.v.wrapping_add(1) & 0x7FFF;
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        if self.v & 0x001F == 0x1F {
+            self.v &= !0x001F;
+            self.v ^= 0x0400;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800;
+        } else if coarse_y == 31 {
+            // Attribute rows wrap without switching nametables
+            coarse_y = 0;
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    fn nametable_addr(&self) -> u16 {
+        0x2000 | (self.v & 0x0FFF)
+    }
+
+    fn attribute_addr(&self) -> u16 {
+        0x23C0 | (self.v & 0x0C00) | ((self.v >> 4) & 0x38) | ((self.v >> 2) & 0x07)
+    }
+
+    fn bg_pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BG_TABLE != 0 {
+            0x1000
+        } else {
+            0
+        };
+        table + self.nametable_latch as u16 * 16 + ((self.v >> 12) & 0x07)
+    }
+
+    fn load_bg_shifters(&mut self) {
+        self.bg_pattern_lo = (self.bg_pattern_lo & 0xFF00) | self.pattern_lo_latch as u16;
+        self.bg_pattern_hi = (self.bg_pattern_hi & 0xFF00) | self.pattern_hi_latch as u16;
+        let attribute_lo = if self.attribute_latch & 1 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let attribute_hi = if self.attribute_latch & 2 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        self.bg_attribute_lo = (self.bg_attribute_lo & 0xFF00) | attribute_lo;
+        self.bg_attribute_hi = (self.bg_attribute_hi & 0xFF00) | attribute_hi;
+    }
+
+    fn shift_bg(&mut self) {
+        self.bg_pattern_lo <<= 1;
+        self.bg_pattern_hi <<= 1;
+        self.bg_attribute_lo <<= 1;
+        self.bg_attribute_hi <<= 1;
+    }
+
+    /// One step of the background fetch cycle: nametable, attribute, and the
+    /// two pattern bytes, each taking two dots
+    fn fetch_bg(&mut self) -> EmuResult<()> {
+        match (self.dot - 1) % 8 {
+            0 => {
+                self.load_bg_shifters();
+                self.nametable_latch = self.fetch(self.nametable_addr())?;
+            }
+            2 => {
+                let attribute = self.fetch(self.attribute_addr())?;
+                let shift = ((self.v >> 4) & 0x04) | (self.v & 0x02);
+                self.attribute_latch = (attribute >> shift) & 0x03;
+            }
+            4 => self.pattern_lo_latch = self.fetch(self.bg_pattern_addr())?,
+            6 => self.pattern_hi_latch = self.fetch(self.bg_pattern_addr() + 8)?,
+            7 => self.increment_coarse_x(),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Find the sprites on the next scanline. Hardware does this over dots
+    /// 65 - 256; the results are the same, minus the overflow flag's false
+    /// positives and negatives.
+    fn evaluate_sprites(&mut self) {
+        self.next_sprite_count = 0;
+        self.next_sprite_zero = false;
+        if self.pre_render_line() {
+            return;
+        }
+        let height = self.sprite_height();
+        for n in 0..64 {
+            let entry = &self.oam[n * 4..n * 4 + 4];
+            let row = self.scanline.wrapping_sub(entry[0] as u16);
+            if row >= height {
+                continue;
+            }
+            if self.next_sprite_count == MAX_SPRITES_PER_LINE {
+                self.status |= STATUS_OVERFLOW;
+                break;
+            }
+            self.next_sprites[self.next_sprite_count].copy_from_slice(entry);
+            self.next_sprite_count += 1;
+            if n == 0 {
+                self.next_sprite_zero = true;
+            }
+        }
+    }
+
+    fn sprite_pattern_addr(&self, slot: usize) -> u16 {
+        let height = self.sprite_height();
+        // Empty slots fetch the first row of tile 0xFF
+        let ([y, tile, attributes, _], scanline) = if slot < self.next_sprite_count {
+            (self.next_sprites[slot], self.scanline)
+        } else {
+            ([0xFF, 0xFF, 0x00, 0xFF], 0xFF)
+        };
+        let mut row = scanline.wrapping_sub(y as u16) & (height - 1);
+        if attributes & 0x80 != 0 {
+            row = height - 1 - row;
+        }
+        if height == 16 {
+            let table = (tile as u16 & 1) * 0x1000;
+            let tile = (tile & 0xFE) as u16 + (row >> 3);
+            table + tile * 16 + (row & 7)
+        } else {
+            let table = if self.ctrl & CTRL_SPRITE_TABLE != 0 {
+                0x1000
+            } else {
+                0
+            };
+            table + tile as u16 * 16 + row
+        }
+    }
+
+    /// Sprite pattern fetches for the next scanline, over dots 257 - 320. The
+    /// PPU fetches two garbage nametable bytes before each sprite's pattern.
+    fn fetch_sprite(&mut self) -> EmuResult<()> {
+        let slot = (self.dot as usize - 257) / 8;
+        match (self.dot - 257) % 8 {
+            0 | 2 => {
+                self.fetch(self.nametable_addr())?;
+            }
+            4 => {
+                let mut data = self.fetch(self.sprite_pattern_addr(slot))?;
+                if slot < self.next_sprite_count {
+                    let [_, _, attributes, x] = self.next_sprites[slot];
+                    if attributes & 0x40 != 0 {
+                        data = data.reverse_bits();
+                    }
+                    self.line_sprites[slot] = LineSprite {
+                        x,
+                        attributes,
+                        pattern_lo: data,
+                        pattern_hi: 0,
+                    };
+                }
+            }
+            6 => {
+                let mut data = self.fetch(self.sprite_pattern_addr(slot) + 8)?;
+                if slot < self.next_sprite_count {
+                    if self.line_sprites[slot].attributes & 0x40 != 0 {
+                        data = data.reverse_bits();
+                    }
+                    self.line_sprites[slot].pattern_hi = data;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn output_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+
+        let mut bg_pixel = 0;
+        if self.mask & MASK_BG != 0 && (x >= 8 || self.mask & MASK_BG_LEFT != 0) {
+            let bit = 15 - self.fine_x;
+            bg_pixel = ((self.bg_pattern_hi >> bit) & 1) << 1 | ((self.bg_pattern_lo >> bit) & 1);
+            if bg_pixel != 0 {
+                let palette =
+                    ((self.bg_attribute_hi >> bit) & 1) << 1 | ((self.bg_attribute_lo >> bit) & 1);
+                bg_pixel |= palette << 2;
+            }
+        }
+
+        let mut sprite = None;
+        if self.mask & MASK_SPRITES != 0 && (x >= 8 || self.mask & MASK_SPRITES_LEFT != 0) {
+            for (slot, line_sprite) in self.line_sprites[..self.line_sprite_count]
+                .iter()
+                .enumerate()
+            {
+                let offset = x.wrapping_sub(line_sprite.x as usize);
+                if offset >= 8 {
+                    continue;
+                }
+                let bit = 7 - offset;
+                let pixel = ((line_sprite.pattern_hi >> bit) & 1) << 1
+                    | ((line_sprite.pattern_lo >> bit) & 1);
+                if pixel != 0 {
+                    sprite = Some((slot, pixel, line_sprite.attributes));
+                    break;
+                }
+            }
+        }
+
+        let palette_addr = match sprite {
+            Some((slot, pixel, attributes)) => {
+                if slot == 0 && self.line_sprite_zero && bg_pixel != 0 && x != 255 {
+                    self.status |= STATUS_SPRITE_ZERO;
+                }
+                if bg_pixel != 0 && attributes & 0x20 != 0 {
+                    bg_pixel
+                } else {
+                    0x10 | ((attributes as u16 & 0x03) << 2) | pixel as u16
+                }
+            }
+            None => bg_pixel,
+        };
+        self.frame[self.scanline as usize * FRAME_WIDTH + x] =
+            self.read_palette(PALETTE_BASE + palette_addr);
+    }
+
+    fn render_dot(&mut self) -> EmuResult<()> {
+        let dot = self.dot;
+        if (2..=257).contains(&dot) || (322..=337).contains(&dot) {
+            self.shift_bg();
+        }
+        match dot {
+            1..=256 | 321..=336 => self.fetch_bg()?,
+            257..=320 => {
+                if dot == 257 {
+                    self.t_to_v_horizontal();
+                    self.evaluate_sprites();
+                }
+                self.fetch_sprite()?;
+            }
+            // Two more nametable fetches, which the PPU throws away
+            337 | 339 => {
+                self.fetch(self.nametable_addr())?;
+            }
+            _ => {}
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if self.pre_render_line() && (280..=304).contains(&dot) {
+            self.t_to_v_vertical();
+        }
+        if self.scanline < VISIBLE_SCANLINES && (1..=256).contains(&dot) {
+            self.output_pixel();
+        }
+        if dot == 320 {
+            self.line_sprite_count = self.next_sprite_count;
+            self.line_sprite_zero = self.next_sprite_zero;
+        }
+        Ok(())
+    }
+
+    fn t_to_v_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    fn t_to_v_vertical(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
+    }
+
+    /// Advance by one dot
+    pub fn step_dot(&mut self) -> EmuResult<()> {
+        if self.rendering_active() {
+            self.render_dot()?;
+        } else if self.scanline < VISIBLE_SCANLINES && (1..=256).contains(&self.dot) {
+            let backdrop = self.read_palette(PALETTE_BASE);
+            self.frame[self.scanline as usize * FRAME_WIDTH + self.dot as usize - 1] = backdrop;
+        }
+
+        if self.dot == 1 {
+            if self.scanline == self.timing.vblank_scanline {
+                if !self.suppress_vblank {
+                    self.status |= STATUS_VBLANK;
+                }
+                self.suppress_vblank = false;
+                self.frame_count += 1;
+                self.update_nmi();
+            } else if self.pre_render_line() {
+                self.status &= !(STATUS_VBLANK | STATUS_SPRITE_ZERO | STATUS_OVERFLOW);
+                self.update_nmi();
+            }
+        }
+
+        // Odd NTSC frames skip the last dot of the pre-render line while
+        // rendering
+        let skip_dot = self.timing.odd_frame_skip
+            && self.pre_render_line()
+            && self.dot == 339
+            && self.odd_frame
+            && self.rendering_enabled();
+        self.dot += if skip_dot { 2 } else { 1 };
+        if self.dot >= DOTS_PER_SCANLINE {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline >= self.timing.scanlines {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self) -> EmuResult<u8> {
+        let addr = self.v & 0x3FFF;
+        let value = if addr >= PALETTE_BASE {
+            // Palette reads are immediate, but still refill the buffer from
+            // the nametable underneath
+            self.read_buffer = self.fetch(addr - 0x1000)?;
+            self.read_palette(addr) | (self.io_latch & 0xC0)
+        } else {
+            let value = self.read_buffer;
+            self.read_buffer = self.fetch(addr)?;
+            value
+        };
+        self.increment_v();
+        Ok(value)
+    }
+
+    fn write_data(&mut self, data: u8) -> EmuResult<()> {
+        let addr = self.v & 0x3FFF;
+        if addr >= PALETTE_BASE {
+            self.palette[Self::palette_index(addr)] = data & 0x3F;
+        } else {
+            self.bus
+                .observe_access(addr as u32, BusActivity::Write(data));
+            self.bus.bus_write(addr as u32, data)?;
+        }
+        self.increment_v();
+        Ok(())
+    }
+}
+
+impl BusDevice for Ppu<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        let value = match addr as usize {
+            PPUSTATUS => {
+                let value = (self.status & 0xE0) | (self.io_latch & 0x1F);
+                self.status &= !STATUS_VBLANK;
+                // Reading as the flag is about to be set keeps it clear for
+                // the whole frame
+                if self.scanline == self.timing.vblank_scanline && self.dot == 1 {
+                    self.suppress_vblank = true;
+                }
+                self.write_toggle = false;
+                self.update_nmi();
+                value
+            }
+            OAMDATA => {
+                let value = self.oam[self.oam_addr as usize];
+                // Attribute bytes don't store bits 2-4
+                if self.oam_addr & 0x03 == 0x02 {
+                    value & 0xE3
+                } else {
+                    value
+                }
+            }
+            PPUDATA => self.read_data()?,
+            // The rest are write-only
+            _ => self.io_latch,
+        };
+        self.io_latch = value;
+        Ok(ReadResult::Data(value))
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        let addr = addr as usize;
+        self.io_latch = data;
+        if self.warming_up() && matches!(addr, PPUCTRL | PPUMASK | PPUSCROLL | PPUADDR) {
+            return Ok(());
+        }
+        match addr {
+            PPUCTRL => {
+                // Enabling NMIs during vertical blank asserts the line
+                // straight away, so the CPU sees another edge
+                self.ctrl = data;
+                self.t = (self.t & !0x0C00) | ((data as u16 & 0x03) << 10);
+                self.update_nmi();
+            }
+            PPUMASK => self.mask = data,
+            OAMADDR => self.oam_addr = data,
+            OAMDATA => {
+                self.oam[self.oam_addr as usize] = data;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            PPUSCROLL => {
+                if self.write_toggle {
+                    self.t = (self.t & !0x73E0)
+                        | ((data as u16 & 0x07) << 12)
+                        | ((data as u16 & 0xF8) << 2);
+                } else {
+                    self.t = (self.t & !0x001F) | (data as u16 >> 3);
+                    self.fine_x = data & 0x07;
+                }
+                self.write_toggle = !self.write_toggle;
+            }
+            PPUADDR => {
+                if self.write_toggle {
+                    self.t = (self.t & 0xFF00) | data as u16;
+                    self.v = self.t;
+                    // The new address goes straight out on the PPU bus
+                    if !self.rendering_active() {
+                        self.bus
+                            .observe_access(self.v as u32 & 0x3FFF, BusActivity::Idle);
+                    }
+                } else {
+                    self.t = (self.t & 0x00FF) | ((data as u16 & 0x3F) << 8);
+                }
+                self.write_toggle = !self.write_toggle;
+            }
+            PPUDATA => self.write_data(data)?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.bus.start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.bus.end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.status = 0;
+        self.oam_addr = 0;
+        self.oam = [0; 256];
+        self.palette = [0; 32];
+        self.v = 0;
+        self.t = 0;
+        self.fine_x = 0;
+        self.write_toggle = false;
+        self.read_buffer = 0;
+        self.io_latch = 0;
+        self.scanline = 0;
+        self.dot = 0;
+        self.odd_frame = false;
+        self.frame_count = 0;
+        self.suppress_vblank = false;
+        self.line_sprite_count = 0;
+        self.next_sprite_count = 0;
+        self.frame.fill(0);
+        self.warmup_remaining = self.timing.ppu_warmup_cpu_cycles;
+        self.update_nmi();
+        self.bus.power_on();
+    }
+
+    fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.t = 0;
+        self.fine_x = 0;
+        self.write_toggle = false;
+        self.read_buffer = 0;
+        self.odd_frame = false;
+        self.warmup_remaining = self.timing.ppu_warmup_cpu_cycles;
+        self.update_nmi();
+        self.bus.reset();
+    }
+
+    /// Only counts down the warmup, which is measured in CPU cycles. Dots
+    /// come from step_dot.
+    fn tick(&mut self) -> EmuResult<()> {
+        self.warmup_remaining = self.warmup_remaining.saturating_sub(1);
+        Ok(())
+    }
+}
+
+/// A Ppu shared between the CPU bus, where its registers are, and the
+/// scheduler that clocks its dots. Clones refer to the same PPU.
+#[derive(Clone)]
+pub struct SharedPpu<'t> {
+    ppu: Rc<RefCell<Ppu<'t>>>,
+}
+
+impl<'t> SharedPpu<'t> {
+    pub fn new(ppu: Ppu<'t>) -> Self {
+        SharedPpu {
+            ppu: Rc::new(RefCell::new(ppu)),
+        }
+    }
+
+    pub fn step_dot(&self) -> EmuResult<()> {
+        self.ppu.borrow_mut().step_dot()
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.borrow().frame_count()
+    }
+
+    pub fn state(&self) -> PpuState {
+        self.ppu.borrow().state()
+    }
+}
+
+impl BusDevice for SharedPpu<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.ppu.borrow_mut().bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.ppu.borrow_mut().bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.ppu.borrow_mut().start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.ppu.borrow_mut().end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.ppu.borrow_mut().power_on();
+    }
+
+    fn reset(&mut self) {
+        self.ppu.borrow_mut().reset();
+    }
+
+    fn tick(&mut self) -> EmuResult<()> {
+        self.ppu.borrow_mut().tick()
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.ppu.borrow_mut().observe_access(addr, activity);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::components::{mem::RAMDevice, signal::WiredOrSignal};
+
+    /// RAM over the whole PPU bus, which also records the addresses the PPU
+    /// reads
+    struct RecordingBus {
+        ram: RAMDevice,
+        reads: Rc<RefCell<Vec<u16>>>,
+    }
+
+    impl BusDevice for RecordingBus {
+        fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+            self.ram.bus_read(addr)
+        }
+
+        fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+            self.ram.bus_write(addr, data)
+        }
+
+        fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+            if activity == BusActivity::Read {
+                self.reads.borrow_mut().push(addr as u16);
+            }
+        }
+    }
+
+    fn make_ppu() -> (Ppu<'static>, Rc<RefCell<Vec<u16>>>) {
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let bus = RecordingBus {
+            ram: RAMDevice::new(0x4000),
+            reads: Rc::clone(&reads),
+        };
+        let mut ppu = Ppu::new(
+            Box::new(bus),
+            WiredOrSignal::new().make_source("ppu"),
+            &ConsoleTiming::NTSC,
+        );
+        ppu.power_on();
+        ppu.warmup_remaining = 0;
+        (ppu, reads)
+    }
+
+    fn read(ppu: &mut Ppu, reg: usize) -> u8 {
+        match ppu.bus_read(reg as u32).unwrap() {
+            ReadResult::Data(value) => value,
+            ReadResult::OpenBus => panic!("open bus"),
+        }
+    }
+
+    fn write_vram(ppu: &mut Ppu, addr: u16, data: &[u8]) {
+        ppu.bus_write(PPUADDR as u32, (addr >> 8) as u8).unwrap();
+        ppu.bus_write(PPUADDR as u32, addr as u8).unwrap();
+        for byte in data {
+            ppu.bus_write(PPUDATA as u32, *byte).unwrap();
+        }
+    }
+
+    /// Run until the PPU reaches the given position
+    fn run_to(ppu: &mut Ppu, scanline: u16, dot: u16) {
+        while ppu.position() != (scanline, dot) {
+            ppu.step_dot().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_vblank_and_nmi() {
+        let mut nmi_signal = WiredOrSignal::new();
+        let nmi = nmi_signal.make_receiver();
+        let mut ppu = Ppu::new(
+            Box::new(RAMDevice::new(0x4000)),
+            nmi_signal.make_source("ppu"),
+            &ConsoleTiming::NTSC,
+        );
+        ppu.power_on();
+
+        // Writes are ignored while warming up
+        ppu.bus_write(PPUCTRL as u32, CTRL_NMI_ENABLE).unwrap();
+        assert_eq!(ppu.ctrl, 0);
+        for _ in 0..ConsoleTiming::NTSC.ppu_warmup_cpu_cycles {
+            ppu.tick().unwrap();
+            for _ in 0..3 {
+                ppu.step_dot().unwrap();
+            }
+        }
+        // Clear the flag left from the first frame, so enabling NMIs doesn't
+        // raise one straight away
+        read(&mut ppu, PPUSTATUS);
+        ppu.bus_write(PPUCTRL as u32, CTRL_NMI_ENABLE).unwrap();
+
+        run_to(&mut ppu, 241, 1);
+        assert!(!nmi.get());
+        ppu.step_dot().unwrap();
+        assert!(nmi.get());
+        assert_eq!(ppu.frame_count(), 2);
+
+        // Reading the status clears the flag, and releases the line
+        assert_eq!(read(&mut ppu, PPUSTATUS) & STATUS_VBLANK, STATUS_VBLANK);
+        assert!(!nmi.get());
+        assert_eq!(read(&mut ppu, PPUSTATUS) & STATUS_VBLANK, 0);
+
+        // Re-enabling NMIs while the flag is set asserts it again
+        ppu.status |= STATUS_VBLANK;
+        ppu.bus_write(PPUCTRL as u32, 0).unwrap();
+        assert!(!nmi.get());
+        ppu.bus_write(PPUCTRL as u32, CTRL_NMI_ENABLE).unwrap();
+        assert!(nmi.get());
+
+        // The pre-render line clears it
+        run_to(&mut ppu, 261, 2);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        assert!(!nmi.get());
+
+        // Reading the status just before the flag is set keeps it clear for
+        // the frame, so there's no NMI
+        run_to(&mut ppu, 241, 1);
+        read(&mut ppu, PPUSTATUS);
+        run_to(&mut ppu, 241, 10);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        assert!(!nmi.get());
+        assert_eq!(ppu.frame_count(), 3);
+    }
+
+    #[test]
+    fn test_data_port() {
+        let (mut ppu, _) = make_ppu();
+        write_vram(&mut ppu, 0x2000, &[0x11, 0x22, 0x33]);
+
+        // Reads come through a one-byte buffer
+        write_vram(&mut ppu, 0x2001, &[]);
+        read(&mut ppu, PPUDATA);
+        assert_eq!(read(&mut ppu, PPUDATA), 0x22);
+        assert_eq!(read(&mut ppu, PPUDATA), 0x33);
+
+        // Increment by 32 steps down a column
+        ppu.bus_write(PPUCTRL as u32, CTRL_INCREMENT_32).unwrap();
+        write_vram(&mut ppu, 0x2400, &[0x44, 0x55]);
+        assert_eq!(ppu.v, 0x2440);
+        ppu.bus_write(PPUCTRL as u32, 0).unwrap();
+        write_vram(&mut ppu, 0x2420, &[]);
+        read(&mut ppu, PPUDATA);
+        assert_eq!(read(&mut ppu, PPUDATA), 0x55);
+
+        // Palette reads skip the buffer, and the backdrop entries mirror
+        write_vram(&mut ppu, 0x3F10, &[0x2A]);
+        write_vram(&mut ppu, 0x3F00, &[]);
+        assert_eq!(read(&mut ppu, PPUDATA), 0x2A);
+    }
+
+    #[test]
+    fn test_fetch_pattern() {
+        let (mut ppu, reads) = make_ppu();
+        // No sprites on screen
+        ppu.oam.fill(0xFF);
+        ppu.bus_write(PPUMASK as u32, MASK_BG | MASK_SPRITES)
+            .unwrap();
+        run_to(&mut ppu, 1, 0);
+        reads.borrow_mut().clear();
+        run_to(&mut ppu, 2, 4);
+        let reads = reads.borrow();
+
+        // 34 tiles of 4 fetches, plus 8 sprites of 4 and the 2 extra
+        // nametable fetches, then the start of the next line
+        assert_eq!(reads.len(), 34 * 4 + 8 * 4 + 2 + 2);
+        let nametable_reads = reads.iter().filter(|addr| **addr & 0x23C0 == 0x2000);
+        assert_eq!(nametable_reads.count(), 34 + 8 * 2 + 2 + 1);
+        // The line ends with the same nametable address read three times,
+        // which is how mappers find the start of a scanline
+        let end = &reads[reads.len() - 4..reads.len() - 1];
+        assert!(end.iter().all(|addr| *addr == end[0]));
+        // Empty sprite slots fetch tile 0xFF
+        assert_eq!(reads[32 * 4 + 2], 0x0FF0);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let (mut ppu, _) = make_ppu();
+        // Tile 1 is solid color 1, placed at tile (2, 2) of the background
+        write_vram(&mut ppu, 0x0010, &[0xFF; 8]);
+        write_vram(&mut ppu, 0x2000 + 2 * 32 + 2, &[0x01]);
+        // Sprite 0 uses the same tile, overlapping its top-left corner
+        ppu.oam.fill(0xFF);
+        ppu.oam[..4].copy_from_slice(&[20, 1, 0, 20]);
+        write_vram(&mut ppu, 0x3F01, &[0x16]);
+        ppu.bus_write(PPUCTRL as u32, 0).unwrap();
+        ppu.bus_write(PPUSCROLL as u32, 0).unwrap();
+        ppu.bus_write(PPUSCROLL as u32, 0).unwrap();
+        ppu.bus_write(PPUMASK as u32, MASK_BG | MASK_SPRITES)
+            .unwrap();
+
+        // Scroll takes effect from the next frame's pre-render line
+        run_to(&mut ppu, 261, 0);
+        run_to(&mut ppu, 21, 0);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO, 0);
+        run_to(&mut ppu, 21, 30);
+        assert_eq!(ppu.status & STATUS_SPRITE_ZERO, STATUS_SPRITE_ZERO);
+        run_to(&mut ppu, 30, 0);
+        assert_eq!(ppu.frame()[16 * FRAME_WIDTH + 16], 0x16);
+        assert_eq!(ppu.frame()[16 * FRAME_WIDTH + 15], 0);
+    }
+
+    #[test]
+    fn test_region_timing() {
+        // PAL has 312 lines, and never skips a dot
+        let mut ppu = Ppu::new(
+            Box::new(RAMDevice::new(0x4000)),
+            WiredOrSignal::new().make_source("ppu"),
+            &ConsoleTiming::PAL,
+        );
+        ppu.power_on();
+        ppu.mask = MASK_BG;
+        ppu.odd_frame = true;
+        run_to(&mut ppu, 311, 340);
+        ppu.step_dot().unwrap();
+        assert_eq!(ppu.position(), (0, 0));
+
+        // Dendy holds vblank off until line 291
+        let mut ppu = Ppu::new(
+            Box::new(RAMDevice::new(0x4000)),
+            WiredOrSignal::new().make_source("ppu"),
+            &ConsoleTiming::DENDY,
+        );
+        ppu.power_on();
+        run_to(&mut ppu, 241, 2);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        run_to(&mut ppu, 291, 2);
+        assert_eq!(ppu.status & STATUS_VBLANK, STATUS_VBLANK);
+        run_to(&mut ppu, 311, 2);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn test_warmup() {
+        let (mut ppu, _) = make_ppu();
+        let restarts: [fn(&mut Ppu); 2] = [|ppu| ppu.power_on(), |ppu| ppu.reset()];
+        for restart in restarts {
+            restart(&mut ppu);
+            assert!(ppu.warming_up());
+            ppu.bus_write(PPUCTRL as u32, 0x03).unwrap();
+            ppu.bus_write(PPUMASK as u32, MASK_BG).unwrap();
+            ppu.bus_write(PPUSCROLL as u32, 0x0F).unwrap();
+            ppu.bus_write(PPUADDR as u32, 0x21).unwrap();
+            assert_eq!((ppu.ctrl, ppu.mask, ppu.t, ppu.fine_x), (0, 0, 0, 0));
+            assert!(!ppu.write_toggle);
+            // Other registers work as usual
+            ppu.bus_write(OAMADDR as u32, 0x10).unwrap();
+            assert_eq!(ppu.oam_addr, 0x10);
+
+            for _ in 0..ConsoleTiming::NTSC.ppu_warmup_cpu_cycles {
+                ppu.tick().unwrap();
+            }
+            assert!(!ppu.warming_up());
+            ppu.bus_write(PPUCTRL as u32, 0x03).unwrap();
+            ppu.bus_write(PPUMASK as u32, MASK_BG).unwrap();
+            assert_eq!((ppu.ctrl, ppu.mask), (0x03, MASK_BG));
+        }
+    }
+}
@@ -1,28 +1,40 @@
-use std::{cell::RefCell, rc::Rc};
+use crate::components::{console_event::ConsoleEvent, scheduler::EventQueue, signal::PulseSignal};
 
-use crate::components::signal::PulseSignal;
-
-#[derive(Debug, Default)]
-struct ResetControllerInner {
-    current_tick: u64,
-    next_reset_tick: Option<u64>,
+/// The two ways the console can be restarted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    /// The reset button. Only state wired to the reset line is reinitialized.
+    Reset,
+    /// Power was removed and reapplied. All state is reinitialized.
+    PowerCycle,
 }
 
+/// Owns the CPU's reset line. Restarts requested through a ResetSource are
+/// queued on the console's scheduler, which hands them back to be carried
+/// out when they come due.
 #[derive(Debug)]
 pub struct ResetController {
-    inner: Rc<RefCell<ResetControllerInner>>,
+    events: EventQueue<ConsoleEvent>,
+    /// Master clock cycles per CPU cycle, which ResetSource delays count in
+    cpu_divider: u64,
     reset_signal: PulseSignal,
 }
 
 #[derive(Clone, Debug)]
 pub struct ResetSource {
-    resetter: Rc<RefCell<ResetControllerInner>>,
+    events: EventQueue<ConsoleEvent>,
+    cpu_divider: u64,
 }
 
 impl ResetController {
-    pub fn new(reset_signal: PulseSignal) -> Self {
+    pub fn new(
+        reset_signal: PulseSignal,
+        events: EventQueue<ConsoleEvent>,
+        cpu_divider: u32,
+    ) -> Self {
         Self {
-            inner: Default::default(),
+            events,
+            cpu_divider: cpu_divider as u64,
             reset_signal,
         }
     }
@@ -31,35 +43,47 @@ impl ResetController {
         self.reset_signal.trigger()
     }
 
-    pub fn tick(&mut self) {
-        let mut inner = self.inner.borrow_mut();
-        inner.current_tick += 1;
-        if let Some(x) = inner.next_reset_tick
-            && x <= inner.current_tick
-        {
-            inner.next_reset_tick = None;
-            self.reset_signal.trigger();
+    /// Start a restart that came due. Resets are signalled to the CPU here;
+    /// the rest of the console is left to the caller.
+    pub fn handle(&mut self, kind: ResetKind) {
+        match kind {
+            ResetKind::Reset => self.trigger_reset(),
+            // A power cycle supersedes any pending reset
+            ResetKind::PowerCycle => self
+                .events
+                .cancel(|event| *event == ConsoleEvent::Restart(ResetKind::Reset)),
         }
     }
 
     pub fn make_reset_source(&mut self) -> ResetSource {
         ResetSource {
-            resetter: Rc::clone(&self.inner),
+            events: self.events.clone(),
+            cpu_divider: self.cpu_divider,
         }
     }
 }
 
 impl ResetSource {
+    /// Press the reset button delay_ticks CPU cycles from now
     pub fn schedule_reset(&self, delay_ticks: u64) {
-        let mut resetter = self.resetter.borrow_mut();
-        let reset_tick = resetter.current_tick + delay_ticks;
-
-        resetter.next_reset_tick = resetter
-            .next_reset_tick
-            .map_or(Some(reset_tick), |x| Some(std::cmp::min(x, reset_tick)))
+        self.events.schedule_in(
+            delay_ticks * self.cpu_divider,
+            ConsoleEvent::Restart(ResetKind::Reset),
+        );
     }
 
     pub fn trigger_reset(&self) {
         self.schedule_reset(0);
     }
+
+    pub fn schedule_power_cycle(&self, delay_ticks: u64) {
+        self.events.schedule_in(
+            delay_ticks * self.cpu_divider,
+            ConsoleEvent::Restart(ResetKind::PowerCycle),
+        );
+    }
+
+    pub fn trigger_power_cycle(&self) {
+        self.schedule_power_cycle(0);
+    }
 }
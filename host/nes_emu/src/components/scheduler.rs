@@ -0,0 +1,343 @@
+use std::{
+    cell::RefCell,
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    rc::Rc,
+};
+
+/// Identifies a clock registered with a Scheduler
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockId(usize);
+
+#[derive(Debug)]
+struct Clock {
+    /// Master clock cycles from one edge to the next
+    period: u64,
+    /// None until the clock has ticked for the first time
+    last_edge: Option<u64>,
+    next_edge: u64,
+}
+
+/// What the scheduler advanced to
+#[derive(Debug, PartialEq, Eq)]
+pub enum Tick<E> {
+    /// An edge of a registered clock
+    Clock(ClockId),
+    /// A one-shot event that came due
+    Event(E),
+}
+
+#[derive(Debug)]
+struct PendingEvent<E> {
+    cycle: u64,
+    /// Orders events due on the same cycle by when they were scheduled
+    seq: u64,
+    event: E,
+}
+
+impl<E> PendingEvent<E> {
+    fn key(&self) -> (u64, u64) {
+        (self.cycle, self.seq)
+    }
+}
+
+impl<E> PartialEq for PendingEvent<E> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl<E> Eq for PendingEvent<E> {}
+
+impl<E> PartialOrd for PendingEvent<E> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<E> Ord for PendingEvent<E> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+#[derive(Debug)]
+struct EventQueueInner<E> {
+    now: u64,
+    next_seq: u64,
+    pending: BinaryHeap<Reverse<PendingEvent<E>>>,
+}
+
+/// One-shot events waiting on a Scheduler. Clones share the same queue, so
+/// components can schedule events without a reference to the scheduler.
+#[derive(Debug)]
+pub struct EventQueue<E> {
+    inner: Rc<RefCell<EventQueueInner<E>>>,
+}
+
+impl<E> Clone for EventQueue<E> {
+    fn clone(&self) -> Self {
+        EventQueue {
+            inner: Rc::clone(&self.inner),
+        }
+    }
+}
+
+impl<E> EventQueue<E> {
+    fn new() -> Self {
+        EventQueue {
+            inner: Rc::new(RefCell::new(EventQueueInner {
+                now: 0,
+                next_seq: 0,
+                pending: BinaryHeap::new(),
+            })),
+        }
+    }
+
+    /// The master clock cycle the scheduler has reached
+    pub fn now(&self) -> u64 {
+        self.inner.borrow().now
+    }
+
+    /// Deliver event once the master clock reaches cycle. Events due on the
+    /// same cycle are delivered in the order they were scheduled, ahead of
+    /// any clock edge on that cycle.
+    pub fn schedule_at(&self, cycle: u64, event: E) {
+        let mut inner = self.inner.borrow_mut();
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner
+            .pending
+            .push(Reverse(PendingEvent { cycle, seq, event }));
+    }
+
+    /// Deliver event delay master clock cycles from now
+    pub fn schedule_in(&self, delay: u64, event: E) {
+        let cycle = self.now() + delay;
+        self.schedule_at(cycle, event);
+    }
+
+    /// Drop every pending event that matches
+    pub fn cancel(&self, mut matches: impl FnMut(&E) -> bool) {
+        self.inner
+            .borrow_mut()
+            .pending
+            .retain(|Reverse(pending)| !matches(&pending.event));
+    }
+
+    /// Whether any pending event matches
+    pub fn is_pending(&self, mut matches: impl FnMut(&E) -> bool) -> bool {
+        self.inner
+            .borrow()
+            .pending
+            .iter()
+            .any(|Reverse(pending)| matches(&pending.event))
+    }
+
+    fn next_due(&self) -> Option<u64> {
+        self.inner
+            .borrow()
+            .pending
+            .peek()
+            .map(|Reverse(pending)| pending.cycle)
+    }
+
+    fn pop(&self) -> Option<PendingEvent<E>> {
+        self.inner
+            .borrow_mut()
+            .pending
+            .pop()
+            .map(|Reverse(pending)| pending)
+    }
+
+    fn advance_to(&self, cycle: u64) {
+        let mut inner = self.inner.borrow_mut();
+        inner.now = inner.now.max(cycle);
+    }
+}
+
+/// Drives components that run at different divisions of one master clock.
+/// Each registers a clock with its divider and phase, and the scheduler
+/// hands out their edges in master clock order, interleaved with one-shot
+/// events from its EventQueue.
+///
+/// Clock periods can change from one edge to the next, for processors
+/// whose cycle length depends on what they access, like the SNES CPU's 6,
+/// 8 or 12 master clocks.
+#[derive(Debug)]
+pub struct Scheduler<E> {
+    clocks: Vec<Clock>,
+    events: EventQueue<E>,
+}
+
+impl<E> Default for Scheduler<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<E> Scheduler<E> {
+    pub fn new() -> Self {
+        Scheduler {
+            clocks: Vec::new(),
+            events: EventQueue::new(),
+        }
+    }
+
+    /// Register a clock that ticks every divider master clock cycles, with
+    /// its first edge on cycle phase. Edges that fall on the same cycle come
+    /// out in the order their clocks were added.
+    pub fn add_clock(&mut self, divider: u32, phase: u32) -> ClockId {
+        assert!(divider > 0);
+        self.clocks.push(Clock {
+            period: divider as u64,
+            last_edge: None,
+            next_edge: self.master_cycle() + phase as u64,
+        });
+        ClockId(self.clocks.len() - 1)
+    }
+
+    /// Change the length of a clock's current period, counted from its last
+    /// edge. Later periods keep the new length until it's changed again.
+    /// Before the first edge, that edge stays on its phase and only the
+    /// periods after it change.
+    pub fn set_period(&mut self, id: ClockId, period: u32) {
+        assert!(period > 0);
+        let clock = &mut self.clocks[id.0];
+        clock.period = period as u64;
+        if let Some(last_edge) = clock.last_edge {
+            clock.next_edge = (last_edge + clock.period).max(self.events.now());
+        }
+    }
+
+    /// Handle for scheduling one-shot events
+    pub fn event_queue(&self) -> EventQueue<E> {
+        self.events.clone()
+    }
+
+    /// Master clock cycles since the scheduler was created
+    pub fn master_cycle(&self) -> u64 {
+        self.events.now()
+    }
+
+    /// Advance the master clock to the next clock edge or due event, and
+    /// report which it was
+    pub fn advance(&mut self) -> Tick<E> {
+        let (index, clock) = self
+            .clocks
+            .iter_mut()
+            .enumerate()
+            .min_by_key(|(_, clock)| clock.next_edge)
+            .expect("scheduler has no clocks");
+        if let Some(due) = self.events.next_due()
+            && due <= clock.next_edge
+        {
+            let pending = self.events.pop().unwrap();
+            self.events.advance_to(pending.cycle);
+            return Tick::Event(pending.event);
+        }
+        self.events.advance_to(clock.next_edge);
+        clock.last_edge = Some(clock.next_edge);
+        clock.next_edge += clock.period;
+        Tick::Clock(ClockId(index))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Run until the given clock has ticked count times, listing every tick
+    /// along the way with the master cycle it happened on
+    fn run_clock(
+        scheduler: &mut Scheduler<&'static str>,
+        id: ClockId,
+        count: usize,
+    ) -> Vec<(u64, Tick<&'static str>)> {
+        let mut ticks = Vec::new();
+        let mut seen = 0;
+        while seen < count {
+            let tick = scheduler.advance();
+            if tick == Tick::Clock(id) {
+                seen += 1;
+            }
+            ticks.push((scheduler.master_cycle(), tick));
+        }
+        ticks
+    }
+
+    #[test]
+    fn test_dividers() {
+        // PAL: 16 dots for every 5 CPU cycles
+        let mut scheduler = Scheduler::new();
+        let ppu = scheduler.add_clock(5, 0);
+        let cpu = scheduler.add_clock(16, 15);
+        let ticks = run_clock(&mut scheduler, cpu, 5);
+        let dots = ticks
+            .iter()
+            .filter(|(_, tick)| *tick == Tick::Clock(ppu))
+            .count();
+        assert_eq!(dots, 16);
+        assert_eq!(scheduler.master_cycle(), 79);
+
+        // Edges on the same cycle come out in the order the clocks were added
+        assert_eq!(
+            ticks[3..5],
+            [(15, Tick::Clock(ppu)), (15, Tick::Clock(cpu))]
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let mut scheduler = Scheduler::new();
+        let cpu = scheduler.add_clock(12, 0);
+        let events = scheduler.event_queue();
+        events.schedule_in(24, "second");
+        events.schedule_at(24, "third");
+        events.schedule_at(13, "first");
+        events.schedule_at(30, "cancelled");
+        events.cancel(|event| *event == "cancelled");
+        assert!(events.is_pending(|event| *event == "first"));
+        assert!(!events.is_pending(|event| *event == "cancelled"));
+
+        let ticks = run_clock(&mut scheduler, cpu, 4);
+        assert_eq!(
+            ticks,
+            [
+                (0, Tick::Clock(cpu)),
+                (12, Tick::Clock(cpu)),
+                (13, Tick::Event("first")),
+                (24, Tick::Event("second")),
+                (24, Tick::Event("third")),
+                (24, Tick::Clock(cpu)),
+                (36, Tick::Clock(cpu)),
+            ]
+        );
+
+        // Delays count from the cycle reached
+        events.schedule_in(1, "later");
+        assert_eq!(scheduler.advance(), Tick::Event("later"));
+        assert_eq!(scheduler.master_cycle(), 37);
+    }
+
+    #[test]
+    fn test_variable_period() {
+        // The SNES CPU takes 6, 8 or 12 master clocks depending on the access
+        let mut scheduler = Scheduler::<()>::new();
+        let cpu = scheduler.add_clock(6, 0);
+        let mut edges = Vec::new();
+        for period in [8, 6, 12, 12, 8] {
+            assert_eq!(scheduler.advance(), Tick::Clock(cpu));
+            edges.push(scheduler.master_cycle());
+            scheduler.set_period(cpu, period);
+        }
+        assert_eq!(edges, [0, 8, 14, 26, 38]);
+
+        // Changing the period before the first edge leaves that edge alone
+        let mut scheduler = Scheduler::new();
+        let cpu = scheduler.add_clock(6, 3);
+        scheduler.set_period(cpu, 8);
+        let ticks = run_clock(&mut scheduler, cpu, 2);
+        assert_eq!(ticks, [(3, Tick::Clock(cpu)), (11, Tick::Clock(cpu))]);
+    }
+}
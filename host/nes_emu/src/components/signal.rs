@@ -1,4 +1,4 @@
-use std::cell::Cell;
+use std::cell::{Cell, RefCell};
 use std::rc::Rc;
 
 /// Driver for a level-oriented signal. Emulator components can use this to
@@ -43,8 +43,9 @@ impl LevelReceiver {
 }
 
 /// Driver for a pulse-oriented signal. Roughly corresponds to an edge-triggered
-/// interrupt in the actual design.
-#[derive(Debug, Default)]
+/// interrupt in the actual design. Clones drive the same signal, so several
+/// components can pulse it.
+#[derive(Clone, Debug, Default)]
 pub struct PulseSignal {
     pulse_id: Rc<Cell<u64>>,
 }
@@ -93,3 +94,120 @@ impl PulseReceiver {
         self.pulse_id.get() != self.last_pulse_id
     }
 }
+
+#[derive(Debug, Default)]
+struct WiredOrState {
+    /// One bit per source, set while that source asserts the line
+    asserted: Cell<u32>,
+    names: RefCell<Vec<String>>,
+}
+
+/// An open-drain line shared by several drivers, like the CPU's IRQ input.
+/// Each driver gets its own named WiredOrSource, and the line is asserted
+/// while any of them is, so one source releasing it can't hide another.
+#[derive(Debug, Default)]
+pub struct WiredOrSignal {
+    state: Rc<WiredOrState>,
+}
+
+/// One driver of a WiredOrSignal
+#[derive(Debug)]
+pub struct WiredOrSource {
+    state: Rc<WiredOrState>,
+    mask: u32,
+}
+
+/// Object for observing a WiredOrSignal, and which sources are driving it
+#[derive(Clone, Debug)]
+pub struct WiredOrReceiver {
+    state: Rc<WiredOrState>,
+}
+
+impl WiredOrSignal {
+    /// Create a new WiredOrSignal, with no sources
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a source that can assert the line. name identifies it in
+    /// traces. A line has room for 32 sources.
+    pub fn make_source(&mut self, name: &str) -> WiredOrSource {
+        let mut names = self.state.names.borrow_mut();
+        assert!(names.len() < 32, "too many sources for one line");
+        names.push(name.to_string());
+        WiredOrSource {
+            state: Rc::clone(&self.state),
+            mask: 1 << (names.len() - 1),
+        }
+    }
+
+    /// Create a new receiver for this signal
+    pub fn make_receiver(&mut self) -> WiredOrReceiver {
+        WiredOrReceiver {
+            state: Rc::clone(&self.state),
+        }
+    }
+}
+
+impl WiredOrSource {
+    /// Assert or release the line on behalf of this source
+    pub fn set(&mut self, value: bool) {
+        let asserted = self.state.asserted.get();
+        self.state.asserted.set(if value {
+            asserted | self.mask
+        } else {
+            asserted & !self.mask
+        });
+    }
+
+    /// Create a receiver for the whole line this source drives
+    pub fn make_receiver(&self) -> WiredOrReceiver {
+        WiredOrReceiver {
+            state: Rc::clone(&self.state),
+        }
+    }
+}
+
+impl WiredOrReceiver {
+    /// Whether any source is asserting the line
+    pub fn get(&self) -> bool {
+        self.state.asserted.get() != 0
+    }
+
+    /// Names of the sources asserting the line
+    pub fn asserting(&self) -> Vec<String> {
+        let asserted = self.state.asserted.get();
+        self.state
+            .names
+            .borrow()
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| asserted & (1 << index) != 0)
+            .map(|(_, name)| name.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_wired_or() {
+        let mut signal = WiredOrSignal::new();
+        let receiver = signal.make_receiver();
+        let mut apu = signal.make_source("apu.frame");
+        let mut cart = signal.make_source("cart");
+        assert!(!receiver.get());
+
+        apu.set(true);
+        cart.set(true);
+        assert_eq!(receiver.asserting(), ["apu.frame", "cart"]);
+        // The line stays asserted until every source lets go
+        apu.set(false);
+        assert!(receiver.get());
+        assert_eq!(receiver.asserting(), ["cart"]);
+        cart.set(false);
+        assert!(!receiver.get());
+    }
+}
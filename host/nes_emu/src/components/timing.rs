@@ -0,0 +1,162 @@
+use super::apu::ApuRates;
+use crate::{nes_file::TimingMode, nsf::NsfRegion};
+
+/// The console a ROM runs on, which sets every clock rate in the system
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    #[default]
+    Ntsc,
+    Pal,
+    /// Famiclone common in Russia: a PAL-rate PPU frame with NTSC-like vblank
+    /// and APU tables, and a CPU divider picked to keep music close to NTSC
+    /// pitch
+    Dendy,
+}
+
+impl Region {
+    /// The region to run a ROM in. Multi-region ROMs adapt to either, so run
+    /// them as NTSC.
+    pub fn from_timing_mode(timing: TimingMode) -> Self {
+        match timing {
+            TimingMode::NTSC | TimingMode::MultiRegion => Region::Ntsc,
+            TimingMode::PAL => Region::Pal,
+            TimingMode::Dendy => Region::Dendy,
+        }
+    }
+
+    /// The region to play an NSF in, once a dual-region tune has been
+    /// assigned one
+    pub fn from_nsf_region(region: NsfRegion) -> Self {
+        match region {
+            NsfRegion::Pal => Region::Pal,
+            NsfRegion::Ntsc | NsfRegion::Dual => Region::Ntsc,
+        }
+    }
+
+    pub fn timing(self) -> &'static ConsoleTiming {
+        match self {
+            Region::Ntsc => &ConsoleTiming::NTSC,
+            Region::Pal => &ConsoleTiming::PAL,
+            Region::Dendy => &ConsoleTiming::DENDY,
+        }
+    }
+}
+
+/// Clock rates and frame shape for one region. Every component is driven
+/// from a single master clock divided down, so this is the one place they
+/// take their timing from.
+#[derive(Debug, PartialEq)]
+pub struct ConsoleTiming {
+    pub region: Region,
+    pub master_clock_hz: f64,
+    /// Master clock cycles per CPU cycle
+    pub cpu_divider: u32,
+    /// Master clock cycles per PPU dot
+    pub ppu_divider: u32,
+    /// Scanlines per frame, including the pre-render line
+    pub scanlines: u16,
+    /// Scanline whose second dot sets the vblank flag
+    pub vblank_scanline: u16,
+    /// Odd frames drop a dot from the pre-render line while rendering
+    pub odd_frame_skip: bool,
+    /// CPU cycles after power-on or reset that the PPU ignores writes for,
+    /// until it reaches the end of the first frame
+    pub ppu_warmup_cpu_cycles: u32,
+    pub apu_rates: &'static ApuRates,
+}
+
+impl ConsoleTiming {
+    pub const NTSC: ConsoleTiming = ConsoleTiming {
+        region: Region::Ntsc,
+        master_clock_hz: 236_250_000.0 / 11.0,
+        cpu_divider: 12,
+        ppu_divider: 4,
+        scanlines: 262,
+        vblank_scanline: 241,
+        odd_frame_skip: true,
+        ppu_warmup_cpu_cycles: 29658,
+        apu_rates: &ApuRates::NTSC,
+    };
+
+    pub const PAL: ConsoleTiming = ConsoleTiming {
+        region: Region::Pal,
+        master_clock_hz: 26_601_712.5,
+        cpu_divider: 16,
+        ppu_divider: 5,
+        scanlines: 312,
+        vblank_scanline: 241,
+        odd_frame_skip: false,
+        ppu_warmup_cpu_cycles: 33132,
+        apu_rates: &ApuRates::PAL,
+    };
+
+    /// Same crystal and PPU as PAL, but vblank starts 50 lines later so it's
+    /// as long as NTSC's, and the APU keeps NTSC's tables
+    pub const DENDY: ConsoleTiming = ConsoleTiming {
+        region: Region::Dendy,
+        master_clock_hz: 26_601_712.5,
+        cpu_divider: 15,
+        ppu_divider: 5,
+        scanlines: 312,
+        vblank_scanline: 291,
+        odd_frame_skip: false,
+        ppu_warmup_cpu_cycles: 35341,
+        apu_rates: &ApuRates::NTSC,
+    };
+
+    pub const fn cpu_clock_hz(&self) -> f64 {
+        self.master_clock_hz / self.cpu_divider as f64
+    }
+
+    pub fn pre_render_scanline(&self) -> u16 {
+        self.scanlines - 1
+    }
+
+    /// Scanlines from the vblank flag being set to the pre-render line
+    pub fn vblank_scanlines(&self) -> u16 {
+        self.pre_render_scanline() - self.vblank_scanline
+    }
+
+    /// CPU cycles in the given number of milliseconds
+    pub fn ms_to_cpu_cycles(&self, ms: u64) -> u64 {
+        (self.cpu_clock_hz() * ms as f64 / 1000.0) as u64
+    }
+
+    /// CPU cycles in the given number of microseconds
+    pub fn us_to_cpu_cycles(&self, us: u64) -> u64 {
+        (self.cpu_clock_hz() * us as f64 / 1_000_000.0) as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clock_rates() {
+        assert_eq!(ConsoleTiming::NTSC.cpu_clock_hz().round(), 1_789_773.0);
+        assert_eq!(ConsoleTiming::PAL.cpu_clock_hz().round(), 1_662_607.0);
+        assert_eq!(ConsoleTiming::DENDY.cpu_clock_hz().round(), 1_773_448.0);
+        assert_eq!(ConsoleTiming::NTSC.ms_to_cpu_cycles(100), 178_977);
+        assert_eq!(ConsoleTiming::PAL.us_to_cpu_cycles(20), 33);
+
+        assert_eq!(ConsoleTiming::NTSC.vblank_scanlines(), 20);
+        assert_eq!(ConsoleTiming::PAL.vblank_scanlines(), 70);
+        assert_eq!(ConsoleTiming::DENDY.vblank_scanlines(), 20);
+    }
+
+    #[test]
+    fn test_from_timing_mode() {
+        assert_eq!(
+            Region::from_timing_mode(TimingMode::MultiRegion),
+            Region::Ntsc
+        );
+        assert_eq!(
+            Region::from_timing_mode(TimingMode::PAL)
+                .timing()
+                .cpu_divider,
+            16
+        );
+        assert_eq!(Region::from_timing_mode(TimingMode::Dendy), Region::Dendy);
+    }
+}
@@ -21,15 +21,18 @@ impl<'a, T: TraceableValue + ?Sized> fmt::Display for TraceDisplay<'a, T> {
     }
 }
 
-/// A single element in the trace hierarchy. The existence of a TraceElement
-/// node indicates that a particular part of the hierarchy is being traced.
+/// A single element in the trace hierarchy. Every component's elements
+/// exist whether or not they're being traced, so tracing can be turned on
+/// and off while running.
 #[derive(Debug)]
 struct TraceElement {
     /// The full hierarchical name of this element
     full_name: String,
     /// A table of all children of this element, keyed by their name
     children: HashMap<String, TraceElementId>,
-    /// Whether all children of this element are also enabled for tracing
+    /// Whether this element is enabled for emitting trace data
+    enabled: bool,
+    /// Whether children registered later start out enabled
     all_children_enabled: bool,
 }
 
@@ -37,17 +40,8 @@ struct TraceElement {
 #[derive(Debug, Clone, Copy)]
 pub struct TraceElementId(usize);
 
-impl TraceElementId {
-    /// Whether this element is enabled for emitting trace data
-    pub fn enabled(&self) -> bool {
-        self.0 != usize::MAX
-    }
-}
 /// Handle for the invisible unnamed root element
 const ROOT_ELEMENT_ID: TraceElementId = TraceElementId(0);
-/// Handle for a disabled trace element. Disabled elements are ignored when
-/// emitting trace data
-const DISABLED_ELEMENT_ID: TraceElementId = TraceElementId(usize::MAX);
 
 /// Inner data for the tracer. Separated out from the root tracer object
 /// to allow for interior mutability, specifically to enable writing to
@@ -94,97 +88,65 @@ impl Tracer {
     /// If trace_file is provided, it will be used for writing trace data.
     /// Otherwise, trace data will be written to stdout.
     pub fn new<T: AsRef<str>>(enabled_trace_elements: &[T], trace_file: Option<File>) -> Self {
-        let mut elements = vec![TraceElement {
-            full_name: "".to_string(),
-            children: HashMap::new(),
-            all_children_enabled: false,
-        }];
-
-        for expr in enabled_trace_elements {
-            let mut parent = ROOT_ELEMENT_ID;
-            for part in expr.as_ref().split('.') {
-                if let Some(existing_id) = elements[parent.0].children.get(part) {
-                    parent = *existing_id;
-                } else {
-                    let child_id = TraceElementId(elements.len());
-                    let parent_data = &mut elements[parent.0];
-                    parent_data.children.insert(part.to_string(), child_id);
-                    let full_name = format_trace_name(&parent_data.full_name, part);
-                    elements.push(TraceElement {
-                        full_name,
-                        children: HashMap::new(),
-                        all_children_enabled: false,
-                    });
-                    parent = child_id;
-                }
-            }
-            // All children of a leaf should be enabled for tracing
-            elements[parent.0].all_children_enabled = true;
-        }
-
-        let trace_writer = trace_file.map(BufWriter::new);
-
-        Tracer {
+        let tracer = Tracer {
             data: RefCell::new(TraceData {
-                elements,
-                trace_writer,
+                elements: vec![TraceElement {
+                    full_name: "".to_string(),
+                    children: HashMap::new(),
+                    enabled: false,
+                    all_children_enabled: false,
+                }],
+                trace_writer: trace_file.map(BufWriter::new),
             }),
+        };
+        for expr in enabled_trace_elements {
+            tracer.set_enabled(expr.as_ref(), true);
         }
+        tracer
     }
 
     /// Register a new trace element
     /// Returns an ID which can be used in future trace calls
     pub fn register_element(&self, name: &str, parent: Option<TraceElementId>) -> TraceElementId {
         let parent = parent.unwrap_or(ROOT_ELEMENT_ID);
-        if !parent.enabled() {
-            // Child is disabled if the parent is not enabled
-            return DISABLED_ELEMENT_ID;
-        }
+        self.data.borrow_mut().child(parent, name)
+    }
 
+    /// Turn tracing on or off for the element with the given dot-separated
+    /// name and all of its children, including ones not registered yet.
+    /// Enabling an element also enables the elements above it.
+    pub fn set_enabled(&self, name: &str, enabled: bool) {
         let mut data = self.data.borrow_mut();
-        // Get the next element index proactively. Needs to happen before
-        // reading parent data to avoid lifetime conflicts
-        let next_element_idx = data.elements.len();
-
-        // Look up the parent
-        let parent_data = &mut data.elements[parent.0];
-
-        if let Some(existing_id) = parent_data.children.get(name) {
-            // Element was pre-created, return it
-            *existing_id
-        } else if parent_data.all_children_enabled {
-            // Element does not exist, but the parent is set to automatically
-            // create and enable any additional children.
-            // Create the element now.
-
-            let child_element_id = TraceElementId(next_element_idx);
-            parent_data
-                .children
-                .insert(name.to_string(), child_element_id);
-            let full_name = format_trace_name(&parent_data.full_name, name);
-            data.elements.push(TraceElement {
-                full_name,
-                children: HashMap::new(),
-                all_children_enabled: true,
-            });
-
-            child_element_id
-        } else {
-            // Element does not already exist and its parent is not accepting new
-            // elements. Return a disabled element.
-            DISABLED_ELEMENT_ID
+        let mut element = ROOT_ELEMENT_ID;
+        for part in name.split('.') {
+            element = data.child(element, part);
+            if enabled {
+                data.elements[element.0].enabled = true;
+            }
         }
+        data.set_subtree_enabled(element, enabled);
+    }
+
+    /// Full names of every element, and whether each is enabled
+    pub fn elements(&self) -> Vec<(String, bool)> {
+        let data = self.data.borrow();
+        let mut elements: Vec<_> = data.elements[1..]
+            .iter()
+            .map(|element| (element.full_name.clone(), element.enabled))
+            .collect();
+        elements.sort();
+        elements
     }
 
     /// Emit an event to the trace log. The message argument is the message to emit
     /// into the trace log, created using the format_args!() macro.
     pub fn trace_event(&self, element: TraceElementId, message: fmt::Arguments) {
-        if !element.enabled() {
-            return;
-        }
         let mut data = self.data.borrow_mut();
         let data = &mut *data;
         let element_data = &data.elements[element.0];
+        if !element_data.enabled {
+            return;
+        }
         if let Some(writer) = &mut data.trace_writer {
             let _ = writeln!(
                 writer,
@@ -197,6 +159,38 @@ impl Tracer {
     }
 }
 
+impl TraceData {
+    /// Look up a child element, creating it if it doesn't exist yet. New
+    /// elements are enabled if their parent enables all of its children.
+    fn child(&mut self, parent: TraceElementId, name: &str) -> TraceElementId {
+        if let Some(existing_id) = self.elements[parent.0].children.get(name) {
+            return *existing_id;
+        }
+        let child_id = TraceElementId(self.elements.len());
+        let parent_data = &mut self.elements[parent.0];
+        parent_data.children.insert(name.to_string(), child_id);
+        let full_name = format_trace_name(&parent_data.full_name, name);
+        let enabled = parent_data.all_children_enabled;
+        self.elements.push(TraceElement {
+            full_name,
+            children: HashMap::new(),
+            enabled,
+            all_children_enabled: enabled,
+        });
+        child_id
+    }
+
+    fn set_subtree_enabled(&mut self, element: TraceElementId, enabled: bool) {
+        let element_data = &mut self.elements[element.0];
+        element_data.enabled = enabled;
+        element_data.all_children_enabled = enabled;
+        let children: Vec<_> = element_data.children.values().copied().collect();
+        for child in children {
+            self.set_subtree_enabled(child, enabled);
+        }
+    }
+}
+
 fn format_trace_name(parent_name: &str, element_name: &str) -> String {
     if parent_name.is_empty() {
         element_name.to_string()
@@ -310,3 +304,41 @@ impl TraceableValue for u16 {
         write!(f, "0x{:04X}", self)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_set_enabled() {
+        let tracer = Tracer::new(&["cpu.regs"], None);
+        let cpu = tracer.register_element("cpu", None);
+        let mem = tracer.register_element("mem", Some(cpu));
+        let regs = tracer.register_element("regs", Some(cpu));
+        let a = tracer.register_element("a", Some(regs));
+        let enabled = |name: &str| {
+            tracer
+                .elements()
+                .into_iter()
+                .find(|(full_name, _)| full_name == name)
+                .unwrap()
+                .1
+        };
+        assert!(enabled("cpu"));
+        assert!(!enabled("cpu.mem"));
+        assert!(enabled("cpu.regs.a"));
+
+        tracer.set_enabled("cpu.regs", false);
+        tracer.set_enabled("cpu.mem", true);
+        assert!(!enabled("cpu.regs.a"));
+        assert!(enabled("cpu.mem"));
+        // Elements registered later follow their parent
+        tracer.register_element("x", Some(regs));
+        assert!(!enabled("cpu.regs.x"));
+        tracer.register_element("page", Some(mem));
+        assert!(enabled("cpu.mem.page"));
+
+        // Disabled elements emit nothing, so this just mustn't panic
+        tracer.trace_event(a, format_args!("A = 0x00"));
+    }
+}
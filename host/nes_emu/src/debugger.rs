@@ -0,0 +1,798 @@
+use std::{
+    fs,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use crate::{
+    components::{
+        EmuResult,
+        apu::ChannelState,
+        breakpoint::{BreakpointId, Condition, Trigger, WatchAccess},
+        cpu::{Interrupt, disassemble},
+        tracer::Tracer,
+    },
+    nes::{NESSystem, StopReason},
+};
+
+const RTS_OPCODE: u8 = 0x60;
+
+/// Most instructions disassemble shows before PC
+const DISASM_CONTEXT: usize = 3;
+
+const HELP: &[&str] = &[
+    "step [N]                 run N instructions (s)",
+    "cycle [N]                run N CPU cycles",
+    "continue [CYCLES]        run until something stops the console (c)",
+    "finish                   run until the current subroutine returns",
+    "frame [N]                run until N more frames have finished",
+    "break ADDR [bank N] [if COND]",
+    "                         stop at the instruction at ADDR (b)",
+    "break nmi|irq|reset [if COND]",
+    "                         stop at the start of an interrupt handler",
+    "watch [r|w|x] ADDR[-END] [if COND]",
+    "                         stop on accesses to memory, writes by default",
+    "list                     show breakpoints and watchpoints",
+    "delete|enable|disable ID",
+    "regs                     show CPU registers (r)",
+    "disasm [ADDR] [N]        disassemble N instructions, around PC by default (d)",
+    "mem ADDR [LEN]           dump memory (m)",
+    "set ADDR BYTE...         write memory, as the CPU would",
+    "bus                      show what's mapped on the CPU bus",
+    "ppu                      show PPU registers and position",
+    "apu                      show APU channels and frame counter",
+    "trace [on|off ELEMENT]   list trace elements, or turn one on or off",
+    "reset | power            press reset, or power cycle",
+    "source FILE              run the commands in FILE",
+    "history [FILE]           show the commands run so far, or save them as a script",
+    "quit                     leave the debugger (q)",
+    "",
+    "Numbers are decimal, or hex with a $ or 0x prefix. Conditions are",
+    "expressions like `A == $40 && [$0300] > 2`. An empty line repeats the",
+    "last command.",
+];
+
+/// A command-line debugger for a console. Commands are given as lines of
+/// text, and the lines they print go to an output function; reading them
+/// in is left to the caller.
+pub struct Debugger<'a, 't> {
+    nes: &'a mut NESSystem<'t>,
+    tracer: &'t Tracer,
+    output: Box<dyn FnMut(&str) + 'a>,
+    /// Set, usually by a signal handler, to stop whatever's running
+    interrupt: &'a AtomicBool,
+    /// Every command run from the prompt, for saving as a script
+    history: Vec<String>,
+    quit: bool,
+    /// Whether a script is being run, so it can't source another
+    sourcing: bool,
+}
+
+impl<'a, 't> Debugger<'a, 't> {
+    /// Debug a console whose simulation has been started, printing with
+    /// output. Setting interrupt stops the command being run, and is cleared
+    /// when the next one starts.
+    pub fn new(
+        nes: &'a mut NESSystem<'t>,
+        tracer: &'t Tracer,
+        interrupt: &'a AtomicBool,
+        output: impl FnMut(&str) + 'a,
+    ) -> Self {
+        Debugger {
+            nes,
+            tracer,
+            output: Box::new(output),
+            interrupt,
+            history: Vec::new(),
+            quit: false,
+            sourcing: false,
+        }
+    }
+
+    /// Whether the quit command has been run
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Run one line from the prompt. Errors are printed rather than
+    /// returned.
+    pub fn execute(&mut self, line: &str) {
+        let line = line.trim();
+        let line = if line.is_empty() {
+            match self.history.last() {
+                Some(last) => last.clone(),
+                None => return,
+            }
+        } else {
+            if !line.starts_with("history") {
+                self.history.push(line.to_string());
+            }
+            line.to_string()
+        };
+        self.interrupt.store(false, Ordering::Relaxed);
+        if let Err(e) = self.command(&line) {
+            self.print(format!("error: {}", e));
+        }
+    }
+
+    fn print(&mut self, line: String) {
+        (self.output)(&line);
+    }
+
+    fn command(&mut self, line: &str) -> Result<(), String> {
+        let (command, args) = line
+            .split_once(char::is_whitespace)
+            .map_or((line, ""), |(command, args)| (command, args.trim()));
+        match command {
+            "help" | "h" | "?" => {
+                for line in HELP {
+                    self.print(line.to_string());
+                }
+            }
+            "step" | "s" => {
+                let count = parse_count(args)?;
+                self.run_steps(count, |nes| nes.step_instruction())?;
+            }
+            "cycle" => {
+                let count = parse_count(args)?;
+                self.run_steps(count, |nes| nes.step_cycle())?;
+            }
+            "continue" | "c" => {
+                let limit = if args.is_empty() {
+                    None
+                } else {
+                    Some(self.nes.get_tick_count() + parse_number(args)? as u64)
+                };
+                self.run(|nes| {
+                    limit
+                        .is_some_and(|limit| nes.get_tick_count() >= limit)
+                        .then_some(StopReason::LimitReached)
+                })?;
+            }
+            "finish" => self.finish()?,
+            "frame" => {
+                let frame = self.nes.frame_count() + parse_count(args)?;
+                self.run(|nes| (nes.frame_count() >= frame).then_some(StopReason::FrameReached))?;
+            }
+            "break" | "b" => self.add_breakpoint(args)?,
+            "watch" | "w" => self.add_watchpoint(args)?,
+            "list" | "l" => self.list_breakpoints(),
+            "delete" => {
+                let id = parse_id(args)?;
+                if !self.nes.breakpoints().remove(id) {
+                    return Err(format!("no breakpoint {}", id));
+                }
+            }
+            "enable" | "disable" => {
+                let id = parse_id(args)?;
+                if !self.nes.breakpoints().set_enabled(id, command == "enable") {
+                    return Err(format!("no breakpoint {}", id));
+                }
+            }
+            "regs" | "r" => self.show_regs(),
+            "disasm" | "d" => self.disasm(args)?,
+            "mem" | "m" => self.dump_memory(args)?,
+            "set" => self.set_memory(args)?,
+            "bus" => self.show_bus_map(),
+            "ppu" => self.show_ppu(),
+            "apu" => self.show_apu(),
+            "trace" => self.trace(args)?,
+            "reset" => self.nes.reset(),
+            "power" => self.nes.power_cycle(),
+            "source" => self.source(args)?,
+            "history" => self.history(args)?,
+            "quit" | "q" | "exit" => self.quit = true,
+            _ => return Err(format!("unknown command `{}`, try help", command)),
+        }
+        Ok(())
+    }
+
+    /// Run step up to count times, stopping early for anything other than
+    /// the step finishing
+    fn run_steps(
+        &mut self,
+        count: u64,
+        mut step: impl FnMut(&mut NESSystem<'t>) -> EmuResult<StopReason>,
+    ) -> Result<(), String> {
+        let mut result = Ok(StopReason::Stepped);
+        for _ in 0..count {
+            if self.interrupted() {
+                result = Ok(StopReason::Interrupted);
+                break;
+            }
+            result = step(self.nes);
+            if result != Ok(StopReason::Stepped) {
+                break;
+            }
+        }
+        self.report(result)
+    }
+
+    /// Run instructions until an RTS pops the return address of the
+    /// subroutine being run
+    fn finish(&mut self) -> Result<(), String> {
+        let start_s = *self.nes.get_regs().s;
+        let mut opcode = self.nes.peek(*self.nes.get_regs().pc);
+        self.run(|nes| {
+            if !nes.at_instruction_boundary() {
+                return None;
+            }
+            if opcode == Some(RTS_OPCODE) && *nes.get_regs().s > start_s {
+                return Some(StopReason::Stepped);
+            }
+            opcode = nes.peek(*nes.get_regs().pc);
+            None
+        })
+    }
+
+    /// Run the console until check or something else stops it, or the
+    /// debugger is interrupted, and report where it stopped
+    fn run(
+        &mut self,
+        mut check: impl FnMut(&NESSystem<'t>) -> Option<StopReason>,
+    ) -> Result<(), String> {
+        let interrupt = self.interrupt;
+        let result = self.nes.run_checked(|nes| {
+            if interrupt.load(Ordering::Relaxed) {
+                Some(StopReason::Interrupted)
+            } else {
+                check(nes)
+            }
+        });
+        self.report(result)
+    }
+
+    fn interrupted(&self) -> bool {
+        self.interrupt.load(Ordering::Relaxed)
+    }
+
+    /// Print why the console stopped, and where
+    fn report(&mut self, result: EmuResult<StopReason>) -> Result<(), String> {
+        let reason = result.map_err(|e| format!("emulation error: {}", e))?;
+        match reason {
+            StopReason::Stepped | StopReason::ConditionMet => {}
+            StopReason::FrameReached => {
+                self.print(format!("Frame {}", self.nes.frame_count()));
+            }
+            StopReason::LimitReached => {
+                self.print(format!("Cycle {}", self.nes.get_tick_count()));
+            }
+            StopReason::TestPassed => self.print("Test ROM passed".to_string()),
+            StopReason::Interrupted => self.print("Interrupted".to_string()),
+            StopReason::TestFailed(code) => {
+                self.print(format!("Test ROM reported failure with code {}", code));
+            }
+            StopReason::Jam(opcode) => {
+                self.print(format!("CPU jammed on opcode ${:02X}", opcode));
+            }
+            StopReason::Breakpoint(id) => {
+                let trigger = self
+                    .nes
+                    .breakpoints()
+                    .list()
+                    .into_iter()
+                    .find(|(bp_id, _)| *bp_id == id)
+                    .map(|(_, bp)| bp.trigger.to_string())
+                    .unwrap_or_default();
+                self.print(format!("Breakpoint {}, {}", id, trigger));
+            }
+        }
+        let pc = *self.nes.get_regs().pc;
+        let (text, _) = self.format_instruction(pc);
+        self.print(text);
+        Ok(())
+    }
+
+    fn add_breakpoint(&mut self, args: &str) -> Result<(), String> {
+        let (spec, condition) = split_condition(args)?;
+        let mut words = spec.split_whitespace();
+        let target = words.next().ok_or("expected an address or interrupt")?;
+        let trigger = match target.to_ascii_lowercase().as_str() {
+            "nmi" => Trigger::Interrupt(Interrupt::Nmi),
+            "irq" => Trigger::Interrupt(Interrupt::Irq),
+            "reset" => Trigger::Interrupt(Interrupt::Reset),
+            _ => {
+                let addr = parse_addr(target)?;
+                let bank = match words.next() {
+                    Some("bank") => {
+                        let bank = words.next().ok_or("expected a bank number")?;
+                        Some(parse_number(bank)? as usize)
+                    }
+                    Some(word) => return Err(format!("unexpected `{}`", word)),
+                    None => None,
+                };
+                Trigger::Pc { addr, bank }
+            }
+        };
+        if let Some(word) = words.next() {
+            return Err(format!("unexpected `{}`", word));
+        }
+        self.add_trigger(trigger, condition);
+        Ok(())
+    }
+
+    fn add_watchpoint(&mut self, args: &str) -> Result<(), String> {
+        let (spec, condition) = split_condition(args)?;
+        let mut words = spec.split_whitespace().peekable();
+        let mut access = WatchAccess::default();
+        match words.peek() {
+            Some(word) if word.chars().all(|c| matches!(c, 'r' | 'w' | 'x')) => {
+                access.read = word.contains('r');
+                access.write = word.contains('w');
+                access.exec = word.contains('x');
+                words.next();
+            }
+            _ => access.write = true,
+        }
+        let range = words.next().ok_or("expected an address range")?;
+        let range = match range.split_once('-') {
+            Some((start, end)) => parse_addr(start)?..=parse_addr(end)?,
+            None => {
+                let addr = parse_addr(range)?;
+                addr..=addr
+            }
+        };
+        if range.is_empty() {
+            return Err("range ends before it starts".to_string());
+        }
+        if let Some(word) = words.next() {
+            return Err(format!("unexpected `{}`", word));
+        }
+        self.add_trigger(Trigger::Watch { range, access }, condition);
+        Ok(())
+    }
+
+    fn add_trigger(&mut self, trigger: Trigger, condition: Option<Condition>) {
+        let description = trigger.to_string();
+        let id = self.nes.breakpoints().add(trigger, condition);
+        self.print(format!("Breakpoint {}, {}", id, description));
+    }
+
+    fn list_breakpoints(&mut self) {
+        let list = self.nes.breakpoints().list();
+        if list.is_empty() {
+            self.print("No breakpoints".to_string());
+        }
+        for (id, bp) in list {
+            let mut line = format!("{:>3}: {}", id.0, bp.trigger);
+            if let Some(condition) = &bp.condition {
+                line += &format!(" if {}", condition);
+            }
+            if !bp.enabled {
+                line += " (disabled)";
+            }
+            self.print(line);
+        }
+    }
+
+    fn show_regs(&mut self) {
+        let regs = self.nes.get_regs();
+        let line = format!(
+            "A=${:02X} X=${:02X} Y=${:02X} S=${:02X} P={} PC=${:04X}",
+            *regs.a, *regs.x, *regs.y, *regs.s, *regs.p, *regs.pc
+        );
+        self.print(line);
+        self.print(format!(
+            "cycle {}, frame {}",
+            self.nes.get_tick_count(),
+            self.nes.frame_count()
+        ));
+    }
+
+    /// Address, bytes and text of the instruction at addr, and its length
+    fn format_instruction(&self, addr: u16) -> (String, u16) {
+        let (text, len) = disassemble(addr, |addr| self.nes.peek(addr));
+        let bytes: Vec<String> = (0..len)
+            .map(|offset| {
+                self.nes
+                    .peek(addr.wrapping_add(offset))
+                    .map_or("??".to_string(), |byte| format!("{:02X}", byte))
+            })
+            .collect();
+        (
+            format!("${:04X}: {:<9} {}", addr, bytes.join(" "), text),
+            len,
+        )
+    }
+
+    /// Where to start disassembling so a few instructions before pc are
+    /// shown. Instructions can't be decoded backwards, so this tries
+    /// starting points until one lines up with pc.
+    fn disasm_start(&self, pc: u16) -> u16 {
+        for back in (1..=DISASM_CONTEXT as u16 * 3).rev() {
+            let start = pc.wrapping_sub(back);
+            let mut addr = start;
+            let mut count = 0;
+            while addr.wrapping_sub(start) < back {
+                addr = addr.wrapping_add(disassemble(addr, |addr| self.nes.peek(addr)).1);
+                count += 1;
+            }
+            if addr == pc && count <= DISASM_CONTEXT {
+                return start;
+            }
+        }
+        pc
+    }
+
+    fn disasm(&mut self, args: &str) -> Result<(), String> {
+        let mut words = args.split_whitespace();
+        let pc = *self.nes.get_regs().pc;
+        let (mut addr, default_count) = match words.next() {
+            Some(addr) => (parse_addr(addr)?, 10),
+            None => (self.disasm_start(pc), 10 + DISASM_CONTEXT),
+        };
+        let count = match words.next() {
+            Some(count) => parse_number(count)? as usize,
+            None => default_count,
+        };
+        for _ in 0..count {
+            let (text, len) = self.format_instruction(addr);
+            let marker = if addr == pc { "=>" } else { "  " };
+            self.print(format!("{} {}", marker, text));
+            addr = addr.wrapping_add(len);
+        }
+        Ok(())
+    }
+
+    fn dump_memory(&mut self, args: &str) -> Result<(), String> {
+        let mut words = args.split_whitespace();
+        let start = parse_addr(words.next().ok_or("expected an address")?)?;
+        let len = match words.next() {
+            Some(len) => parse_number(len)?,
+            None => 0x40,
+        };
+        for row in (0..len).step_by(16) {
+            let addr = start.wrapping_add(row as u16);
+            let bytes: Vec<String> = (0..(len - row).min(16))
+                .map(|offset| {
+                    self.nes
+                        .peek(addr.wrapping_add(offset as u16))
+                        .map_or("--".to_string(), |byte| format!("{:02X}", byte))
+                })
+                .collect();
+            self.print(format!("${:04X}: {}", addr, bytes.join(" ")));
+        }
+        Ok(())
+    }
+
+    fn set_memory(&mut self, args: &str) -> Result<(), String> {
+        let mut words = args.split_whitespace();
+        let addr = parse_addr(words.next().ok_or("expected an address")?)?;
+        let bytes = words
+            .map(|byte| {
+                u8::try_from(parse_number(byte)?).map_err(|_| format!("`{}` isn't a byte", byte))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if bytes.is_empty() {
+            return Err("expected bytes to write".to_string());
+        }
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            self.nes
+                .write_memory(addr.wrapping_add(offset as u16), byte)
+                .map_err(|e| format!("emulation error: {}", e))?;
+        }
+        Ok(())
+    }
+
+    fn show_bus_map(&mut self) {
+        let mut map = self.nes.bus_map().to_vec();
+        map.sort_by_key(|(range, _)| *range.start());
+        for (range, name) in map {
+            self.print(format!(
+                "${:04X}-${:04X}  {}",
+                range.start(),
+                range.end(),
+                name
+            ));
+        }
+    }
+
+    fn show_ppu(&mut self) {
+        let Some(ppu) = self.nes.ppu_state() else {
+            self.print("No PPU".to_string());
+            return;
+        };
+        self.print(format!(
+            "frame {}, scanline {}, dot {}",
+            ppu.frame, ppu.scanline, ppu.dot
+        ));
+        self.print(format!(
+            "PPUCTRL=${:02X} PPUMASK=${:02X} PPUSTATUS=${:02X} OAMADDR=${:02X}",
+            ppu.ctrl, ppu.mask, ppu.status, ppu.oam_addr
+        ));
+        self.print(format!(
+            "v=${:04X} t=${:04X} fine x={} write toggle={}",
+            ppu.v, ppu.t, ppu.fine_x, ppu.write_toggle as u8
+        ));
+    }
+
+    fn show_apu(&mut self) {
+        let apu = self.nes.apu_state();
+        let channels: [(&str, ChannelState); 5] = [
+            ("pulse 1", apu.pulse1),
+            ("pulse 2", apu.pulse2),
+            ("triangle", apu.triangle),
+            ("noise", apu.noise),
+            ("dmc", apu.dmc),
+        ];
+        for (name, channel) in channels {
+            self.print(format!(
+                "{:<9} length {:>4}  period ${:04X}  output {:>3}",
+                name, channel.length, channel.period, channel.output
+            ));
+        }
+        self.print(format!(
+            "frame counter {}-step, frame IRQ {}, DMC IRQ {}",
+            if apu.five_step_mode { 5 } else { 4 },
+            apu.frame_irq as u8,
+            apu.dmc_irq as u8
+        ));
+    }
+
+    fn trace(&mut self, args: &str) -> Result<(), String> {
+        let mut words = args.split_whitespace();
+        match (words.next(), words.next()) {
+            (None, _) => {
+                for (name, enabled) in self.tracer.elements() {
+                    self.print(format!(
+                        "{:<3} {}",
+                        if enabled { "on" } else { "off" },
+                        name
+                    ));
+                }
+            }
+            (Some(state @ ("on" | "off")), Some(element)) => {
+                self.tracer.set_enabled(element, state == "on");
+            }
+            _ => return Err("expected `trace on|off ELEMENT`".to_string()),
+        }
+        Ok(())
+    }
+
+    /// Run the commands in a file, one per line, until one fails or the
+    /// debugger is interrupted. Lines starting with # are comments. Scripts
+    /// can't source other scripts, which keeps one that sources itself from
+    /// recursing forever.
+    fn source(&mut self, path: &str) -> Result<(), String> {
+        if self.sourcing {
+            return Err("can't source a script from a script".to_string());
+        }
+        let script = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        self.sourcing = true;
+        let result = self.run_script(&script);
+        self.sourcing = false;
+        result
+    }
+
+    fn run_script(&mut self, script: &str) -> Result<(), String> {
+        for line in script.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.print(format!("> {}", line));
+            self.command(line)?;
+            if self.quit || self.interrupted() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn history(&mut self, path: &str) -> Result<(), String> {
+        if path.is_empty() {
+            for line in self.history.clone() {
+                self.print(line);
+            }
+            return Ok(());
+        }
+        let mut script = self.history.join("\n");
+        script.push('\n');
+        fs::write(path, script).map_err(|e| format!("{}: {}", path, e))
+    }
+}
+
+/// Split a trailing `if CONDITION` off a command's arguments
+fn split_condition(args: &str) -> Result<(&str, Option<Condition>), String> {
+    let split = args
+        .split_once(" if ")
+        .or_else(|| args.strip_prefix("if ").map(|condition| ("", condition)));
+    match split {
+        Some((spec, condition)) => Ok((spec, Some(Condition::parse(condition)?))),
+        None => Ok((args, None)),
+    }
+}
+
+fn parse_number(arg: &str) -> Result<u32, String> {
+    let hex = arg
+        .strip_prefix('$')
+        .or_else(|| arg.strip_prefix("0x"))
+        .or_else(|| arg.strip_prefix("0X"));
+    match hex {
+        Some(digits) => u32::from_str_radix(digits, 16),
+        None => arg.parse(),
+    }
+    .map_err(|_| format!("bad number `{}`", arg))
+}
+
+fn parse_addr(arg: &str) -> Result<u16, String> {
+    u16::try_from(parse_number(arg)?).map_err(|_| format!("`{}` isn't a CPU address", arg))
+}
+
+fn parse_id(arg: &str) -> Result<BreakpointId, String> {
+    Ok(BreakpointId(parse_number(arg)?))
+}
+
+/// Parse an optional repeat count, which defaults to 1
+fn parse_count(arg: &str) -> Result<u64, String> {
+    if arg.is_empty() {
+        Ok(1)
+    } else {
+        Ok(parse_number(arg)? as u64)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+
+    use super::*;
+    use crate::nes::test::build_nes;
+
+    #[test]
+    fn test_debugger() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(
+            &tracer,
+            &[
+                0x20, 0x06, 0xC0, // JSR $C006
+                0x4C, 0x03, 0xC0, // JMP $C003
+                0xA9, 0x40, // LDA #$40
+                0x85, 0x10, // STA $10
+                0x60, // RTS
+            ],
+        );
+        nes.start_simulation().unwrap();
+        let output = RefCell::new(Vec::new());
+        let interrupt = AtomicBool::new(false);
+        let mut debugger = Debugger::new(&mut nes, &tracer, &interrupt, |line| {
+            output.borrow_mut().push(line.to_string())
+        });
+        let run = |debugger: &mut Debugger, command: &str| {
+            debugger.execute(command);
+            output.take()
+        };
+
+        assert_eq!(run(&mut debugger, "s"), ["$C000: 20 06 C0  JSR $C006"]);
+        assert_eq!(run(&mut debugger, "step 2"), ["$C008: 85 10     STA $10"]);
+        assert_eq!(
+            run(&mut debugger, "disasm"),
+            [
+                "   $C000: 20 06 C0  JSR $C006",
+                "   $C003: 4C 03 C0  JMP $C003",
+                "   $C006: A9 40     LDA #$40",
+                "=> $C008: 85 10     STA $10",
+                "   $C00A: 60        RTS",
+                "   $C00B: EA        NOP",
+                "   $C00C: EA        NOP",
+                "   $C00D: EA        NOP",
+                "   $C00E: EA        NOP",
+                "   $C00F: EA        NOP",
+                "   $C010: EA        NOP",
+                "   $C011: EA        NOP",
+                "   $C012: EA        NOP",
+            ]
+        );
+        assert_eq!(run(&mut debugger, "finish"), ["$C003: 4C 03 C0  JMP $C003"]);
+        assert_eq!(run(&mut debugger, "mem $10 4"), ["$0010: 40 00 00 00"]);
+        assert!(run(&mut debugger, "set $10 $12 $34").is_empty());
+        assert_eq!(run(&mut debugger, "m 0x10 2"), ["$0010: 12 34"]);
+
+        assert_eq!(
+            run(&mut debugger, "watch w $10-$11 if A == $40"),
+            ["Breakpoint 0, watch w $0010-$0011"]
+        );
+        assert_eq!(
+            run(&mut debugger, "b $C000 bank 1"),
+            ["Breakpoint 1, pc $C000 bank 1"]
+        );
+        assert_eq!(run(&mut debugger, "disable 1"), Vec::<String>::new(),);
+        assert_eq!(
+            run(&mut debugger, "list"),
+            [
+                "  0: watch w $0010-$0011 if A == $40",
+                "  1: pc $C000 bank 1 (disabled)"
+            ]
+        );
+        run(&mut debugger, "reset");
+        assert_eq!(
+            run(&mut debugger, "c"),
+            ["Breakpoint 0, watch w $0010-$0011", "$C00A: 60        RTS"]
+        );
+        assert_eq!(run(&mut debugger, "delete 7"), ["error: no breakpoint 7"]);
+        assert_eq!(
+            run(&mut debugger, "break $C000 if Q"),
+            ["error: unknown register `Q`"]
+        );
+
+        // An empty line repeats the last command
+        run(&mut debugger, "delete 0");
+        run(&mut debugger, "cycle");
+        let tick = debugger.nes.get_tick_count();
+        run(&mut debugger, "");
+        assert_eq!(debugger.nes.get_tick_count(), tick + 1);
+
+        assert_eq!(
+            run(&mut debugger, "history"),
+            [
+                "s",
+                "step 2",
+                "disasm",
+                "finish",
+                "mem $10 4",
+                "set $10 $12 $34",
+                "m 0x10 2",
+                "watch w $10-$11 if A == $40",
+                "b $C000 bank 1",
+                "disable 1",
+                "list",
+                "reset",
+                "c",
+                "delete 7",
+                "break $C000 if Q",
+                "delete 0",
+                "cycle",
+            ]
+        );
+        assert!(!debugger.quit_requested());
+        run(&mut debugger, "quit");
+        assert!(debugger.quit_requested());
+    }
+
+    #[test]
+    fn test_source() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(&tracer, &[0x4C, 0x00, 0xC0]); // JMP $C000
+        nes.start_simulation().unwrap();
+        let path = std::env::temp_dir().join(format!("debugger_{}.txt", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "# Runs forever\ncontinue\nstep\nsource {}\n",
+                path.display()
+            ),
+        )
+        .unwrap();
+        let output = RefCell::new(Vec::new());
+        let interrupt = AtomicBool::new(false);
+        // Press Ctrl-C as soon as the script starts continuing
+        let mut debugger = Debugger::new(&mut nes, &tracer, &interrupt, |line| {
+            if line == "> continue" {
+                interrupt.store(true, Ordering::Relaxed);
+            }
+            output.borrow_mut().push(line.to_string())
+        });
+        let command = format!("source {}", path.display());
+
+        debugger.execute(&command);
+        let lines = output.take();
+        // The step after continue never runs
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[..2], ["> continue", "Interrupted"]);
+        let tick = debugger.nes.get_tick_count();
+        assert_eq!(tick, 1);
+
+        // Without the interrupt, the script gets as far as sourcing itself
+        fs::write(&path, format!("step\nsource {}\n", path.display())).unwrap();
+        debugger.execute(&command);
+        assert!(debugger.nes.get_tick_count() > tick);
+        assert_eq!(
+            output.take(),
+            [
+                "> step".to_string(),
+                "$C000: 4C 00 C0  JMP $C000".to_string(),
+                format!("> source {}", path.display()),
+                "error: can't source a script from a script".to_string(),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+use std::fmt;
+
+use thiserror::Error;
+
+/// Size of one disk side in an .fds file, which stores blocks without CRCs
+pub const FDS_SIDE_SIZE: usize = 65500;
+/// Size of one disk side in a .qd file, which stores a CRC after each block
+pub const QD_SIDE_SIZE: usize = 65536;
+
+const FDS_HEADER_MAGIC: &[u8; 4] = b"FDS\x1A";
+const DISK_INFO_MAGIC: &[u8; 14] = b"*NINTENDO-HVC*";
+
+/// Gap before the first block, and between blocks, as the drive sees them
+const LEADING_GAP_BYTES: usize = 28300 / 8;
+const BLOCK_GAP_BYTES: usize = 976 / 8;
+/// Marks the end of a gap. The drive syncs to this before each block.
+const GAP_TERMINATOR: u8 = 0x80;
+/// A raw side is padded out to roughly the length of the real track, so there
+/// is room for the BIOS to append files
+pub const RAW_SIDE_SIZE: usize = 80000;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum FdsError {
+    #[error("Disk image size {0} is not a whole number of sides")]
+    BadSize(usize),
+    #[error("Side {side} doesn't start with a disk info block")]
+    MissingDiskInfo { side: usize },
+    #[error("Side {side} block {block} is truncated")]
+    TruncatedBlock { side: usize, block: usize },
+    #[error("Side {side} block {block} CRC mismatch: expected {expected:04X}, got {actual:04X}")]
+    BadCrc {
+        side: usize,
+        block: usize,
+        expected: u16,
+        actual: u16,
+    },
+}
+
+/// Container format of a disk image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdsFormat {
+    /// .fds, optionally with a 16 byte fwNES header
+    Fds { header: bool },
+    /// .qd, the raw Quick Disk layout including block CRCs
+    QuickDisk,
+}
+
+/// Block types on an FDS disk side, identified by their first byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdsBlockType {
+    DiskInfo,
+    FileAmount,
+    FileHeader,
+    FileData,
+}
+
+impl FdsBlockType {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            1 => Some(FdsBlockType::DiskInfo),
+            2 => Some(FdsBlockType::FileAmount),
+            3 => Some(FdsBlockType::FileHeader),
+            4 => Some(FdsBlockType::FileData),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FdsBlockType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FdsBlockType::DiskInfo => write!(f, "disk info"),
+            FdsBlockType::FileAmount => write!(f, "file amount"),
+            FdsBlockType::FileHeader => write!(f, "file header"),
+            FdsBlockType::FileData => write!(f, "file data"),
+        }
+    }
+}
+
+/// Walks the blocks on a side, working out each block's length from its type
+/// and the preceding file header
+struct BlockLengths {
+    next_file_size: usize,
+}
+
+impl BlockLengths {
+    fn new() -> Self {
+        BlockLengths { next_file_size: 0 }
+    }
+
+    /// Length of the block starting at data[0], including the type byte, or
+    /// None if data doesn't start with a valid block type
+    fn next(&mut self, data: &[u8]) -> Option<usize> {
+        let block_type = FdsBlockType::from_u8(*data.first()?)?;
+        Some(match block_type {
+            FdsBlockType::DiskInfo => 56,
+            FdsBlockType::FileAmount => 2,
+            FdsBlockType::FileHeader => {
+                if let Some(size) = data.get(13..15) {
+                    self.next_file_size = u16::from_le_bytes([size[0], size[1]]) as usize;
+                }
+                16
+            }
+            FdsBlockType::FileData => 1 + self.next_file_size,
+        })
+    }
+}
+
+/// A disk image, as a list of sides, each of which is a list of blocks.
+/// Blocks include their type byte, but not their CRC.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FdsImage {
+    pub format: FdsFormat,
+    pub sides: Vec<Vec<Vec<u8>>>,
+}
+
+impl FdsImage {
+    /// Whether a file looks like a disk image, either by its fwNES header or
+    /// by the disk info block at the start of the first side
+    pub fn is_disk_image(data: &[u8]) -> bool {
+        data.starts_with(FDS_HEADER_MAGIC)
+            || data
+                .get(1..15)
+                .is_some_and(|magic| magic == DISK_INFO_MAGIC)
+    }
+
+    /// Parse an .fds or .qd image. The format is taken from the size, since
+    /// neither has a magic number of its own.
+    pub fn parse(data: &[u8]) -> Result<Self, FdsError> {
+        let (format, sides) = if data.starts_with(FDS_HEADER_MAGIC) {
+            (FdsFormat::Fds { header: true }, &data[16.min(data.len())..])
+        } else if data.len().is_multiple_of(QD_SIDE_SIZE) && !data.is_empty() {
+            (FdsFormat::QuickDisk, data)
+        } else {
+            (FdsFormat::Fds { header: false }, data)
+        };
+        let side_size = match format {
+            FdsFormat::Fds { .. } => FDS_SIDE_SIZE,
+            FdsFormat::QuickDisk => QD_SIDE_SIZE,
+        };
+        if sides.is_empty() || !sides.len().is_multiple_of(side_size) {
+            return Err(FdsError::BadSize(data.len()));
+        }
+
+        let sides = sides
+            .chunks(side_size)
+            .enumerate()
+            .map(|(side, data)| Self::parse_side(format, side, data))
+            .collect::<Result<_, _>>()?;
+        Ok(FdsImage { format, sides })
+    }
+
+    fn parse_side(format: FdsFormat, side: usize, data: &[u8]) -> Result<Vec<Vec<u8>>, FdsError> {
+        let crc_size = if format == FdsFormat::QuickDisk { 2 } else { 0 };
+        let mut blocks = Vec::new();
+        let mut lengths = BlockLengths::new();
+        let mut pos = 0;
+        while let Some(len) = lengths.next(&data[pos..]) {
+            let block_idx = blocks.len();
+            let Some(block) = data.get(pos..pos + len) else {
+                return Err(FdsError::TruncatedBlock {
+                    side,
+                    block: block_idx,
+                });
+            };
+            if crc_size > 0 {
+                let Some(stored) = data.get(pos + len..pos + len + 2) else {
+                    return Err(FdsError::TruncatedBlock {
+                        side,
+                        block: block_idx,
+                    });
+                };
+                let expected = u16::from_le_bytes([stored[0], stored[1]]);
+                let actual = fds_crc(block);
+                if expected != actual {
+                    return Err(FdsError::BadCrc {
+                        side,
+                        block: block_idx,
+                        expected,
+                        actual,
+                    });
+                }
+            }
+            blocks.push(block.to_vec());
+            pos += len + crc_size;
+        }
+        if blocks
+            .first()
+            .is_none_or(|info| info.get(1..15) != Some(DISK_INFO_MAGIC))
+        {
+            return Err(FdsError::MissingDiskInfo { side });
+        }
+        Ok(blocks)
+    }
+
+    /// Serialize in the image's own format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        if self.format == (FdsFormat::Fds { header: true }) {
+            data.extend_from_slice(FDS_HEADER_MAGIC);
+            data.push(self.sides.len() as u8);
+            data.resize(16, 0);
+        }
+        for blocks in &self.sides {
+            let start = data.len();
+            for block in blocks {
+                data.extend_from_slice(block);
+                if self.format == FdsFormat::QuickDisk {
+                    data.extend_from_slice(&fds_crc(block).to_le_bytes());
+                }
+            }
+            let side_size = match self.format {
+                FdsFormat::Fds { .. } => FDS_SIDE_SIZE,
+                FdsFormat::QuickDisk => QD_SIDE_SIZE,
+            };
+            // Files appended by the BIOS may not fit back into the image
+            data.truncate(start + side_size);
+            data.resize(start + side_size, 0);
+        }
+        data
+    }
+
+    /// The bytes of a side as the drive head sees them: gaps, gap terminators,
+    /// blocks, and CRCs
+    pub fn raw_side(&self, side: usize) -> Vec<u8> {
+        let mut raw = vec![0; LEADING_GAP_BYTES];
+        for block in &self.sides[side] {
+            raw.push(GAP_TERMINATOR);
+            raw.extend_from_slice(block);
+            raw.extend_from_slice(&fds_crc(block).to_le_bytes());
+            raw.resize(raw.len() + BLOCK_GAP_BYTES, 0);
+        }
+        raw.resize(raw.len().max(RAW_SIDE_SIZE), 0);
+        raw
+    }
+
+    /// Inverse of raw_side, for picking up blocks written by the BIOS. Parsing
+    /// stops at the first block with a bad CRC, since the BIOS treats that as
+    /// the end of the disk as well.
+    pub fn blocks_from_raw_side(raw: &[u8]) -> Vec<Vec<u8>> {
+        let mut blocks = Vec::new();
+        let mut lengths = BlockLengths::new();
+        let mut pos = 0;
+        loop {
+            while raw.get(pos) == Some(&0) {
+                pos += 1;
+            }
+            if raw.get(pos) != Some(&GAP_TERMINATOR) {
+                break;
+            }
+            pos += 1;
+            let Some(len) = lengths.next(&raw[pos..]) else {
+                break;
+            };
+            let (Some(block), Some(crc)) =
+                (raw.get(pos..pos + len), raw.get(pos + len..pos + len + 2))
+            else {
+                break;
+            };
+            if fds_crc(block) != u16::from_le_bytes([crc[0], crc[1]]) {
+                break;
+            }
+            blocks.push(block.to_vec());
+            pos += len + 2;
+        }
+        blocks
+    }
+}
+
+/// Feed one byte into the drive's CRC register. The register starts at zero
+/// at the start of the gap, and the gap terminator brings it to 0x8000.
+pub fn fds_crc_update(crc: u16, byte: u8) -> u16 {
+    let mut crc = crc;
+    for bit in 0..8 {
+        let carry = crc & 1 != 0;
+        crc = (crc >> 1) | (((byte >> bit) as u16 & 1) << 15);
+        if carry {
+            crc ^= 0x8408;
+        }
+    }
+    crc
+}
+
+/// CRC of a block, as stored after it on disk
+pub fn fds_crc(block: &[u8]) -> u16 {
+    block
+        .iter()
+        .chain(&[0, 0])
+        .fold(fds_crc_update(0, GAP_TERMINATOR), |crc, byte| {
+            fds_crc_update(crc, *byte)
+        })
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A single side with one file, in .fds layout
+    pub(crate) fn build_side() -> Vec<u8> {
+        let mut side = vec![1];
+        side.extend_from_slice(DISK_INFO_MAGIC);
+        side.resize(56, 0);
+        // File amount
+        side.extend_from_slice(&[2, 1]);
+        // File header: number, ID, name, address, size, type
+        side.extend_from_slice(&[3, 0, 0]);
+        side.extend_from_slice(b"TESTFILE");
+        side.extend_from_slice(&[0x00, 0x60, 0x04, 0x00, 0x00]);
+        side.extend_from_slice(&[4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        side.resize(FDS_SIDE_SIZE, 0);
+        side
+    }
+
+    #[test]
+    fn test_parse_fds() {
+        let image = FdsImage::parse(&build_side()).unwrap();
+        assert_eq!(image.format, FdsFormat::Fds { header: false });
+        assert_eq!(image.sides.len(), 1);
+        let blocks = &image.sides[0];
+        assert_eq!(blocks.len(), 4);
+        assert_eq!(blocks[3], [4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        assert_eq!(image.to_bytes(), build_side());
+
+        let mut with_header = b"FDS\x1A\x02".to_vec();
+        with_header.resize(16, 0);
+        with_header.extend(build_side());
+        with_header.extend(build_side());
+        let image = FdsImage::parse(&with_header).unwrap();
+        assert_eq!(image.format, FdsFormat::Fds { header: true });
+        assert_eq!(image.sides.len(), 2);
+        assert_eq!(image.to_bytes(), with_header);
+    }
+
+    #[test]
+    fn test_quick_disk_crc() {
+        let image = FdsImage::parse(&build_side()).unwrap();
+        let qd_image = FdsImage {
+            format: FdsFormat::QuickDisk,
+            ..image.clone()
+        };
+        let qd = qd_image.to_bytes();
+        assert_eq!(qd.len(), QD_SIDE_SIZE);
+        assert_eq!(FdsImage::parse(&qd).unwrap().sides, image.sides);
+
+        // Corrupt the file data
+        let mut bad = qd.clone();
+        bad[56 + 2 + 2 + 2 + 16 + 2 + 1] ^= 0xFF;
+        assert!(matches!(
+            FdsImage::parse(&bad),
+            Err(FdsError::BadCrc {
+                side: 0,
+                block: 3,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_raw_side_roundtrip() {
+        let image = FdsImage::parse(&build_side()).unwrap();
+        let raw = image.raw_side(0);
+        assert_eq!(raw.len(), RAW_SIDE_SIZE);
+        assert_eq!(FdsImage::blocks_from_raw_side(&raw), image.sides[0]);
+
+        // Running the drive's CRC over a block and its stored CRC gives zero
+        let block = &image.sides[0][0];
+        let crc = fds_crc(block);
+        let check = [GAP_TERMINATOR]
+            .iter()
+            .chain(block)
+            .chain(&crc.to_le_bytes())
+            .fold(0, |crc, byte| fds_crc_update(crc, *byte));
+        assert_eq!(check, 0);
+    }
+
+    #[test]
+    fn test_bad_images() {
+        assert_eq!(FdsImage::parse(&[0; 100]), Err(FdsError::BadSize(100)));
+        assert_eq!(
+            FdsImage::parse(&[0; FDS_SIDE_SIZE]),
+            Err(FdsError::MissingDiskInfo { side: 0 })
+        );
+        assert!(FdsImage::is_disk_image(&build_side()));
+        assert!(!FdsImage::is_disk_image(b"NES\x1A"));
+    }
+}
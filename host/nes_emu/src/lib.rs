@@ -1,3 +1,11 @@
 pub mod components;
+pub mod debugger;
+pub mod fds;
 pub mod nes;
 pub mod nes_file;
+pub mod nsf;
+pub mod nsf_player;
+pub mod patch;
+pub mod rom_db;
+pub mod unif;
+pub mod wav;
@@ -1,17 +1,129 @@
-use std::{fs::File, path::PathBuf};
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rustyline::{DefaultEditor, error::ReadlineError};
 
 use nes_emu::{
-    components::{EmuError, tracer::Tracer},
-    nes::NESSystem,
-    nes_file::NesFile,
+    components::{
+        debug::UninitReadMode, mapper::saves_to_prg_rom, reset_controller::ResetKind,
+        timing::Region, tracer::Tracer,
+    },
+    debugger::Debugger,
+    fds::FdsImage,
+    nes::{NESConfig, NESSystem, StopReason},
+    nes_file::{HeaderVersion, MapperId, NametableLayout, NesFile, TimingMode},
+    nsf::NsfFile,
+    nsf_player::{RenderOptions, render_track},
+    patch::apply_patch,
+    rom_db::RomDatabase,
+    wav::write_wav,
 };
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum UninitCheckArg {
+    /// Trace uninitialized reads on the "debug.uninit" trace element
+    Warn,
+    /// Stop emulation on the first uninitialized read
+    Strict,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum RegionArg {
+    Ntsc,
+    Pal,
+    Dendy,
+}
+
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
-struct Args {
-    rom_path: PathBuf,
+#[command(
+    version,
+    about,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print, edit, or convert the header of an NES file
+    Header(HeaderArgs),
+    /// Render tracks from an NSF or NSFe file to WAV
+    Nsf(NsfArgs),
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum HeaderSourceArg {
+    /// Trust the file's header, only report where the database disagrees
+    Header,
+    /// Override header fields with the ROM database entry, if there is one
+    Database,
+}
+
+#[derive(Args, Debug)]
+struct LoadArgs {
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "ROM database, NES 2.0 XML or TOML [default: built-in database]"
+    )]
+    rom_db: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Where to take header fields from [default: database when running, header when editing]"
+    )]
+    header_source: Option<HeaderSourceArg>,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Apply an IPS, UPS or BPS patch to the ROM, in the order given"
+    )]
+    patch: Vec<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Don't apply a .ips or .bps patch found next to the ROM when no --patch is given"
+    )]
+    no_auto_patch: bool,
+}
+
+/// How loading a ROM behaves when LoadArgs doesn't say. Running a ROM
+/// patches and corrects it; editing a header sees the file as it is.
+#[derive(Clone, Copy, Debug)]
+struct LoadDefaults {
+    auto_patch: bool,
+    header_source: HeaderSourceArg,
+}
+
+const RUN_DEFAULTS: LoadDefaults = LoadDefaults {
+    auto_patch: true,
+    header_source: HeaderSourceArg::Database,
+};
+
+const HEADER_DEFAULTS: LoadDefaults = LoadDefaults {
+    auto_patch: false,
+    header_source: HeaderSourceArg::Header,
+};
+
+#[derive(Args, Debug)]
+struct RunArgs {
+    #[arg(required = true)]
+    rom_path: Option<PathBuf>,
+
+    #[command(flatten)]
+    load: LoadArgs,
 
     #[arg(short, long)]
     trace: Vec<String>,
@@ -21,46 +133,697 @@ struct Args {
 
     #[arg(long, short, help = "Number of CPU cycles to run before exiting")]
     cycles: Option<u64>,
+
+    #[arg(long, help = "Detect reads of internal RAM that was never written")]
+    uninit_check: Option<UninitCheckArg>,
+
+    #[arg(
+        long,
+        help = "Console to emulate [default: the ROM's timing, with multi-region ROMs as NTSC]"
+    )]
+    region: Option<RegionArg>,
+
+    #[arg(
+        long,
+        value_name = "CYCLE",
+        help = "Press the reset button at the given CPU cycle"
+    )]
+    reset_at: Vec<u64>,
+
+    #[arg(
+        long,
+        value_name = "CYCLE",
+        help = "Power cycle the console at the given CPU cycle"
+    )]
+    power_cycle_at: Vec<u64>,
+
+    #[arg(
+        long,
+        help = "File for battery-backed PRG-RAM, self-flashed PRG-ROM or disk writes [default: ROM path with a .sav or .sav.ips extension]"
+    )]
+    save_file: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with = "save_file",
+        help = "Don't load or save battery-backed PRG-RAM, flash or disk writes"
+    )]
+    no_save: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "FDS BIOS ROM for disk images [default: disksys.rom next to the ROM]"
+    )]
+    fds_bios: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "CYCLE:SIDE",
+        value_parser = parse_side_change,
+        help = "Eject the disk at the given CPU cycle and insert SIDE (0 = disk 1 side A, 1 = side B, ...)"
+    )]
+    insert_side: Vec<(u64, usize)>,
+
+    #[arg(
+        long,
+        value_name = "CYCLE",
+        help = "Eject the disk at the given CPU cycle"
+    )]
+    eject_at: Vec<u64>,
+
+    #[arg(long, help = "Start in the interactive debugger instead of running")]
+    debug: bool,
+
+    #[arg(
+        long,
+        value_name = "PATH",
+        help = "Run the debugger commands in a file before prompting; implies --debug"
+    )]
+    debug_script: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+struct NsfArgs {
+    nsf_path: PathBuf,
+
+    #[arg(
+        long,
+        conflicts_with = "all_tracks",
+        help = "Track to render, counting from 1 [default: the file's starting track]"
+    )]
+    track: Option<u8>,
+
+    #[arg(long, help = "Render every track, to numbered files")]
+    all_tracks: bool,
+
+    #[arg(
+        short,
+        long,
+        help = "WAV file to write [default: the NSF path with a .wav extension]"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 150.0,
+        help = "Track length before the fade, when the file doesn't give one"
+    )]
+    time: f64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 8.0,
+        help = "Fade-out length, when the file doesn't give one"
+    )]
+    fade: f64,
+
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 3.0,
+        help = "Stop after this much silence, 0 to never stop early"
+    )]
+    silence: f64,
+
+    #[arg(long, default_value_t = 44100)]
+    sample_rate: u32,
+
+    #[arg(long, help = "Play dual-region tunes at PAL speed")]
+    pal: bool,
+
+    #[arg(short, long)]
+    trace: Vec<String>,
+
+    #[arg(long)]
+    trace_file: Option<PathBuf>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum HeaderFormatArg {
+    Ines,
+    Nes2,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum LayoutArg {
+    /// Vertical arrangement (horizontal mirroring)
+    Vertical,
+    /// Horizontal arrangement (vertical mirroring)
+    Horizontal,
+    /// Vertical arrangement, with the alternative layout bit set
+    AltVertical,
+    /// Horizontal arrangement, with the alternative layout bit set
+    AltHorizontal,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum TimingArg {
+    Ntsc,
+    Pal,
+    Multi,
+    Dendy,
+}
+
+#[derive(Args, Debug)]
+struct HeaderArgs {
+    rom_path: PathBuf,
+
+    #[command(flatten)]
+    load: LoadArgs,
+
+    #[arg(short, long, help = "Write the edited file here")]
+    output: Option<PathBuf>,
+
+    #[arg(long, conflicts_with = "output", help = "Overwrite the input file")]
+    in_place: bool,
+
+    #[arg(long, help = "Header format to write")]
+    format: Option<HeaderFormatArg>,
+
+    #[arg(long)]
+    mapper: Option<u16>,
+
+    #[arg(long)]
+    submapper: Option<u8>,
+
+    #[arg(long, help = "Nametable arrangement")]
+    layout: Option<LayoutArg>,
+
+    #[arg(long, help = "Whether the cartridge has battery-backed memory")]
+    battery: Option<bool>,
+
+    #[arg(long, value_parser = parse_size, help = "Volatile PRG-RAM size, e.g. 8K")]
+    prg_ram: Option<usize>,
+
+    #[arg(long, value_parser = parse_size, help = "Battery-backed PRG-RAM size")]
+    prg_nvram: Option<usize>,
+
+    #[arg(long, value_parser = parse_size, help = "Volatile CHR-RAM size")]
+    chr_ram: Option<usize>,
+
+    #[arg(long, value_parser = parse_size, help = "Battery-backed CHR-RAM size")]
+    chr_nvram: Option<usize>,
+
+    #[arg(long)]
+    timing: Option<TimingArg>,
+}
+
+/// Parse a byte count, with an optional K suffix for kilobytes
+fn parse_size(arg: &str) -> Result<usize, String> {
+    let (digits, scale) = match arg.strip_suffix(['K', 'k']) {
+        Some(digits) => (digits, 1024),
+        None => (arg, 1),
+    };
+    digits
+        .parse::<usize>()
+        .map(|value| value * scale)
+        .map_err(|e| format!("invalid size \"{}\": {}", arg, e))
+}
+
+/// Patches to apply to a ROM: the ones given on the command line, or else a
+/// patch with the same name as the ROM, if there is one. When there's both a
+/// .bps and an .ips, they're usually the same change, so only the .bps is
+/// applied.
+fn patch_paths(rom_path: &Path, load_args: &LoadArgs, defaults: LoadDefaults) -> Vec<PathBuf> {
+    if !load_args.patch.is_empty() || load_args.no_auto_patch || !defaults.auto_patch {
+        return load_args.patch.clone();
+    }
+    let mut found = ["bps", "ips"]
+        .iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .filter(|path| path.is_file());
+    let Some(patch) = found.next() else {
+        return Vec::new();
+    };
+    eprintln!("Applying patch {}", patch.display());
+    for ignored in found {
+        eprintln!(
+            "Ignoring patch {}, use --patch to apply it",
+            ignored.display()
+        );
+    }
+    vec![patch]
+}
+
+/// Read a ROM or disk image and apply any patches to it. Also returns
+/// whether there were any.
+fn read_rom_data(path: &PathBuf, load_args: &LoadArgs, defaults: LoadDefaults) -> (Vec<u8>, bool) {
+    let mut data = fs::read(path).expect("Failed to open ROM file");
+    let patch_paths = patch_paths(path, load_args, defaults);
+    for patch_path in &patch_paths {
+        let patch = fs::read(patch_path).expect("Failed to open patch file");
+        data = apply_patch(&data, &patch).unwrap_or_else(|e| {
+            eprintln!("Failed to apply {}: {}", patch_path.display(), e);
+            std::process::exit(1);
+        });
+    }
+    (data, !patch_paths.is_empty())
+}
+
+/// Parse a ROM, correcting its header from the ROM database. Also returns
+/// whether the database changed anything.
+fn parse_rom(data: &[u8], load_args: &LoadArgs, defaults: LoadDefaults) -> (NesFile, bool) {
+    let mut rom = NesFile::load(&mut &data[..]).unwrap_or_else(|e| {
+        eprintln!("Failed to read NES file: {}", e);
+        std::process::exit(1);
+    });
+
+    let db = match &load_args.rom_db {
+        Some(db_path) => RomDatabase::load(db_path).unwrap_or_else(|e| {
+            eprintln!("Failed to load ROM database: {}", e);
+            std::process::exit(1);
+        }),
+        None => RomDatabase::builtin(),
+    };
+    let mut corrected = false;
+    if let Some(entry) = db.lookup(&rom) {
+        let name = entry.name.as_deref().unwrap_or("unnamed entry");
+        match load_args.header_source.unwrap_or(defaults.header_source) {
+            HeaderSourceArg::Database => {
+                for change in entry.apply(&mut rom) {
+                    eprintln!("ROM database ({}): corrected {}", name, change);
+                    corrected = true;
+                }
+            }
+            HeaderSourceArg::Header => {
+                for change in entry.apply(&mut rom.clone()) {
+                    eprintln!("ROM database ({}): ignoring {}", name, change);
+                }
+            }
+        }
+    }
+    for warning in rom.validate() {
+        eprintln!("Warning: {}", warning);
+    }
+    (rom, corrected)
+}
+
+fn print_header(rom: &NesFile) {
+    println!("Format:        {:?}", rom.header_version);
+    println!(
+        "Mapper:        {} (submapper {})",
+        rom.mapper.id, rom.mapper.sub_id
+    );
+    println!("Layout:        {:?}", rom.nametable_layout);
+    println!("Battery:       {}", rom.nvram_present);
+    println!("Console:       {:?}", rom.console_type);
+    println!("Timing:        {:?}", rom.timing);
+    println!("PRG-ROM:       {} bytes", rom.prg_rom.len());
+    println!("CHR-ROM:       {} bytes", rom.chr_rom.len());
+    println!("PRG-RAM:       {} bytes", rom.prg_ram_size);
+    println!("PRG-NVRAM:     {} bytes", rom.prg_nvram_size);
+    println!("CHR-RAM:       {} bytes", rom.chr_ram_size);
+    println!("CHR-NVRAM:     {} bytes", rom.chr_nvram_size);
+    println!("Trainer:       {}", rom.trainer.is_some());
+    println!(
+        "Misc ROMs:     {} ({} bytes)",
+        rom.misc_rom_count,
+        rom.misc_rom.len()
+    );
+    println!("Expansion dev: {}", rom.default_expansion_device);
+}
+
+fn header_command(args: HeaderArgs) {
+    let (data, patched) = read_rom_data(&args.rom_path, &args.load, HEADER_DEFAULTS);
+    let (mut rom, corrected) = parse_rom(&data, &args.load, HEADER_DEFAULTS);
+    if args.in_place && (patched || corrected) {
+        // The original dump would be lost along with its header
+        eprintln!(
+            "Refusing to overwrite {} with a patched or database-corrected image, use --output instead",
+            args.rom_path.display()
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(format) = args.format {
+        rom.header_version = match format {
+            HeaderFormatArg::Ines => HeaderVersion::INes,
+            HeaderFormatArg::Nes2 => HeaderVersion::Nes2,
+        };
+    }
+    rom.mapper = MapperId {
+        id: args.mapper.unwrap_or(rom.mapper.id),
+        sub_id: args.submapper.unwrap_or(rom.mapper.sub_id),
+    };
+    if let Some(layout) = args.layout {
+        rom.nametable_layout = match layout {
+            LayoutArg::Vertical => NametableLayout::Vertical,
+            LayoutArg::Horizontal => NametableLayout::Horizontal,
+            LayoutArg::AltVertical => NametableLayout::AlternateVertical,
+            LayoutArg::AltHorizontal => NametableLayout::AlternateHorizontal,
+        };
+    }
+    rom.nvram_present = args.battery.unwrap_or(rom.nvram_present);
+    rom.prg_ram_size = args.prg_ram.unwrap_or(rom.prg_ram_size);
+    rom.prg_nvram_size = args.prg_nvram.unwrap_or(rom.prg_nvram_size);
+    rom.chr_ram_size = args.chr_ram.unwrap_or(rom.chr_ram_size);
+    rom.chr_nvram_size = args.chr_nvram.unwrap_or(rom.chr_nvram_size);
+    if let Some(timing) = args.timing {
+        rom.timing = match timing {
+            TimingArg::Ntsc => TimingMode::NTSC,
+            TimingArg::Pal => TimingMode::PAL,
+            TimingArg::Multi => TimingMode::MultiRegion,
+            TimingArg::Dendy => TimingMode::Dendy,
+        };
+    }
+
+    let output_path = if args.in_place {
+        Some(&args.rom_path)
+    } else {
+        args.output.as_ref()
+    };
+    let Some(output_path) = output_path else {
+        print_header(&rom);
+        return;
+    };
+
+    if matches!(args.format, Some(HeaderFormatArg::Ines))
+        && rom.output_header_version() != HeaderVersion::INes
+    {
+        eprintln!("Warning: header can't be represented in iNES 1.0, writing NES 2.0 instead");
+    }
+    rom.header_version = rom.output_header_version();
+    for warning in rom.validate() {
+        eprintln!("Warning: {}", warning);
+    }
+    let mut output = File::create(output_path).expect("Failed to create output file");
+    rom.write_to(&mut output).unwrap_or_else(|e| {
+        eprintln!("Failed to write NES file: {}", e);
+        std::process::exit(1);
+    });
+    print_header(&rom);
 }
 
 fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    match cli.command {
+        Some(Command::Header(args)) => header_command(args),
+        Some(Command::Nsf(args)) => nsf_command(args),
+        None => run_command(cli.run),
+    }
+}
 
-    let mut rom_file = File::open(args.rom_path).expect("Failed to open ROM file");
-    let rom = NesFile::from_stream(&mut rom_file).expect("Failed to read NES file");
+fn nsf_command(args: NsfArgs) {
+    let data = fs::read(&args.nsf_path).expect("Failed to open NSF file");
+    let nsf = NsfFile::parse(&data).unwrap_or_else(|e| {
+        eprintln!("Failed to read NSF file: {}", e);
+        std::process::exit(1);
+    });
+    println!("Title:     {}", nsf.title);
+    println!("Artist:    {}", nsf.artist);
+    println!("Copyright: {}", nsf.copyright);
+    let chips = nsf.expansion_chips();
+    if !chips.is_empty() {
+        let names: Vec<String> = chips.iter().map(|chip| chip.to_string()).collect();
+        println!("Expansion: {}", names.join(", "));
+    }
+
+    let tracks: Vec<u8> = if args.all_tracks {
+        (0..nsf.song_count).collect()
+    } else {
+        match args.track {
+            Some(0) => {
+                eprintln!("Tracks are numbered from 1");
+                std::process::exit(1);
+            }
+            Some(track) if track > nsf.song_count => {
+                eprintln!("The file only has {} tracks", nsf.song_count);
+                std::process::exit(1);
+            }
+            Some(track) => vec![track - 1],
+            None => vec![nsf.start_song],
+        }
+    };
+    let seconds_to_ms = |seconds: f64| (seconds * 1000.0) as u32;
+    let options = RenderOptions {
+        sample_rate: args.sample_rate,
+        default_time_ms: seconds_to_ms(args.time),
+        default_fade_ms: seconds_to_ms(args.fade),
+        silence_ms: (args.silence > 0.0).then(|| seconds_to_ms(args.silence)),
+        prefer_pal: args.pal,
+    };
+    let base_path = args
+        .output
+        .clone()
+        .unwrap_or_else(|| args.nsf_path.with_extension("wav"));
+
+    let trace_file = args
+        .trace_file
+        .as_ref()
+        .map(|path| File::create(path).expect("Failed to create trace output file"));
+    let tracer = Tracer::new(&args.trace, trace_file);
+    for track in tracks {
+        let output_path = if args.all_tracks {
+            let stem = base_path.file_stem().unwrap_or_default().to_string_lossy();
+            base_path.with_file_name(format!("{}-{:02}.wav", stem, track + 1))
+        } else {
+            base_path.clone()
+        };
+        let name = nsf.track_name(track).unwrap_or("");
+        println!("Track {}: {}", track + 1, name);
+
+        let samples = render_track(&tracer, &nsf, track, &options).unwrap_or_else(|e| {
+            eprintln!("Emulation error in track {}: {}", track + 1, e);
+            std::process::exit(1);
+        });
+        let mut output = File::create(&output_path).expect("Failed to create output file");
+        write_wav(&mut output, options.sample_rate, &samples).unwrap_or_else(|e| {
+            eprintln!("Failed to write {}: {}", output_path.display(), e);
+            std::process::exit(1);
+        });
+        println!(
+            "Wrote {:.1}s to {}",
+            samples.len() as f64 / options.sample_rate as f64,
+            output_path.display()
+        );
+    }
+}
+
+fn load_fds_bios(path: &Path) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| {
+        eprintln!(
+            "Failed to read FDS BIOS {}: {} (use --fds-bios to give its location)",
+            path.display(),
+            e
+        );
+        std::process::exit(1);
+    })
+}
+
+/// Things that can happen partway through a run, at a given CPU cycle
+#[derive(Debug, Clone, Copy)]
+enum ScheduledEvent {
+    Restart(ResetKind),
+    InsertSide(usize),
+    Eject,
+}
+
+/// Parse a CYCLE:SIDE pair for --insert-side
+fn parse_side_change(arg: &str) -> Result<(u64, usize), String> {
+    let (cycle, side) = arg
+        .split_once(':')
+        .ok_or_else(|| format!("expected CYCLE:SIDE, got \"{}\"", arg))?;
+    let cycle = cycle
+        .parse()
+        .map_err(|e| format!("invalid cycle \"{}\": {}", cycle, e))?;
+    let side = side
+        .parse()
+        .map_err(|e| format!("invalid side \"{}\": {}", side, e))?;
+    Ok((cycle, side))
+}
+
+fn dump_regs(nes: &NESSystem) {
+    eprintln!("Register dump:");
+    let regs = nes.get_regs();
+    eprintln!("A:  0x{:02X}   S: 0x{:02X}", *regs.a, *regs.s);
+    eprintln!("X:  0x{:02X}   Y: 0x{:02X}", *regs.x, *regs.y);
+    eprintln!("P:  {}", *regs.p);
+    eprintln!("PC: 0x{:04X}", *regs.pc);
+}
+
+/// Prompt for debugger commands until the user quits, after running the
+/// script, if there is one
+fn run_debugger<'t>(nes: &mut NESSystem<'t>, tracer: &'t Tracer, script: Option<&Path>) {
+    // Ctrl-C while the console is running stops it rather than the program,
+    // so the simulation still gets ended and saves are written
+    static INTERRUPT: AtomicBool = AtomicBool::new(false);
+    ctrlc::set_handler(|| INTERRUPT.store(true, Ordering::Relaxed))
+        .expect("Failed to install the Ctrl-C handler");
+    let mut debugger = Debugger::new(nes, tracer, &INTERRUPT, |line| println!("{}", line));
+    if let Some(script) = script {
+        debugger.execute(&format!("source {}", script.display()));
+    }
+    let mut editor = DefaultEditor::new().expect("Failed to open the terminal");
+    while !debugger.quit_requested() {
+        match editor.readline("(nes) ") {
+            Ok(line) => {
+                let _ = editor.add_history_entry(line.as_str());
+                debugger.execute(&line);
+            }
+            // At the prompt, Ctrl-C abandons the line being typed
+            Err(ReadlineError::Interrupted) => {}
+            Err(_) => break,
+        }
+    }
+}
+
+fn run_command(mut args: RunArgs) {
+    // Required by clap whenever there's no subcommand
+    let rom_path = args.rom_path.clone().unwrap();
+    let (rom_data, _) = read_rom_data(&rom_path, &args.load, RUN_DEFAULTS);
+    let is_disk = FdsImage::is_disk_image(&rom_data);
 
     let trace_file = args
         .trace_file
         .as_ref()
         .map(|path| File::create(path).expect("Failed to create trace output file"));
+    let uninit_check = args.uninit_check.map(|mode| match mode {
+        UninitCheckArg::Warn => UninitReadMode::Warn,
+        UninitCheckArg::Strict => UninitReadMode::Strict,
+    });
+    if uninit_check == Some(UninitReadMode::Warn) {
+        // Warnings are reported through the tracer, so make sure they're visible
+        args.trace.push("debug.uninit".to_string());
+    }
+
     let tracer = Tracer::new(&args.trace, trace_file);
-    let mut nes = NESSystem::new(&tracer, rom);
+    let rom = (!is_disk).then(|| parse_rom(&rom_data, &args.load, RUN_DEFAULTS).0);
+    // Disk writes, and boards that save to their own PRG-ROM, are kept as a
+    // patch against the image
+    let saves_patch = is_disk
+        || rom
+            .as_ref()
+            .is_some_and(|rom| saves_to_prg_rom(rom.mapper, rom.nvram_present));
+    let default_save_extension = if saves_patch { "sav.ips" } else { "sav" };
+    let save_path = if args.no_save {
+        None
+    } else {
+        Some(
+            args.save_file
+                .clone()
+                .unwrap_or_else(|| rom_path.with_extension(default_save_extension)),
+        )
+    };
+    let config = NESConfig {
+        uninit_check,
+        save_path,
+        audio: None,
+        region: args.region.map(|region| match region {
+            RegionArg::Ntsc => Region::Ntsc,
+            RegionArg::Pal => Region::Pal,
+            RegionArg::Dendy => Region::Dendy,
+        }),
+    };
+    let mut nes = if let Some(rom) = rom {
+        NESSystem::with_config(&tracer, rom, &config).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        })
+    } else {
+        let disk = FdsImage::parse(&rom_data).unwrap_or_else(|e| {
+            eprintln!("Failed to read disk image: {}", e);
+            std::process::exit(1);
+        });
+        let bios_path = args
+            .fds_bios
+            .clone()
+            .unwrap_or_else(|| rom_path.with_file_name("disksys.rom"));
+        NESSystem::with_fds(&tracer, disk, load_fds_bios(&bios_path), &config).unwrap_or_else(|e| {
+            eprintln!("{}: {}", bios_path.display(), e);
+            std::process::exit(1);
+        })
+    };
+
+    let mut events: Vec<(u64, ScheduledEvent)> = args
+        .reset_at
+        .iter()
+        .map(|cycle| (*cycle, ScheduledEvent::Restart(ResetKind::Reset)))
+        .chain(
+            args.power_cycle_at
+                .iter()
+                .map(|cycle| (*cycle, ScheduledEvent::Restart(ResetKind::PowerCycle))),
+        )
+        .chain(
+            args.insert_side
+                .iter()
+                .map(|(cycle, side)| (*cycle, ScheduledEvent::InsertSide(*side))),
+        )
+        .chain(
+            args.eject_at
+                .iter()
+                .map(|cycle| (*cycle, ScheduledEvent::Eject)),
+        )
+        .collect();
+    events.sort_by_key(|(cycle, _)| *cycle);
+    if !is_disk
+        && events.iter().any(|(_, event)| {
+            matches!(event, ScheduledEvent::InsertSide(_) | ScheduledEvent::Eject)
+        })
+    {
+        eprintln!("Warning: disk changes are ignored, the ROM isn't a disk image");
+    }
+
+    if args.debug || args.debug_script.is_some() {
+        if !events.is_empty() || args.cycles.is_some() {
+            eprintln!("Warning: --cycles and scheduled events are ignored in the debugger");
+        }
+        if let Err(e) = nes.start_simulation() {
+            eprintln!("Emulation error: {}", e);
+            std::process::exit(1);
+        }
+        run_debugger(&mut nes, &tracer, args.debug_script.as_deref());
+        nes.end_simulation();
+        return;
+    }
 
     let run_result = (|| {
         nes.start_simulation()?;
+        for (cycle, event) in &events {
+            if args.cycles.is_some_and(|limit| *cycle >= limit) {
+                break;
+            }
+            match nes.run(Some(*cycle))? {
+                StopReason::LimitReached => {}
+                reason => return Ok(reason),
+            }
+            match event {
+                ScheduledEvent::Restart(ResetKind::Reset) => nes.reset(),
+                ScheduledEvent::Restart(ResetKind::PowerCycle) => nes.power_cycle(),
+                ScheduledEvent::InsertSide(side) => {
+                    if let Some(control) = nes.disk_control() {
+                        control.insert_side(*side);
+                    }
+                }
+                ScheduledEvent::Eject => {
+                    if let Some(control) = nes.disk_control() {
+                        control.eject();
+                    }
+                }
+            }
+        }
         nes.run(args.cycles)
     })();
 
     nes.end_simulation();
 
     match run_result {
+        Ok(StopReason::LimitReached) => println!("Cycle limit exceeded"),
+        Ok(StopReason::Jam(opcode)) => {
+            eprintln!("CPU jammed on opcode 0x{:02X}", opcode);
+            dump_regs(&nes);
+        }
+        Ok(StopReason::TestFailed(code)) => {
+            eprintln!("Test ROM reported failure with code {}", code);
+            dump_regs(&nes);
+        }
         Ok(_) => {}
-        Err(e) => match e {
-            EmuError::StopEmulation => {}
-            EmuError::CycleLimitReached => {
-                println!("Cycle limit exceeded");
-            }
-            _ => {
-                eprintln!("Emulation error: {}", e);
-
-                eprintln!("Register dump:");
-                let regs = nes.get_regs();
-                eprintln!("A:  0x{:02X}   S: 0x{:02X}", *regs.a, *regs.s);
-                eprintln!("X:  0x{:02X}   Y: 0x{:02X}", *regs.x, *regs.y);
-                eprintln!("P:  {}", *regs.p);
-                eprintln!("PC: 0x{:04X}", *regs.pc);
-            }
-        },
+        Err(e) => {
+            eprintln!("Emulation error: {}", e);
+            dump_regs(&nes);
+        }
     }
 
     println!("Total CPU cycles executed: {}", nes.get_tick_count());
@@ -1,32 +1,310 @@
+use std::{cell::RefCell, ops::RangeInclusive, path::PathBuf, rc::Rc};
+
 use crate::components::{
-    BusDevice, EmuError, EmuResult, ReadResult,
+    BusActivity, BusDevice, EmuError, EmuResult, ReadResult,
+    apu::{Apu, ApuState, DmcDma, SharedApu},
+    audio::AudioOutput,
+    breakpoint::{BreakpointId, Breakpoints, CpuState, PRG_BANK_SIZE, WatchpointMonitor},
     bus::{GenericRouter, MirroringWrapper},
+    console_event::ConsoleEvent,
     cpu::{ArchRegs, BusAccess, Cpu6502},
-    debug::TestROMMonitor,
-    mem::{RAMDevice, ROMDevice},
-    reset_controller::ResetController,
-    signal::{LevelSignal, PulseSignal},
+    debug::{
+        CpuAccessInfo, CpuAccessMonitor, TestROMMonitor, UninitMemoryDetector, UninitReadMode,
+    },
+    fds::{FdsDiskControl, FdsRamAdapter},
+    mapper::{
+        CartridgeParts, ChrMemory, PrgRomMap, build_mapper, connect_cartridge, saves_to_prg_rom,
+    },
+    mem::{BatteryRAMDevice, PowerOnImage, RAMDevice, ROMDevice},
+    nametables::{NametableDevice, Nametables},
+    nsf::NsfCart,
+    ppu::{Ppu, PpuState, SharedPpu},
+    reset_controller::{ResetController, ResetKind, ResetSource},
+    scheduler::{ClockId, Scheduler, Tick},
+    signal::{PulseSignal, WiredOrSignal, WiredOrSource},
+    timing::{ConsoleTiming, Region},
     tracer::Tracer,
 };
-use crate::nes_file::NesFile;
+use crate::{
+    fds::FdsImage,
+    nes_file::{NametableLayout, NesFile},
+    nsf::{NsfFile, NsfRegion},
+};
+
+/// Optional behavior for an NESSystem, beyond what the ROM itself specifies
+#[derive(Debug, Default, Clone)]
+pub struct NESConfig {
+    /// Report reads of internal RAM bytes that were never written since power-on
+    pub uninit_check: Option<UninitReadMode>,
+    /// File used to persist battery-backed PRG-RAM, or writes to an FDS disk.
+    /// None disables saving.
+    pub save_path: Option<PathBuf>,
+    /// Where the APU's output goes. None skips mixing entirely.
+    pub audio: Option<AudioOutput>,
+    /// Console to run on, overriding the region the ROM asks for
+    pub region: Option<Region>,
+}
+
+/// Where sprite DMA writes each byte it copies
+const OAMDATA_ADDR: u16 = 0x2004;
+
+/// Why running the console stopped. Anything not listed here that ends a run
+/// comes back as an EmuError instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// The cycle or instruction asked for by a step has run
+    Stepped,
+    /// The PPU finished the frame run_until_frame was waiting for
+    FrameReached,
+    /// run_until's condition held
+    ConditionMet,
+    /// The tick limit passed to run was reached
+    LimitReached,
+    /// A test ROM reported that it passed
+    TestPassed,
+    /// A test ROM reported a failure with the given code
+    TestFailed(u8),
+    /// The CPU fetched one of the JAM opcodes, which halt a real 2A03 until
+    /// it's reset
+    Jam(u8),
+    /// A breakpoint or watchpoint fired
+    Breakpoint(BreakpointId),
+    /// Whoever is driving the console asked it to stop, e.g. with Ctrl-C
+    Interrupted,
+}
+
+/// Signals from the console's components that carts can drive or observe
+struct ConsoleSignals {
+    reset_source: ResetSource,
+    /// The CPU's IRQ line, which carts add their own sources to
+    irq: WiredOrSignal,
+    /// The CPU's NMI line, driven by the PPU or a stand-in for it
+    nmi: WiredOrSignal,
+}
+
+/// Whatever's plugged into the cartridge slot, shared between the CPU bus
+/// and the console. The bus's tick doesn't reach it; the console clocks it
+/// from M2 instead.
+#[derive(Clone)]
+struct CartridgeSlot<'t> {
+    device: Rc<RefCell<Box<dyn BusDevice + 't>>>,
+}
+
+impl CartridgeSlot<'_> {
+    fn clock_m2(&self) -> EmuResult<()> {
+        self.device.borrow_mut().tick()
+    }
+}
 
+impl BusDevice for CartridgeSlot<'_> {
+    fn bus_read(&mut self, addr: u32) -> EmuResult<ReadResult> {
+        self.device.borrow_mut().bus_read(addr)
+    }
+
+    fn bus_write(&mut self, addr: u32, data: u8) -> EmuResult<()> {
+        self.device.borrow_mut().bus_write(addr, data)
+    }
+
+    fn start_of_simulation(&mut self) -> EmuResult<()> {
+        self.device.borrow_mut().start_of_simulation()
+    }
+
+    fn end_of_simulation(&mut self) {
+        self.device.borrow_mut().end_of_simulation();
+    }
+
+    fn power_on(&mut self) {
+        self.device.borrow_mut().power_on();
+    }
+
+    fn reset(&mut self) {
+        self.device.borrow_mut().reset();
+    }
+
+    fn observe_access(&mut self, addr: u32, activity: BusActivity) {
+        self.device.borrow_mut().observe_access(addr, activity);
+    }
+
+    fn peek(&self, addr: u32) -> Option<u8> {
+        self.device.borrow().peek(addr)
+    }
+}
+
+/// A sprite DMA in progress. The CPU is halted while the page written to
+/// $4014 is copied to OAMDATA, reading on one cycle and writing on the next.
+struct SpriteDma {
+    page: u8,
+    /// Cycles left before the first read: the one the CPU is halted on, and
+    /// one more if the copy has to wait for a read cycle
+    wait: u8,
+    copied: u16,
+    /// Byte read on the last cycle, to be written on this one
+    data: Option<u8>,
+    /// What the CPU's last read left on the data bus, for when it resumes
+    cpu_data_bus: u8,
+}
+
+/// A console and whatever's plugged into it. The scheduler divides the
+/// master clock into PPU dots, the APU's half-rate cycles, M2 for the
+/// cartridge and the rest of the APU, and CPU cycles, which tick the other
+/// devices on the CPU bus. In between it delivers ConsoleEvents: restarts,
+/// and the starts of DMC fetches and sprite DMA. DMA happens within CPU
+/// cycles, since it takes them from the CPU.
 pub struct NESSystem<'t> {
     cpu: Cpu6502<'t>,
-    cpu_bus: GenericRouter,
+    cpu_bus: WatchpointMonitor<GenericRouter<'t>>,
+    breakpoints: Breakpoints,
+    /// Where the cartridge maps PRG-ROM, for banked breakpoints
+    prg_rom_map: Option<PrgRomMap<'t>>,
+    /// What each range of the CPU's address space is mapped to
+    bus_map: Vec<(RangeInclusive<u16>, &'static str)>,
+    ppu: Option<SharedPpu<'t>>,
+    apu: SharedApu,
     data_bus_state: u8,
     tracer: &'t Tracer,
     tick_count: u64,
+    scheduler: Scheduler<ConsoleEvent>,
+    cpu_clock: ClockId,
+    ppu_clock: ClockId,
+    apu_clock: ClockId,
+    m2_clock: ClockId,
+    cartridge: Option<CartridgeSlot<'t>>,
     reset_controller: ResetController,
+    access_monitor: Option<CpuAccessMonitor>,
+    disk_control: Option<FdsDiskControl>,
+    dmc_dma: DmcDma,
+    sprite_dma: Option<SpriteDma>,
+    audio: Option<AudioOutput>,
+    timing: &'static ConsoleTiming,
 }
 
 impl<'t> NESSystem<'t> {
-    pub fn new(tracer: &'t Tracer, rom: NesFile) -> Self {
+    pub fn new(tracer: &'t Tracer, rom: NesFile) -> EmuResult<Self> {
+        Self::with_config(tracer, rom, &NESConfig::default())
+    }
+
+    /// Build a console with rom plugged in, or fail if there's no board for
+    /// its mapper
+    pub fn with_config(tracer: &'t Tracer, rom: NesFile, config: &NESConfig) -> EmuResult<Self> {
+        // Every board maps PRG-ROM banks modulo its size
+        if rom.prg_rom.is_empty() {
+            return Err(EmuError::EmptyPrgRom);
+        }
+        let region = config
+            .region
+            .unwrap_or_else(|| Region::from_timing_mode(rom.timing));
+        let (mut system, mut signals) = Self::new_console(tracer, config, region.timing());
+        let cart_trace_element = tracer.register_element("cart", None);
+
+        // PRG-RAM, which the board maps from 0x6000. Boards without RAM
+        // still get 8KB, since test ROMs report through it regardless.
+        // A trainer is loaded at 0x7000. Battery RAM holds it from the
+        // start, under any save; plain RAM gets it again at every power-on.
+        let mut prg_ram_size = rom.prg_ram_size.max(0x2000);
+        if rom.trainer.is_some() {
+            tracer.trace_event(
+                cart_trace_element,
+                format_args!("WARNING: ROM has a trainer, loading it into PRG-RAM at 0x7000"),
+            );
+        }
+        let prg_ram: Box<dyn BusDevice + 't> =
+            if rom.nvram_present && !saves_to_prg_rom(rom.mapper, rom.nvram_present) {
+                // The battery flag can be set without an NVRAM size, assume the usual 8KB
+                let mut nvram_size = if rom.prg_nvram_size > 0 {
+                    rom.prg_nvram_size
+                } else {
+                    0x2000
+                };
+                if rom.trainer.is_some() {
+                    // The trainer needs the full 8KB to reach 0x7000
+                    nvram_size = nvram_size.max(0x2000);
+                }
+                prg_ram_size = nvram_size;
+                let mut nvram =
+                    BatteryRAMDevice::new(nvram_size, config.save_path.clone(), system.timing);
+                if let Some(trainer) = &rom.trainer {
+                    nvram = nvram.with_image(0x1000, &trainer[..]);
+                }
+                Box::new(MirroringWrapper::new(
+                    nvram,
+                    nvram_size.trailing_zeros() as usize,
+                ))
+            } else if let Some(trainer) = &rom.trainer {
+                let ram = RAMDevice::new(prg_ram_size);
+                Box::new(PowerOnImage::new(ram, 0x1000, trainer.to_vec()))
+            } else {
+                Box::new(RAMDevice::new(prg_ram_size))
+            };
+        let prg_ram = Box::new(TestROMMonitor::new(
+            prg_ram,
+            0x0,
+            signals.reset_source,
+            system.timing,
+        ));
+
+        // The board decides what appears at 0x4020 - 0xFFFF, and on the PPU
+        // bus below the palette
+        let parts = CartridgeParts {
+            prg_rom: rom.prg_rom,
+            chr: ChrMemory::new(rom.chr_rom, rom.chr_ram_size + rom.chr_nvram_size),
+            prg_ram,
+            prg_ram_size,
+            nametables: Nametables::new(rom.nametable_layout),
+            layout: rom.nametable_layout,
+            battery: rom.nvram_present,
+            save_path: config.save_path.clone(),
+            irq: signals.irq.make_source("cart"),
+            audio: config.audio.clone(),
+            timing: system.timing,
+        };
+        let mapper =
+            build_mapper(rom.mapper, parts).ok_or(EmuError::UnsupportedMapper(rom.mapper.id))?;
+        let (cpu_port, ppu_port) = connect_cartridge(mapper);
+        system.prg_rom_map = Some(cpu_port.prg_rom_map());
+        system.plug_in_cartridge("cartridge", 0x4020, 0x4020, 0xBFE0, Box::new(cpu_port));
+        let mut ppu_bus = GenericRouter::new();
+        ppu_bus.add_device(0x0000, 0x0000, 0x3F00, Box::new(ppu_port));
+        let nmi_line = signals.nmi.make_source("ppu");
+        system.attach_ppu(Box::new(ppu_bus), nmi_line);
+
+        Ok(system)
+    }
+
+    /// Build the parts of the console that don't depend on what's plugged
+    /// into the cartridge slot: CPU, internal RAM and APU. The PPU is added
+    /// by attach_ppu once its bus is built.
+    fn new_console(
+        tracer: &'t Tracer,
+        config: &NESConfig,
+        timing: &'static ConsoleTiming,
+    ) -> (Self, ConsoleSignals) {
         let mut reset_signal = PulseSignal::new();
-        let mut irq_signal = LevelSignal::new();
-        let mut nmi_signal = PulseSignal::new();
+        let mut irq_signal = WiredOrSignal::new();
+        let mut nmi_signal = WiredOrSignal::new();
+        // Dots that fall on the same master clock cycle as a CPU cycle's end
+        // come first. M2 and the APU's clocks are ticked ahead of the CPU's
+        // bus access on the cycles they share with it.
+        let mut scheduler = Scheduler::new();
+        let ppu_clock = scheduler.add_clock(timing.ppu_divider, 0);
+        let apu_clock = scheduler.add_clock(timing.cpu_divider * 2, timing.cpu_divider * 2 - 1);
+        let m2_clock = scheduler.add_clock(timing.cpu_divider, timing.cpu_divider - 1);
+        let cpu_clock = scheduler.add_clock(timing.cpu_divider, timing.cpu_divider - 1);
+        let mut apu = Apu::new(timing.apu_rates)
+            .with_irq_lines(
+                irq_signal.make_source("apu.frame"),
+                irq_signal.make_source("apu.dmc"),
+            )
+            .with_events(scheduler.event_queue());
+        if let Some(audio) = &config.audio {
+            apu = apu.with_audio_output(audio.clone());
+        }
+        let dmc_dma = apu.dmc_dma();
+        let apu = SharedApu::new(apu);
         let cpu_reset_signal = reset_signal.make_receiver();
-        let mut reset_controller = ResetController::new(reset_signal);
+        let mut reset_controller =
+            ResetController::new(reset_signal, scheduler.event_queue(), timing.cpu_divider);
         let reset_source = reset_controller.make_reset_source();
+        let breakpoints = Breakpoints::new();
         let mut system = NESSystem {
             cpu: Cpu6502::new(
                 tracer,
@@ -34,63 +312,323 @@ impl<'t> NESSystem<'t> {
                 irq_signal.make_receiver(),
                 cpu_reset_signal,
             ),
-            cpu_bus: GenericRouter::new(),
+            cpu_bus: WatchpointMonitor::new(GenericRouter::new(), breakpoints.clone()),
+            breakpoints,
+            prg_rom_map: None,
+            bus_map: Vec::new(),
+            ppu: None,
+            apu: apu.clone(),
             data_bus_state: 0,
             tracer,
+            scheduler,
+            cpu_clock,
+            ppu_clock,
+            apu_clock,
+            m2_clock,
+            cartridge: None,
             reset_controller,
             tick_count: 0,
+            access_monitor: None,
+            disk_control: None,
+            dmc_dma,
+            sprite_dma: None,
+            audio: config.audio.clone(),
+            timing,
         };
         // Internal RAM: 0x0000 - 0x1FFF, mirroring every 0x0800 bytes
         let internal_ram = RAMDevice::new(0x800);
-        let mirrorred_internal_ram = MirroringWrapper::new(internal_ram, 11);
-        system
-            .cpu_bus
-            .add_device(0x0000, 0x0000, 0x2000, Box::new(mirrorred_internal_ram));
+        if let Some(mode) = config.uninit_check {
+            let access_monitor = CpuAccessMonitor::new();
+            let checked_ram = UninitMemoryDetector::new(
+                internal_ram,
+                0x800,
+                mode,
+                access_monitor.clone(),
+                tracer,
+            );
+            system.access_monitor = Some(access_monitor);
+            system.map_cpu_device(
+                "internal RAM",
+                0x0000,
+                0x0000,
+                0x2000,
+                Box::new(MirroringWrapper::new(checked_ram, 11)),
+            );
+        } else {
+            let mirrorred_internal_ram = MirroringWrapper::new(internal_ram, 11);
+            system.map_cpu_device(
+                "internal RAM",
+                0x0000,
+                0x0000,
+                0x2000,
+                Box::new(mirrorred_internal_ram),
+            );
+        }
+
+        // APU and IO: 0x4000 - 0x4017
+        system.map_cpu_device("APU and IO", 0x4000, 0x0, 0x18, Box::new(apu));
 
+        let signals = ConsoleSignals {
+            reset_source,
+            irq: irq_signal,
+            nmi: nmi_signal,
+        };
+        (system, signals)
+    }
+
+    /// Connect the PPU, with the cartridge's side of its bus
+    fn attach_ppu(&mut self, ppu_bus: Box<dyn BusDevice + 't>, nmi_line: WiredOrSource) {
         // PPU: 0x2000 - 0x3FFF, mirroring every 0x0008 bytes
-        let fake_ppu = RAMDevice::new(0x8);
-        let mirrored_ppu = MirroringWrapper::new(fake_ppu, 3);
-        system
-            .cpu_bus
-            .add_device(0x2000, 0x0, 0x2000, Box::new(mirrored_ppu));
+        let ppu = SharedPpu::new(Ppu::new(ppu_bus, nmi_line, self.timing));
+        self.ppu = Some(ppu.clone());
+        let mirrored_ppu = MirroringWrapper::new(ppu, 3);
+        self.map_cpu_device("PPU", 0x2000, 0x0, 0x2000, Box::new(mirrored_ppu));
+    }
 
-        // APU and IO: 0x4000 - 0x4017
-        let fake_apu = RAMDevice::new(0x18);
-        system
-            .cpu_bus
-            .add_device(0x4000, 0x0, 0x18, Box::new(fake_apu));
+    /// Put a device on the CPU bus, recording it in the bus map under name
+    fn map_cpu_device(
+        &mut self,
+        name: &'static str,
+        start: u32,
+        target: u32,
+        len: u32,
+        device: Box<dyn BusDevice + 't>,
+    ) {
+        self.bus_map
+            .push((start as u16..=(start + len - 1) as u16, name));
+        self.cpu_bus
+            .device_mut()
+            .add_device(start, target, len, device);
+    }
 
-        //let prg_ram = Box::new(RAMDevice::new(rom.prg_ram_size));
-        let prg_ram = Box::new(TestROMMonitor::new(
-            RAMDevice::new(0x2000),
+    /// Put the device in the cartridge slot on the CPU bus. It's clocked by
+    /// M2 rather than by the bus's tick.
+    fn plug_in_cartridge(
+        &mut self,
+        name: &'static str,
+        start: u32,
+        target: u32,
+        len: u32,
+        device: Box<dyn BusDevice + 't>,
+    ) {
+        let slot = CartridgeSlot {
+            device: Rc::new(RefCell::new(device)),
+        };
+        self.cartridge = Some(slot.clone());
+        self.map_cpu_device(name, start, target, len, Box::new(slot));
+    }
+
+    /// Build a Famicom with the Disk System RAM adapter in the cartridge slot.
+    /// bios is the 8KB disk system BIOS ROM, mapped at 0xE000.
+    pub fn with_fds(
+        tracer: &'t Tracer,
+        disk: FdsImage,
+        bios: Vec<u8>,
+        config: &NESConfig,
+    ) -> EmuResult<Self> {
+        if bios.len() != 0x2000 {
+            return Err(EmuError::BadFdsBiosSize(bios.len()));
+        }
+        // The Disk System was only sold in Japan
+        let region = config.region.unwrap_or(Region::Ntsc);
+        let (mut system, mut signals) = Self::new_console(tracer, config, region.timing());
+
+        // The RAM adapter has 8KB of CHR-RAM, and controls mirroring
+        let nametables = NametableDevice::new(NametableLayout::Vertical);
+        let mut ppu_bus = GenericRouter::new();
+        ppu_bus.add_device(0x0000, 0x0000, 0x2000, Box::new(RAMDevice::new(0x2000)));
+        ppu_bus.add_device(0x2000, 0x2000, 0x1F00, Box::new(nametables.clone()));
+        let nmi_line = signals.nmi.make_source("ppu");
+        system.attach_ppu(Box::new(ppu_bus), nmi_line);
+
+        // Disk, timer and sound registers: 0x4020 - 0x4092
+        let mut adapter = FdsRamAdapter::new(
+            disk,
+            signals.irq.make_source("fds"),
+            config.save_path.clone(),
+            system.timing,
+        )
+        .with_nametables(nametables);
+        if let Some(audio) = &config.audio {
+            adapter = adapter.with_audio_output(audio.clone());
+        }
+        system.disk_control = Some(adapter.make_disk_control());
+        system.plug_in_cartridge("disk system", 0x4020, 0x0, 0x73, Box::new(adapter));
+
+        // PRG-RAM: 0x6000 - 0xDFFF
+        system.map_cpu_device(
+            "PRG-RAM",
+            0x6000,
             0x0,
-            reset_source,
-        ));
-        system.cpu_bus.add_device(0x6000, 0x0, 0x2000, prg_ram);
+            0x8000,
+            Box::new(RAMDevice::new(0x8000)),
+        );
 
-        // FIXME: temporary assumptions that work for the test ROMs
-        // Mapper 1, PRG-ROM small enough that no bank switching is needed
-        assert!(rom.mapper.id == 0);
-        assert!(rom.prg_rom.len() == 0x8000);
-        let prg_rom = Box::new(ROMDevice::new(rom.prg_rom));
-        system.cpu_bus.add_device(0x8000, 0x0000, 0x8000, prg_rom);
+        // BIOS: 0xE000 - 0xFFFF
+        system.map_cpu_device("BIOS", 0xE000, 0x0, 0x2000, Box::new(ROMDevice::new(bios)));
+
+        Ok(system)
+    }
+
+    /// Build a console that plays one song from an NSF, on a console of the
+    /// given region. A dual-region tune must be assigned one first.
+    pub fn with_nsf(
+        tracer: &'t Tracer,
+        nsf: &NsfFile,
+        song: u8,
+        region: NsfRegion,
+        config: &NESConfig,
+    ) -> Self {
+        let timing = Region::from_nsf_region(region).timing();
+        let (mut system, mut signals) = Self::new_console(tracer, config, timing);
+
+        // Expansion audio, driver, bankswitching, PRG-RAM and the tune:
+        // 0x4040 - 0xFFFF
+        // Nothing draws on the screen, so the PPU has an empty bus
+        let nmi_line = signals.nmi.make_source("ppu");
+        system.attach_ppu(Box::new(GenericRouter::new()), nmi_line);
+        let nmi_line = signals.nmi.make_source("nsf");
+        let mut cart = NsfCart::new(nsf, song, region, timing.cpu_clock_hz(), nmi_line);
+        if let Some(audio) = &config.audio {
+            cart = cart.with_audio_output(audio.clone());
+        }
+        system.plug_in_cartridge("NSF", 0x4040, 0x4040, 0xBFC0, Box::new(cart));
 
         system
     }
 
+    /// Clock rates and frame shape of the console being emulated
+    pub fn timing(&self) -> &'static ConsoleTiming {
+        self.timing
+    }
+
+    /// Handle for changing disks, if this console has a disk drive
+    pub fn disk_control(&self) -> Option<&FdsDiskControl> {
+        self.disk_control.as_ref()
+    }
+
     pub fn start_simulation(&mut self) -> EmuResult<()> {
         self.tick_count = 0;
-        self.cpu_bus.start_of_simulation()
+        self.cpu_bus.start_of_simulation()?;
+        self.power_cycle();
+        Ok(())
     }
 
     pub fn end_simulation(&mut self) {
         self.cpu_bus.end_of_simulation();
     }
 
+    /// Remove and reapply power. Every component returns to its power-on state.
+    pub fn power_cycle(&mut self) {
+        self.data_bus_state = 0;
+        self.sprite_dma = None;
+        // DMA that was about to start goes with the power
+        self.scheduler
+            .event_queue()
+            .cancel(|event| !matches!(event, ConsoleEvent::Restart(_)));
+        self.cpu.power_on();
+        self.cpu_bus.power_on();
+    }
+
+    /// Press the reset button. Only state wired to the reset line is
+    /// reinitialized; memory contents are retained.
+    pub fn reset(&mut self) {
+        self.reset_controller.trigger_reset();
+        self.cpu_bus.reset();
+    }
+
+    /// Run the scheduler through the end of the next CPU cycle
     fn run_tick(&mut self) -> EmuResult<()> {
-        self.reset_controller.tick();
-        match self.cpu.tick(self.data_bus_state)? {
+        loop {
+            match self.scheduler.advance() {
+                Tick::Event(event) => self.handle_event(event)?,
+                Tick::Clock(clock) if clock == self.ppu_clock => {
+                    if let Some(ppu) = &self.ppu {
+                        ppu.step_dot()?;
+                    }
+                }
+                Tick::Clock(clock) if clock == self.apu_clock => self.apu.clock_apu_cycle(),
+                Tick::Clock(clock) if clock == self.m2_clock => {
+                    self.apu.clock_cpu_cycle()?;
+                    if let Some(cartridge) = &self.cartridge {
+                        cartridge.clock_m2()?;
+                    }
+                }
+                Tick::Clock(clock) => {
+                    debug_assert_eq!(clock, self.cpu_clock);
+                    return self.run_cpu_cycle();
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: ConsoleEvent) -> EmuResult<()> {
+        match event {
+            ConsoleEvent::Restart(kind) => {
+                // The controller signals the CPU itself
+                self.reset_controller.handle(kind);
+                match kind {
+                    ResetKind::Reset => self.cpu_bus.reset(),
+                    ResetKind::PowerCycle => self.power_cycle(),
+                }
+            }
+            // DMC sample fetches take the bus without stalling the CPU for now
+            ConsoleEvent::DmcFetch(addr) => {
+                self.cpu_bus.observe_access(addr as u32, BusActivity::Read);
+                let data = match self.cpu_bus.bus_read(addr as u32)? {
+                    ReadResult::Data(value) => value,
+                    ReadResult::OpenBus => self.data_bus_state,
+                };
+                self.dmc_dma.complete(data);
+            }
+            ConsoleEvent::SpriteDma(page) => {
+                self.sprite_dma = Some(SpriteDma {
+                    page,
+                    // The next cycle follows the write to $4014. A write on
+                    // an odd cycle leaves the copy waiting one more for a
+                    // read cycle.
+                    wait: 1 + self.tick_count.is_multiple_of(2) as u8,
+                    copied: 0,
+                    data: None,
+                    cpu_data_bus: self.data_bus_state,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn run_cpu_cycle(&mut self) -> EmuResult<()> {
+        self.cpu_bus.tick()?;
+        if self.sprite_dma.is_some() {
+            self.run_sprite_dma_cycle()?;
+        } else {
+            self.run_cpu_access()?;
+        }
+        self.cpu.end_cycle();
+        if let Some(audio) = &self.audio {
+            audio.end_cycle();
+        }
+        self.tick_count += 1;
+        Ok(())
+    }
+
+    /// Let the CPU make its access for this cycle
+    fn run_cpu_access(&mut self) -> EmuResult<()> {
+        let access = self.cpu.tick(self.data_bus_state)?;
+        if let Some(monitor) = &self.access_monitor {
+            let addr = match access {
+                BusAccess::Read(addr) | BusAccess::Write(addr, _) => addr,
+            };
+            monitor.set(CpuAccessInfo {
+                pc: self.cpu.instruction_pc(),
+                cycle: self.tick_count,
+                addr,
+                stack: self.cpu.stack_access(),
+            });
+        }
+        match access {
             BusAccess::Read(addr) => {
+                self.cpu_bus.observe_access(addr as u32, BusActivity::Read);
                 match self.cpu_bus.bus_read(addr as u32)? {
                     ReadResult::Data(value) => self.data_bus_state = value,
                     ReadResult::OpenBus => {}
@@ -104,6 +642,8 @@ impl<'t> NESSystem<'t> {
             BusAccess::Write(addr, value) => {
                 // Handle write operation
                 self.data_bus_state = value;
+                self.cpu_bus
+                    .observe_access(addr as u32, BusActivity::Write(value));
                 self.cpu_bus.bus_write(addr as u32, value)?;
 
                 self.tracer.trace_event(
@@ -112,7 +652,34 @@ impl<'t> NESSystem<'t> {
                 );
             }
         }
-        self.tick_count += 1;
+        Ok(())
+    }
+
+    /// Run one cycle of the sprite DMA in place of the halted CPU
+    fn run_sprite_dma_cycle(&mut self) -> EmuResult<()> {
+        let Some(mut dma) = self.sprite_dma.take() else {
+            return Ok(());
+        };
+        if dma.wait > 0 {
+            dma.wait -= 1;
+        } else if let Some(data) = dma.data.take() {
+            self.cpu_bus
+                .observe_access(OAMDATA_ADDR as u32, BusActivity::Write(data));
+            self.cpu_bus.bus_write(OAMDATA_ADDR as u32, data)?;
+            dma.copied += 1;
+            if dma.copied == 0x100 {
+                self.data_bus_state = dma.cpu_data_bus;
+                return Ok(());
+            }
+        } else {
+            let addr = (dma.page as u16) << 8 | dma.copied;
+            self.cpu_bus.observe_access(addr as u32, BusActivity::Read);
+            if let ReadResult::Data(value) = self.cpu_bus.bus_read(addr as u32)? {
+                self.data_bus_state = value;
+            }
+            dma.data = Some(self.data_bus_state);
+        }
+        self.sprite_dma = Some(dma);
         Ok(())
     }
 
@@ -124,14 +691,501 @@ impl<'t> NESSystem<'t> {
         self.tick_count
     }
 
-    pub fn run(&mut self, tick_limit: Option<u64>) -> EmuResult<()> {
+    /// Master clock cycles since the console was built
+    pub fn master_cycle(&self) -> u64 {
+        self.scheduler.master_cycle()
+    }
+
+    /// Frames the PPU has finished since power-on
+    pub fn frame_count(&self) -> u64 {
+        self.ppu.as_ref().map_or(0, |ppu| ppu.frame_count())
+    }
+
+    /// Breakpoints checked as the console runs. They stop it with
+    /// StopReason::Breakpoint.
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    /// Read the CPU's address space without side effects. None for
+    /// addresses with nothing behind them, and registers whose reads
+    /// change state.
+    pub fn peek(&self, addr: u16) -> Option<u8> {
+        self.cpu_bus.peek(addr as u32)
+    }
+
+    /// Write to the CPU's address space as the CPU would, so registers and
+    /// mapper ports act on it. Watchpoints don't see these writes.
+    pub fn write_memory(&mut self, addr: u16, value: u8) -> EmuResult<()> {
+        let bus = self.cpu_bus.device_mut();
+        bus.observe_access(addr as u32, BusActivity::Write(value));
+        bus.bus_write(addr as u32, value)
+    }
+
+    /// What each range of the CPU's address space is mapped to, in the
+    /// order the devices were added
+    pub fn bus_map(&self) -> &[(RangeInclusive<u16>, &'static str)] {
+        &self.bus_map
+    }
+
+    /// The PPU's registers and position in the frame
+    pub fn ppu_state(&self) -> Option<PpuState> {
+        self.ppu.as_ref().map(|ppu| ppu.state())
+    }
+
+    pub fn apu_state(&self) -> ApuState {
+        self.apu.state()
+    }
+
+    /// Offset into the cartridge's PRG-ROM that the CPU sees at addr
+    pub fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        self.prg_rom_map.as_ref()?.offset(addr)
+    }
+
+    /// Whether the CPU is between instructions, so the next cycle fetches
+    /// an opcode. A sprite DMA counts as part of the write to $4014 that
+    /// started it.
+    pub fn at_instruction_boundary(&self) -> bool {
+        self.cpu.at_instruction_boundary()
+            && self.sprite_dma.is_none()
+            && !self
+                .scheduler
+                .event_queue()
+                .is_pending(|event| matches!(event, ConsoleEvent::SpriteDma(_)))
+    }
+
+    /// Run a single CPU cycle, along with the PPU dots and events that fall
+    /// before it
+    pub fn step_cycle(&mut self) -> EmuResult<StopReason> {
+        Ok(self.run_cycle()?.unwrap_or(StopReason::Stepped))
+    }
+
+    /// Run to the end of the current instruction, or through the next one if
+    /// the CPU is already between instructions. Interrupt and reset
+    /// sequences count as instructions.
+    pub fn step_instruction(&mut self) -> EmuResult<StopReason> {
+        self.run_checked(|nes| nes.at_instruction_boundary().then_some(StopReason::Stepped))
+    }
+
+    /// Run until the PPU has finished at least frame frames since power-on
+    pub fn run_until_frame(&mut self, frame: u64) -> EmuResult<StopReason> {
+        self.run_checked(|nes| (nes.frame_count() >= frame).then_some(StopReason::FrameReached))
+    }
+
+    /// Run until stop returns true. It's checked at the end of every CPU
+    /// cycle, starting after the first.
+    pub fn run_until(&mut self, mut stop: impl FnMut(&Self) -> bool) -> EmuResult<StopReason> {
+        self.run_checked(|nes| stop(nes).then_some(StopReason::ConditionMet))
+    }
+
+    /// Run until the CPU has executed tick_limit cycles since the simulation
+    /// started, or without a limit until something else stops it
+    pub fn run(&mut self, tick_limit: Option<u64>) -> EmuResult<StopReason> {
+        let limit_reached = |nes: &Self| {
+            tick_limit
+                .is_some_and(|limit| nes.tick_count >= limit)
+                .then_some(StopReason::LimitReached)
+        };
+        if let Some(reason) = limit_reached(self) {
+            return Ok(reason);
+        }
+        self.run_checked(limit_reached)
+    }
+
+    /// Run cycles until one stops on its own or check gives a reason to
+    pub(crate) fn run_checked(
+        &mut self,
+        mut check: impl FnMut(&Self) -> Option<StopReason>,
+    ) -> EmuResult<StopReason> {
         loop {
-            if let Some(limit) = tick_limit
-                && self.tick_count >= limit
-            {
-                return Err(EmuError::CycleLimitReached);
+            if let Some(reason) = self.run_cycle()?.or_else(|| check(self)) {
+                return Ok(reason);
             }
-            self.run_tick()?;
         }
     }
+
+    /// Run one CPU cycle, turning the errors that mean the program being
+    /// run has finished into stop reasons
+    fn run_cycle(&mut self) -> EmuResult<Option<StopReason>> {
+        match self.run_tick() {
+            Ok(()) => Ok(self.check_breakpoints()),
+            Err(EmuError::StopEmulation) => Ok(Some(StopReason::TestPassed)),
+            Err(EmuError::TestROMFailure(code)) => Ok(Some(StopReason::TestFailed(code))),
+            Err(EmuError::IllegalCpuOpcode(opcode)) => Ok(Some(StopReason::Jam(opcode))),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn check_breakpoints(&self) -> Option<StopReason> {
+        if self.breakpoints.is_empty() {
+            return None;
+        }
+        let regs = self.cpu.get_regs();
+        let cpu = CpuState {
+            regs,
+            at_boundary: self.at_instruction_boundary(),
+            interrupt: self.cpu.interrupt(),
+            bank: self
+                .prg_rom_offset(*regs.pc)
+                .map(|offset| offset / PRG_BANK_SIZE),
+        };
+        self.breakpoints
+            .check(&cpu, &|addr| self.peek(addr))
+            .map(StopReason::Breakpoint)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::components::{
+        breakpoint::{Condition, Trigger, WatchAccess},
+        cpu::Interrupt,
+    };
+
+    /// NROM image with program at 0xC000, which reset jumps to
+    pub(crate) fn build_nes<'t>(tracer: &'t Tracer, program: &[u8]) -> NESSystem<'t> {
+        NESSystem::new(tracer, build_rom(program, 0, None)).unwrap()
+    }
+
+    /// NROM image with the given flags in header byte 6, and a trainer if
+    /// one is given
+    fn build_rom(program: &[u8], flags6: u8, trainer: Option<&[u8; 512]>) -> NesFile {
+        let flags6 = flags6 | if trainer.is_some() { 0x04 } else { 0 };
+        let mut data = vec![
+            b'N', b'E', b'S', 0x1A, 1, 1, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        if let Some(trainer) = trainer {
+            data.extend(trainer);
+        }
+        let mut prg = vec![0xEA; 0x4000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x3FFC..0x3FFE].copy_from_slice(&[0x00, 0xC0]);
+        data.extend(prg);
+        data.resize(data.len() + 0x2000, 0);
+        NesFile::from_stream(&mut &data[..]).unwrap()
+    }
+
+    #[test]
+    fn test_stepping() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(
+            &tracer,
+            &[
+                0xA9, 0x40, // LDA #$40
+                0x85, 0x00, // STA $00
+                0xE6, 0x00, // INC $00
+                0x4C, 0x04, 0xC0, // JMP $C004
+            ],
+        );
+        nes.start_simulation().unwrap();
+        assert!(!nes.at_instruction_boundary());
+
+        assert_eq!(nes.step_cycle(), Ok(StopReason::Stepped));
+        assert_eq!(nes.get_tick_count(), 1);
+
+        // Finish the reset sequence, then run LDA and STA a cycle at a time
+        assert_eq!(nes.step_instruction(), Ok(StopReason::Stepped));
+        assert_eq!(*nes.get_regs().pc, 0xC000);
+        let start = nes.get_tick_count();
+        assert_eq!(nes.step_instruction(), Ok(StopReason::Stepped));
+        assert_eq!(*nes.get_regs().a, 0x40);
+        assert_eq!(nes.get_tick_count() - start, 2);
+        assert_eq!(nes.step_instruction(), Ok(StopReason::Stepped));
+        assert_eq!(*nes.get_regs().pc, 0xC004);
+        assert_eq!(nes.get_tick_count() - start, 5);
+
+        assert_eq!(
+            nes.run_until(|nes| *nes.get_regs().pc == 0xC006 && nes.at_instruction_boundary()),
+            Ok(StopReason::ConditionMet)
+        );
+        assert_eq!(nes.get_tick_count() - start, 10);
+
+        let limit = nes.get_tick_count() + 100;
+        assert_eq!(nes.run(Some(limit)), Ok(StopReason::LimitReached));
+        assert_eq!(nes.get_tick_count(), limit);
+        assert_eq!(nes.run(Some(limit)), Ok(StopReason::LimitReached));
+        assert_eq!(nes.get_tick_count(), limit);
+
+        assert_eq!(nes.run_until_frame(2), Ok(StopReason::FrameReached));
+        assert_eq!(nes.frame_count(), 2);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(
+            &tracer,
+            &[
+                0xA9, 0x40, // LDA #$40
+                0x85, 0x00, // STA $00
+                0xE6, 0x00, // INC $00
+                0x4C, 0x04, 0xC0, // JMP $C004
+            ],
+        );
+        let breakpoints = nes.breakpoints().clone();
+        let reset = breakpoints.add(Trigger::Interrupt(Interrupt::Reset), None);
+        // The 16KB of PRG-ROM is mirrored, so 0xC004 is in the first bank
+        breakpoints.add(
+            Trigger::Pc {
+                addr: 0xC004,
+                bank: Some(1),
+            },
+            None,
+        );
+        let loop_start = breakpoints.add(
+            Trigger::Pc {
+                addr: 0xC004,
+                bank: Some(0),
+            },
+            None,
+        );
+        nes.start_simulation().unwrap();
+
+        assert_eq!(nes.run(None), Ok(StopReason::Breakpoint(reset)));
+        assert_eq!(*nes.get_regs().pc, 0xC000);
+        assert_eq!(nes.run(None), Ok(StopReason::Breakpoint(loop_start)));
+        assert_eq!(nes.peek(0x0000), Some(0x40));
+        assert_eq!(nes.peek(0xC004), Some(0xE6));
+
+        breakpoints.remove(loop_start);
+        let watch = breakpoints.add(
+            Trigger::Watch {
+                range: 0x0000..=0x0000,
+                access: WatchAccess {
+                    write: true,
+                    ..Default::default()
+                },
+            },
+            Some(Condition::parse("[$00] == $43").unwrap()),
+        );
+        assert_eq!(nes.run(None), Ok(StopReason::Breakpoint(watch)));
+        // Stopped on INC's final write, not the dummy write before it
+        assert_eq!(nes.peek(0x0000), Some(0x43));
+        assert!(!nes.at_instruction_boundary());
+    }
+
+    #[test]
+    fn test_jam() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(&tracer, &[0xEA, 0x02]);
+        nes.start_simulation().unwrap();
+        assert_eq!(nes.run(None), Ok(StopReason::Jam(0x02)));
+    }
+
+    #[test]
+    fn test_unsupported_mapper() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        // Mapper 4, MMC3
+        let result = NESSystem::new(&tracer, build_rom(&[], 0x40, None));
+        assert!(matches!(result, Err(EmuError::UnsupportedMapper(4))));
+    }
+
+    #[test]
+    fn test_empty_prg_rom() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut rom = build_rom(&[], 0, None);
+        rom.prg_rom.clear();
+        let result = NESSystem::new(&tracer, rom);
+        assert!(matches!(result, Err(EmuError::EmptyPrgRom)));
+    }
+
+    #[test]
+    fn test_ppu_warmup_after_reset() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        // LDA #$1E; STA $2001; JMP $C000
+        let mut nes = build_nes(&tracer, &[0xA9, 0x1E, 0x8D, 0x01, 0x20, 0x4C, 0x00, 0xC0]);
+        let warmup = nes.timing().ppu_warmup_cpu_cycles as u64;
+        let mask = |nes: &NESSystem| nes.ppu_state().unwrap().mask;
+        nes.start_simulation().unwrap();
+
+        // The program's writes to PPUMASK only land once the PPU has warmed
+        // up, after power-on and again after a reset
+        for _ in 0..2 {
+            let start = nes.get_tick_count();
+            nes.run(Some(start + 100)).unwrap();
+            assert_eq!(mask(&nes), 0);
+            nes.run(Some(start + warmup - 10)).unwrap();
+            assert_eq!(mask(&nes), 0);
+            nes.run(Some(start + warmup + 100)).unwrap();
+            assert_eq!(mask(&nes), 0x1E);
+            nes.reset();
+        }
+    }
+
+    #[test]
+    fn test_fds_bios_size() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let disk = || FdsImage::parse(&crate::fds::test::build_side()).unwrap();
+        let result = NESSystem::with_fds(&tracer, disk(), vec![0; 0x1000], &NESConfig::default());
+        assert!(matches!(result, Err(EmuError::BadFdsBiosSize(0x1000))));
+        assert!(
+            NESSystem::with_fds(&tracer, disk(), vec![0; 0x2000], &NESConfig::default()).is_ok()
+        );
+    }
+
+    #[test]
+    fn test_trainer() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut trainer = [0xA5; 512];
+        trainer[0] = 0x12;
+
+        // Plain PRG-RAM gets the trainer back at every power-on
+        let mut nes = NESSystem::new(&tracer, build_rom(&[], 0, Some(&trainer))).unwrap();
+        nes.start_simulation().unwrap();
+        assert_eq!(nes.peek(0x7000), Some(0x12));
+        assert_eq!(nes.peek(0x71FF), Some(0xA5));
+        nes.write_memory(0x7000, 0x99).unwrap();
+        nes.power_cycle();
+        assert_eq!(nes.peek(0x7000), Some(0x12));
+
+        // Battery RAM starts out with it, but isn't saved because of it
+        let save_path =
+            std::env::temp_dir().join(format!("trainer_test_{}.sav", std::process::id()));
+        let _ = std::fs::remove_file(&save_path);
+        let config = NESConfig {
+            save_path: Some(save_path.clone()),
+            ..Default::default()
+        };
+        let battery_rom = || build_rom(&[], 0x02, Some(&trainer));
+        let mut nes = NESSystem::with_config(&tracer, battery_rom(), &config).unwrap();
+        nes.start_simulation().unwrap();
+        assert_eq!(nes.peek(0x7000), Some(0x12));
+        nes.power_cycle();
+        nes.end_simulation();
+        assert!(!save_path.exists());
+
+        // A save replaces it, and survives power-on
+        let mut save = vec![0; 0x2000];
+        save[0x0000] = 0x56;
+        save[0x1000] = 0x34;
+        std::fs::write(&save_path, &save).unwrap();
+        let mut nes = NESSystem::with_config(&tracer, battery_rom(), &config).unwrap();
+        nes.start_simulation().unwrap();
+        assert_eq!(nes.peek(0x6000), Some(0x56));
+        assert_eq!(nes.peek(0x7000), Some(0x34));
+        nes.power_cycle();
+        nes.run(Some(100)).unwrap();
+        assert_eq!(nes.peek(0x7000), Some(0x34));
+        nes.end_simulation();
+        assert_eq!(std::fs::read(&save_path).unwrap(), save);
+        std::fs::remove_file(&save_path).unwrap();
+    }
+
+    #[test]
+    fn test_reset_and_power_cycle() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(&tracer, &[0x4C, 0x00, 0xC0]); // JMP $C000
+        nes.start_simulation().unwrap();
+        nes.run(Some(100)).unwrap();
+        assert_eq!(*nes.get_regs().s, 0xFD);
+        nes.write_memory(0x0300, 0x55).unwrap();
+        nes.write_memory(0x6000, 0x66).unwrap();
+
+        // Reset runs the reset sequence again, which leaves RAM alone and
+        // moves the stack pointer down another 3
+        nes.reset();
+        nes.run(Some(200)).unwrap();
+        nes.run_until(|nes| nes.at_instruction_boundary()).unwrap();
+        assert_eq!(*nes.get_regs().s, 0xFA);
+        assert_eq!(*nes.get_regs().pc, 0xC000);
+        assert_eq!(nes.peek(0x0300), Some(0x55));
+        assert_eq!(nes.peek(0x6000), Some(0x66));
+
+        // A power cycle starts everything over
+        nes.power_cycle();
+        nes.run(Some(300)).unwrap();
+        assert_eq!(*nes.get_regs().s, 0xFD);
+        assert_eq!(nes.peek(0x0300), Some(0));
+        assert_eq!(nes.peek(0x6000), Some(0));
+    }
+
+    #[test]
+    fn test_sprite_dma() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut nes = build_nes(
+            &tracer,
+            &[
+                0xA9, 0x02, // LDA #$02
+                0x8D, 0x14, 0x40, // STA $4014
+                0xA9, 0x05, // LDA #$05
+                0x8D, 0x03, 0x20, // STA $2003
+                0xAD, 0x04, 0x20, // LDA $2004
+            ],
+        );
+        nes.start_simulation().unwrap();
+        nes.step_instruction().unwrap();
+        for i in 0..=0xFF {
+            nes.write_memory(0x0200 + i, i as u8 ^ 0x5A).unwrap();
+        }
+        nes.step_instruction().unwrap();
+
+        // The CPU is halted for 513 cycles after the write
+        let start = nes.get_tick_count();
+        nes.step_instruction().unwrap();
+        assert_eq!(*nes.get_regs().pc, 0xC005);
+        assert_eq!(nes.get_tick_count() - start, 4 + 513);
+        // 256 writes to OAMDATA bring OAMADDR back around to 0
+        assert_eq!(nes.ppu_state().unwrap().oam_addr, 0);
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+        assert_eq!(*nes.get_regs().a, 0x05 ^ 0x5A);
+
+        // A 3-cycle instruction first moves the write to an odd cycle, which
+        // costs one more
+        let mut nes = build_nes(
+            &tracer,
+            &[
+                0xA5, 0x00, // LDA $00
+                0xA9, 0x02, // LDA #$02
+                0x8D, 0x14, 0x40, // STA $4014
+            ],
+        );
+        nes.start_simulation().unwrap();
+        for _ in 0..3 {
+            nes.step_instruction().unwrap();
+        }
+        let start = nes.get_tick_count();
+        nes.step_instruction().unwrap();
+        assert_eq!(nes.get_tick_count() - start, 4 + 514);
+    }
+
+    #[test]
+    fn test_interrupt_return() {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let mut rom = build_rom(
+            &[
+                0x58, // CLI
+                0xE8, // INX
+                0x4C, 0x01, 0xC0, // JMP $C001
+                0xAD, 0x15, 0x40, // LDA $4015
+                0x40, // RTI
+            ],
+            0,
+            None,
+        );
+        // IRQ handler at 0xC005, where the APU's frame IRQ goes
+        rom.prg_rom[0x3FFE..].copy_from_slice(&[0x05, 0xC0]);
+        let mut nes = NESSystem::new(&tracer, rom).unwrap();
+        nes.start_simulation().unwrap();
+
+        let mut displaced = 0;
+        while *nes.get_regs().pc != 0xC005 {
+            displaced = *nes.get_regs().pc;
+            assert_eq!(nes.step_instruction(), Ok(StopReason::Stepped));
+        }
+        // The return address is the instruction the IRQ was taken in place of
+        let s = *nes.get_regs().s as u16;
+        let pushed_pc = u16::from_le_bytes([
+            nes.peek(0x0100 + s + 2).unwrap(),
+            nes.peek(0x0100 + s + 3).unwrap(),
+        ]);
+        assert_eq!(pushed_pc, displaced);
+
+        nes.step_instruction().unwrap();
+        nes.step_instruction().unwrap();
+        assert_eq!(*nes.get_regs().pc, displaced);
+    }
 }
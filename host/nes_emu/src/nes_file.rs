@@ -1,7 +1,112 @@
-use std::io::{self, Read};
+use std::{
+    fmt,
+    io::{self, Read, Write},
+};
 
-#[derive(Clone)]
+use thiserror::Error;
+
+/// Section of an NES file, used for error reporting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NesFileSection {
+    Header,
+    Trainer,
+    PrgRom,
+    ChrRom,
+    UnifChunk,
+}
+
+impl fmt::Display for NesFileSection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NesFileSection::Header => write!(f, "header"),
+            NesFileSection::Trainer => write!(f, "trainer"),
+            NesFileSection::PrgRom => write!(f, "PRG-ROM"),
+            NesFileSection::ChrRom => write!(f, "CHR-ROM"),
+            NesFileSection::UnifChunk => write!(f, "UNIF chunk"),
+        }
+    }
+}
+
+/// Fatal errors encountered while parsing an NES file
+#[derive(Debug, Error)]
+pub enum NesFileError {
+    #[error("I/O error reading NES file: {0}")]
+    Io(#[from] io::Error),
+    #[error("Bad magic number {0:02X?}, expected \"NES\\x1A\" or \"UNIF\"")]
+    BadMagic([u8; 4]),
+    #[error("Truncated {section}: expected {expected} bytes, found {actual}")]
+    Truncated {
+        section: NesFileSection,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("{section} size in header doesn't fit in memory")]
+    SizeOverflow { section: NesFileSection },
+    #[error("Invalid timing mode {value} in header byte {offset}")]
+    InvalidTimingMode { value: u8, offset: usize },
+    #[error("Invalid console type 0x{value:02X} in header byte {offset}")]
+    InvalidConsoleType { value: u8, offset: usize },
+    #[error("UNIF file has no {0} chunk")]
+    MissingUnifChunk(&'static str),
+    #[error("Unknown UNIF board \"{0}\"")]
+    UnknownUnifBoard(String),
+}
+
+/// Non-fatal inconsistencies found by NesFile::validate. The file is still
+/// usable, but some of its header fields are probably wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NesFileWarning {
+    /// CHR-RAM is declared alongside CHR-ROM, which few boards actually have
+    ChrRamWithChrRom {
+        chr_rom_size: usize,
+        chr_ram_size: usize,
+    },
+    /// The misc ROM count doesn't agree with the data after CHR-ROM
+    MiscRomMismatch { declared: u8, trailing_bytes: usize },
+    /// Battery-backed RAM sizes are given, but the battery flag is clear
+    NvramWithoutBattery,
+    /// The battery flag is set, but there's no battery-backed RAM to keep
+    BatteryWithoutNvram,
+    /// There's no PRG-ROM, so the CPU has nothing to execute
+    EmptyPrgRom,
+}
+
+impl fmt::Display for NesFileWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NesFileWarning::ChrRamWithChrRom {
+                chr_rom_size,
+                chr_ram_size,
+            } => write!(
+                f,
+                "{} bytes of CHR-RAM declared alongside {} bytes of CHR-ROM",
+                chr_ram_size, chr_rom_size
+            ),
+            NesFileWarning::MiscRomMismatch {
+                declared,
+                trailing_bytes,
+            } => write!(
+                f,
+                "Header declares {} misc ROMs, but {} bytes follow CHR-ROM",
+                declared, trailing_bytes
+            ),
+            NesFileWarning::NvramWithoutBattery => {
+                write!(
+                    f,
+                    "Battery-backed RAM declared, but the battery flag is clear"
+                )
+            }
+            NesFileWarning::BatteryWithoutNvram => {
+                write!(f, "Battery flag set, but no battery-backed RAM is declared")
+            }
+            NesFileWarning::EmptyPrgRom => write!(f, "No PRG-ROM present"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct NesFile {
+    pub header_version: HeaderVersion,
     pub nametable_layout: NametableLayout,
     pub nvram_present: bool,
     pub mapper: MapperId,
@@ -21,19 +126,27 @@ pub struct NesFile {
 }
 
 impl NesFile {
-    pub fn from_stream(reader: &mut dyn Read) -> Result<Self, io::Error> {
-        let header = {
-            let mut buf = [0u8; 16];
-            reader.read_exact(&mut buf)?;
-            buf
-        };
+    /// Read an iNES/NES 2.0 or UNIF file, depending on its magic number
+    pub fn load(reader: &mut dyn Read) -> Result<Self, NesFileError> {
+        let magic = read_exact_to_vec(reader, 4, NesFileSection::Header)?;
+        let mut reader = magic.as_slice().chain(reader);
+        if magic == b"UNIF" {
+            Self::from_unif_stream(&mut reader)
+        } else {
+            Self::from_stream(&mut reader)
+        }
+    }
+
+    pub fn from_stream(reader: &mut dyn Read) -> Result<Self, NesFileError> {
+        let header: [u8; 16] = read_exact_to_vec(reader, 16, NesFileSection::Header)?
+            .try_into()
+            .unwrap();
         if &header[0..=3] != b"NES\x1A" {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "Invalid NES file",
-            ));
+            return Err(NesFileError::BadMagic(header[0..4].try_into().unwrap()));
         }
 
+        let header_version = HeaderVersion::detect(&header);
+
         let prg_rom_size_lsb = header[4];
         let chr_rom_size_lsb = header[5];
 
@@ -45,6 +158,252 @@ impl NesFile {
         let trainer_present = header[6] & 0b0000_0100 != 0;
         let mapper_0to3 = header[6] >> 4;
 
+        let header_fields = match header_version {
+            HeaderVersion::Nes2 => Self::decode_nes2_fields(&header, mapper_0to3)?,
+            HeaderVersion::INes => Self::decode_ines_fields(&header, mapper_0to3, nvram_present),
+            HeaderVersion::ArchaicINes => {
+                Self::decode_archaic_ines_fields(mapper_0to3, nvram_present)
+            }
+            HeaderVersion::Unif => unreachable!("not detected from an iNES header"),
+        };
+        let HeaderFields {
+            mapper,
+            timing,
+            console_type,
+            misc_rom_count,
+            default_expansion_device,
+            prg_ram_size,
+            prg_nvram_size,
+            mut chr_ram_size,
+            chr_nvram_size,
+            prg_rom_size_msb,
+            chr_rom_size_msb,
+        } = header_fields;
+
+        let prg_rom_size = decode_rom_size(prg_rom_size_lsb, prg_rom_size_msb, 16 * 1024).ok_or(
+            NesFileError::SizeOverflow {
+                section: NesFileSection::PrgRom,
+            },
+        )?;
+        let chr_rom_size = decode_rom_size(chr_rom_size_lsb, chr_rom_size_msb, 8 * 1024).ok_or(
+            NesFileError::SizeOverflow {
+                section: NesFileSection::ChrRom,
+            },
+        )?;
+        if header_version != HeaderVersion::Nes2 && chr_rom_size == 0 {
+            // Older headers imply 8KB of CHR-RAM when there's no CHR-ROM
+            chr_ram_size = 8 * 1024;
+        }
+
+        let trainer = if trainer_present {
+            let buf = read_exact_to_vec(reader, 512, NesFileSection::Trainer)?;
+            Some(buf.try_into().unwrap())
+        } else {
+            None
+        };
+        let prg_rom = read_exact_to_vec(reader, prg_rom_size, NesFileSection::PrgRom)?;
+        let chr_rom = read_exact_to_vec(reader, chr_rom_size, NesFileSection::ChrRom)?;
+        let misc_rom = {
+            let mut buf = Vec::new();
+            reader.read_to_end(&mut buf)?;
+            buf
+        };
+
+        Ok(NesFile {
+            header_version,
+            nametable_layout,
+            nvram_present,
+            mapper,
+            timing,
+            console_type,
+            misc_rom_count,
+            default_expansion_device,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size,
+            trainer,
+            prg_rom,
+            chr_rom,
+            misc_rom,
+        })
+    }
+
+    /// Serialize the file, using the format in header_version. Archaic iNES
+    /// headers are written as iNES 1.0, UNIF files as NES 2.0, and files that
+    /// can't be represented in iNES 1.0 are written as NES 2.0.
+    pub fn write_to(&self, writer: &mut dyn Write) -> io::Result<()> {
+        let header = match self.header_version {
+            HeaderVersion::INes | HeaderVersion::ArchaicINes => self
+                .encode_ines_header()
+                .map_or_else(|| self.encode_nes2_header(), Ok)?,
+            HeaderVersion::Nes2 | HeaderVersion::Unif => self.encode_nes2_header()?,
+        };
+        writer.write_all(&header)?;
+        if let Some(trainer) = &self.trainer {
+            writer.write_all(trainer)?;
+        }
+        writer.write_all(&self.prg_rom)?;
+        writer.write_all(&self.chr_rom)?;
+        writer.write_all(&self.misc_rom)?;
+        Ok(())
+    }
+
+    /// The version write_to will actually use for the header
+    pub fn output_header_version(&self) -> HeaderVersion {
+        match self.header_version {
+            HeaderVersion::INes | HeaderVersion::ArchaicINes
+                if self.encode_ines_header().is_some() =>
+            {
+                HeaderVersion::INes
+            }
+            _ => HeaderVersion::Nes2,
+        }
+    }
+
+    fn encode_flags6(&self) -> u8 {
+        let (arrangement, alternative) = self.nametable_layout.to_flags();
+        let mut flags6 = ((self.mapper.id & 0x0F) as u8) << 4;
+        if arrangement {
+            flags6 |= 0b0000_0001;
+        }
+        if self.nvram_present {
+            flags6 |= 0b0000_0010;
+        }
+        if self.trainer.is_some() {
+            flags6 |= 0b0000_0100;
+        }
+        if alternative {
+            flags6 |= 0b0000_1000;
+        }
+        flags6
+    }
+
+    fn encode_nes2_header(&self) -> io::Result<[u8; 16]> {
+        let invalid = |what: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} can't be represented in an NES 2.0 header", what),
+            )
+        };
+        if self.mapper.id > 0xFFF || self.mapper.sub_id > 0xF {
+            return Err(invalid("Mapper number"));
+        }
+        let (prg_lsb, prg_msb) = encode_rom_size(self.prg_rom.len(), 16 * 1024)
+            .ok_or_else(|| invalid("PRG-ROM size"))?;
+        let (chr_lsb, chr_msb) =
+            encode_rom_size(self.chr_rom.len(), 8 * 1024).ok_or_else(|| invalid("CHR-ROM size"))?;
+        let ram_shift = |size| encode_ram_size(size).ok_or_else(|| invalid("RAM size"));
+        let (console_base_type, console_extended_type) = self.console_type.to_flags();
+
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_lsb;
+        header[5] = chr_lsb;
+        header[6] = self.encode_flags6();
+        header[7] = (((self.mapper.id >> 4) & 0x0F) as u8) << 4 | 0x08 | console_base_type;
+        header[8] = (self.mapper.sub_id << 4) | ((self.mapper.id >> 8) & 0x0F) as u8;
+        header[9] = (chr_msb << 4) | prg_msb;
+        header[10] = (ram_shift(self.prg_nvram_size)? << 4) | ram_shift(self.prg_ram_size)?;
+        header[11] = (ram_shift(self.chr_nvram_size)? << 4) | ram_shift(self.chr_ram_size)?;
+        header[12] = self.timing.to_u8();
+        header[13] = console_extended_type;
+        header[14] = self.misc_rom_count & 0x3;
+        header[15] = self.default_expansion_device & 0x3F;
+        Ok(header)
+    }
+
+    /// Encode an iNES 1.0 header, if every field can be represented in one
+    fn encode_ines_header(&self) -> Option<[u8; 16]> {
+        let prg_units = self.prg_rom.len() / (16 * 1024);
+        let chr_units = self.chr_rom.len() / (8 * 1024);
+        // iNES 1.0 has a single PRG-RAM size in 8KB units, which is
+        // battery-backed if the battery flag is set. Zero means 8KB.
+        let (prg_ram_size, other_prg_ram_size) = if self.nvram_present {
+            (self.prg_nvram_size, self.prg_ram_size)
+        } else {
+            (self.prg_ram_size, self.prg_nvram_size)
+        };
+        let implied_chr_ram_size = if self.chr_rom.is_empty() { 8 * 1024 } else { 0 };
+        let console_bits = match self.console_type {
+            ConsoleType::NES => 0,
+            ConsoleType::VsSystem {
+                ppu: 0,
+                hardware: 0,
+            } => 1,
+            ConsoleType::Playchoice => 2,
+            _ => return None,
+        };
+        let representable = self.mapper.id <= 0xFF
+            && self.mapper.sub_id == 0
+            && self.prg_rom.len().is_multiple_of(16 * 1024)
+            && prg_units <= 0xFF
+            && self.chr_rom.len().is_multiple_of(8 * 1024)
+            && chr_units <= 0xFF
+            && prg_ram_size > 0
+            && prg_ram_size.is_multiple_of(8 * 1024)
+            && prg_ram_size / (8 * 1024) <= 0xFF
+            && other_prg_ram_size == 0
+            && self.chr_ram_size == implied_chr_ram_size
+            && self.chr_nvram_size == 0
+            && matches!(self.timing, TimingMode::NTSC | TimingMode::PAL)
+            && self.misc_rom_count == 0
+            && self.misc_rom.is_empty()
+            && self.default_expansion_device == 0;
+        if !representable {
+            return None;
+        }
+
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = prg_units as u8;
+        header[5] = chr_units as u8;
+        header[6] = self.encode_flags6();
+        header[7] = (self.mapper.id & 0xF0) as u8 | console_bits;
+        header[8] = (prg_ram_size / (8 * 1024)) as u8;
+        header[9] = if self.timing == TimingMode::PAL { 1 } else { 0 };
+        Some(header)
+    }
+
+    /// Check the parsed header for inconsistencies that don't prevent the file
+    /// from loading, but suggest a bad dump or a badly edited header
+    pub fn validate(&self) -> Vec<NesFileWarning> {
+        let mut warnings = Vec::new();
+        if self.prg_rom.is_empty() {
+            warnings.push(NesFileWarning::EmptyPrgRom);
+        }
+        if !self.chr_rom.is_empty() && self.chr_ram_size + self.chr_nvram_size > 0 {
+            warnings.push(NesFileWarning::ChrRamWithChrRom {
+                chr_rom_size: self.chr_rom.len(),
+                chr_ram_size: self.chr_ram_size + self.chr_nvram_size,
+            });
+        }
+        if (self.misc_rom_count == 0) != self.misc_rom.is_empty() {
+            warnings.push(NesFileWarning::MiscRomMismatch {
+                declared: self.misc_rom_count,
+                trailing_bytes: self.misc_rom.len(),
+            });
+        }
+        let nvram_declared = self.prg_nvram_size + self.chr_nvram_size > 0;
+        if nvram_declared && !self.nvram_present {
+            warnings.push(NesFileWarning::NvramWithoutBattery);
+        }
+        // Older headers can't express the NVRAM size separately. On UNROM
+        // 512 the flag means the PRG-ROM is a flash the game saves to.
+        if self.header_version == HeaderVersion::Nes2
+            && self.nvram_present
+            && !nvram_declared
+            && self.mapper.id != 30
+        {
+            warnings.push(NesFileWarning::BatteryWithoutNvram);
+        }
+        warnings
+    }
+
+    fn decode_nes2_fields(
+        header: &[u8; 16],
+        mapper_0to3: u8,
+    ) -> Result<HeaderFields, NesFileError> {
         let console_base_type = header[7] & 0x3;
         let mapper_7to4 = header[7] >> 4;
 
@@ -67,9 +426,6 @@ impl NesFile {
         let misc_rom_count = header[14] & 0x3;
         let default_expansion_device = header[15] & 0x3F;
 
-        let prg_rom_size = decode_rom_size(prg_rom_size_lsb, prg_rom_size_msb, 16 * 1024);
-        let chr_rom_size = decode_rom_size(chr_rom_size_lsb, chr_rom_size_msb, 8 * 1024);
-
         let mapper_id =
             (mapper_11to8 as u16) << 8 | (mapper_7to4 as u16) << 4 | (mapper_0to3 as u16);
         let mapper = MapperId {
@@ -78,24 +434,7 @@ impl NesFile {
         };
         let console_type = ConsoleType::from_flags(console_base_type, console_extended_type)?;
 
-        let trainer = if trainer_present {
-            let mut buf = [0u8; 512];
-            reader.read_exact(&mut buf)?;
-            Some(buf)
-        } else {
-            None
-        };
-        let prg_rom = read_exact_to_vec(reader, prg_rom_size)?;
-        let chr_rom = read_exact_to_vec(reader, chr_rom_size)?;
-        let misc_rom = {
-            let mut buf = Vec::new();
-            reader.read_to_end(&mut buf)?;
-            buf
-        };
-
-        Ok(NesFile {
-            nametable_layout,
-            nvram_present,
+        Ok(HeaderFields {
             mapper,
             timing,
             console_type,
@@ -105,25 +444,132 @@ impl NesFile {
             prg_nvram_size,
             chr_ram_size,
             chr_nvram_size,
-            trainer,
-            prg_rom,
-            chr_rom,
-            misc_rom,
+            prg_rom_size_msb,
+            chr_rom_size_msb,
         })
     }
+
+    fn decode_ines_fields(header: &[u8; 16], mapper_0to3: u8, nvram_present: bool) -> HeaderFields {
+        let mapper_7to4 = header[7] >> 4;
+        let console_type = match header[7] & 0x3 {
+            // VS System PPU and hardware types aren't specified by iNES 1.0
+            1 => ConsoleType::VsSystem {
+                ppu: 0,
+                hardware: 0,
+            },
+            2 => ConsoleType::Playchoice,
+            _ => ConsoleType::NES,
+        };
+
+        // PRG-RAM size is in 8KB units, with 0 meaning 8KB for compatibility
+        let prg_ram_units = (header[8] as usize).max(1);
+        let timing = if header[9] & 0x01 != 0 {
+            TimingMode::PAL
+        } else {
+            TimingMode::NTSC
+        };
+
+        HeaderFields {
+            mapper: MapperId {
+                id: (mapper_7to4 as u16) << 4 | (mapper_0to3 as u16),
+                sub_id: 0,
+            },
+            timing,
+            console_type,
+            ..HeaderFields::ines_defaults(prg_ram_units * 8 * 1024, nvram_present)
+        }
+    }
+
+    fn decode_archaic_ines_fields(mapper_0to3: u8, nvram_present: bool) -> HeaderFields {
+        // Bytes 7-15 may contain garbage such as "DiskDude!", so only the
+        // fields from bytes 4-6 can be trusted
+        HeaderFields {
+            mapper: MapperId {
+                id: mapper_0to3 as u16,
+                sub_id: 0,
+            },
+            ..HeaderFields::ines_defaults(8 * 1024, nvram_present)
+        }
+    }
+}
+
+/// Header fields whose encoding differs between the header versions
+struct HeaderFields {
+    mapper: MapperId,
+    timing: TimingMode,
+    console_type: ConsoleType,
+    misc_rom_count: u8,
+    default_expansion_device: u8,
+    prg_ram_size: usize,
+    prg_nvram_size: usize,
+    chr_ram_size: usize,
+    chr_nvram_size: usize,
+    prg_rom_size_msb: u8,
+    chr_rom_size_msb: u8,
+}
+
+impl HeaderFields {
+    fn ines_defaults(prg_ram_size: usize, nvram_present: bool) -> Self {
+        // Older headers have a single PRG-RAM size, made battery-backed by the
+        // battery flag
+        let (prg_ram_size, prg_nvram_size) = if nvram_present {
+            (0, prg_ram_size)
+        } else {
+            (prg_ram_size, 0)
+        };
+        HeaderFields {
+            mapper: MapperId { id: 0, sub_id: 0 },
+            timing: TimingMode::NTSC,
+            console_type: ConsoleType::NES,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            prg_rom_size_msb: 0,
+            chr_rom_size_msb: 0,
+        }
+    }
 }
 
-fn decode_rom_size(size_lsb: u8, size_msb: u8, scale: usize) -> usize {
+/// Returns None if the exponent-multiplier notation gives a size that doesn't
+/// fit in a usize
+fn decode_rom_size(size_lsb: u8, size_msb: u8, scale: usize) -> Option<usize> {
     if size_msb == 0xF {
         let multiplier = (size_lsb & 0x3) as usize;
-        let exponent = (size_lsb >> 2) as usize;
-        (multiplier * 2 + 1) * (1 << exponent)
+        let exponent = (size_lsb >> 2) as u32;
+        1usize
+            .checked_shl(exponent)?
+            .checked_mul(multiplier * 2 + 1)
     } else {
         let size_in_units = ((size_msb as usize) << 8) | (size_lsb as usize);
-        size_in_units * scale
+        size_in_units.checked_mul(scale)
     }
 }
 
+/// Inverse of decode_rom_size, preferring the plain unit count when possible
+fn encode_rom_size(size: usize, scale: usize) -> Option<(u8, u8)> {
+    let units = size / scale;
+    if size.is_multiple_of(scale) && units <= 0xEFF {
+        return Some(((units & 0xFF) as u8, (units >> 8) as u8));
+    }
+    // Exponent-multiplier notation: size = 2^exponent * (multiplier * 2 + 1)
+    let exponent = size.trailing_zeros() as usize;
+    let multiplier = (size >> exponent) / 2;
+    if size == 0 || exponent > 0x3F || multiplier > 0x3 {
+        return None;
+    }
+    Some((((exponent as u8) << 2) | multiplier as u8, 0xF))
+}
+
+fn encode_ram_size(size: usize) -> Option<u8> {
+    if size == 0 {
+        return Some(0);
+    }
+    (1..=15u8).find(|shift| decode_ram_size(*shift) == size)
+}
+
 fn decode_ram_size(shift: u8) -> usize {
     if shift == 0 {
         0
@@ -132,12 +578,48 @@ fn decode_ram_size(shift: u8) -> usize {
     }
 }
 
-fn read_exact_to_vec(reader: &mut dyn Read, len: usize) -> Result<Vec<u8>, io::Error> {
-    let mut buf = vec![0u8; len];
-    reader.read_exact(&mut buf)?;
+pub(crate) fn read_exact_to_vec(
+    reader: &mut dyn Read,
+    len: usize,
+    section: NesFileSection,
+) -> Result<Vec<u8>, NesFileError> {
+    // Don't reserve len up front: the header can claim far more than the file
+    // holds, so only grow as data actually arrives
+    let mut buf = Vec::new();
+    reader.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < len {
+        return Err(NesFileError::Truncated {
+            section,
+            expected: len,
+            actual: buf.len(),
+        });
+    }
     Ok(buf)
 }
 
+/// Which revision of the iNES header format a file uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderVersion {
+    /// Pre-1.0 iNES, where bytes 7-15 are unused and often contain garbage
+    ArchaicINes,
+    /// iNES 1.0
+    INes,
+    /// NES 2.0
+    Nes2,
+    /// UNIF, which has no header; the fields come from its chunks instead
+    Unif,
+}
+
+impl HeaderVersion {
+    fn detect(header: &[u8; 16]) -> Self {
+        match header[7] & 0x0C {
+            0x08 => HeaderVersion::Nes2,
+            0x00 if header[12..16].iter().all(|&b| b == 0) => HeaderVersion::INes,
+            _ => HeaderVersion::ArchaicINes,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum NametableLayout {
     Vertical,
@@ -155,6 +637,15 @@ impl NametableLayout {
             (true, true) => NametableLayout::AlternateHorizontal,
         }
     }
+
+    fn to_flags(self) -> (bool, bool) {
+        match self {
+            NametableLayout::Vertical => (false, false),
+            NametableLayout::Horizontal => (true, false),
+            NametableLayout::AlternateVertical => (false, true),
+            NametableLayout::AlternateHorizontal => (true, true),
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -172,16 +663,22 @@ pub enum TimingMode {
 }
 
 impl TimingMode {
-    fn from_u8(value: u8) -> io::Result<Self> {
+    fn from_u8(value: u8) -> Result<Self, NesFileError> {
         match value {
             0 => Ok(TimingMode::NTSC),
             1 => Ok(TimingMode::PAL),
             2 => Ok(TimingMode::MultiRegion),
             3 => Ok(TimingMode::Dendy),
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Invalid TimingMode value {}", value),
-            )),
+            _ => Err(NesFileError::InvalidTimingMode { value, offset: 12 }),
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            TimingMode::NTSC => 0,
+            TimingMode::PAL => 1,
+            TimingMode::MultiRegion => 2,
+            TimingMode::Dendy => 3,
         }
     }
 }
@@ -204,7 +701,7 @@ pub enum ConsoleType {
 }
 
 impl ConsoleType {
-    fn from_flags(base_type: u8, extended_type: u8) -> io::Result<Self> {
+    fn from_flags(base_type: u8, extended_type: u8) -> Result<Self, NesFileError> {
         match base_type {
             0 => Ok(ConsoleType::NES),
             1 => Ok(ConsoleType::VsSystem {
@@ -223,15 +720,320 @@ impl ConsoleType {
                 0xA => Ok(ConsoleType::VT369),
                 0xB => Ok(ConsoleType::UM6578),
                 0xC => Ok(ConsoleType::FamicomNetworkSystem),
-                _ => Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Invalid ConsoleType extended code {}", extended_type),
-                )),
+                _ => Err(NesFileError::InvalidConsoleType {
+                    value: extended_type,
+                    offset: 13,
+                }),
             },
-            _ => Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Invalid ConsoleType base code {}", base_type),
-            )),
+            _ => Err(NesFileError::InvalidConsoleType {
+                value: base_type,
+                offset: 7,
+            }),
+        }
+    }
+
+    /// Inverse of from_flags, returning the base and extended type codes
+    fn to_flags(self) -> (u8, u8) {
+        match self {
+            ConsoleType::NES => (0, 0),
+            ConsoleType::VsSystem { ppu, hardware } => (1, (hardware << 4) | (ppu & 0x0F)),
+            ConsoleType::Playchoice => (2, 0),
+            ConsoleType::FamicloneDecimal => (3, 0x3),
+            ConsoleType::FamicomEPSM => (3, 0x4),
+            ConsoleType::VT01 => (3, 0x5),
+            ConsoleType::VT02 => (3, 0x6),
+            ConsoleType::VT03 => (3, 0x7),
+            ConsoleType::VT09 => (3, 0x8),
+            ConsoleType::VT32 => (3, 0x9),
+            ConsoleType::VT369 => (3, 0xA),
+            ConsoleType::UM6578 => (3, 0xB),
+            ConsoleType::FamicomNetworkSystem => (3, 0xC),
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn build_rom(header: [u8; 16]) -> Vec<u8> {
+        let mut data = header.to_vec();
+        data.resize(16 + 2 * 16 * 1024, 0xEA);
+        data
+    }
+
+    #[test]
+    fn test_nes2_header() {
+        let data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x12, 0x48, 0x31, 0x00, 0x70, 0x07, 0x01, 0x00, 0x00,
+            0x00,
+        ]);
+        let rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        assert_eq!(rom.header_version, HeaderVersion::Nes2);
+        assert_eq!(
+            rom.mapper,
+            MapperId {
+                id: 0x141,
+                sub_id: 3
+            }
+        );
+        assert!(rom.nvram_present);
+        assert_eq!(rom.prg_ram_size, 0);
+        assert_eq!(rom.prg_nvram_size, 8 * 1024);
+        assert_eq!(rom.chr_ram_size, 8 * 1024);
+        assert_eq!(rom.timing, TimingMode::PAL);
+        assert_eq!(rom.prg_rom.len(), 2 * 16 * 1024);
+        assert!(rom.chr_rom.is_empty());
+    }
+
+    #[test]
+    fn test_ines_header() {
+        let data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x12, 0x40, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ]);
+        let rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        assert_eq!(rom.header_version, HeaderVersion::INes);
+        assert_eq!(
+            rom.mapper,
+            MapperId {
+                id: 0x41,
+                sub_id: 0
+            }
+        );
+        assert_eq!(rom.prg_ram_size, 0);
+        assert_eq!(rom.prg_nvram_size, 16 * 1024);
+        assert_eq!(rom.chr_ram_size, 8 * 1024);
+        assert_eq!(rom.timing, TimingMode::PAL);
+        assert_eq!(rom.console_type, ConsoleType::NES);
+    }
+
+    #[test]
+    fn test_ines_header_default_prg_ram() {
+        let data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ]);
+        let rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        assert_eq!(rom.header_version, HeaderVersion::INes);
+        assert_eq!(rom.prg_ram_size, 8 * 1024);
+        assert_eq!(rom.prg_nvram_size, 0);
+        assert_eq!(rom.timing, TimingMode::NTSC);
+    }
+
+    #[test]
+    fn test_archaic_ines_header() {
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(b"NES\x1A");
+        header[4] = 2;
+        header[6] = 0x40;
+        header[7..16].copy_from_slice(b"DiskDude!");
+        let rom = NesFile::from_stream(&mut &build_rom(header)[..]).unwrap();
+        assert_eq!(rom.header_version, HeaderVersion::ArchaicINes);
+        assert_eq!(rom.mapper, MapperId { id: 0x4, sub_id: 0 });
+        assert_eq!(rom.prg_ram_size, 8 * 1024);
+        assert_eq!(rom.timing, TimingMode::NTSC);
+        assert_eq!(rom.console_type, ConsoleType::NES);
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        let mut header = [0u8; 16];
+        header[0..4].copy_from_slice(b"NES\x00");
+        let result = NesFile::from_stream(&mut &build_rom(header)[..]);
+        assert!(matches!(
+            result,
+            Err(NesFileError::BadMagic([b'N', b'E', b'S', 0]))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_prg_rom() {
+        let mut data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ]);
+        data.truncate(16 + 0x5000);
+        let result = NesFile::from_stream(&mut &data[..]);
+        assert!(matches!(
+            result,
+            Err(NesFileError::Truncated {
+                section: NesFileSection::PrgRom,
+                expected: 0x8000,
+                actual: 0x5000,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rom_size_overflow() {
+        // PRG-ROM of 2^63 * 3 bytes
+        let data = build_rom([
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            (63 << 2) | 1,
+            0,
+            0x00,
+            0x08,
+            0x00,
+            0x0F,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ]);
+        let result = NesFile::from_stream(&mut &data[..]);
+        assert!(matches!(
+            result,
+            Err(NesFileError::SizeOverflow {
+                section: NesFileSection::PrgRom,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_huge_rom_size_truncated() {
+        // PRG-ROM of 2^40 bytes, which must not be allocated before reading
+        let mut data = build_rom([
+            b'N',
+            b'E',
+            b'S',
+            0x1A,
+            40 << 2,
+            0,
+            0x00,
+            0x08,
+            0x00,
+            0x0F,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ]);
+        data.truncate(16 + 0x4000);
+        let result = NesFile::from_stream(&mut &data[..]);
+        assert!(matches!(
+            result,
+            Err(NesFileError::Truncated {
+                section: NesFileSection::PrgRom,
+                expected: 0x100_0000_0000,
+                actual: 0x4000,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_invalid_console_type() {
+        let data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x00, 0x0B, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0F, 0x00,
+            0x00,
+        ]);
+        let result = NesFile::from_stream(&mut &data[..]);
+        assert!(matches!(
+            result,
+            Err(NesFileError::InvalidConsoleType {
+                value: 0x0F,
+                offset: 13
+            })
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 1, 0x02, 0x08, 0x00, 0x00, 0x00, 0x07, 0x00, 0x00, 0x00,
+            0x00,
+        ]);
+        data.resize(data.len() + 8 * 1024 + 100, 0);
+        let rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        assert_eq!(
+            rom.validate(),
+            vec![
+                NesFileWarning::ChrRamWithChrRom {
+                    chr_rom_size: 8 * 1024,
+                    chr_ram_size: 8 * 1024
+                },
+                NesFileWarning::MiscRomMismatch {
+                    declared: 0,
+                    trailing_bytes: 100
+                },
+                NesFileWarning::BatteryWithoutNvram,
+            ]
+        );
+    }
+
+    fn roundtrip(rom: &NesFile) -> (Vec<u8>, NesFile) {
+        let mut data = Vec::new();
+        rom.write_to(&mut data).unwrap();
+        let reparsed = NesFile::from_stream(&mut &data[..]).unwrap();
+        (data, reparsed)
+    }
+
+    #[test]
+    fn test_write_ines_identical() {
+        let data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x13, 0x40, 0x02, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ]);
+        let rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        let (written, reparsed) = roundtrip(&rom);
+        assert_eq!(written, data);
+        assert_eq!(reparsed, rom);
+    }
+
+    #[test]
+    fn test_write_nes2_with_trainer_and_misc_rom() {
+        let mut header = [
+            b'N', b'E', b'S', 0x1A, 2, 1, 0x0E, 0x08, 0x31, 0x00, 0x07, 0x70, 0x03, 0x00, 0x01,
+            0x05,
+        ];
+        header[7] |= 0x40;
+        let mut data = header.to_vec();
+        data.extend((0..512).map(|i| i as u8));
+        data.extend((0..2 * 16 * 1024 + 8 * 1024).map(|i| (i * 7) as u8));
+        data.extend(b"misc rom contents");
+        let rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        assert!(rom.trainer.is_some());
+        assert_eq!(rom.misc_rom, b"misc rom contents");
+
+        let (written, reparsed) = roundtrip(&rom);
+        assert_eq!(written, data);
+        assert_eq!(reparsed, rom);
+    }
+
+    #[test]
+    fn test_convert_nes2_to_ines() {
+        let data = build_rom([
+            b'N', b'E', b'S', 0x1A, 2, 0, 0x12, 0x48, 0x00, 0x00, 0x70, 0x07, 0x00, 0x00, 0x00,
+            0x00,
+        ]);
+        let mut rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        rom.header_version = HeaderVersion::INes;
+        assert_eq!(rom.output_header_version(), HeaderVersion::INes);
+        let (_, reparsed) = roundtrip(&rom);
+        assert_eq!(reparsed, rom);
+
+        // Submappers need NES 2.0
+        rom.mapper.sub_id = 1;
+        assert_eq!(rom.output_header_version(), HeaderVersion::Nes2);
+        let (_, reparsed) = roundtrip(&rom);
+        assert_eq!(reparsed.header_version, HeaderVersion::Nes2);
+        assert_eq!(reparsed.mapper, rom.mapper);
+    }
+
+    #[test]
+    fn test_exponent_rom_size() {
+        assert_eq!(
+            encode_rom_size(3 * 512, 16 * 1024),
+            Some(((9 << 2) | 1, 0xF))
+        );
+        assert_eq!(decode_rom_size((9 << 2) | 1, 0xF, 16 * 1024), Some(3 * 512));
+        assert_eq!(encode_rom_size(9 * 512, 16 * 1024), None);
+    }
+}
@@ -0,0 +1,503 @@
+use std::fmt;
+
+use thiserror::Error;
+
+const NSF_MAGIC: &[u8; 5] = b"NESM\x1A";
+const NSFE_MAGIC: &[u8; 4] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+// NSF2 flags at 0x7C
+const NSF2_IRQ_SUPPORT: u8 = 0x10;
+const NSF2_NON_RETURNING_INIT: u8 = 0x20;
+const NSF2_SUPPRESS_PLAY: u8 = 0x40;
+const NSF2_METADATA: u8 = 0x80;
+
+/// Play routine period when a file doesn't give one, in microseconds
+pub const DEFAULT_NTSC_PLAY_PERIOD_US: u16 = 16639;
+pub const DEFAULT_PAL_PLAY_PERIOD_US: u16 = 19997;
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum NsfError {
+    #[error("Not an NSF or NSFe file")]
+    BadMagic,
+    #[error("File is truncated")]
+    Truncated,
+    #[error("NSFe file has no {0} chunk")]
+    MissingChunk(&'static str),
+    #[error("Unsupported required chunk \"{0}\"")]
+    UnknownChunk(String),
+    #[error("Load address 0x{0:04X} is below 0x8000")]
+    BadLoadAddress(u16),
+}
+
+/// Sound chips an NSF can use in addition to the 2A03's APU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpansionChip {
+    Vrc6,
+    Vrc7,
+    Fds,
+    Mmc5,
+    N163,
+    Sunsoft5B,
+}
+
+impl ExpansionChip {
+    pub const ALL: [ExpansionChip; 6] = [
+        ExpansionChip::Vrc6,
+        ExpansionChip::Vrc7,
+        ExpansionChip::Fds,
+        ExpansionChip::Mmc5,
+        ExpansionChip::N163,
+        ExpansionChip::Sunsoft5B,
+    ];
+
+    /// Bit for this chip in the header's expansion chip flags
+    pub fn flag(self) -> u8 {
+        1 << ExpansionChip::ALL
+            .iter()
+            .position(|chip| *chip == self)
+            .unwrap()
+    }
+
+    /// Chips selected in a set of expansion chip flags
+    pub fn from_flags(flags: u8) -> Vec<ExpansionChip> {
+        ExpansionChip::ALL
+            .into_iter()
+            .filter(|chip| flags & chip.flag() != 0)
+            .collect()
+    }
+}
+
+impl fmt::Display for ExpansionChip {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpansionChip::Vrc6 => write!(f, "VRC6"),
+            ExpansionChip::Vrc7 => write!(f, "VRC7"),
+            ExpansionChip::Fds => write!(f, "FDS"),
+            ExpansionChip::Mmc5 => write!(f, "MMC5"),
+            ExpansionChip::N163 => write!(f, "N163"),
+            ExpansionChip::Sunsoft5B => write!(f, "Sunsoft 5B"),
+        }
+    }
+}
+
+/// Consoles a tune was written for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NsfRegion {
+    Ntsc,
+    Pal,
+    /// Plays correctly on either, told which it's on by the X register
+    Dual,
+}
+
+impl NsfRegion {
+    fn from_u8(value: u8) -> Self {
+        match value & 0x03 {
+            0 => NsfRegion::Ntsc,
+            1 => NsfRegion::Pal,
+            _ => NsfRegion::Dual,
+        }
+    }
+}
+
+/// An NSF, NSF2 or NSFe music file. Metadata that only NSFe (or NSF2 with
+/// appended chunks) can carry is empty for plain NSF files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NsfFile {
+    /// NSF header version, or 0 for NSFe files
+    pub version: u8,
+    pub song_count: u8,
+    /// First song to play, counted from 0
+    pub start_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: String,
+    pub ntsc_play_period_us: u16,
+    pub pal_play_period_us: u16,
+    /// Initial banks for 0x8000 - 0xFFFF, or None if the tune isn't bankswitched
+    pub initial_banks: Option<[u8; 8]>,
+    pub region: NsfRegion,
+    pub expansion_chip_flags: u8,
+    pub irq_support: bool,
+    /// INIT runs forever, with PLAY interrupting it
+    pub non_returning_init: bool,
+    /// PLAY is never called; only valid with a non-returning INIT
+    pub suppress_play: bool,
+    pub data: Vec<u8>,
+
+    pub track_names: Vec<String>,
+    /// Length of each track in milliseconds, where the file gives one
+    pub track_times: Vec<Option<u32>>,
+    /// Fade-out length of each track in milliseconds, where the file gives one
+    pub track_fades: Vec<Option<u32>>,
+    pub playlist: Option<Vec<u8>>,
+}
+
+impl NsfFile {
+    pub fn is_nsf(data: &[u8]) -> bool {
+        data.starts_with(NSF_MAGIC) || data.starts_with(NSFE_MAGIC)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, NsfError> {
+        let nsf = if data.starts_with(NSF_MAGIC) {
+            Self::parse_nsf(data)?
+        } else if data.starts_with(NSFE_MAGIC) {
+            Self::parse_nsfe(&data[NSFE_MAGIC.len()..])?
+        } else {
+            return Err(NsfError::BadMagic);
+        };
+        if nsf.initial_banks.is_none() && nsf.load_addr < 0x8000 {
+            return Err(NsfError::BadLoadAddress(nsf.load_addr));
+        }
+        Ok(nsf)
+    }
+
+    fn empty() -> Self {
+        NsfFile {
+            version: 0,
+            song_count: 1,
+            start_song: 0,
+            load_addr: 0x8000,
+            init_addr: 0x8000,
+            play_addr: 0x8000,
+            title: String::new(),
+            artist: String::new(),
+            copyright: String::new(),
+            ripper: String::new(),
+            ntsc_play_period_us: DEFAULT_NTSC_PLAY_PERIOD_US,
+            pal_play_period_us: DEFAULT_PAL_PLAY_PERIOD_US,
+            initial_banks: None,
+            region: NsfRegion::Ntsc,
+            expansion_chip_flags: 0,
+            irq_support: false,
+            non_returning_init: false,
+            suppress_play: false,
+            data: Vec::new(),
+            track_names: Vec::new(),
+            track_times: Vec::new(),
+            track_fades: Vec::new(),
+            playlist: None,
+        }
+    }
+
+    fn parse_nsf(data: &[u8]) -> Result<Self, NsfError> {
+        let header = data.get(..NSF_HEADER_SIZE).ok_or(NsfError::Truncated)?;
+        let mut nsf = NsfFile::empty();
+        nsf.version = header[0x05];
+        nsf.song_count = header[0x06];
+        nsf.start_song = header[0x07].saturating_sub(1);
+        nsf.load_addr = read_u16(&header[0x08..]);
+        nsf.init_addr = read_u16(&header[0x0A..]);
+        nsf.play_addr = read_u16(&header[0x0C..]);
+        nsf.title = read_string(&header[0x0E..0x2E]);
+        nsf.artist = read_string(&header[0x2E..0x4E]);
+        nsf.copyright = read_string(&header[0x4E..0x6E]);
+        nsf.ntsc_play_period_us = nonzero_or(read_u16(&header[0x6E..]), nsf.ntsc_play_period_us);
+        nsf.initial_banks = banks_if_used(&header[0x70..0x78]);
+        nsf.pal_play_period_us = nonzero_or(read_u16(&header[0x78..]), nsf.pal_play_period_us);
+        nsf.region = NsfRegion::from_u8(header[0x7A]);
+        nsf.expansion_chip_flags = header[0x7B];
+
+        let body = &data[NSF_HEADER_SIZE..];
+        if nsf.version >= 2 {
+            let flags = header[0x7C];
+            nsf.irq_support = flags & NSF2_IRQ_SUPPORT != 0;
+            nsf.non_returning_init = flags & NSF2_NON_RETURNING_INIT != 0;
+            nsf.suppress_play = flags & NSF2_SUPPRESS_PLAY != 0;
+            let program_len = header[0x7D] as usize
+                | (header[0x7E] as usize) << 8
+                | (header[0x7F] as usize) << 16;
+            if program_len != 0 {
+                let (program, metadata) = body
+                    .split_at_checked(program_len)
+                    .ok_or(NsfError::Truncated)?;
+                nsf.data = program.to_vec();
+                if flags & NSF2_METADATA != 0 {
+                    nsf.parse_chunks(metadata, false)?;
+                }
+                return Ok(nsf);
+            }
+        }
+        nsf.data = body.to_vec();
+        Ok(nsf)
+    }
+
+    fn parse_nsfe(data: &[u8]) -> Result<Self, NsfError> {
+        let mut nsf = NsfFile::empty();
+        let found = nsf.parse_chunks(data, true)?;
+        if !found.info {
+            return Err(NsfError::MissingChunk("INFO"));
+        }
+        if !found.data {
+            return Err(NsfError::MissingChunk("DATA"));
+        }
+        Ok(nsf)
+    }
+
+    /// Read NSFe chunks up to NEND or the end of the data. The INFO, DATA and
+    /// BANK chunks are only accepted in a standalone NSFe file.
+    fn parse_chunks(&mut self, mut data: &[u8], nsfe: bool) -> Result<FoundChunks, NsfError> {
+        let mut found = FoundChunks::default();
+        while !data.is_empty() {
+            let header = data.get(..8).ok_or(NsfError::Truncated)?;
+            let len = u32::from_le_bytes(header[0..4].try_into().unwrap()) as usize;
+            let id: [u8; 4] = header[4..8].try_into().unwrap();
+            let body = data.get(8..8 + len).ok_or(NsfError::Truncated)?;
+            data = &data[8 + len..];
+
+            match &id {
+                b"INFO" if nsfe => {
+                    found.info = true;
+                    self.parse_info_chunk(body)?;
+                }
+                b"DATA" if nsfe => {
+                    found.data = true;
+                    self.data = body.to_vec();
+                }
+                b"BANK" if nsfe => {
+                    let mut banks = [0; 8];
+                    let count = body.len().min(8);
+                    banks[..count].copy_from_slice(&body[..count]);
+                    self.initial_banks = banks_if_used(&banks);
+                }
+                b"RATE" => {
+                    if let Some(period) = body.get(0..2) {
+                        self.ntsc_play_period_us =
+                            nonzero_or(read_u16(period), DEFAULT_NTSC_PLAY_PERIOD_US);
+                    }
+                    if let Some(period) = body.get(2..4) {
+                        self.pal_play_period_us =
+                            nonzero_or(read_u16(period), DEFAULT_PAL_PLAY_PERIOD_US);
+                    }
+                }
+                b"NEND" => break,
+                b"tlbl" => self.track_names = read_strings(body),
+                b"auth" => {
+                    let mut strings = read_strings(body).into_iter();
+                    for field in [
+                        &mut self.title,
+                        &mut self.artist,
+                        &mut self.copyright,
+                        &mut self.ripper,
+                    ] {
+                        if let Some(value) = strings.next() {
+                            *field = value;
+                        }
+                    }
+                }
+                b"time" => self.track_times = read_times(body),
+                b"fade" => self.track_fades = read_times(body),
+                b"plst" => self.playlist = Some(body.to_vec()),
+                // Chunks starting with an uppercase letter must be understood
+                // to play the file correctly
+                _ if id[0].is_ascii_uppercase() => {
+                    return Err(NsfError::UnknownChunk(
+                        String::from_utf8_lossy(&id).into_owned(),
+                    ));
+                }
+                _ => {}
+            }
+        }
+        Ok(found)
+    }
+
+    fn parse_info_chunk(&mut self, body: &[u8]) -> Result<(), NsfError> {
+        let fixed = body.get(..8).ok_or(NsfError::Truncated)?;
+        self.load_addr = read_u16(&fixed[0..]);
+        self.init_addr = read_u16(&fixed[2..]);
+        self.play_addr = read_u16(&fixed[4..]);
+        self.region = NsfRegion::from_u8(fixed[6]);
+        self.expansion_chip_flags = fixed[7];
+        // The song count and starting song are optional
+        self.song_count = body.get(8).copied().unwrap_or(1);
+        self.start_song = body.get(9).copied().unwrap_or(0);
+        Ok(())
+    }
+
+    pub fn expansion_chips(&self) -> Vec<ExpansionChip> {
+        ExpansionChip::from_flags(self.expansion_chip_flags)
+    }
+
+    /// Name of a track, counted from 0, if the file gives one
+    pub fn track_name(&self, track: u8) -> Option<&str> {
+        self.track_names
+            .get(track as usize)
+            .map(String::as_str)
+            .filter(|name| !name.is_empty())
+    }
+
+    /// Length of a track in milliseconds, if the file gives one
+    pub fn track_time(&self, track: u8) -> Option<u32> {
+        self.track_times.get(track as usize).copied().flatten()
+    }
+
+    /// Fade-out length of a track in milliseconds, if the file gives one
+    pub fn track_fade(&self, track: u8) -> Option<u32> {
+        self.track_fades.get(track as usize).copied().flatten()
+    }
+}
+
+#[derive(Debug, Default)]
+struct FoundChunks {
+    info: bool,
+    data: bool,
+}
+
+fn read_u16(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn nonzero_or(value: u16, default: u16) -> u16 {
+    if value == 0 { default } else { value }
+}
+
+/// A tune is bankswitched if any of its initial bank values are nonzero
+fn banks_if_used(banks: &[u8]) -> Option<[u8; 8]> {
+    let banks: [u8; 8] = banks.try_into().unwrap();
+    banks.iter().any(|bank| *bank != 0).then_some(banks)
+}
+
+/// A null-padded string from a fixed size header field
+fn read_string(field: &[u8]) -> String {
+    let end = field.iter().position(|c| *c == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// A sequence of null-terminated strings
+fn read_strings(body: &[u8]) -> Vec<String> {
+    let body = body.strip_suffix(&[0]).unwrap_or(body);
+    if body.is_empty() {
+        return Vec::new();
+    }
+    body.split(|c| *c == 0)
+        .map(|s| String::from_utf8_lossy(s).into_owned())
+        .collect()
+}
+
+/// Per-track times in milliseconds, where negative means not given
+fn read_times(body: &[u8]) -> Vec<Option<u32>> {
+    body.chunks_exact(4)
+        .map(|time| u32::try_from(i32::from_le_bytes(time.try_into().unwrap())).ok())
+        .collect()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// A minimal NSF: INIT at 0x8000 and PLAY at 0x8001, both RTS
+    pub(crate) fn build_nsf(banks: [u8; 8]) -> Vec<u8> {
+        let mut data = vec![0; NSF_HEADER_SIZE];
+        data[..5].copy_from_slice(NSF_MAGIC);
+        data[0x05] = 1;
+        data[0x06] = 3;
+        data[0x07] = 2;
+        data[0x08..0x0A].copy_from_slice(&0x8000u16.to_le_bytes());
+        data[0x0A..0x0C].copy_from_slice(&0x8000u16.to_le_bytes());
+        data[0x0C..0x0E].copy_from_slice(&0x8001u16.to_le_bytes());
+        data[0x0E..0x13].copy_from_slice(b"Title");
+        data[0x6E..0x70].copy_from_slice(&16639u16.to_le_bytes());
+        data[0x70..0x78].copy_from_slice(&banks);
+        data[0x7B] = 0x01;
+        data.extend_from_slice(&[0x60, 0x60]);
+        data
+    }
+
+    pub(crate) fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = (body.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    #[test]
+    fn test_parse_nsf() {
+        let nsf = NsfFile::parse(&build_nsf([0; 8])).unwrap();
+        assert_eq!(nsf.version, 1);
+        assert_eq!(nsf.song_count, 3);
+        assert_eq!(nsf.start_song, 1);
+        assert_eq!(nsf.play_addr, 0x8001);
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.initial_banks, None);
+        assert_eq!(nsf.region, NsfRegion::Ntsc);
+        assert_eq!(nsf.pal_play_period_us, DEFAULT_PAL_PLAY_PERIOD_US);
+        assert_eq!(nsf.expansion_chips(), vec![ExpansionChip::Vrc6]);
+        assert_eq!(nsf.data, vec![0x60, 0x60]);
+
+        let banked = NsfFile::parse(&build_nsf([0, 1, 2, 3, 4, 5, 6, 7])).unwrap();
+        assert_eq!(banked.initial_banks, Some([0, 1, 2, 3, 4, 5, 6, 7]));
+
+        assert_eq!(NsfFile::parse(b"NESM\x1A\x01"), Err(NsfError::Truncated));
+        assert_eq!(NsfFile::parse(b"NES\x1A"), Err(NsfError::BadMagic));
+    }
+
+    #[test]
+    fn test_parse_nsf2_metadata() {
+        let mut data = build_nsf([0; 8]);
+        data[0x05] = 2;
+        data[0x7C] = NSF2_METADATA | NSF2_NON_RETURNING_INIT;
+        data[0x7D] = 2;
+        data.extend(chunk(b"tlbl", b"One\0Two\0"));
+        data.extend(chunk(
+            b"time",
+            &[(-1i32).to_le_bytes(), 5000i32.to_le_bytes()].concat(),
+        ));
+        data.extend(chunk(b"NEND", &[]));
+
+        let nsf = NsfFile::parse(&data).unwrap();
+        assert!(nsf.non_returning_init);
+        assert!(!nsf.suppress_play);
+        assert_eq!(nsf.data, vec![0x60, 0x60]);
+        assert_eq!(nsf.track_name(1), Some("Two"));
+        assert_eq!(nsf.track_name(2), None);
+        assert_eq!(nsf.track_time(0), None);
+        assert_eq!(nsf.track_time(1), Some(5000));
+    }
+
+    #[test]
+    fn test_parse_nsfe() {
+        let mut info = Vec::new();
+        for addr in [0x8000u16, 0x8000, 0x8001] {
+            info.extend_from_slice(&addr.to_le_bytes());
+        }
+        info.extend_from_slice(&[0x01, 0x00, 4, 2]);
+        let mut data = NSFE_MAGIC.to_vec();
+        data.extend(chunk(b"INFO", &info));
+        data.extend(chunk(b"BANK", &[0, 1]));
+        data.extend(chunk(b"DATA", &[0x60, 0x60]));
+        data.extend(chunk(b"auth", b"Song\0Composer\0\0Ripper\0"));
+        data.extend(chunk(b"fade", &1000i32.to_le_bytes()));
+        data.extend(chunk(b"xtra", &[1, 2, 3]));
+        data.extend(chunk(b"NEND", &[]));
+
+        let nsf = NsfFile::parse(&data).unwrap();
+        assert_eq!(nsf.version, 0);
+        assert_eq!(nsf.song_count, 4);
+        assert_eq!(nsf.start_song, 2);
+        assert_eq!(nsf.region, NsfRegion::Pal);
+        assert_eq!(nsf.initial_banks, Some([0, 1, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(nsf.title, "Song");
+        assert_eq!(nsf.artist, "Composer");
+        assert_eq!(nsf.copyright, "");
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(nsf.track_fade(0), Some(1000));
+
+        // Unknown chunks are only an error when they're marked as required
+        let mut required = data[..data.len() - 8].to_vec();
+        required.extend(chunk(b"XTRA", &[]));
+        assert_eq!(
+            NsfFile::parse(&required),
+            Err(NsfError::UnknownChunk("XTRA".to_string()))
+        );
+
+        let no_data = [NSFE_MAGIC.as_slice(), &chunk(b"INFO", &info)].concat();
+        assert_eq!(
+            NsfFile::parse(&no_data),
+            Err(NsfError::MissingChunk("DATA"))
+        );
+    }
+}
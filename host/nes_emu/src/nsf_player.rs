@@ -0,0 +1,236 @@
+use crate::{
+    components::{EmuError, EmuResult, audio::AudioOutput, timing::Region, tracer::Tracer},
+    nes::{NESConfig, NESSystem, StopReason},
+    nsf::{NsfFile, NsfRegion},
+};
+
+/// How long to emulate between checks for silence, in CPU cycles
+const CHUNK_CYCLES: u64 = 29781;
+
+/// Output below this level counts as silence
+const SILENCE_THRESHOLD: f32 = 1.0 / 1024.0;
+
+/// How to render one track of an NSF
+#[derive(Debug, Clone)]
+pub struct RenderOptions {
+    pub sample_rate: u32,
+    /// Length before the fade starts, in milliseconds, when the file doesn't
+    /// give one
+    pub default_time_ms: u32,
+    /// Fade-out length in milliseconds, when the file doesn't give one
+    pub default_fade_ms: u32,
+    /// Stop once the output has been silent this long, in milliseconds
+    pub silence_ms: Option<u32>,
+    /// Region to play a dual-region tune in. Single-region tunes always play
+    /// in their own.
+    pub prefer_pal: bool,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        RenderOptions {
+            sample_rate: 44100,
+            default_time_ms: 150_000,
+            default_fade_ms: 8_000,
+            silence_ms: Some(3_000),
+            prefer_pal: false,
+        }
+    }
+}
+
+/// Play a track, counted from 0, and return its samples. The track runs for
+/// its NSFe time plus its fade, or the defaults from options, and is cut
+/// short once it falls silent. Silence before the first sound doesn't count,
+/// so a track that starts quietly isn't cut before it begins.
+pub fn render_track(
+    tracer: &Tracer,
+    nsf: &NsfFile,
+    track: u8,
+    options: &RenderOptions,
+) -> EmuResult<Vec<f32>> {
+    let region = match nsf.region {
+        NsfRegion::Dual if options.prefer_pal => NsfRegion::Pal,
+        NsfRegion::Dual => NsfRegion::Ntsc,
+        region => region,
+    };
+    let timing = Region::from_nsf_region(region).timing();
+
+    let audio = AudioOutput::new(options.sample_rate, timing.cpu_clock_hz());
+    let config = NESConfig {
+        audio: Some(audio.clone()),
+        ..Default::default()
+    };
+    let mut nes = NESSystem::with_nsf(tracer, nsf, track, region, &config);
+
+    let ms_to_samples = |ms: u32| (ms as u64 * options.sample_rate as u64 / 1000) as usize;
+    let time = ms_to_samples(nsf.track_time(track).unwrap_or(options.default_time_ms));
+    let fade = ms_to_samples(nsf.track_fade(track).unwrap_or(options.default_fade_ms));
+    let silence_limit = options.silence_ms.map(ms_to_samples);
+
+    let mut samples = Vec::new();
+    let mut silent_samples = 0;
+    let mut heard_sound = false;
+    nes.start_simulation()?;
+    let result = loop {
+        let limit = nes.get_tick_count() + CHUNK_CYCLES;
+        match nes.run(Some(limit)) {
+            Ok(StopReason::LimitReached) => {}
+            Ok(StopReason::Jam(opcode)) => break Err(EmuError::IllegalCpuOpcode(opcode)),
+            // There's no test ROM monitor on an NSF console
+            Ok(reason) => unreachable!("{reason:?}"),
+            Err(e) => break Err(e),
+        }
+
+        for sample in audio.take_samples() {
+            if sample.abs() >= SILENCE_THRESHOLD {
+                heard_sound = true;
+                silent_samples = 0;
+            } else if heard_sound {
+                silent_samples += 1;
+            }
+            samples.push(sample);
+        }
+        if samples.len() >= time + fade {
+            samples.truncate(time + fade);
+            break Ok(());
+        }
+        if let Some(limit) = silence_limit
+            && silent_samples >= limit
+        {
+            samples.truncate(samples.len() - silent_samples);
+            break Ok(());
+        }
+    };
+    nes.end_simulation();
+    result?;
+
+    // Fade out linearly over the end of the track
+    for (i, sample) in samples.iter_mut().enumerate().skip(time) {
+        *sample *= (time + fade - i) as f32 / fade as f32;
+    }
+    Ok(samples)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::nsf::test::{build_nsf, chunk};
+
+    const SAMPLE_RATE: u32 = 8000;
+
+    /// Silent for the first 6 calls to PLAY, about 100ms, then a square wave
+    /// until the 12th call, then silent again
+    fn tone_program() -> Vec<u8> {
+        vec![
+            0x60, // INIT: RTS
+            0xE6, 0x00, // PLAY: INC $00
+            0xA5, 0x00, // LDA $00
+            0xC9, 6, // CMP #6
+            0xD0, 0x13, // BNE stop
+            0xA9, 0xBF, // LDA #$BF: constant volume 15, length halted
+            0x8D, 0x00, 0x40, // STA $4000
+            0xA9, 0x00, // LDA #0
+            0x8D, 0x02, 0x40, // STA $4002
+            0xA9, 0x01, // LDA #1
+            0x8D, 0x15, 0x40, // STA $4015
+            0x8D, 0x03, 0x40, // STA $4003
+            0x60, // RTS
+            0xC9, 12, // stop: CMP #12
+            0xD0, 0x05, // BNE done
+            0xA9, 0x00, // LDA #0
+            0x8D, 0x15, 0x40, // STA $4015
+            0x60, // done: RTS
+        ]
+    }
+
+    fn tone_nsf() -> NsfFile {
+        let mut data = build_nsf([0; 8]);
+        data.truncate(0x80);
+        // No expansion chips
+        data[0x7B] = 0;
+        data.extend(tone_program());
+        NsfFile::parse(&data).unwrap()
+    }
+
+    fn render(nsf: &NsfFile, options: RenderOptions) -> Vec<f32> {
+        let tracer = Tracer::new::<&str>(&[], None);
+        let options = RenderOptions {
+            sample_rate: SAMPLE_RATE,
+            ..options
+        };
+        render_track(&tracer, nsf, 0, &options).unwrap()
+    }
+
+    fn ms(ms: u32) -> usize {
+        (ms * SAMPLE_RATE / 1000) as usize
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples
+            .iter()
+            .fold(0.0, |peak, sample| peak.max(sample.abs()))
+    }
+
+    #[test]
+    fn test_track_length_and_fade() {
+        let samples = render(
+            &tone_nsf(),
+            RenderOptions {
+                default_time_ms: 150,
+                default_fade_ms: 40,
+                silence_ms: None,
+                ..Default::default()
+            },
+        );
+        assert_eq!(samples.len(), ms(190));
+        // Full volume before the fade, and almost nothing at its end
+        let level = peak(&samples[ms(110)..ms(150)]);
+        assert!(level > 0.01);
+        assert!(peak(&samples[ms(185)..]) < level * 0.2);
+    }
+
+    #[test]
+    fn test_silence_cutoff() {
+        // The silence at the start doesn't end the track, but the silence
+        // after the tone does
+        let samples = render(
+            &tone_nsf(),
+            RenderOptions {
+                default_time_ms: 1000,
+                silence_ms: Some(50),
+                ..Default::default()
+            },
+        );
+        // The tone stops about 200ms in, and takes a few more to die away
+        // through the high-pass filter
+        assert!((ms(190)..ms(250)).contains(&samples.len()));
+        assert!(peak(&samples[ms(110)..ms(190)]) > 0.01);
+    }
+
+    #[test]
+    fn test_nsfe_times() {
+        let mut info = Vec::new();
+        for addr in [0x8000u16, 0x8000, 0x8001] {
+            info.extend_from_slice(&addr.to_le_bytes());
+        }
+        info.extend_from_slice(&[0x00, 0x00, 1, 0]);
+        let mut data = b"NSFE".to_vec();
+        data.extend(chunk(b"INFO", &info));
+        data.extend(chunk(b"DATA", &tone_program()));
+        data.extend(chunk(b"time", &150i32.to_le_bytes()));
+        data.extend(chunk(b"fade", &40i32.to_le_bytes()));
+        data.extend(chunk(b"NEND", &[]));
+        let nsf = NsfFile::parse(&data).unwrap();
+
+        // The file's time and fade win over the defaults
+        let samples = render(
+            &nsf,
+            RenderOptions {
+                silence_ms: None,
+                ..Default::default()
+            },
+        );
+        assert_eq!(samples.len(), ms(190));
+        assert!(peak(&samples[ms(185)..]) < peak(&samples[ms(110)..ms(150)]) * 0.2);
+    }
+}
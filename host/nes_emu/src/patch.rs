@@ -0,0 +1,701 @@
+use std::fmt;
+
+use thiserror::Error;
+
+/// Largest file a UPS or BPS patch may produce. Well past any real ROM or
+/// disk image, but small enough that a corrupt size can't exhaust memory.
+pub const MAX_TARGET_SIZE: usize = 64 << 20;
+
+/// Soft-patch formats, identified by the magic number at the start of the
+/// patch file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Ups,
+    Bps,
+}
+
+impl fmt::Display for PatchFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchFormat::Ips => write!(f, "IPS"),
+            PatchFormat::Ups => write!(f, "UPS"),
+            PatchFormat::Bps => write!(f, "BPS"),
+        }
+    }
+}
+
+/// Which of the checksums stored in a UPS or BPS patch failed to match
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchChecksum {
+    /// The file being patched isn't the one the patch was made for
+    Source,
+    /// The patched file isn't what the patch author produced
+    Target,
+    /// The patch file itself is corrupted
+    Patch,
+}
+
+impl fmt::Display for PatchChecksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PatchChecksum::Source => write!(f, "source file"),
+            PatchChecksum::Target => write!(f, "patched file"),
+            PatchChecksum::Patch => write!(f, "patch file"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum PatchError {
+    #[error("Unrecognized patch format (magic {0:02X?})")]
+    UnknownFormat(Vec<u8>),
+    #[error("{format} patch is truncated at offset {offset}")]
+    Truncated { format: PatchFormat, offset: usize },
+    #[error(
+        "{format} patch CRC32 mismatch for the {which}: expected {expected:08X}, got {actual:08X}"
+    )]
+    ChecksumMismatch {
+        format: PatchFormat,
+        which: PatchChecksum,
+        expected: u32,
+        actual: u32,
+    },
+    #[error("{format} patch expects a {expected} byte source file, got {actual} bytes")]
+    SourceSizeMismatch {
+        format: PatchFormat,
+        expected: usize,
+        actual: usize,
+    },
+    #[error("{format} patch reads or writes out of bounds at offset {offset}")]
+    OutOfBounds { format: PatchFormat, offset: usize },
+    #[error("{format} patch produces a {size} byte file, more than the {MAX_TARGET_SIZE} allowed")]
+    TargetTooLarge { format: PatchFormat, size: usize },
+}
+
+/// Detect the format of a patch from its magic number
+pub fn detect_format(patch: &[u8]) -> Result<PatchFormat, PatchError> {
+    if patch.starts_with(b"PATCH") {
+        Ok(PatchFormat::Ips)
+    } else if patch.starts_with(b"UPS1") {
+        Ok(PatchFormat::Ups)
+    } else if patch.starts_with(b"BPS1") {
+        Ok(PatchFormat::Bps)
+    } else {
+        Err(PatchError::UnknownFormat(
+            patch[..patch.len().min(5)].to_vec(),
+        ))
+    }
+}
+
+/// Apply a patch of any supported format to the raw contents of a ROM file,
+/// header included, returning the patched contents
+pub fn apply_patch(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    match detect_format(patch)? {
+        PatchFormat::Ips => apply_ips(source, patch),
+        PatchFormat::Ups => apply_ups(source, patch),
+        PatchFormat::Bps => apply_bps(source, patch),
+    }
+}
+
+/// Build an IPS patch that turns original into modified. Used to store
+/// changes to a file without rewriting the file itself.
+pub fn create_ips(original: &[u8], modified: &[u8]) -> Vec<u8> {
+    // Offsets are 24 bits, and a record at 0x454F46 would look like "EOF"
+    const EOF_OFFSET: usize = 0x454F46;
+    const MAX_RECORD_LEN: usize = 0xFFFF;
+
+    let mut patch = b"PATCH".to_vec();
+    let mut pos = 0;
+    while pos < modified.len() {
+        if original.get(pos) == Some(&modified[pos]) {
+            pos += 1;
+            continue;
+        }
+        let mut start = pos;
+        if start == EOF_OFFSET {
+            start -= 1;
+        }
+        let mut end = pos;
+        while end < modified.len()
+            && end - start < MAX_RECORD_LEN
+            && original.get(end) != Some(&modified[end])
+        {
+            end += 1;
+        }
+        patch.extend_from_slice(&(start as u32).to_be_bytes()[1..]);
+        patch.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        patch.extend_from_slice(&modified[start..end]);
+        pos = end;
+    }
+    patch.extend_from_slice(b"EOF");
+    if modified.len() < original.len() {
+        patch.extend_from_slice(&(modified.len() as u32).to_be_bytes()[1..]);
+    }
+    patch
+}
+
+/// Sequential reader over patch data that reports truncation with the offset
+struct PatchReader<'a> {
+    format: PatchFormat,
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(format: PatchFormat, data: &'a [u8], pos: usize) -> Self {
+        PatchReader { format, data, pos }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos + len)
+            .ok_or(PatchError::Truncated {
+                format: self.format,
+                offset: self.pos,
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, byte| (acc << 8) | *byte as usize))
+    }
+
+    /// Variable-length integer used by UPS and BPS. Each byte holds 7 bits,
+    /// least significant first, and the top bit marks the last byte. Every
+    /// continuation also adds one, so there's only one encoding per value.
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let offset = self.pos;
+            let byte = self.byte()?;
+            let overflow = PatchError::OutOfBounds {
+                format: self.format,
+                offset,
+            };
+            value = (byte as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|x| x.checked_add(value))
+                .ok_or(overflow.clone())?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(overflow.clone())?;
+            value = value.checked_add(shift).ok_or(overflow)?;
+        }
+    }
+}
+
+/// IPS: a list of (offset, data) records, where a zero length introduces a
+/// run-length record. An optional 3 byte length after the EOF marker
+/// truncates the output.
+fn apply_ips(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut target = source.to_vec();
+    let mut reader = PatchReader::new(PatchFormat::Ips, patch, 5);
+    loop {
+        if reader.bytes(3)? == b"EOF" {
+            break;
+        }
+        reader.pos -= 3;
+        let offset = reader.big_endian(3)?;
+        let len = reader.big_endian(2)?;
+        if len == 0 {
+            let run_len = reader.big_endian(2)?;
+            let value = reader.byte()?;
+            if target.len() < offset + run_len {
+                target.resize(offset + run_len, 0);
+            }
+            target[offset..offset + run_len].fill(value);
+        } else {
+            let data = reader.bytes(len)?;
+            if target.len() < offset + len {
+                target.resize(offset + len, 0);
+            }
+            target[offset..offset + len].copy_from_slice(data);
+        }
+    }
+    if let Ok(truncate_len) = reader.big_endian(3) {
+        target.truncate(truncate_len);
+    }
+    Ok(target)
+}
+
+/// Check the CRC32 footer shared by UPS and BPS: source, target and patch
+/// CRCs, little-endian, with the patch CRC covering everything before it
+fn check_footer(
+    format: PatchFormat,
+    patch: &[u8],
+    which: PatchChecksum,
+    data: &[u8],
+) -> Result<(), PatchError> {
+    let footer = patch.len() - 12;
+    let field = match which {
+        PatchChecksum::Source => footer,
+        PatchChecksum::Target => footer + 4,
+        PatchChecksum::Patch => footer + 8,
+    };
+    let expected = u32::from_le_bytes(patch[field..field + 4].try_into().unwrap());
+    let actual = crc32fast::hash(data);
+    if expected != actual {
+        return Err(PatchError::ChecksumMismatch {
+            format,
+            which,
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn check_target_size(format: PatchFormat, size: usize) -> Result<(), PatchError> {
+    if size > MAX_TARGET_SIZE {
+        return Err(PatchError::TargetTooLarge { format, size });
+    }
+    Ok(())
+}
+
+fn check_patch_size(format: PatchFormat, patch: &[u8]) -> Result<(), PatchError> {
+    // Magic number plus the three CRCs
+    if patch.len() < 4 + 12 {
+        return Err(PatchError::Truncated {
+            format,
+            offset: patch.len(),
+        });
+    }
+    Ok(())
+}
+
+/// UPS: source and target sizes, then hunks of bytes to XOR with the source,
+/// each preceded by the distance from the end of the previous hunk
+fn apply_ups(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let format = PatchFormat::Ups;
+    check_patch_size(format, patch)?;
+    check_footer(
+        format,
+        patch,
+        PatchChecksum::Patch,
+        &patch[..patch.len() - 4],
+    )?;
+    let footer = patch.len() - 12;
+    let mut reader = PatchReader::new(format, &patch[..footer], 4);
+
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    check_target_size(format, target_size)?;
+    if source.len() != source_size {
+        return Err(PatchError::SourceSizeMismatch {
+            format,
+            expected: source_size,
+            actual: source.len(),
+        });
+    }
+    check_footer(format, patch, PatchChecksum::Source, source)?;
+
+    let mut target = source.to_vec();
+    target.resize(target_size, 0);
+    let mut pos = 0usize;
+    while reader.pos < footer {
+        let offset = reader.pos;
+        pos = pos
+            .checked_add(reader.varint()?)
+            .ok_or(PatchError::OutOfBounds { format, offset })?;
+        loop {
+            let offset = reader.pos;
+            let xor = reader.byte()?;
+            let next = pos
+                .checked_add(1)
+                .ok_or(PatchError::OutOfBounds { format, offset })?;
+            if xor == 0 {
+                pos = next;
+                break;
+            }
+            // Bytes past the end of the target are dropped, as they are when
+            // the patch shrinks the file
+            if let Some(byte) = target.get_mut(pos) {
+                *byte ^= xor;
+            } else if pos >= target_size.max(source_size) {
+                return Err(PatchError::OutOfBounds { format, offset });
+            }
+            pos = next;
+        }
+    }
+
+    check_footer(format, patch, PatchChecksum::Target, &target)?;
+    Ok(target)
+}
+
+/// BPS: source and target sizes and metadata, then a stream of actions that
+/// build the target from the source, literal patch data, or earlier output
+fn apply_bps(source: &[u8], patch: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let format = PatchFormat::Bps;
+    check_patch_size(format, patch)?;
+    check_footer(
+        format,
+        patch,
+        PatchChecksum::Patch,
+        &patch[..patch.len() - 4],
+    )?;
+    let footer = patch.len() - 12;
+    let mut reader = PatchReader::new(format, &patch[..footer], 4);
+
+    let source_size = reader.varint()?;
+    let target_size = reader.varint()?;
+    check_target_size(format, target_size)?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source.len() != source_size {
+        return Err(PatchError::SourceSizeMismatch {
+            format,
+            expected: source_size,
+            actual: source.len(),
+        });
+    }
+    check_footer(format, patch, PatchChecksum::Source, source)?;
+
+    let mut target = Vec::with_capacity(target_size);
+    let mut source_offset = 0usize;
+    let mut target_offset = 0usize;
+    // Signed relative offset: bit 0 is the sign, the rest the magnitude
+    let relative = |reader: &mut PatchReader, base: usize| -> Result<usize, PatchError> {
+        let offset = reader.pos;
+        let data = reader.varint()?;
+        let delta = data >> 1;
+        let result = if data & 1 != 0 {
+            base.checked_sub(delta)
+        } else {
+            base.checked_add(delta)
+        };
+        result.ok_or(PatchError::OutOfBounds { format, offset })
+    };
+
+    while reader.pos < footer {
+        let offset = reader.pos;
+        let out_of_bounds = PatchError::OutOfBounds { format, offset };
+        let action = reader.varint()?;
+        let len = (action >> 2) + 1;
+        if target.len() + len > target_size {
+            return Err(out_of_bounds);
+        }
+        match action & 3 {
+            // SourceRead: copy from the source at the same position
+            0 => {
+                let start = target.len();
+                let data = source.get(start..start + len).ok_or(out_of_bounds)?;
+                target.extend_from_slice(data);
+            }
+            // TargetRead: literal data from the patch
+            1 => {
+                let data = reader.bytes(len)?;
+                target.extend_from_slice(data);
+            }
+            // SourceCopy: copy from anywhere in the source
+            2 => {
+                source_offset = relative(&mut reader, source_offset)?;
+                let data = source
+                    .get(source_offset..)
+                    .and_then(|data| data.get(..len))
+                    .ok_or(out_of_bounds)?;
+                target.extend_from_slice(data);
+                source_offset += len;
+            }
+            // TargetCopy: copy earlier output, byte by byte so that the
+            // ranges may overlap for run-length encoding
+            _ => {
+                target_offset = relative(&mut reader, target_offset)?;
+                if target_offset >= target.len() {
+                    return Err(out_of_bounds);
+                }
+                for _ in 0..len {
+                    target.push(target[target_offset]);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+    if target.len() != target_size {
+        return Err(PatchError::Truncated {
+            format,
+            offset: footer,
+        });
+    }
+
+    check_footer(format, patch, PatchChecksum::Target, &target)?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode_varint(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(x | 0x80);
+                return;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    fn append_footer(patch: &mut Vec<u8>, source: &[u8], target: &[u8]) {
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+        let patch_crc = crc32fast::hash(patch);
+        patch.extend_from_slice(&patch_crc.to_le_bytes());
+    }
+
+    #[test]
+    fn test_varint_roundtrip() {
+        for value in [0, 1, 127, 128, 129, 16511, 16512, 0x123456] {
+            let mut data = vec![];
+            encode_varint(&mut data, value);
+            let mut reader = PatchReader::new(PatchFormat::Ups, &data, 0);
+            assert_eq!(reader.varint().unwrap(), value);
+            assert_eq!(reader.pos, data.len());
+        }
+    }
+
+    #[test]
+    fn test_ips_records() {
+        let source = [0u8; 8];
+        let mut patch = b"PATCH".to_vec();
+        // Plain record at offset 1
+        patch.extend_from_slice(&[0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record at offset 4, 3 bytes of 0x55
+        patch.extend_from_slice(&[0x00, 0x00, 0x04, 0x00, 0x00, 0x00, 0x03, 0x55]);
+        // Record that extends the file
+        patch.extend_from_slice(&[0x00, 0x00, 0x09, 0x00, 0x01, 0xCC]);
+        patch.extend_from_slice(b"EOF");
+        let target = apply_patch(&source, &patch).unwrap();
+        assert_eq!(
+            target,
+            [0x00, 0xAA, 0xBB, 0x00, 0x55, 0x55, 0x55, 0x00, 0x00, 0xCC]
+        );
+    }
+
+    #[test]
+    fn test_ips_truncation() {
+        let source = [1u8; 16];
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x01, 0x02]);
+        patch.extend_from_slice(b"EOF");
+        patch.extend_from_slice(&[0x00, 0x00, 0x04]);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), [2, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_ips_truncated_record() {
+        let mut patch = b"PATCH".to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x04, 0x02]);
+        assert_eq!(
+            apply_patch(&[0; 4], &patch),
+            Err(PatchError::Truncated {
+                format: PatchFormat::Ips,
+                offset: 10
+            })
+        );
+    }
+
+    fn make_ups(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"UPS1".to_vec();
+        encode_varint(&mut patch, source.len());
+        encode_varint(&mut patch, target.len());
+        let mut last = 0;
+        let mut pos = 0;
+        while pos < target.len() {
+            let xor = |i: usize| source.get(i).copied().unwrap_or(0) ^ target[i];
+            if xor(pos) == 0 {
+                pos += 1;
+                continue;
+            }
+            encode_varint(&mut patch, pos - last);
+            while pos < target.len() && xor(pos) != 0 {
+                patch.push(xor(pos));
+                pos += 1;
+            }
+            patch.push(0);
+            pos += 1;
+            last = pos;
+        }
+        append_footer(&mut patch, source, target);
+        patch
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = b"Hello, world!".to_vec();
+        let target = b"Hello, NES world!!".to_vec();
+        let patch = make_ups(&source, &target);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_ups_wrong_source() {
+        let source = b"Hello, world!".to_vec();
+        let patch = make_ups(&source, b"Hello, NES!!!");
+        let result = apply_patch(b"Hello, there!", &patch);
+        assert!(matches!(
+            result,
+            Err(PatchError::ChecksumMismatch {
+                which: PatchChecksum::Source,
+                ..
+            })
+        ));
+
+        let result = apply_patch(b"Hello", &patch);
+        assert!(matches!(
+            result,
+            Err(PatchError::SourceSizeMismatch {
+                expected: 13,
+                actual: 5,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_ups_corrupt_patch() {
+        let source = b"Hello, world!".to_vec();
+        let mut patch = make_ups(&source, b"Hello, NES!!!");
+        patch[6] ^= 0x01;
+        let result = apply_patch(&source, &patch);
+        assert!(matches!(
+            result,
+            Err(PatchError::ChecksumMismatch {
+                which: PatchChecksum::Patch,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_ups_offset_overflow() {
+        // An empty hunk at the very last address, whose end is past it
+        let mut patch = b"UPS1".to_vec();
+        encode_varint(&mut patch, 0);
+        encode_varint(&mut patch, 0);
+        encode_varint(&mut patch, usize::MAX);
+        patch.push(0);
+        append_footer(&mut patch, &[], &[]);
+        assert!(matches!(
+            apply_patch(&[], &patch),
+            Err(PatchError::OutOfBounds { .. })
+        ));
+    }
+
+    fn encode_action(out: &mut Vec<u8>, len: usize, action: usize) {
+        encode_varint(out, ((len - 1) << 2) | action);
+    }
+
+    /// BPS patch exercising every action type, producing "ABCDxxxxxxCDAB!"
+    /// from "ABCD"
+    fn make_bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = b"BPS1".to_vec();
+        encode_varint(&mut patch, source.len());
+        encode_varint(&mut patch, target.len());
+        encode_varint(&mut patch, 3);
+        patch.extend_from_slice(b"meh");
+        // SourceRead 4: "ABCD"
+        encode_action(&mut patch, 4, 0);
+        // TargetRead 1: "x"
+        encode_action(&mut patch, 1, 1);
+        patch.push(b'x');
+        // TargetCopy 5 from target offset 4: overlapping run of "x"
+        encode_action(&mut patch, 5, 3);
+        encode_varint(&mut patch, 4 << 1);
+        // SourceCopy 2 from source offset 2: "CD"
+        encode_action(&mut patch, 2, 2);
+        encode_varint(&mut patch, 2 << 1);
+        // SourceCopy 2 from source offset 0 (relative -4): "AB"
+        encode_action(&mut patch, 2, 2);
+        encode_varint(&mut patch, (4 << 1) | 1);
+        // TargetRead 1: "!"
+        encode_action(&mut patch, 1, 1);
+        patch.push(b'!');
+        append_footer(&mut patch, source, target);
+        patch
+    }
+
+    #[test]
+    fn test_bps() {
+        let target = b"ABCDxxxxxxCDAB!";
+        let patch = make_bps(b"ABCD", target);
+        assert_eq!(apply_patch(b"ABCD", &patch).unwrap(), target);
+    }
+
+    #[test]
+    fn test_bps_checksums() {
+        let target = b"ABCDxxxxxxCDAB!";
+        let patch = make_bps(b"ABCD", target);
+        assert!(matches!(
+            apply_patch(b"ABCE", &patch),
+            Err(PatchError::ChecksumMismatch {
+                which: PatchChecksum::Source,
+                ..
+            })
+        ));
+
+        // Patch checksum correct, but the recorded target CRC is wrong
+        let patch = make_bps(b"ABCD", b"something else!");
+        assert!(matches!(
+            apply_patch(b"ABCD", &patch),
+            Err(PatchError::ChecksumMismatch {
+                which: PatchChecksum::Target,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_oversized_target() {
+        // A patch with valid checksums whose target size would take 1TB
+        for magic in [b"UPS1", b"BPS1"] {
+            let mut patch = magic.to_vec();
+            encode_varint(&mut patch, 4);
+            encode_varint(&mut patch, 1 << 40);
+            encode_varint(&mut patch, 0);
+            append_footer(&mut patch, b"ABCD", b"");
+            assert!(matches!(
+                apply_patch(b"ABCD", &patch),
+                Err(PatchError::TargetTooLarge { size, .. }) if size == 1 << 40
+            ));
+        }
+    }
+
+    #[test]
+    fn test_create_ips() {
+        let original = vec![0u8; 0x20000];
+        let mut modified = original.clone();
+        modified[5..9].copy_from_slice(b"abcd");
+        modified[0x1FFFF] = 1;
+        modified.extend_from_slice(b"more");
+        let patch = create_ips(&original, &modified);
+        assert_eq!(apply_patch(&original, &patch).unwrap(), modified);
+
+        let shorter = &original[..100];
+        let patch = create_ips(&original, shorter);
+        assert_eq!(apply_patch(&original, &patch).unwrap(), shorter);
+        assert_eq!(create_ips(&original, &original), b"PATCHEOF");
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        assert_eq!(
+            apply_patch(&[], b"NOPE!"),
+            Err(PatchError::UnknownFormat(b"NOPE!".to_vec()))
+        );
+    }
+}
@@ -0,0 +1,592 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::nes_file::{ConsoleType, MapperId, NametableLayout, NesFile, TimingMode};
+
+#[derive(Debug, Error)]
+pub enum RomDbError {
+    #[error("I/O error reading ROM database: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid TOML ROM database: {0}")]
+    Toml(#[from] toml::de::Error),
+    #[error("Invalid ROM database entry {entry}: {message}")]
+    InvalidEntry { entry: usize, message: String },
+}
+
+/// Header fields for a single ROM, as recorded in the database. Fields that
+/// are None aren't known to the database and are left as the header has them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RomDbEntry {
+    pub name: Option<String>,
+    /// CRC32 of the PRG-ROM and CHR-ROM together
+    pub crc32: Option<u32>,
+    /// SHA-1 of the PRG-ROM and CHR-ROM together, as lowercase hex
+    pub sha1: Option<String>,
+
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub nametable_layout: Option<NametableLayout>,
+    pub battery: Option<bool>,
+    pub prg_ram_size: Option<usize>,
+    pub prg_nvram_size: Option<usize>,
+    pub chr_ram_size: Option<usize>,
+    pub chr_nvram_size: Option<usize>,
+    pub timing: Option<TimingMode>,
+    pub console_type: Option<ConsoleType>,
+    pub default_expansion_device: Option<u8>,
+}
+
+/// A header field that was changed by applying a database entry
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderChange {
+    pub field: &'static str,
+    pub header_value: String,
+    pub database_value: String,
+}
+
+impl fmt::Display for HeaderChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: header has {}, database has {}",
+            self.field, self.header_value, self.database_value
+        )
+    }
+}
+
+/// Local database of known-good header fields, keyed by the hash of the ROM
+/// contents. Used to correct dumps whose headers have the wrong mapper,
+/// layout, or RAM sizes.
+///
+/// Two formats are supported: the NES 2.0 XML database (nes20db.xml), and a
+/// simpler TOML format with one [[game]] table per ROM. See data/romdb.toml
+/// in this crate for a description of the TOML format.
+#[derive(Debug, Default)]
+pub struct RomDatabase {
+    entries: Vec<RomDbEntry>,
+    by_sha1: HashMap<String, usize>,
+    by_crc32: HashMap<u32, usize>,
+}
+
+impl RomDatabase {
+    /// Load a database from a file. Files ending in .xml are read as NES 2.0
+    /// XML, anything else as TOML.
+    pub fn load(path: &Path) -> Result<Self, RomDbError> {
+        let contents = fs::read_to_string(path)?;
+        if path
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("xml"))
+        {
+            Self::from_nes20db_xml(&contents)
+        } else {
+            Self::from_toml(&contents)
+        }
+    }
+
+    /// The database shipped with the emulator, data/romdb.toml
+    pub fn builtin() -> Self {
+        Self::from_toml(include_str!("../data/romdb.toml"))
+            .expect("built-in ROM database is invalid")
+    }
+
+    pub fn from_entries(entries: Vec<RomDbEntry>) -> Self {
+        let mut by_sha1 = HashMap::new();
+        let mut by_crc32 = HashMap::new();
+        for (idx, entry) in entries.iter().enumerate() {
+            if let Some(sha1) = &entry.sha1 {
+                by_sha1.entry(sha1.clone()).or_insert(idx);
+            }
+            if let Some(crc32) = entry.crc32 {
+                by_crc32.entry(crc32).or_insert(idx);
+            }
+        }
+        RomDatabase {
+            entries,
+            by_sha1,
+            by_crc32,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Find the entry for a ROM, preferring a SHA-1 match over a CRC32 match
+    pub fn lookup(&self, rom: &NesFile) -> Option<&RomDbEntry> {
+        let mut sha1 = sha1_smol::Sha1::new();
+        sha1.update(&rom.prg_rom);
+        sha1.update(&rom.chr_rom);
+
+        let mut crc32 = crc32fast::Hasher::new();
+        crc32.update(&rom.prg_rom);
+        crc32.update(&rom.chr_rom);
+
+        self.lookup_hashes(&sha1.digest().to_string(), crc32.finalize())
+    }
+
+    /// Find an entry by the SHA-1, as lowercase hex, or CRC32 of a ROM's
+    /// PRG-ROM and CHR-ROM
+    fn lookup_hashes(&self, sha1: &str, crc32: u32) -> Option<&RomDbEntry> {
+        self.by_sha1
+            .get(sha1)
+            .or_else(|| self.by_crc32.get(&crc32))
+            .map(|idx| &self.entries[*idx])
+    }
+
+    pub fn from_toml(contents: &str) -> Result<Self, RomDbError> {
+        let file: TomlDatabase = toml::from_str(contents)?;
+        let entries = file
+            .game
+            .into_iter()
+            .enumerate()
+            .map(|(idx, game)| game.into_entry(idx))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self::from_entries(entries))
+    }
+
+    /// Parse the NES 2.0 XML database. Only the flat structure used by
+    /// nes20db.xml is understood: a <game> element per ROM, containing
+    /// self-closing elements whose attributes hold the header fields.
+    pub fn from_nes20db_xml(contents: &str) -> Result<Self, RomDbError> {
+        let mut entries = Vec::new();
+        let mut rest = contents;
+        while let Some(start) = rest.find("<game>") {
+            let body_start = start + "<game>".len();
+            let Some(len) = rest[body_start..].find("</game>") else {
+                return Err(RomDbError::InvalidEntry {
+                    entry: entries.len(),
+                    message: "unterminated <game> element".to_string(),
+                });
+            };
+            let body = &rest[body_start..body_start + len];
+            entries.push(parse_xml_game(body, entries.len())?);
+            rest = &rest[body_start + len..];
+        }
+        Ok(Self::from_entries(entries))
+    }
+}
+
+impl RomDbEntry {
+    /// Overwrite the header fields of a ROM with the fields known to the
+    /// database. Returns the fields that actually changed.
+    pub fn apply(&self, rom: &mut NesFile) -> Vec<HeaderChange> {
+        let mut changes = Vec::new();
+        fn update<T: PartialEq + fmt::Debug + Copy>(
+            changes: &mut Vec<HeaderChange>,
+            field: &'static str,
+            current: &mut T,
+            new: Option<T>,
+        ) {
+            if let Some(new) = new
+                && *current != new
+            {
+                changes.push(HeaderChange {
+                    field,
+                    header_value: format!("{:?}", current),
+                    database_value: format!("{:?}", new),
+                });
+                *current = new;
+            }
+        }
+
+        let new_mapper = MapperId {
+            id: self.mapper.unwrap_or(rom.mapper.id),
+            sub_id: self.submapper.unwrap_or(rom.mapper.sub_id),
+        };
+        update(&mut changes, "mapper", &mut rom.mapper, Some(new_mapper));
+        update(
+            &mut changes,
+            "nametable layout",
+            &mut rom.nametable_layout,
+            self.nametable_layout,
+        );
+        update(
+            &mut changes,
+            "battery",
+            &mut rom.nvram_present,
+            self.battery,
+        );
+        update(
+            &mut changes,
+            "PRG-RAM size",
+            &mut rom.prg_ram_size,
+            self.prg_ram_size,
+        );
+        update(
+            &mut changes,
+            "PRG-NVRAM size",
+            &mut rom.prg_nvram_size,
+            self.prg_nvram_size,
+        );
+        update(
+            &mut changes,
+            "CHR-RAM size",
+            &mut rom.chr_ram_size,
+            self.chr_ram_size,
+        );
+        update(
+            &mut changes,
+            "CHR-NVRAM size",
+            &mut rom.chr_nvram_size,
+            self.chr_nvram_size,
+        );
+        update(&mut changes, "timing", &mut rom.timing, self.timing);
+        update(
+            &mut changes,
+            "console type",
+            &mut rom.console_type,
+            self.console_type,
+        );
+        update(
+            &mut changes,
+            "default expansion device",
+            &mut rom.default_expansion_device,
+            self.default_expansion_device,
+        );
+        changes
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TomlDatabase {
+    #[serde(default)]
+    game: Vec<TomlGame>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TomlGame {
+    name: Option<String>,
+    crc32: Option<String>,
+    sha1: Option<String>,
+    mapper: Option<u16>,
+    submapper: Option<u8>,
+    layout: Option<String>,
+    battery: Option<bool>,
+    prg_ram: Option<usize>,
+    prg_nvram: Option<usize>,
+    chr_ram: Option<usize>,
+    chr_nvram: Option<usize>,
+    timing: Option<String>,
+    expansion_device: Option<u8>,
+}
+
+impl TomlGame {
+    fn into_entry(self, idx: usize) -> Result<RomDbEntry, RomDbError> {
+        let invalid = |message: String| RomDbError::InvalidEntry {
+            entry: idx,
+            message,
+        };
+        if self.crc32.is_none() && self.sha1.is_none() {
+            return Err(invalid("needs a crc32 or sha1 key".to_string()));
+        }
+        let crc32 = self
+            .crc32
+            .map(|crc| parse_crc32(&crc).ok_or_else(|| invalid(format!("bad crc32 \"{}\"", crc))))
+            .transpose()?;
+        let nametable_layout = self
+            .layout
+            .map(|layout| match layout.as_str() {
+                "vertical" => Ok(NametableLayout::Vertical),
+                "horizontal" => Ok(NametableLayout::Horizontal),
+                "alt-vertical" => Ok(NametableLayout::AlternateVertical),
+                "alt-horizontal" => Ok(NametableLayout::AlternateHorizontal),
+                _ => Err(invalid(format!("unknown layout \"{}\"", layout))),
+            })
+            .transpose()?;
+        let timing = self
+            .timing
+            .map(|timing| match timing.as_str() {
+                "ntsc" => Ok(TimingMode::NTSC),
+                "pal" => Ok(TimingMode::PAL),
+                "multi" => Ok(TimingMode::MultiRegion),
+                "dendy" => Ok(TimingMode::Dendy),
+                _ => Err(invalid(format!("unknown timing \"{}\"", timing))),
+            })
+            .transpose()?;
+        Ok(RomDbEntry {
+            name: self.name,
+            crc32,
+            sha1: self.sha1.map(|sha1| sha1.to_ascii_lowercase()),
+            mapper: self.mapper,
+            submapper: self.submapper,
+            nametable_layout,
+            battery: self.battery,
+            prg_ram_size: self.prg_ram,
+            prg_nvram_size: self.prg_nvram,
+            chr_ram_size: self.chr_ram,
+            chr_nvram_size: self.chr_nvram,
+            timing,
+            console_type: None,
+            default_expansion_device: self.expansion_device,
+        })
+    }
+}
+
+fn parse_crc32(text: &str) -> Option<u32> {
+    let text = text.trim_start_matches("0x");
+    u32::from_str_radix(text, 16).ok()
+}
+
+/// Extract the attributes of every self-closing element in an XML fragment,
+/// keyed by element name
+fn parse_xml_elements(body: &str) -> HashMap<&str, HashMap<&str, &str>> {
+    let mut elements = HashMap::new();
+    let mut rest = body;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start + 1..start + len];
+        rest = &rest[start + len + 1..];
+        // Skip comments and anything that isn't a self-closing element
+        let Some(tag) = tag.strip_suffix('/') else {
+            continue;
+        };
+        if tag.starts_with('!') {
+            continue;
+        }
+        let (name, mut attrs_text) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let mut attrs = HashMap::new();
+        while let Some((key, value_text)) = attrs_text.split_once('=') {
+            let value_text = value_text.trim_start();
+            let Some(value_text) = value_text.strip_prefix('"') else {
+                break;
+            };
+            let Some((value, remaining)) = value_text.split_once('"') else {
+                break;
+            };
+            attrs.insert(key.trim(), value);
+            attrs_text = remaining;
+        }
+        elements.insert(name, attrs);
+    }
+    elements
+}
+
+fn parse_xml_game(body: &str, idx: usize) -> Result<RomDbEntry, RomDbError> {
+    let invalid = |message: String| RomDbError::InvalidEntry {
+        entry: idx,
+        message,
+    };
+    let elements = parse_xml_elements(body);
+    let attr = |element: &str, name: &str| elements.get(element).and_then(|e| e.get(name)).copied();
+    fn number<T: std::str::FromStr>(
+        value: Option<&str>,
+        invalid: &dyn Fn(String) -> RomDbError,
+    ) -> Result<Option<T>, RomDbError> {
+        value
+            .map(|text| {
+                text.parse()
+                    .map_err(|_| invalid(format!("bad number \"{}\"", text)))
+            })
+            .transpose()
+    }
+
+    // The <rom> element covers the PRG-ROM and CHR-ROM together
+    let crc32 = attr("rom", "crc32")
+        .map(|crc| parse_crc32(crc).ok_or_else(|| invalid(format!("bad crc32 \"{}\"", crc))))
+        .transpose()?;
+    let sha1 = attr("rom", "sha1").map(|sha1| sha1.to_ascii_lowercase());
+    if crc32.is_none() && sha1.is_none() {
+        return Err(invalid("<rom> element has no crc32 or sha1".to_string()));
+    }
+
+    let nametable_layout = attr("pcb", "mirroring")
+        .map(|mirroring| match mirroring {
+            // Horizontal mirroring is a vertical arrangement, and vice versa
+            "H" => Ok(NametableLayout::Vertical),
+            "V" => Ok(NametableLayout::Horizontal),
+            "4" => Ok(NametableLayout::AlternateVertical),
+            _ => Err(invalid(format!("unknown mirroring \"{}\"", mirroring))),
+        })
+        .transpose()?;
+    let timing = attr("console", "region")
+        .map(|region| match region {
+            "0" => Ok(TimingMode::NTSC),
+            "1" => Ok(TimingMode::PAL),
+            "2" => Ok(TimingMode::MultiRegion),
+            "3" => Ok(TimingMode::Dendy),
+            _ => Err(invalid(format!("unknown region \"{}\"", region))),
+        })
+        .transpose()?;
+    let console_type = match number::<u8>(attr("console", "type"), &invalid)? {
+        None | Some(0) => None,
+        Some(1) => Some(ConsoleType::VsSystem {
+            ppu: number(attr("vs", "ppu"), &invalid)?.unwrap_or(0),
+            hardware: number(attr("vs", "hardware"), &invalid)?.unwrap_or(0),
+        }),
+        Some(2) => Some(ConsoleType::Playchoice),
+        // Extended console types aren't needed for any current corrections
+        Some(_) => None,
+    };
+
+    Ok(RomDbEntry {
+        name: None,
+        crc32,
+        sha1,
+        mapper: number(attr("pcb", "mapper"), &invalid)?,
+        submapper: number(attr("pcb", "submapper"), &invalid)?,
+        nametable_layout,
+        battery: number::<u8>(attr("pcb", "battery"), &invalid)?.map(|b| b != 0),
+        prg_ram_size: Some(number(attr("prgram", "size"), &invalid)?.unwrap_or(0)),
+        prg_nvram_size: Some(number(attr("prgnvram", "size"), &invalid)?.unwrap_or(0)),
+        chr_ram_size: Some(number(attr("chrram", "size"), &invalid)?.unwrap_or(0)),
+        chr_nvram_size: Some(number(attr("chrnvram", "size"), &invalid)?.unwrap_or(0)),
+        timing,
+        console_type,
+        default_expansion_device: number(attr("expansion", "type"), &invalid)?,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_rom() -> NesFile {
+        let mut data = vec![
+            b'N', b'E', b'S', 0x1A, 1, 1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ];
+        data.resize(16 + 16 * 1024 + 8 * 1024, 0x42);
+        NesFile::from_stream(&mut &data[..]).unwrap()
+    }
+
+    fn test_rom_crc32() -> u32 {
+        crc32fast::hash(&vec![0x42; 16 * 1024 + 8 * 1024])
+    }
+
+    #[test]
+    fn test_toml_lookup_and_apply() {
+        let db = RomDatabase::from_toml(&format!(
+            r#"
+            [[game]]
+            name = "Test game"
+            crc32 = "{:08X}"
+            mapper = 3
+            layout = "vertical"
+            battery = true
+            prg_ram = 0
+            prg_nvram = 8192
+            "#,
+            test_rom_crc32()
+        ))
+        .unwrap();
+        let mut rom = test_rom();
+        let entry = db.lookup(&rom).unwrap();
+        assert_eq!(entry.name.as_deref(), Some("Test game"));
+
+        let changes = entry.apply(&mut rom);
+        let fields: Vec<_> = changes.iter().map(|change| change.field).collect();
+        assert_eq!(
+            fields,
+            [
+                "mapper",
+                "nametable layout",
+                "battery",
+                "PRG-RAM size",
+                "PRG-NVRAM size"
+            ]
+        );
+        assert_eq!(rom.mapper, MapperId { id: 3, sub_id: 0 });
+        assert_eq!(rom.nametable_layout, NametableLayout::Vertical);
+        assert!(rom.nvram_present);
+        assert_eq!(rom.prg_nvram_size, 8192);
+
+        // Applying again is a no-op
+        assert!(entry.apply(&mut rom).is_empty());
+    }
+
+    #[test]
+    fn test_xml_lookup_and_apply() {
+        let sha1 = sha1_smol::Sha1::from(vec![0x42; 16 * 1024 + 8 * 1024])
+            .digest()
+            .to_string();
+        let db = RomDatabase::from_nes20db_xml(&format!(
+            r#"<?xml version="1.0"?>
+            <nes20db date="2024-01-01">
+            <game>
+                <!-- Some other game -->
+                <rom size="24576" crc32="00000000"/>
+                <pcb mapper="1" submapper="0" mirroring="H" battery="0"/>
+            </game>
+            <game>
+                <!-- Test game -->
+                <prgrom size="16384" crc32="11111111"/>
+                <chrrom size="8192" crc32="22222222"/>
+                <rom size="24576" crc32="33333333" sha1="{}"/>
+                <prgram size="2048"/>
+                <pcb mapper="66" submapper="0" mirroring="4" battery="0"/>
+                <console type="0" region="1"/>
+                <expansion type="1"/>
+            </game>
+            </nes20db>"#,
+            sha1.to_ascii_uppercase()
+        ))
+        .unwrap();
+        assert_eq!(db.len(), 2);
+
+        let mut rom = test_rom();
+        let changes = db.lookup(&rom).unwrap().apply(&mut rom);
+        assert_eq!(changes.len(), 5);
+        assert_eq!(rom.mapper, MapperId { id: 66, sub_id: 0 });
+        assert_eq!(rom.nametable_layout, NametableLayout::AlternateVertical);
+        assert_eq!(rom.prg_ram_size, 2048);
+        assert_eq!(rom.timing, TimingMode::PAL);
+        assert_eq!(rom.default_expansion_device, 1);
+    }
+
+    #[test]
+    fn test_unknown_rom() {
+        let db = RomDatabase::from_toml(
+            r#"
+            [[game]]
+            crc32 = "DEADBEEF"
+            mapper = 1
+            "#,
+        )
+        .unwrap();
+        assert!(db.lookup(&test_rom()).is_none());
+    }
+
+    #[test]
+    fn test_builtin_database() {
+        let db = RomDatabase::builtin();
+        assert!(!db.is_empty());
+
+        // Super Mario Bros. dumped with horizontal mirroring and a battery.
+        // The hash stands in for the real PRG-ROM and CHR-ROM.
+        let mut data = vec![
+            b'N', b'E', b'S', 0x1A, 2, 1, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ];
+        data.resize(16 + 32 * 1024 + 8 * 1024, 0);
+        let mut rom = NesFile::from_stream(&mut &data[..]).unwrap();
+        let entry = db.lookup_hashes("", 0x3337EC46).unwrap();
+        assert_eq!(entry.name.as_deref(), Some("Super Mario Bros. (World)"));
+
+        let fields: Vec<_> = entry
+            .apply(&mut rom)
+            .iter()
+            .map(|change| change.field)
+            .collect();
+        assert_eq!(fields, ["nametable layout", "battery"]);
+        assert_eq!(rom.nametable_layout, NametableLayout::Horizontal);
+        assert!(!rom.nvram_present);
+    }
+
+    #[test]
+    fn test_toml_entry_needs_key() {
+        let result = RomDatabase::from_toml("[[game]]\nmapper = 1\n");
+        assert!(matches!(
+            result,
+            Err(RomDbError::InvalidEntry { entry: 0, .. })
+        ));
+    }
+}
@@ -0,0 +1,332 @@
+use std::{collections::BTreeMap, io::Read};
+
+use crate::nes_file::{
+    ConsoleType, HeaderVersion, MapperId, NametableLayout, NesFile, NesFileError, NesFileSection,
+    TimingMode, read_exact_to_vec,
+};
+
+/// Board names, without their "NES-"/"UNL-"/etc. prefix, and the NES 2.0
+/// mapper and submapper they correspond to
+const UNIF_BOARDS: &[(&str, u16, u8)] = &[
+    ("NROM", 0, 0),
+    ("NROM-128", 0, 0),
+    ("NROM-256", 0, 0),
+    ("RROM", 0, 0),
+    ("SAROM", 1, 0),
+    ("SBROM", 1, 0),
+    ("SCROM", 1, 0),
+    ("SEROM", 1, 5),
+    ("SGROM", 1, 0),
+    ("SKROM", 1, 0),
+    ("SLROM", 1, 0),
+    ("SL1ROM", 1, 0),
+    ("SNROM", 1, 0),
+    ("SOROM", 1, 0),
+    ("SUROM", 1, 0),
+    ("SXROM", 1, 0),
+    ("UNROM", 2, 0),
+    ("UOROM", 2, 0),
+    ("CNROM", 3, 0),
+    ("TBROM", 4, 0),
+    ("TEROM", 4, 0),
+    ("TFROM", 4, 0),
+    ("TGROM", 4, 0),
+    ("TKROM", 4, 0),
+    ("TLROM", 4, 0),
+    ("TL1ROM", 4, 0),
+    ("TR1ROM", 4, 0),
+    ("TSROM", 4, 0),
+    ("TVROM", 4, 0),
+    ("HKROM", 4, 1),
+    ("EKROM", 5, 0),
+    ("ELROM", 5, 0),
+    ("ETROM", 5, 0),
+    ("EWROM", 5, 0),
+    ("AMROM", 7, 0),
+    ("ANROM", 7, 0),
+    ("AOROM", 7, 0),
+    ("PNROM", 9, 0),
+    ("FJROM", 10, 0),
+    ("FKROM", 10, 0),
+    ("CPROM", 13, 0),
+    ("SL1632", 14, 0),
+    ("BNROM", 34, 2),
+    ("NINA-001", 34, 1),
+    ("SC-127", 35, 0),
+    ("MARIO1-MALEE2", 55, 0),
+    ("GNROM", 66, 0),
+    ("MHROM", 66, 0),
+    ("TEK90", 90, 0),
+    ("BB", 108, 0),
+    ("TLSROM", 118, 0),
+    ("TKSROM", 118, 0),
+    ("TQROM", 119, 0),
+    ("H2288", 123, 0),
+    ("22211", 132, 0),
+    ("SACHEN-8259D", 137, 0),
+    ("SACHEN-8259B", 138, 0),
+    ("SACHEN-8259C", 139, 0),
+    ("SACHEN-8259A", 141, 0),
+    ("KS7032", 142, 0),
+    ("SA-NROM", 143, 0),
+    ("SA-72007", 145, 0),
+    ("SACHEN-74LS374N", 150, 0),
+    ("FK23C", 176, 0),
+    ("8237", 215, 0),
+    ("42IN1RESETSWITCH", 233, 0),
+    ("70IN1", 236, 0),
+    ("603-5052", 238, 0),
+    ("SHERO", 262, 0),
+    ("KOF97", 263, 0),
+    ("YOKO", 264, 0),
+    ("T-262", 265, 0),
+    ("CITYFIGHT", 266, 0),
+    ("GS-2004", 283, 0),
+    ("A65AS", 285, 0),
+    ("BS-5", 286, 0),
+    ("13IN1JY110", 295, 0),
+    ("TF1201", 298, 0),
+    ("190IN1", 300, 0),
+    ("8157", 301, 0),
+    ("KS7057", 302, 0),
+    ("SMB2J", 304, 0),
+    ("64IN1NOREPEAT", 314, 0),
+    ("MALISB", 325, 0),
+    ("EDU2000", 329, 0),
+];
+
+/// Prefixes that UNIF board names carry to show who made the board
+const UNIF_BOARD_PREFIXES: &[&str] = &["NES-", "HVC-", "UNL-", "BMC-", "BTL-"];
+
+/// Look up the mapper for a UNIF board name. Matching ignores case and the
+/// manufacturer prefix.
+pub fn unif_board_mapper(board: &str) -> Option<MapperId> {
+    let board = board.to_ascii_uppercase();
+    let name = UNIF_BOARD_PREFIXES
+        .iter()
+        .find_map(|prefix| board.strip_prefix(prefix))
+        .unwrap_or(&board);
+    UNIF_BOARDS
+        .iter()
+        .find(|(known, _, _)| *known == name)
+        .map(|(_, id, sub_id)| MapperId {
+            id: *id,
+            sub_id: *sub_id,
+        })
+}
+
+impl NesFile {
+    /// Read a UNIF file. The header fields that UNIF can't express, such as
+    /// PRG-RAM size, get the same defaults as an iNES 1.0 header.
+    pub fn from_unif_stream(reader: &mut dyn Read) -> Result<Self, NesFileError> {
+        // "UNIF", a 32-bit revision number, and 24 reserved bytes
+        let header = read_exact_to_vec(reader, 32, NesFileSection::Header)?;
+        if &header[0..4] != b"UNIF" {
+            return Err(NesFileError::BadMagic(header[0..4].try_into().unwrap()));
+        }
+
+        let mut board = None;
+        let mut prg_chunks = BTreeMap::new();
+        let mut chr_chunks = BTreeMap::new();
+        let mut nametable_layout = NametableLayout::Vertical;
+        let mut nvram_present = false;
+        let mut timing = TimingMode::NTSC;
+        loop {
+            let mut chunk_header = [0u8; 8];
+            let read = reader.read(&mut chunk_header)?;
+            if read == 0 {
+                break;
+            }
+            reader
+                .read_exact(&mut chunk_header[read..])
+                .map_err(|_| NesFileError::Truncated {
+                    section: NesFileSection::UnifChunk,
+                    expected: 8,
+                    actual: read,
+                })?;
+            let id: [u8; 4] = chunk_header[0..4].try_into().unwrap();
+            let len = u32::from_le_bytes(chunk_header[4..8].try_into().unwrap()) as usize;
+            let data = read_exact_to_vec(reader, len, NesFileSection::UnifChunk)?;
+
+            match &id {
+                b"MAPR" => {
+                    let name = data.split(|b| *b == 0).next().unwrap_or_default();
+                    board = Some(String::from_utf8_lossy(name).trim().to_string());
+                }
+                [b'P', b'R', b'G', n] | [b'C', b'H', b'R', n] if n.is_ascii_hexdigit() => {
+                    let index = (*n as char).to_digit(16).unwrap();
+                    let chunks = if id[0] == b'P' {
+                        &mut prg_chunks
+                    } else {
+                        &mut chr_chunks
+                    };
+                    chunks.insert(index, data);
+                }
+                b"MIRR" => {
+                    // Modes 2 and 3 (single screen) and 5 (mapper controlled)
+                    // are handled by the mapper itself
+                    nametable_layout = match data.first() {
+                        Some(0) => NametableLayout::Vertical,
+                        Some(1) => NametableLayout::Horizontal,
+                        Some(4) => NametableLayout::AlternateVertical,
+                        _ => NametableLayout::Vertical,
+                    };
+                }
+                b"BATR" => nvram_present = data.first().is_none_or(|b| *b != 0),
+                b"TVCI" => {
+                    timing = match data.first() {
+                        Some(1) => TimingMode::PAL,
+                        Some(2) => TimingMode::MultiRegion,
+                        _ => TimingMode::NTSC,
+                    };
+                }
+                // Names, dump information, checksums and so on aren't needed
+                _ => {}
+            }
+        }
+
+        let board = board.ok_or(NesFileError::MissingUnifChunk("MAPR"))?;
+        let mapper =
+            unif_board_mapper(&board).ok_or(NesFileError::UnknownUnifBoard(board.clone()))?;
+        let prg_rom: Vec<u8> = prg_chunks.into_values().flatten().collect();
+        let chr_rom: Vec<u8> = chr_chunks.into_values().flatten().collect();
+        if prg_rom.is_empty() {
+            return Err(NesFileError::MissingUnifChunk("PRG0"));
+        }
+
+        let (prg_ram_size, prg_nvram_size) = if nvram_present {
+            (0, 8 * 1024)
+        } else {
+            (8 * 1024, 0)
+        };
+        let chr_ram_size = if chr_rom.is_empty() { 8 * 1024 } else { 0 };
+
+        Ok(NesFile {
+            header_version: HeaderVersion::Unif,
+            nametable_layout,
+            nvram_present,
+            mapper,
+            timing,
+            console_type: ConsoleType::NES,
+            misc_rom_count: 0,
+            default_expansion_device: 0,
+            prg_ram_size,
+            prg_nvram_size,
+            chr_ram_size,
+            chr_nvram_size: 0,
+            trainer: None,
+            prg_rom,
+            chr_rom,
+            misc_rom: Vec::new(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn build_unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut data = b"UNIF".to_vec();
+        data.extend_from_slice(&7u32.to_le_bytes());
+        data.resize(32, 0);
+        for chunk in chunks {
+            data.extend_from_slice(chunk);
+        }
+        data
+    }
+
+    #[test]
+    fn test_unif_file() {
+        let data = build_unif(&[
+            chunk(b"MAPR", b"NES-TLROM\0"),
+            chunk(b"NAME", b"Test\0"),
+            // Chunks are concatenated in numeric order, not file order
+            chunk(b"PRG1", &[0x22; 0x4000]),
+            chunk(b"PRG0", &[0x11; 0x4000]),
+            chunk(b"CHR0", &[0x33; 0x2000]),
+            chunk(b"MIRR", &[1]),
+            chunk(b"BATR", &[1]),
+            chunk(b"TVCI", &[1]),
+        ]);
+        let rom = NesFile::load(&mut &data[..]).unwrap();
+        assert_eq!(rom.header_version, HeaderVersion::Unif);
+        assert_eq!(rom.mapper, MapperId { id: 4, sub_id: 0 });
+        assert_eq!(rom.prg_rom.len(), 0x8000);
+        assert_eq!(rom.prg_rom[0], 0x11);
+        assert_eq!(rom.prg_rom[0x4000], 0x22);
+        assert_eq!(rom.chr_rom, [0x33; 0x2000]);
+        assert_eq!(rom.chr_ram_size, 0);
+        assert_eq!(rom.nametable_layout, NametableLayout::Horizontal);
+        assert!(rom.nvram_present);
+        assert_eq!(rom.prg_nvram_size, 8 * 1024);
+        assert_eq!(rom.timing, TimingMode::PAL);
+        assert!(rom.validate().is_empty());
+
+        // UNIF files are converted to NES 2.0 when written
+        let mut output = Vec::new();
+        rom.write_to(&mut output).unwrap();
+        let converted = NesFile::load(&mut &output[..]).unwrap();
+        assert_eq!(converted.header_version, HeaderVersion::Nes2);
+        assert_eq!(converted.mapper, rom.mapper);
+        assert_eq!(converted.prg_rom, rom.prg_rom);
+    }
+
+    #[test]
+    fn test_chr_ram_without_chr_chunks() {
+        let data = build_unif(&[
+            chunk(b"MAPR", b"UNL-SA-NROM\0"),
+            chunk(b"PRG0", &[0; 0x8000]),
+        ]);
+        let rom = NesFile::load(&mut &data[..]).unwrap();
+        assert_eq!(rom.mapper, MapperId { id: 143, sub_id: 0 });
+        assert_eq!(rom.chr_ram_size, 8 * 1024);
+        assert_eq!(rom.prg_ram_size, 8 * 1024);
+    }
+
+    #[test]
+    fn test_board_names() {
+        assert_eq!(
+            unif_board_mapper("BMC-FK23C"),
+            Some(MapperId { id: 176, sub_id: 0 })
+        );
+        assert_eq!(
+            unif_board_mapper("nes-sxrom"),
+            Some(MapperId { id: 1, sub_id: 0 })
+        );
+        assert_eq!(unif_board_mapper("UNL-NotARealBoard"), None);
+    }
+
+    #[test]
+    fn test_unif_errors() {
+        let data = build_unif(&[chunk(b"MAPR", b"UNL-Mystery\0"), chunk(b"PRG0", &[0; 16])]);
+        assert!(matches!(
+            NesFile::load(&mut &data[..]),
+            Err(NesFileError::UnknownUnifBoard(board)) if board == "UNL-Mystery"
+        ));
+
+        let data = build_unif(&[chunk(b"PRG0", &[0; 16])]);
+        assert!(matches!(
+            NesFile::load(&mut &data[..]),
+            Err(NesFileError::MissingUnifChunk("MAPR"))
+        ));
+
+        let mut data = build_unif(&[chunk(b"MAPR", b"NES-NROM-256\0"), chunk(b"PRG0", &[0; 16])]);
+        data.truncate(data.len() - 4);
+        assert!(matches!(
+            NesFile::load(&mut &data[..]),
+            Err(NesFileError::Truncated {
+                section: NesFileSection::UnifChunk,
+                expected: 16,
+                actual: 12,
+            })
+        ));
+    }
+}
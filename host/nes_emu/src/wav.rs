@@ -0,0 +1,47 @@
+use std::io::{self, Write};
+
+/// Write samples as a 16-bit mono PCM WAV file. Samples are clipped to the
+/// range -1.0 to 1.0.
+pub fn write_wav(writer: &mut dyn Write, sample_rate: u32, samples: &[f32]) -> io::Result<()> {
+    let data_len = (samples.len() * 2) as u32;
+    let mut header = Vec::with_capacity(44);
+    header.extend_from_slice(b"RIFF");
+    header.extend_from_slice(&(36 + data_len).to_le_bytes());
+    header.extend_from_slice(b"WAVE");
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&16u32.to_le_bytes());
+    // PCM, 1 channel
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&1u16.to_le_bytes());
+    header.extend_from_slice(&sample_rate.to_le_bytes());
+    header.extend_from_slice(&(sample_rate * 2).to_le_bytes());
+    // Block alignment, bits per sample
+    header.extend_from_slice(&2u16.to_le_bytes());
+    header.extend_from_slice(&16u16.to_le_bytes());
+    header.extend_from_slice(b"data");
+    header.extend_from_slice(&data_len.to_le_bytes());
+    writer.write_all(&header)?;
+
+    let data: Vec<u8> = samples
+        .iter()
+        .flat_map(|sample| ((sample.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes())
+        .collect();
+    writer.write_all(&data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_write_wav() {
+        let mut out = Vec::new();
+        write_wav(&mut out, 44100, &[0.0, 1.0, -2.0]).unwrap();
+        assert_eq!(out.len(), 44 + 6);
+        assert_eq!(&out[0..4], b"RIFF");
+        assert_eq!(u32::from_le_bytes(out[4..8].try_into().unwrap()), 42);
+        assert_eq!(u32::from_le_bytes(out[24..28].try_into().unwrap()), 44100);
+        assert_eq!(u32::from_le_bytes(out[40..44].try_into().unwrap()), 6);
+        assert_eq!(&out[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
@@ -1,8 +1,8 @@
 use std::{env, fs::File, path::PathBuf};
 
 use nes_emu::{
-    components::{EmuError, tracer::Tracer},
-    nes::NESSystem,
+    components::tracer::Tracer,
+    nes::{NESSystem, StopReason},
     nes_file::NesFile,
 };
 
@@ -13,11 +13,17 @@ fn run_test_rom(rom_path: &str, tick_limit: u64) {
     path.push("tests/nes-test-roms");
     path.push(rom_path);
 
-    let mut rom_file = File::open(&path).expect("Failed to open ROM file");
+    let mut rom_file = File::open(&path).unwrap_or_else(|e| {
+        panic!(
+            "Failed to open {}: {} (the ROMs come from the tests/nes-test-roms submodule)",
+            path.display(),
+            e
+        )
+    });
     let rom = NesFile::from_stream(&mut rom_file).expect("Failed to read NES file");
 
     let tracer = Tracer::new::<&str>(&[], None);
-    let mut nes = NESSystem::new(&tracer, rom);
+    let mut nes = NESSystem::new(&tracer, rom).unwrap();
 
     let run_result = (|| {
         nes.start_simulation()?;
@@ -26,7 +32,7 @@ fn run_test_rom(rom_path: &str, tick_limit: u64) {
 
     nes.end_simulation();
 
-    assert_eq!(run_result.err(), Some(EmuError::StopEmulation));
+    assert_eq!(run_result, Ok(StopReason::TestPassed));
 }
 
 #[test]
@@ -93,3 +99,73 @@ fn test_cpu_reset_registers() {
 fn test_cpu_reset_ram() {
     run_test_rom("cpu_reset/ram_after_reset.nes", 4_046_000);
 }
+
+#[test]
+fn test_apu_reset_4015_cleared() {
+    run_test_rom("apu_reset/4015_cleared.nes", 5_000_000);
+}
+
+#[test]
+fn test_apu_reset_4017_timing() {
+    run_test_rom("apu_reset/4017_timing.nes", 8_000_000);
+}
+
+#[test]
+fn test_apu_reset_4017_written() {
+    run_test_rom("apu_reset/4017_written.nes", 8_000_000);
+}
+
+#[test]
+fn test_apu_reset_irq_flag_cleared() {
+    run_test_rom("apu_reset/irq_flag_cleared.nes", 5_000_000);
+}
+
+#[test]
+fn test_apu_reset_len_ctrs_enabled() {
+    run_test_rom("apu_reset/len_ctrs_enabled.nes", 5_000_000);
+}
+
+#[test]
+fn test_apu_reset_works_immediately() {
+    run_test_rom("apu_reset/works_immediately.nes", 5_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_01_vbl_basics() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/01-vbl_basics.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_02_vbl_set_time() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/02-vbl_set_time.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_03_vbl_clear_time() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/03-vbl_clear_time.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_04_nmi_control() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/04-nmi_control.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_05_nmi_timing() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/05-nmi_timing.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_06_suppression() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/06-suppression.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_07_nmi_on_timing() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/07-nmi_on_timing.nes", 10_000_000);
+}
+
+#[test]
+fn test_ppu_vbl_nmi_08_nmi_off_timing() {
+    run_test_rom("ppu_vbl_nmi/rom_singles/08-nmi_off_timing.nes", 10_000_000);
+}